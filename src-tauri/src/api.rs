@@ -1,34 +1,45 @@
 use crate::core::accounts::{create_account, delete_account, get_accounts};
-use crate::core::auth::{handle_oauth_callback, start_oauth_flow, AppState, OAuth2StateMap};
-use crate::core::config::{load_config, save_config};
+use crate::core::auth::{
+    start_device_flow, start_oauth_flow, AppState, OAuth2StateMap, SignInPrompt,
+};
+use crate::core::config::{load_config, load_config_info, save_config};
 use crate::core::export::export_account;
+use crate::core::providers::{self, client_for_provider, ProviderDefinition, ProviderRegistry};
 use crate::core::validation;
 use crate::error::DEmailError;
-use crate::models::{Account, OAuthProviderConfig};
+use crate::models::{Account, OAuthProviderConfig, OAuthProviderInfo};
 use std::collections::HashMap;
 use tauri::{AppHandle, Manager, State};
 
+/// Common guard for commands that return or change mail data: refused while the app
+/// lock is engaged
+fn ensure_unlocked(app_state: &AppState) -> Result<(), DEmailError> {
+    app_state.app_lock.ensure_unlocked()
+}
+
+/// Starts signing in to an account. With `device_code`, the user enters a code on
+/// another device instead of being redirected back to DEmail by the browser.
 #[tauri::command]
 pub async fn add_account(
     app_handle: AppHandle,
     email_address: String,
-) -> Result<String, DEmailError> {
+    device_code: Option<bool>,
+) -> Result<SignInPrompt, DEmailError> {
     validation::validate_email(&email_address)?;
-    start_oauth_flow(app_handle, email_address).await
-}
-
-#[tauri::command]
-pub async fn handle_callback(
-    app_handle: AppHandle,
-    code: String,
-    state: String,
-) -> Result<Account, DEmailError> {
-    handle_oauth_callback(app_handle, code, state).await
+    ensure_unlocked(&app_handle.state::<AppState>())?;
+    if device_code.unwrap_or(false) {
+        let authorization = start_device_flow(app_handle, email_address).await?;
+        Ok(SignInPrompt::DeviceCode(authorization))
+    } else {
+        let auth_url = start_oauth_flow(app_handle, email_address).await?;
+        Ok(SignInPrompt::Browser { auth_url })
+    }
 }
 
 #[tauri::command]
 pub fn get_accounts(app_handle: AppHandle) -> Result<Vec<Account>, DEmailError> {
     let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
     let pool = &app_state.db_pool;
     get_accounts(pool)
 }
@@ -36,7 +47,11 @@ pub fn get_accounts(app_handle: AppHandle) -> Result<Vec<Account>, DEmailError>
 #[tauri::command]
 pub fn delete_account(app_handle: AppHandle, account_id: i64) -> Result<(), DEmailError> {
     let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
     let pool = &app_state.db_pool;
+    app_state.imap_pool.discard(account_id);
+    app_state.token_manager.invalidate(account_id);
+    app_state.draft_sync.discard(account_id);
     delete_account(pool, account_id)
 }
 
@@ -47,6 +62,7 @@ pub fn save_oauth_provider_config(
     config: OAuthProviderConfig,
 ) -> Result<(), DEmailError> {
     let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
     let app_config = app_state.app_config.lock().unwrap();
     let mut configs = load_config(&app_config)?;
     configs.insert(provider, config);
@@ -56,10 +72,43 @@ pub fn save_oauth_provider_config(
 #[tauri::command]
 pub fn get_oauth_provider_configs(
     app_handle: AppHandle,
-) -> Result<HashMap<String, OAuthProviderConfig>, DEmailError> {
+) -> Result<HashMap<String, OAuthProviderInfo>, DEmailError> {
+    let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
+    let app_config = app_state.app_config.lock().unwrap();
+    load_config_info(&app_config)
+}
+
+#[tauri::command]
+pub fn list_oauth_providers(
+    app_handle: AppHandle,
+) -> Result<Vec<ProviderDefinition>, DEmailError> {
+    let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
+    let app_config = app_state.app_config.lock().unwrap();
+    Ok(ProviderRegistry::load(&app_config)?.providers().to_vec())
+}
+
+#[tauri::command]
+pub async fn save_custom_oauth_provider(
+    app_handle: AppHandle,
+    provider: ProviderDefinition,
+) -> Result<ProviderDefinition, DEmailError> {
+    let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
+    let app_config = app_state.app_config.lock().unwrap().clone();
+    providers::save_custom_provider(&app_config, provider).await
+}
+
+#[tauri::command]
+pub fn delete_custom_oauth_provider(
+    app_handle: AppHandle,
+    provider_id: String,
+) -> Result<(), DEmailError> {
     let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
     let app_config = app_state.app_config.lock().unwrap();
-    load_config(&app_config)
+    providers::delete_custom_provider(&app_config, &provider_id)
 }
 
 #[tauri::command]
@@ -68,6 +117,7 @@ pub fn get_folders(
     account_id: i64,
 ) -> Result<Vec<crate::models::Folder>, DEmailError> {
     let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
     let pool = &app_state.db_pool;
     let conn = pool
         .get()
@@ -100,13 +150,18 @@ pub fn get_messages(
     folder_id: i64,
 ) -> Result<Vec<crate::models::MessageHeader>, DEmailError> {
     let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
     let pool = &app_state.db_pool;
     let conn = pool
         .get()
         .map_err(|_| DEmailError::Database(rusqlite::Error::InvalidQuery))?;
 
     let mut stmt = conn.prepare(
-        "SELECT id, subject, from_header, date, is_read, has_attachments, is_starred FROM messages WHERE folder_id = ?1 ORDER BY date DESC",
+        "SELECT m.id, m.subject, m.from_header, m.date, m.is_read, m.has_attachments, m.is_starred
+         FROM messages m JOIN message_locations l ON l.message_id = m.id
+         WHERE l.folder_id = ?1
+           AND NOT EXISTS (SELECT 1 FROM snoozes s WHERE s.message_id = m.id AND s.kind = 'snooze')
+         ORDER BY MAX(m.date, COALESCE(m.resurfaced_at, 0)) DESC",
     )?;
     let message_iter = stmt.query_map([&folder_id], |row| {
         Ok(crate::models::MessageHeader {
@@ -133,6 +188,7 @@ pub fn get_message_details(
     message_id: i64,
 ) -> Result<crate::models::Message, DEmailError> {
     let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
     let pool = &app_state.db_pool;
     let conn = pool
         .get()
@@ -163,16 +219,44 @@ pub fn get_message_details(
             is_starred: row.get(15)?,
             thread_id: row.get(16)?,
             attachments: Vec::new(),
+            security: None,
+            authentication: None,
+            remote_content: None,
         })
     })?;
 
     message.attachments = crate::core::cache::db::get_attachments_for_message(pool, message_id)?;
+    message.security = crate::core::crypto::get_message_security(&conn, message_id)?;
+    // Decrypted bodies are not cached by default; messages that decrypt without a
+    // passphrase are decrypted again for display
+    let decrypted_on_sync = message.security.as_ref().is_some_and(|security| {
+        security.is_encrypted
+            && security.decryption_status == crate::core::crypto::DECRYPTION_DECRYPTED
+    });
+    if decrypted_on_sync && !crate::core::crypto::cache_decrypted_bodies(&conn) {
+        if let Ok(decrypted) = crate::core::crypto::decrypt_stored_message(&conn, message_id, "")
+        {
+            show_decrypted(&mut message, decrypted);
+        }
+    }
+    message.authentication =
+        crate::core::sender_auth::get_message_authentication(&conn, message_id)?;
+    crate::core::remote_content::render_message(&conn, &mut message, false)?;
 
     Ok(message)
 }
 
-use crate::core::accounts::get_refresh_token;
-use oauth2::{RefreshToken, TokenResponse};
+/// Shows decrypted bodies in place of the stored ciphertext
+fn show_decrypted(
+    message: &mut crate::models::Message,
+    decrypted: crate::core::crypto::IncomingSecurity,
+) {
+    if decrypted.body_plain.is_some() || decrypted.body_html.is_some() {
+        message.body_plain = decrypted.body_plain;
+        message.body_html = crate::core::cache::db::sanitize_html(&decrypted.body_html);
+    }
+    message.security = Some(decrypted.security);
+}
 
 #[tauri::command]
 pub async fn send_email(
@@ -181,6 +265,9 @@ pub async fn send_email(
     to: String,
     subject: String,
     body: String,
+    security: Option<crate::core::crypto::OutgoingSecurity>,
+    identity_id: Option<i64>,
+    draft_id: Option<i64>,
 ) -> Result<(), DEmailError> {
     // Validate inputs
     validation::validate_email(&to)?;
@@ -188,6 +275,7 @@ pub async fn send_email(
     validation::validate_body(&body)?;
 
     let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
     let pool = &app_state.db_pool;
     let conn = pool
         .get()
@@ -201,49 +289,90 @@ pub async fn send_email(
         Ok((provider, email))
     })?;
 
-    let message = lettre::Message::builder()
-        .from(email_address.parse().map_err(|_| {
-            DEmailError::Smtp(lettre::transport::smtp::Error::from("Invalid from address"))
-        })?)
+    // Send as the chosen identity, or the account's default one when there is one
+    let identity = crate::core::identities::resolve_identity(&conn, account_id, identity_id)?;
+    let (from, from_address) = match &identity {
+        Some(identity) => (identity.mailbox()?, identity.email_address.clone()),
+        None => (
+            email_address.parse().map_err(|_| {
+                DEmailError::Smtp(lettre::transport::smtp::Error::from("Invalid from address"))
+            })?,
+            email_address.clone(),
+        ),
+    };
+
+    let mut builder = lettre::Message::builder()
+        .from(from)
         .to(to.parse().map_err(|_| {
             DEmailError::Smtp(lettre::transport::smtp::Error::from("Invalid to address"))
         })?)
-        .subject(subject)
-        .body(body)
-        .map_err(|_| DEmailError::Smtp(lettre::transport::smtp::Error::from("Invalid message")))?;
+        .subject(subject);
+    if let Some(reply_to) = identity
+        .as_ref()
+        .map(|identity| identity.reply_to_mailbox())
+        .transpose()?
+        .flatten()
+    {
+        builder = builder.reply_to(reply_to);
+    }
 
-    let app_config = app_state.app_config.lock().unwrap();
-    let configs = load_config(&app_config)?;
-    let provider_config = configs
-        .get(&provider)
-        .ok_or_else(|| DEmailError::Config(format!("OAuth config for {} not found", provider)))?;
-
-    let client = match provider.as_str() {
-        "google" => crate::core::auth::get_google_client(
-            provider_config.client_id.clone(),
-            provider_config.client_secret.clone(),
-        )?,
-        "microsoft" => crate::core::auth::get_microsoft_client(
-            provider_config.client_id.clone(),
-            provider_config.client_secret.clone(),
-        )?,
-        _ => {
-            return Err(DEmailError::Smtp(lettre::transport::smtp::Error::from(
-                "Unsupported provider".to_string(),
-            )))
-        }
+    // Files stored with the draft being sent go along as attachments
+    let attachments = match draft_id {
+        Some(draft_id) => crate::core::drafts::draft_attachment_contents(&conn, draft_id)?,
+        None => Vec::new(),
     };
 
-    let refresh_token = get_refresh_token(account_id)?;
-    let token_result = client
-        .exchange_refresh_token(&RefreshToken::new(refresh_token))
-        .request_async(oauth2::reqwest::async_http_client)
-        .await
-        .map_err(|e| DEmailError::OAuth(e.to_string()))?;
+    // Sign and/or encrypt the body when requested in compose
+    let message = match security.filter(|s| s.sign || s.encrypt) {
+        Some(_) if !attachments.is_empty() => {
+            return Err(DEmailError::Validation(
+                "Messages with attachments cannot be signed or encrypted yet".to_string(),
+            ))
+        }
+        Some(security) => {
+            let protected = crate::core::crypto::protect_outgoing(
+                &conn,
+                &from_address,
+                &[to.clone()],
+                &body,
+                &security,
+            )?;
+            protected.build(builder)
+        }
+        None if attachments.is_empty() => builder.body(body),
+        None => builder.multipart(crate::core::drafts::with_attachments(body, attachments)),
+    }
+    .map_err(|_| DEmailError::Smtp(lettre::transport::smtp::Error::from("Invalid message")))?;
+
+    // An identity with its own SMTP server signs in there with its stored password
+    if let Some(identity) = &identity {
+        if let (Some(smtp), Some(username)) = (&identity.smtp, identity.smtp_username()) {
+            let password = crate::core::identities::get_smtp_password(identity.id)?;
+            return crate::core::sync::smtp_send::send_email_with_password(
+                message,
+                &smtp.endpoint(),
+                &username,
+                &password,
+            )
+            .await;
+        }
+    }
+
+    let app_config = app_state.app_config.lock().unwrap().clone();
+    let (provider, client) = client_for_provider(&app_config, &provider)?;
 
-    let access_token = token_result.access_token();
+    let access_token = app_state
+        .token_manager
+        .access_token(pool, account_id, &client)
+        .await?;
 
-    crate::core::sync::smtp_send::send_email(message, &provider, &email_address, access_token).await
+    crate::core::sync::smtp_send::send_email(
+        message,
+        &provider.smtp,
+        &email_address,
+        &access_token,
+    )
+    .await
 }
 
 #[tauri::command]
@@ -253,18 +382,22 @@ pub fn start_export(
     destination_path: String,
 ) -> Result<(), DEmailError> {
     let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
     let pool = &app_state.db_pool;
     let conn = pool
         .get()
         .map_err(|_| DEmailError::Database(rusqlite::Error::InvalidQuery))?;
 
-    let mut stmt = conn.prepare("SELECT * FROM accounts WHERE id = ?1")?;
+    let mut stmt = conn.prepare(
+        "SELECT id, email_address, display_name, provider_type, needs_reauth FROM accounts WHERE id = ?1",
+    )?;
     let account = stmt.query_row([&account_id], |row| {
         Ok(Account {
             id: row.get(0)?,
             email_address: row.get(1)?,
             display_name: row.get(2)?,
             provider_type: row.get(3)?,
+            needs_reauth: row.get(4)?,
         })
     })?;
 
@@ -274,6 +407,7 @@ pub fn start_export(
 #[tauri::command]
 pub fn mark_message_read(app_handle: AppHandle, message_id: i64) -> Result<(), DEmailError> {
     let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
     let pool = &app_state.db_pool;
     crate::core::cache::db::update_message_read_status(pool, message_id, true)
 }
@@ -281,6 +415,7 @@ pub fn mark_message_read(app_handle: AppHandle, message_id: i64) -> Result<(), D
 #[tauri::command]
 pub fn mark_message_unread(app_handle: AppHandle, message_id: i64) -> Result<(), DEmailError> {
     let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
     let pool = &app_state.db_pool;
     crate::core::cache::db::update_message_read_status(pool, message_id, false)
 }
@@ -288,377 +423,1553 @@ pub fn mark_message_unread(app_handle: AppHandle, message_id: i64) -> Result<(),
 #[tauri::command]
 pub async fn refresh_account(app_handle: AppHandle, account_id: i64) -> Result<(), DEmailError> {
     let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
     let pool = &app_state.db_pool;
-    let conn = pool
-        .get()
-        .map_err(|_| DEmailError::Database(rusqlite::Error::InvalidQuery))?;
-
-    let mut stmt =
-        conn.prepare("SELECT provider_type, email_address FROM accounts WHERE id = ?1")?;
-    let (provider, email_address) = stmt.query_row([&account_id], |row| {
-        let provider: String = row.get(0)?;
-        let email: String = row.get(1)?;
-        Ok((provider, email))
-    })?;
-    drop(stmt);
-    drop(conn);
-
-    let app_config = app_state.app_config.lock().unwrap();
-    let configs = load_config(&app_config)?;
-    let provider_config = configs
-        .get(&provider)
-        .ok_or_else(|| DEmailError::Config(format!("OAuth config for {} not found", provider)))?;
-
-    let client = match provider.as_str() {
-        "google" => crate::core::auth::get_google_client(
-            provider_config.client_id.clone(),
-            provider_config.client_secret.clone(),
-        )?,
-        "microsoft" => crate::core::auth::get_microsoft_client(
-            provider_config.client_id.clone(),
-            provider_config.client_secret.clone(),
-        )?,
-        _ => {
-            return Err(DEmailError::Api(format!(
-                "Unsupported provider: {}",
-                provider
-            )))
-        }
-    };
-    drop(app_config);
-
-    let app_state_arc = std::sync::Arc::new(AppState {
-        db_pool: app_state.db_pool.clone(),
-        app_config: app_state.app_config.clone(),
-    });
 
-    crate::core::sync::imap_sync::sync_account_manually(
-        app_state_arc,
-        account_id,
-        &provider,
-        &client,
-        &email_address,
-    )
-    .await
+    if !crate::core::accounts::get_accounts(pool)?
+        .iter()
+        .any(|account| account.id == account_id)
+    {
+        return Err(DEmailError::NotFound(format!(
+            "Account {} not found",
+            account_id
+        )));
+    }
+    // Handed to the scheduler so a manual sync never races the background one
+    let scheduler = app_state.sync_scheduler.clone();
+    scheduler.sync_now(account_id).await?;
+    Ok(())
 }
 
+/// Last sync outcome of every account, for the sync health view
 #[tauri::command]
-pub fn search_messages(
+pub fn get_sync_status(
     app_handle: AppHandle,
-    account_id: i64,
-    query: String,
-) -> Result<Vec<crate::models::MessageHeader>, DEmailError> {
-    validation::validate_search_query(&query)?;
-
+) -> Result<Vec<crate::models::AccountSyncStatus>, DEmailError> {
     let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
     let pool = &app_state.db_pool;
-    crate::core::cache::db::search_messages_fts(pool, account_id, &query)
+    crate::core::sync::status::get_sync_status(pool)
 }
 
+/// Sync intervals and when each account syncs next
 #[tauri::command]
-pub fn save_draft(app_handle: AppHandle, draft: crate::models::Draft) -> Result<i64, DEmailError> {
-    // Validate draft fields
-    validation::validate_subject(&draft.subject)?;
-    if let Some(ref body) = draft.body_plain {
-        validation::validate_body(body)?;
-    }
-    if let Some(ref body) = draft.body_html {
-        validation::validate_body(body)?;
-    }
-
+pub fn get_sync_schedule(
+    app_handle: AppHandle,
+) -> Result<crate::core::sync::scheduler::SyncSchedule, DEmailError> {
     let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
     let pool = &app_state.db_pool;
-    crate::core::drafts::create_or_update_draft(pool, &draft)
+    crate::core::sync::scheduler::get_schedule(&app_state.sync_scheduler, pool)
 }
 
+/// Sets the default sync interval, or one account's; 0 minutes syncs only on request
+/// and `None` makes an account use the default again
 #[tauri::command]
-pub fn get_drafts(
+pub fn set_sync_interval(
     app_handle: AppHandle,
-    account_id: i64,
-) -> Result<Vec<crate::models::Draft>, DEmailError> {
+    account_id: Option<i64>,
+    minutes: Option<u64>,
+) -> Result<(), DEmailError> {
     let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
     let pool = &app_state.db_pool;
-    crate::core::drafts::load_drafts_for_account(pool, account_id)
+    if account_id.is_none() && minutes.is_none() {
+        return Err(DEmailError::Validation(
+            "The default sync interval cannot be removed".to_string(),
+        ));
+    }
+    crate::core::sync::scheduler::set_interval(pool, account_id, minutes)?;
+    app_state.sync_scheduler.reschedule();
+    Ok(())
 }
 
+/// All rules, or the ones that apply to an account, in the order they run
 #[tauri::command]
-pub fn delete_draft(app_handle: AppHandle, draft_id: i64) -> Result<(), DEmailError> {
+pub fn list_rules(
+    app_handle: AppHandle,
+    account_id: Option<i64>,
+) -> Result<Vec<crate::core::rules::Rule>, DEmailError> {
     let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
     let pool = &app_state.db_pool;
-    crate::core::drafts::remove_draft(pool, draft_id)
+    crate::core::rules::list_rules(pool, account_id)
 }
 
+/// Creates a rule when its id is 0, otherwise updates it
 #[tauri::command]
-pub fn save_signature(
+pub fn save_rule(
     app_handle: AppHandle,
-    signature: crate::models::EmailSignature,
-) -> Result<i64, DEmailError> {
+    rule: crate::core::rules::Rule,
+) -> Result<crate::core::rules::Rule, DEmailError> {
     let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
     let pool = &app_state.db_pool;
-    crate::core::cache::db::save_signature(pool, &signature)
+    crate::core::rules::save_rule(pool, &rule)
 }
 
 #[tauri::command]
-pub fn get_signatures(
-    app_handle: AppHandle,
-    account_id: i64,
-) -> Result<Vec<crate::models::EmailSignature>, DEmailError> {
+pub fn delete_rule(app_handle: AppHandle, rule_id: i64) -> Result<(), DEmailError> {
     let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
     let pool = &app_state.db_pool;
-    crate::core::cache::db::get_signatures(pool, account_id)
+    crate::core::rules::delete_rule(pool, rule_id)
 }
 
+/// Lists the messages of a folder a rule would match, without running its actions
 #[tauri::command]
-pub fn delete_signature(app_handle: AppHandle, signature_id: i64) -> Result<(), DEmailError> {
+pub fn dry_run_rule(
+    app_handle: AppHandle,
+    rule: crate::core::rules::Rule,
+    folder_id: i64,
+) -> Result<Vec<crate::core::rules::RuleMatch>, DEmailError> {
     let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
     let pool = &app_state.db_pool;
-    crate::core::cache::db::delete_signature(pool, signature_id)
+    crate::core::rules::dry_run(pool, &rule, folder_id)
 }
 
+/// Runs the rules, or one rule, over a folder's messages and returns how many matched
 #[tauri::command]
-pub fn download_attachment(
+pub async fn run_rules_on_folder(
     app_handle: AppHandle,
-    attachment_id: i64,
-    destination_path: String,
-) -> Result<(), DEmailError> {
-    // Validate destination path
-    let dest_path = std::path::Path::new(&destination_path);
-    validation::sanitize_filename(
-        dest_path
-            .file_name()
-            .and_then(|n| n.to_str())
-            .ok_or_else(|| DEmailError::Validation("Invalid destination path".to_string()))?,
-    )?;
-
+    folder_id: i64,
+    rule_id: Option<i64>,
+) -> Result<usize, DEmailError> {
     let app_state = app_handle.state::<AppState>();
-    let pool = &app_state.db_pool;
-    let conn = pool
-        .get()
-        .map_err(|_| DEmailError::Database(rusqlite::Error::InvalidQuery))?;
+    ensure_unlocked(&app_state)?;
+    crate::core::sync::rule_actions::run_on_folder(&app_state, folder_id, rule_id).await
+}
 
-    let mut stmt = conn.prepare("SELECT * FROM attachments WHERE id = ?1")?;
-    let attachment = stmt.query_row([attachment_id], |row| {
-        Ok(crate::models::Attachment {
-            id: row.get(0)?,
-            message_id: row.get(1)?,
-            filename: row.get(2)?,
-            mime_type: row.get(3)?,
-            size_bytes: row.get(4)?,
-            local_path: row.get(5)?,
+/// Scripts on the account's ManageSieve server and the extensions it supports
+#[tauri::command]
+pub async fn list_sieve_scripts(
+    app_handle: AppHandle,
+    account_id: i64,
+) -> Result<crate::core::sieve::SieveOverview, DEmailError> {
+    let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
+    crate::core::sieve::with_client(&app_state, account_id, |client| {
+        Ok(crate::core::sieve::SieveOverview {
+            capabilities: client.capabilities().clone(),
+            scripts: client.list_scripts()?,
         })
-    })?;
-
-    crate::core::attachments::save_attachment_to_disk(&conn, &attachment, &destination_path)
+    })
+    .await
 }
 
 #[tauri::command]
-pub fn get_messages_paginated(
+pub async fn get_sieve_script(
     app_handle: AppHandle,
-    folder_id: i64,
-    limit: i64,
-    offset: i64,
-) -> Result<Vec<crate::models::MessageHeader>, DEmailError> {
-    validation::validate_pagination(limit, offset)?;
+    account_id: i64,
+    name: String,
+) -> Result<String, DEmailError> {
+    let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
+    crate::core::sieve::with_client(&app_state, account_id, move |client| {
+        client.get_script(&name)
+    })
+    .await
+}
 
+/// Uploads a script, optionally making it the active one
+#[tauri::command]
+pub async fn put_sieve_script(
+    app_handle: AppHandle,
+    account_id: i64,
+    name: String,
+    script: String,
+    activate: bool,
+) -> Result<(), DEmailError> {
+    if name.trim().is_empty() {
+        return Err(DEmailError::Validation("Script name is empty".to_string()));
+    }
     let app_state = app_handle.state::<AppState>();
-    let pool = &app_state.db_pool;
-    crate::core::cache::db::get_messages_paginated(pool, folder_id, limit, offset)
+    ensure_unlocked(&app_state)?;
+    crate::core::sieve::with_client(&app_state, account_id, move |client| {
+        crate::core::sieve::check_extensions(client, &script)?;
+        client.put_script(&name, &script)?;
+        if activate {
+            client.set_active(Some(&name))?;
+        }
+        Ok(())
+    })
+    .await
 }
 
+/// Has the server check a script without storing it; returns its warnings
 #[tauri::command]
-pub fn count_messages_in_folder(app_handle: AppHandle, folder_id: i64) -> Result<i64, DEmailError> {
+pub async fn check_sieve_script(
+    app_handle: AppHandle,
+    account_id: i64,
+    script: String,
+) -> Result<Option<String>, DEmailError> {
     let app_state = app_handle.state::<AppState>();
-    let pool = &app_state.db_pool;
-    crate::core::cache::db::count_messages_in_folder(pool, folder_id)
+    ensure_unlocked(&app_state)?;
+    crate::core::sieve::with_client(&app_state, account_id, move |client| {
+        crate::core::sieve::check_extensions(client, &script)?;
+        client.check_script(&script)
+    })
+    .await
 }
 
+/// Activates a script; `None` turns server-side filtering off
 #[tauri::command]
-pub fn delete_message(app_handle: AppHandle, message_id: i64) -> Result<(), DEmailError> {
+pub async fn activate_sieve_script(
+    app_handle: AppHandle,
+    account_id: i64,
+    name: Option<String>,
+) -> Result<(), DEmailError> {
     let app_state = app_handle.state::<AppState>();
-    let pool = &app_state.db_pool;
-    crate::core::cache::db::delete_message(pool, message_id)
+    ensure_unlocked(&app_state)?;
+    crate::core::sieve::with_client(&app_state, account_id, move |client| {
+        client.set_active(name.as_deref())
+    })
+    .await
 }
 
 #[tauri::command]
-pub fn move_message(
+pub async fn delete_sieve_script(
     app_handle: AppHandle,
-    message_id: i64,
-    target_folder_id: i64,
+    account_id: i64,
+    name: String,
 ) -> Result<(), DEmailError> {
     let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
+    crate::core::sieve::with_client(&app_state, account_id, move |client| {
+        client.delete_script(&name)
+    })
+    .await
+}
+
+/// Sieve script for the account's enabled rules, with an optional out-of-office reply
+#[tauri::command]
+pub fn generate_sieve_script(
+    app_handle: AppHandle,
+    account_id: i64,
+    vacation: Option<crate::core::sieve::script::VacationSettings>,
+) -> Result<String, DEmailError> {
+    let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
     let pool = &app_state.db_pool;
-    crate::core::cache::db::move_message(pool, message_id, target_folder_id)
+    let conn = pool
+        .get()
+        .map_err(|_| DEmailError::Database(rusqlite::Error::InvalidQuery))?;
+    let rules = crate::core::rules::rules_for_account(&conn, account_id)?;
+    crate::core::sieve::script::build_script(&rules, vacation.as_ref())
 }
 
+/// The account's tags with message and unread counts
 #[tauri::command]
-pub fn save_setting(app_handle: AppHandle, key: String, value: String) -> Result<(), DEmailError> {
+pub fn list_tags(
+    app_handle: AppHandle,
+    account_id: i64,
+) -> Result<Vec<crate::core::tags::TagCount>, DEmailError> {
     let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
     let pool = &app_state.db_pool;
-    crate::core::cache::db::save_setting(pool, &key, &value)
+    crate::core::tags::list_tags(pool, account_id)
 }
 
 #[tauri::command]
-pub fn get_setting(app_handle: AppHandle, key: String) -> Result<Option<String>, DEmailError> {
+pub fn get_message_tags(app_handle: AppHandle, message_id: i64) -> Result<Vec<String>, DEmailError> {
     let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
     let pool = &app_state.db_pool;
-    crate::core::cache::db::get_setting(pool, &key)
+    let conn = pool
+        .get()
+        .map_err(|_| DEmailError::Database(rusqlite::Error::InvalidQuery))?;
+    crate::core::tags::message_tags(&conn, message_id)
 }
 
 #[tauri::command]
-pub fn get_all_settings(
+pub fn get_messages_by_tag(
     app_handle: AppHandle,
-) -> Result<Vec<crate::models::AppSetting>, DEmailError> {
+    account_id: i64,
+    tag: String,
+) -> Result<Vec<crate::models::MessageHeader>, DEmailError> {
     let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
     let pool = &app_state.db_pool;
-    crate::core::cache::db::get_all_settings(pool)
+    crate::core::tags::messages_with_tag(pool, account_id, &tag)
 }
 
+/// Tags a message on the server and locally, returning its tags
 #[tauri::command]
-pub fn prepare_reply(
+pub async fn add_tag(
     app_handle: AppHandle,
     message_id: i64,
-    reply_all: bool,
-) -> Result<crate::core::reply_forward::ReplyData, DEmailError> {
-    let message = get_message_details(app_handle, message_id)?;
-    crate::core::reply_forward::prepare_reply(&message, reply_all)
+    tag: String,
+) -> Result<Vec<String>, DEmailError> {
+    let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
+    crate::core::sync::tag_sync::set_message_tag(&app_state, message_id, &tag, true).await
 }
 
+/// Removes a tag from a message on the server and locally, returning its tags
 #[tauri::command]
-pub fn prepare_forward(
+pub async fn remove_tag(
     app_handle: AppHandle,
     message_id: i64,
-) -> Result<crate::core::reply_forward::ForwardData, DEmailError> {
-    let message = get_message_details(app_handle, message_id)?;
-    crate::core::reply_forward::prepare_forward(&message)
+    tag: String,
+) -> Result<Vec<String>, DEmailError> {
+    let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
+    crate::core::sync::tag_sync::set_message_tag(&app_state, message_id, &tag, false).await
 }
 
-// ============================================================================
-// STARRING OPERATIONS
-// ============================================================================
-
+/// Hides a message until `until` (Unix timestamp), when it returns unread
 #[tauri::command]
-pub fn star_message(app_handle: AppHandle, message_id: i64) -> Result<(), DEmailError> {
+pub async fn snooze_message(
+    app_handle: AppHandle,
+    message_id: i64,
+    until: i64,
+) -> Result<crate::core::snooze::Reminder, DEmailError> {
     let app_state = app_handle.state::<AppState>();
-    let pool = &app_state.db_pool;
-    crate::core::cache::db::star_message(pool, message_id)
+    ensure_unlocked(&app_state)?;
+    crate::core::sync::reminders::snooze_message(&app_state, message_id, until).await
 }
 
+/// Reminds about a sent message at `due_at` unless a reply arrives first
 #[tauri::command]
-pub fn unstar_message(app_handle: AppHandle, message_id: i64) -> Result<(), DEmailError> {
+pub fn set_follow_up(
+    app_handle: AppHandle,
+    message_id: i64,
+    due_at: i64,
+) -> Result<crate::core::snooze::Reminder, DEmailError> {
     let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
     let pool = &app_state.db_pool;
-    crate::core::cache::db::unstar_message(pool, message_id)
+    let conn = pool
+        .get()
+        .map_err(|_| DEmailError::Database(rusqlite::Error::InvalidQuery))?;
+    crate::core::snooze::add_reminder(
+        &conn,
+        message_id,
+        crate::core::snooze::ReminderKind::FollowUp,
+        due_at,
+    )
 }
 
 #[tauri::command]
-pub fn get_starred_messages(
-    app_handle: AppHandle,
-    account_id: i64,
-) -> Result<Vec<crate::models::MessageHeader>, DEmailError> {
+pub async fn cancel_reminder(app_handle: AppHandle, reminder_id: i64) -> Result<(), DEmailError> {
     let app_state = app_handle.state::<AppState>();
-    let pool = &app_state.db_pool;
-    crate::core::cache::db::get_starred_messages(pool, account_id)
+    ensure_unlocked(&app_state)?;
+    crate::core::sync::reminders::cancel_reminder(&app_state, reminder_id).await
 }
 
-// ============================================================================
-// BULK OPERATIONS
-// ============================================================================
-
 #[tauri::command]
-pub fn bulk_mark_read(app_handle: AppHandle, message_ids: Vec<i64>) -> Result<(), DEmailError> {
+pub fn list_reminders(
+    app_handle: AppHandle,
+    account_id: Option<i64>,
+) -> Result<Vec<crate::core::snooze::Reminder>, DEmailError> {
     let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
     let pool = &app_state.db_pool;
-    crate::core::cache::db::bulk_mark_read(pool, &message_ids)
+    crate::core::snooze::list_reminders(pool, account_id)
 }
 
 #[tauri::command]
-pub fn bulk_mark_unread(app_handle: AppHandle, message_ids: Vec<i64>) -> Result<(), DEmailError> {
+pub fn get_notification_settings(
+    app_handle: AppHandle,
+) -> Result<crate::core::notifications::NotificationSettings, DEmailError> {
     let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
     let pool = &app_state.db_pool;
-    crate::core::cache::db::bulk_mark_unread(pool, &message_ids)
+    crate::core::notifications::get_settings(pool)
 }
 
 #[tauri::command]
-pub fn bulk_delete_messages(app_handle: AppHandle, message_ids: Vec<i64>) -> Result<(), DEmailError> {
+pub fn save_notification_settings(
+    app_handle: AppHandle,
+    settings: crate::core::notifications::NotificationSettings,
+) -> Result<(), DEmailError> {
     let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
     let pool = &app_state.db_pool;
-    crate::core::cache::db::bulk_delete_messages(pool, &message_ids)
+    crate::core::notifications::save_settings(pool, &settings)
 }
 
+/// Marks the messages of a new-mail notification read, or archives them, on the server
 #[tauri::command]
-pub fn bulk_star_messages(app_handle: AppHandle, message_ids: Vec<i64>) -> Result<(), DEmailError> {
+pub async fn run_notification_action(
+    app_handle: AppHandle,
+    account_id: i64,
+    message_ids: Vec<i64>,
+    action: crate::core::notifications::NotificationAction,
+) -> Result<(), DEmailError> {
+    let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
+    crate::core::notifications::run_action(&app_state, account_id, &message_ids, action).await
+}
+
+/// Unread mail in the inboxes, for the window title and badge
+#[tauri::command]
+pub fn get_unread_count(
+    app_handle: AppHandle,
+) -> Result<crate::core::notifications::UnreadCount, DEmailError> {
+    let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
+    let pool = &app_state.db_pool;
+    crate::core::notifications::unread_count(pool)
+}
+
+#[tauri::command]
+pub fn list_identities(
+    app_handle: AppHandle,
+    account_id: i64,
+) -> Result<Vec<crate::core::identities::Identity>, DEmailError> {
+    let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
+    let pool = &app_state.db_pool;
+    let conn = pool
+        .get()
+        .map_err(|_| DEmailError::Database(rusqlite::Error::InvalidQuery))?;
+    crate::core::identities::list_identities(&conn, account_id)
+}
+
+/// Saves an identity. A non-empty `smtp_password` is stored in the keyring for its SMTP server.
+#[tauri::command]
+pub fn save_identity(
+    app_handle: AppHandle,
+    identity: crate::core::identities::Identity,
+    smtp_password: Option<String>,
+) -> Result<i64, DEmailError> {
+    let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
+    let pool = &app_state.db_pool;
+    let conn = pool
+        .get()
+        .map_err(|_| DEmailError::Database(rusqlite::Error::InvalidQuery))?;
+    let identity_id = crate::core::identities::save_identity(&conn, &identity)?;
+    if let Some(password) = smtp_password.filter(|p| !p.is_empty()) {
+        crate::core::identities::store_smtp_password(identity_id, &password)?;
+    }
+    Ok(identity_id)
+}
+
+#[tauri::command]
+pub fn delete_identity(app_handle: AppHandle, identity_id: i64) -> Result<(), DEmailError> {
+    let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
+    let pool = &app_state.db_pool;
+    let conn = pool
+        .get()
+        .map_err(|_| DEmailError::Database(rusqlite::Error::InvalidQuery))?;
+    crate::core::identities::delete_identity(&conn, identity_id)
+}
+
+/// The identity a reply to `message_id` should be sent from, if the account has any
+#[tauri::command]
+pub fn get_reply_identity(
+    app_handle: AppHandle,
+    message_id: i64,
+) -> Result<Option<crate::core::identities::Identity>, DEmailError> {
+    let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
+    let pool = &app_state.db_pool;
+    let conn = pool
+        .get()
+        .map_err(|_| DEmailError::Database(rusqlite::Error::InvalidQuery))?;
+    crate::core::identities::identity_for_reply(&conn, message_id)
+}
+
+/// Starts a new message, reply or forward with the signature inserted; `message_id` is the
+/// original for replies and forwards
+#[tauri::command]
+pub fn prepare_compose(
+    app_handle: AppHandle,
+    account_id: i64,
+    mode: crate::core::reply_forward::ComposeMode,
+    message_id: Option<i64>,
+    identity_id: Option<i64>,
+) -> Result<crate::core::reply_forward::ComposeData, DEmailError> {
+    let original = match message_id {
+        Some(message_id) => Some(get_message_details(app_handle.clone(), message_id)?),
+        None => None,
+    };
+    let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
+    let pool = &app_state.db_pool;
+    crate::core::reply_forward::prepare_compose(
+        pool,
+        account_id,
+        mode,
+        original.as_ref(),
+        identity_id,
+    )
+}
+
+#[tauri::command]
+pub fn get_signature_settings(
+    app_handle: AppHandle,
+) -> Result<crate::core::signatures::SignatureSettings, DEmailError> {
+    let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
+    let pool = &app_state.db_pool;
+    crate::core::signatures::get_settings(pool)
+}
+
+#[tauri::command]
+pub fn save_signature_settings(
+    app_handle: AppHandle,
+    settings: crate::core::signatures::SignatureSettings,
+) -> Result<(), DEmailError> {
+    let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
+    let pool = &app_state.db_pool;
+    crate::core::signatures::save_settings(pool, &settings)
+}
+
+#[tauri::command]
+pub fn list_templates(
+    app_handle: AppHandle,
+    account_id: Option<i64>,
+) -> Result<Vec<crate::core::templates::MessageTemplate>, DEmailError> {
+    let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
+    let pool = &app_state.db_pool;
+    let conn = pool
+        .get()
+        .map_err(|_| DEmailError::Database(rusqlite::Error::InvalidQuery))?;
+    crate::core::templates::list_templates(&conn, account_id)
+}
+
+#[tauri::command]
+pub fn save_template(
+    app_handle: AppHandle,
+    template: crate::core::templates::MessageTemplate,
+) -> Result<i64, DEmailError> {
+    validation::validate_subject(&template.subject)?;
+    validation::validate_body(&template.body)?;
+
+    let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
+    let pool = &app_state.db_pool;
+    let conn = pool
+        .get()
+        .map_err(|_| DEmailError::Database(rusqlite::Error::InvalidQuery))?;
+    crate::core::templates::save_template(&conn, &template)
+}
+
+#[tauri::command]
+pub fn delete_template(app_handle: AppHandle, template_id: i64) -> Result<(), DEmailError> {
+    let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
+    let pool = &app_state.db_pool;
+    let conn = pool
+        .get()
+        .map_err(|_| DEmailError::Database(rusqlite::Error::InvalidQuery))?;
+    crate::core::templates::delete_template(&conn, template_id)
+}
+
+#[tauri::command]
+pub fn add_template_attachment(
+    app_handle: AppHandle,
+    template_id: i64,
+    file_path: String,
+) -> Result<i64, DEmailError> {
+    let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
+    let pool = &app_state.db_pool;
+    let conn = pool
+        .get()
+        .map_err(|_| DEmailError::Database(rusqlite::Error::InvalidQuery))?;
+    crate::core::templates::add_template_attachment(&conn, template_id, &file_path)
+}
+
+#[tauri::command]
+pub fn remove_template_attachment(
+    app_handle: AppHandle,
+    attachment_id: i64,
+) -> Result<(), DEmailError> {
+    let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
+    let pool = &app_state.db_pool;
+    let conn = pool
+        .get()
+        .map_err(|_| DEmailError::Database(rusqlite::Error::InvalidQuery))?;
+    crate::core::templates::remove_template_attachment(&conn, attachment_id)
+}
+
+/// The placeholders of a template, with the values `message_id` (the message being
+/// replied to) provides
+#[tauri::command]
+pub fn get_template_placeholders(
+    app_handle: AppHandle,
+    template_id: i64,
+    message_id: Option<i64>,
+) -> Result<Vec<crate::core::templates::Placeholder>, DEmailError> {
+    let original = match message_id {
+        Some(message_id) => Some(get_message_details(app_handle.clone(), message_id)?),
+        None => None,
+    };
+    let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
+    let pool = &app_state.db_pool;
+    let conn = pool
+        .get()
+        .map_err(|_| DEmailError::Database(rusqlite::Error::InvalidQuery))?;
+    crate::core::templates::template_placeholders(&conn, template_id, original.as_ref())
+}
+
+/// Creates a draft from a template, as a reply to `message_id` when given
+#[tauri::command]
+pub fn create_draft_from_template(
+    app_handle: AppHandle,
+    account_id: i64,
+    template_id: i64,
+    message_id: Option<i64>,
+    values: std::collections::HashMap<String, String>,
+) -> Result<crate::models::Draft, DEmailError> {
+    let original = match message_id {
+        Some(message_id) => Some(get_message_details(app_handle.clone(), message_id)?),
+        None => None,
+    };
+    let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
+    let pool = &app_state.db_pool;
+    let draft = crate::core::templates::create_draft(
+        pool,
+        account_id,
+        template_id,
+        original.as_ref(),
+        &values,
+    )?;
+    crate::core::sync::draft_sync::schedule_upload(app_handle.clone(), account_id, draft.id);
+    Ok(draft)
+}
+
+#[tauri::command]
+pub fn export_templates(
+    app_handle: AppHandle,
+    account_id: Option<i64>,
+    destination_path: String,
+) -> Result<usize, DEmailError> {
+    let dest_path = std::path::Path::new(&destination_path);
+    validation::sanitize_filename(
+        dest_path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .ok_or_else(|| DEmailError::Validation("Invalid destination path".to_string()))?,
+    )?;
+
+    let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
+    let pool = &app_state.db_pool;
+    let conn = pool
+        .get()
+        .map_err(|_| DEmailError::Database(rusqlite::Error::InvalidQuery))?;
+    crate::core::templates::export_templates(&conn, account_id, &destination_path)
+}
+
+#[tauri::command]
+pub fn import_templates(
+    app_handle: AppHandle,
+    source_path: String,
+    account_id: Option<i64>,
+) -> Result<usize, DEmailError> {
+    let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
+    let pool = &app_state.db_pool;
+    let conn = pool
+        .get()
+        .map_err(|_| DEmailError::Database(rusqlite::Error::InvalidQuery))?;
+    crate::core::templates::import_templates(&conn, account_id, &source_path)
+}
+
+#[tauri::command]
+pub fn list_draft_attachments(
+    app_handle: AppHandle,
+    draft_id: i64,
+) -> Result<Vec<crate::core::drafts::DraftAttachment>, DEmailError> {
+    let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
+    let pool = &app_state.db_pool;
+    let conn = pool
+        .get()
+        .map_err(|_| DEmailError::Database(rusqlite::Error::InvalidQuery))?;
+    crate::core::drafts::list_draft_attachments(&conn, draft_id)
+}
+
+#[tauri::command]
+pub fn add_draft_attachment(
+    app_handle: AppHandle,
+    draft_id: i64,
+    file_path: String,
+) -> Result<i64, DEmailError> {
+    let (filename, mime_type, data) =
+        crate::core::attachments::read_attachment_file(&file_path)?;
+
+    let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
+    let pool = &app_state.db_pool;
+    let conn = pool
+        .get()
+        .map_err(|_| DEmailError::Database(rusqlite::Error::InvalidQuery))?;
+    let id =
+        crate::core::drafts::add_draft_attachment(&conn, draft_id, &filename, &mime_type, &data)?;
+    if let Some(account_id) = crate::core::drafts::draft_account_id(&conn, draft_id)? {
+        crate::core::sync::draft_sync::schedule_upload(app_handle.clone(), account_id, draft_id);
+    }
+    Ok(id)
+}
+
+#[tauri::command]
+pub fn remove_draft_attachment(
+    app_handle: AppHandle,
+    attachment_id: i64,
+) -> Result<(), DEmailError> {
+    let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
+    let pool = &app_state.db_pool;
+    let conn = pool
+        .get()
+        .map_err(|_| DEmailError::Database(rusqlite::Error::InvalidQuery))?;
+    if let Some(draft_id) = crate::core::drafts::remove_draft_attachment(&conn, attachment_id)? {
+        if let Some(account_id) = crate::core::drafts::draft_account_id(&conn, draft_id)? {
+            crate::core::sync::draft_sync::schedule_upload(
+                app_handle.clone(),
+                account_id,
+                draft_id,
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Mirrors the account's drafts to its Drafts folder and imports the ones written
+/// elsewhere
+#[tauri::command]
+pub async fn sync_drafts(
+    app_handle: AppHandle,
+    account_id: i64,
+) -> Result<crate::core::sync::draft_sync::DraftSyncReport, DEmailError> {
+    ensure_unlocked(&app_handle.state::<AppState>())?;
+    crate::core::sync::draft_sync::sync_and_notify(&app_handle, account_id).await
+}
+
+#[tauri::command]
+pub fn search_messages(
+    app_handle: AppHandle,
+    account_id: i64,
+    query: String,
+) -> Result<Vec<crate::models::MessageHeader>, DEmailError> {
+    validation::validate_search_query(&query)?;
+
+    let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
+    let pool = &app_state.db_pool;
+    crate::core::cache::db::search_messages_fts(pool, account_id, &query)
+}
+
+#[tauri::command]
+pub fn save_draft(app_handle: AppHandle, draft: crate::models::Draft) -> Result<i64, DEmailError> {
+    // Validate draft fields
+    validation::validate_subject(&draft.subject)?;
+    if let Some(ref body) = draft.body_plain {
+        validation::validate_body(body)?;
+    }
+    if let Some(ref body) = draft.body_html {
+        validation::validate_body(body)?;
+    }
+
+    let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
+    let pool = &app_state.db_pool;
+    let id = crate::core::drafts::create_or_update_draft(pool, &draft)?;
+    crate::core::sync::draft_sync::schedule_upload(app_handle.clone(), draft.account_id, id);
+    Ok(id)
+}
+
+/// Uploads a draft's pending edits when its editor closes
+#[tauri::command]
+pub fn close_draft(app_handle: AppHandle, draft_id: i64) -> Result<(), DEmailError> {
+    let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
+    let conn = app_state
+        .db_pool
+        .get()
+        .map_err(|_| DEmailError::Database(rusqlite::Error::InvalidQuery))?;
+    if let Some(account_id) = crate::core::drafts::draft_account_id(&conn, draft_id)? {
+        crate::core::sync::draft_sync::flush_upload(app_handle.clone(), account_id, draft_id);
+    }
+    Ok(())
+}
+
+#[tauri::command]
+pub fn get_drafts(
+    app_handle: AppHandle,
+    account_id: i64,
+) -> Result<Vec<crate::models::Draft>, DEmailError> {
+    let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
+    let pool = &app_state.db_pool;
+    crate::core::drafts::load_drafts_for_account(pool, account_id)
+}
+
+#[tauri::command]
+pub fn delete_draft(app_handle: AppHandle, draft_id: i64) -> Result<(), DEmailError> {
+    let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
+    let pool = &app_state.db_pool;
+    let account_id = {
+        let conn = pool
+            .get()
+            .map_err(|_| DEmailError::Database(rusqlite::Error::InvalidQuery))?;
+        crate::core::drafts::draft_account_id(&conn, draft_id)?
+    };
+    crate::core::drafts::remove_draft(pool, draft_id)?;
+    if let Some(account_id) = account_id {
+        crate::core::sync::draft_sync::flush_upload(app_handle.clone(), account_id, draft_id);
+    }
+    Ok(())
+}
+
+#[tauri::command]
+pub fn save_signature(
+    app_handle: AppHandle,
+    signature: crate::models::EmailSignature,
+) -> Result<i64, DEmailError> {
+    let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
+    let pool = &app_state.db_pool;
+    crate::core::cache::db::save_signature(pool, &signature)
+}
+
+#[tauri::command]
+pub fn get_signatures(
+    app_handle: AppHandle,
+    account_id: i64,
+) -> Result<Vec<crate::models::EmailSignature>, DEmailError> {
+    let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
+    let pool = &app_state.db_pool;
+    crate::core::cache::db::get_signatures(pool, account_id)
+}
+
+#[tauri::command]
+pub fn delete_signature(app_handle: AppHandle, signature_id: i64) -> Result<(), DEmailError> {
+    let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
+    let pool = &app_state.db_pool;
+    crate::core::cache::db::delete_signature(pool, signature_id)
+}
+
+#[tauri::command]
+pub fn download_attachment(
+    app_handle: AppHandle,
+    attachment_id: i64,
+    destination_path: String,
+) -> Result<(), DEmailError> {
+    // Validate destination path
+    let dest_path = std::path::Path::new(&destination_path);
+    validation::sanitize_filename(
+        dest_path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .ok_or_else(|| DEmailError::Validation("Invalid destination path".to_string()))?,
+    )?;
+
+    let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
+    let pool = &app_state.db_pool;
+    let conn = pool
+        .get()
+        .map_err(|_| DEmailError::Database(rusqlite::Error::InvalidQuery))?;
+
+    let mut stmt = conn.prepare("SELECT * FROM attachments WHERE id = ?1")?;
+    let attachment = stmt.query_row([attachment_id], |row| {
+        Ok(crate::models::Attachment {
+            id: row.get(0)?,
+            message_id: row.get(1)?,
+            filename: row.get(2)?,
+            mime_type: row.get(3)?,
+            size_bytes: row.get(4)?,
+            local_path: row.get(5)?,
+        })
+    })?;
+
+    crate::core::attachments::save_attachment_to_disk(&conn, &attachment, &destination_path)
+}
+
+#[tauri::command]
+pub fn get_messages_paginated(
+    app_handle: AppHandle,
+    folder_id: i64,
+    limit: i64,
+    offset: i64,
+) -> Result<Vec<crate::models::MessageHeader>, DEmailError> {
+    validation::validate_pagination(limit, offset)?;
+
+    let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
+    let pool = &app_state.db_pool;
+    crate::core::cache::db::get_messages_paginated(pool, folder_id, limit, offset)
+}
+
+#[tauri::command]
+pub fn count_messages_in_folder(app_handle: AppHandle, folder_id: i64) -> Result<i64, DEmailError> {
+    let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
+    let pool = &app_state.db_pool;
+    crate::core::cache::db::count_messages_in_folder(pool, folder_id)
+}
+
+#[tauri::command]
+pub fn delete_message(app_handle: AppHandle, message_id: i64) -> Result<(), DEmailError> {
+    let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
+    let pool = &app_state.db_pool;
+    crate::core::cache::db::delete_message(pool, message_id)
+}
+
+#[tauri::command]
+pub fn move_message(
+    app_handle: AppHandle,
+    message_id: i64,
+    target_folder_id: i64,
+) -> Result<(), DEmailError> {
+    let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
+    let pool = &app_state.db_pool;
+    crate::core::cache::db::move_message(pool, message_id, target_folder_id)
+}
+
+#[tauri::command]
+pub fn save_setting(app_handle: AppHandle, key: String, value: String) -> Result<(), DEmailError> {
+    let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
+    let pool = &app_state.db_pool;
+    reject_reserved_setting(&key)?;
+    crate::core::cache::db::save_setting(pool, &key, &value)
+}
+
+#[tauri::command]
+pub fn get_setting(app_handle: AppHandle, key: String) -> Result<Option<String>, DEmailError> {
+    let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
+    let pool = &app_state.db_pool;
+    reject_reserved_setting(&key)?;
+    crate::core::cache::db::get_setting(pool, &key)
+}
+
+#[tauri::command]
+pub fn get_all_settings(
+    app_handle: AppHandle,
+) -> Result<Vec<crate::models::AppSetting>, DEmailError> {
+    let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
+    let pool = &app_state.db_pool;
+    let mut settings = crate::core::cache::db::get_all_settings(pool)?;
+    settings.retain(|setting| !crate::core::app_lock::is_reserved_setting(&setting.key));
+    Ok(settings)
+}
+
+/// App lock settings are only changed through the app lock commands
+fn reject_reserved_setting(key: &str) -> Result<(), DEmailError> {
+    if crate::core::app_lock::is_reserved_setting(key) {
+        return Err(DEmailError::Validation(format!(
+            "Setting {} is managed by the app lock",
+            key
+        )));
+    }
+    Ok(())
+}
+
+#[tauri::command]
+pub fn prepare_reply(
+    app_handle: AppHandle,
+    message_id: i64,
+    reply_all: bool,
+) -> Result<crate::core::reply_forward::ReplyData, DEmailError> {
+    let message = get_message_details(app_handle, message_id)?;
+    crate::core::reply_forward::prepare_reply(&message, reply_all)
+}
+
+#[tauri::command]
+pub fn prepare_forward(
+    app_handle: AppHandle,
+    message_id: i64,
+) -> Result<crate::core::reply_forward::ForwardData, DEmailError> {
+    let message = get_message_details(app_handle, message_id)?;
+    crate::core::reply_forward::prepare_forward(&message)
+}
+
+// ============================================================================
+// STARRING OPERATIONS
+// ============================================================================
+
+#[tauri::command]
+pub fn star_message(app_handle: AppHandle, message_id: i64) -> Result<(), DEmailError> {
+    let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
+    let pool = &app_state.db_pool;
+    crate::core::cache::db::star_message(pool, message_id)
+}
+
+#[tauri::command]
+pub fn unstar_message(app_handle: AppHandle, message_id: i64) -> Result<(), DEmailError> {
+    let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
+    let pool = &app_state.db_pool;
+    crate::core::cache::db::unstar_message(pool, message_id)
+}
+
+#[tauri::command]
+pub fn get_starred_messages(
+    app_handle: AppHandle,
+    account_id: i64,
+) -> Result<Vec<crate::models::MessageHeader>, DEmailError> {
+    let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
+    let pool = &app_state.db_pool;
+    crate::core::cache::db::get_starred_messages(pool, account_id)
+}
+
+// ============================================================================
+// BULK OPERATIONS
+// ============================================================================
+
+#[tauri::command]
+pub fn bulk_mark_read(app_handle: AppHandle, message_ids: Vec<i64>) -> Result<(), DEmailError> {
+    let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
+    let pool = &app_state.db_pool;
+    crate::core::cache::db::bulk_mark_read(pool, &message_ids)
+}
+
+#[tauri::command]
+pub fn bulk_mark_unread(app_handle: AppHandle, message_ids: Vec<i64>) -> Result<(), DEmailError> {
+    let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
+    let pool = &app_state.db_pool;
+    crate::core::cache::db::bulk_mark_unread(pool, &message_ids)
+}
+
+#[tauri::command]
+pub fn bulk_delete_messages(app_handle: AppHandle, message_ids: Vec<i64>) -> Result<(), DEmailError> {
+    let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
+    let pool = &app_state.db_pool;
+    crate::core::cache::db::bulk_delete_messages(pool, &message_ids)
+}
+
+#[tauri::command]
+pub fn bulk_star_messages(app_handle: AppHandle, message_ids: Vec<i64>) -> Result<(), DEmailError> {
+    let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
+    let pool = &app_state.db_pool;
+    crate::core::cache::db::bulk_star_messages(pool, &message_ids)
+}
+
+#[tauri::command]
+pub fn bulk_unstar_messages(
+    app_handle: AppHandle,
+    message_ids: Vec<i64>,
+) -> Result<(), DEmailError> {
+    let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
+    let pool = &app_state.db_pool;
+    crate::core::cache::db::bulk_unstar_messages(pool, &message_ids)
+}
+
+// ============================================================================
+// CONTACTS OPERATIONS
+// ============================================================================
+
+#[tauri::command]
+pub fn search_contacts(
+    app_handle: AppHandle,
+    account_id: i64,
+    query: String,
+) -> Result<Vec<crate::models::Contact>, DEmailError> {
+    let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
+    let pool = &app_state.db_pool;
+    crate::core::contacts::search_contacts(pool, account_id, &query)
+}
+
+#[tauri::command]
+pub fn get_recent_contacts(
+    app_handle: AppHandle,
+    account_id: i64,
+    limit: i32,
+) -> Result<Vec<crate::models::Contact>, DEmailError> {
+    let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
+    let pool = &app_state.db_pool;
+    crate::core::contacts::get_recent_contacts(pool, account_id, limit)
+}
+
+#[tauri::command]
+pub fn get_frequent_contacts(
+    app_handle: AppHandle,
+    account_id: i64,
+    limit: i32,
+) -> Result<Vec<crate::models::Contact>, DEmailError> {
+    let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
+    let pool = &app_state.db_pool;
+    crate::core::contacts::get_frequent_contacts(pool, account_id, limit)
+}
+
+// ============================================================================
+// THREADING OPERATIONS
+// ============================================================================
+
+#[tauri::command]
+pub fn get_thread_messages(
+    app_handle: AppHandle,
+    thread_id: i64,
+) -> Result<Vec<crate::models::Message>, DEmailError> {
     let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
     let pool = &app_state.db_pool;
-    crate::core::cache::db::bulk_star_messages(pool, &message_ids)
+    let conn = pool
+        .get()
+        .map_err(|_| DEmailError::Database(rusqlite::Error::InvalidQuery))?;
+
+    let mut messages = crate::core::threading::get_thread_messages(&conn, thread_id)?;
+    for message in &mut messages {
+        crate::core::remote_content::render_message(&conn, message, false)?;
+    }
+    Ok(messages)
 }
 
 #[tauri::command]
-pub fn bulk_unstar_messages(
+pub fn get_thread(
     app_handle: AppHandle,
-    message_ids: Vec<i64>,
+    thread_id: i64,
+) -> Result<crate::models::Thread, DEmailError> {
+    let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
+    let pool = &app_state.db_pool;
+    crate::core::threading::get_thread(pool, thread_id)
+}
+
+// ============================================================================
+// BACKUP OPERATIONS
+// ============================================================================
+
+#[tauri::command]
+pub fn create_backup(
+    app_handle: AppHandle,
+    destination_path: String,
+    passphrase: String,
+    include_credentials: bool,
+) -> Result<crate::core::backup::BackupManifest, DEmailError> {
+    let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
+    let pool = &app_state.db_pool;
+    let app_config = app_state.app_config.lock().unwrap();
+    crate::core::backup::create_backup(
+        pool,
+        &app_config,
+        &destination_path,
+        &passphrase,
+        include_credentials,
+    )
+}
+
+#[tauri::command]
+pub fn restore_backup(
+    app_handle: AppHandle,
+    source_path: String,
+    passphrase: String,
+) -> Result<crate::core::backup::BackupManifest, DEmailError> {
+    let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
+    let pool = &app_state.db_pool;
+    let app_config = app_state.app_config.lock().unwrap();
+    let manifest =
+        crate::core::backup::restore_backup(pool, &app_config, &source_path, &passphrase)?;
+    // Tokens, sessions and schedules of the replaced accounts must not outlive them
+    app_state.token_manager.clear();
+    app_state.imap_pool.discard_all();
+    app_state.sync_scheduler.reschedule();
+    Ok(manifest)
+}
+
+// ============================================================================
+// OPENPGP OPERATIONS
+// ============================================================================
+
+#[tauri::command]
+pub fn import_pgp_key(
+    app_handle: AppHandle,
+    armored_key: String,
+    passphrase: Option<String>,
+) -> Result<crate::core::crypto::openpgp::PgpKeyInfo, DEmailError> {
+    let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
+    let pool = &app_state.db_pool;
+    let conn = pool
+        .get()
+        .map_err(|_| DEmailError::Database(rusqlite::Error::InvalidQuery))?;
+    crate::core::crypto::openpgp::import_key(&conn, &armored_key, passphrase.as_deref())
+}
+
+#[tauri::command]
+pub fn list_pgp_keys(
+    app_handle: AppHandle,
+) -> Result<Vec<crate::core::crypto::openpgp::PgpKeyInfo>, DEmailError> {
+    let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
+    let pool = &app_state.db_pool;
+    let conn = pool
+        .get()
+        .map_err(|_| DEmailError::Database(rusqlite::Error::InvalidQuery))?;
+    crate::core::crypto::openpgp::list_keys(&conn)
+}
+
+#[tauri::command]
+pub fn export_pgp_key(
+    app_handle: AppHandle,
+    fingerprint: String,
+    include_secret: bool,
+) -> Result<String, DEmailError> {
+    let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
+    let pool = &app_state.db_pool;
+    let conn = pool
+        .get()
+        .map_err(|_| DEmailError::Database(rusqlite::Error::InvalidQuery))?;
+    crate::core::crypto::openpgp::export_key(&conn, &fingerprint, include_secret)
+}
+
+#[tauri::command]
+pub fn delete_pgp_key(app_handle: AppHandle, fingerprint: String) -> Result<(), DEmailError> {
+    let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
+    let pool = &app_state.db_pool;
+    let conn = pool
+        .get()
+        .map_err(|_| DEmailError::Database(rusqlite::Error::InvalidQuery))?;
+    crate::core::crypto::openpgp::delete_key(&conn, &fingerprint)
+}
+
+#[tauri::command]
+pub fn decrypt_message(
+    app_handle: AppHandle,
+    message_id: i64,
+    passphrase: String,
+) -> Result<crate::models::Message, DEmailError> {
+    let decrypted = {
+        let app_state = app_handle.state::<AppState>();
+        ensure_unlocked(&app_state)?;
+        let pool = &app_state.db_pool;
+        let conn = pool
+            .get()
+            .map_err(|_| DEmailError::Database(rusqlite::Error::InvalidQuery))?;
+        crate::core::crypto::decrypt_stored_message(&conn, message_id, &passphrase)?
+    };
+    let mut message = get_message_details(app_handle.clone(), message_id)?;
+    show_decrypted(&mut message, decrypted);
+
+    let app_state = app_handle.state::<AppState>();
+    let conn = app_state
+        .db_pool
+        .get()
+        .map_err(|_| DEmailError::Database(rusqlite::Error::InvalidQuery))?;
+    crate::core::remote_content::render_message(&conn, &mut message, false)?;
+    Ok(message)
+}
+
+// ============================================================================
+// S/MIME OPERATIONS
+// ============================================================================
+
+#[tauri::command]
+pub fn import_smime_identity(
+    app_handle: AppHandle,
+    pkcs12_path: String,
+    password: String,
+) -> Result<crate::core::crypto::smime::SmimeCertificateInfo, DEmailError> {
+    let pkcs12 = std::fs::read(&pkcs12_path)?;
+    let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
+    let pool = &app_state.db_pool;
+    let conn = pool
+        .get()
+        .map_err(|_| DEmailError::Database(rusqlite::Error::InvalidQuery))?;
+    crate::core::crypto::smime::import_identity(&conn, &pkcs12, &password)
+}
+
+#[tauri::command]
+pub fn import_smime_certificate(
+    app_handle: AppHandle,
+    certificate_path: String,
+    trust_anchor: bool,
+) -> Result<crate::core::crypto::smime::SmimeCertificateInfo, DEmailError> {
+    let certificate = std::fs::read(&certificate_path)?;
+    let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
+    let pool = &app_state.db_pool;
+    let conn = pool
+        .get()
+        .map_err(|_| DEmailError::Database(rusqlite::Error::InvalidQuery))?;
+    crate::core::crypto::smime::import_certificate(&conn, &certificate, trust_anchor)
+}
+
+#[tauri::command]
+pub fn list_smime_certificates(
+    app_handle: AppHandle,
+) -> Result<Vec<crate::core::crypto::smime::SmimeCertificateInfo>, DEmailError> {
+    let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
+    let pool = &app_state.db_pool;
+    let conn = pool
+        .get()
+        .map_err(|_| DEmailError::Database(rusqlite::Error::InvalidQuery))?;
+    crate::core::crypto::smime::list_certificates(&conn)
+}
+
+#[tauri::command]
+pub fn delete_smime_certificate(
+    app_handle: AppHandle,
+    fingerprint: String,
 ) -> Result<(), DEmailError> {
     let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
     let pool = &app_state.db_pool;
-    crate::core::cache::db::bulk_unstar_messages(pool, &message_ids)
+    let conn = pool
+        .get()
+        .map_err(|_| DEmailError::Database(rusqlite::Error::InvalidQuery))?;
+    crate::core::crypto::smime::delete_certificate(&conn, &fingerprint)
 }
 
 // ============================================================================
-// CONTACTS OPERATIONS
+// SENDER AUTHENTICATION OPERATIONS
 // ============================================================================
 
 #[tauri::command]
-pub fn search_contacts(
+pub fn add_dkim_key(
     app_handle: AppHandle,
-    account_id: i64,
-    query: String,
-) -> Result<Vec<crate::models::Contact>, DEmailError> {
+    domain: String,
+    selector: String,
+    record: String,
+) -> Result<crate::models::DkimKey, DEmailError> {
     let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
     let pool = &app_state.db_pool;
-    crate::core::contacts::search_contacts(pool, account_id, &query)
+    let conn = pool
+        .get()
+        .map_err(|_| DEmailError::Database(rusqlite::Error::InvalidQuery))?;
+    crate::core::sender_auth::add_dkim_key(&conn, &domain, &selector, &record)
 }
 
 #[tauri::command]
-pub fn get_recent_contacts(
+pub fn list_dkim_keys(app_handle: AppHandle) -> Result<Vec<crate::models::DkimKey>, DEmailError> {
+    let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
+    let pool = &app_state.db_pool;
+    let conn = pool
+        .get()
+        .map_err(|_| DEmailError::Database(rusqlite::Error::InvalidQuery))?;
+    crate::core::sender_auth::list_dkim_keys(&conn)
+}
+
+#[tauri::command]
+pub fn delete_dkim_key(
     app_handle: AppHandle,
-    account_id: i64,
-    limit: i32,
-) -> Result<Vec<crate::models::Contact>, DEmailError> {
+    domain: String,
+    selector: String,
+) -> Result<(), DEmailError> {
     let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
     let pool = &app_state.db_pool;
-    crate::core::contacts::get_recent_contacts(pool, account_id, limit)
+    let conn = pool
+        .get()
+        .map_err(|_| DEmailError::Database(rusqlite::Error::InvalidQuery))?;
+    crate::core::sender_auth::delete_dkim_key(&conn, &domain, &selector)
 }
 
+// ============================================================================
+// REMOTE CONTENT OPERATIONS
+// ============================================================================
+
+/// Returns the message with its remote images loaded once through the local proxy
 #[tauri::command]
-pub fn get_frequent_contacts(
+pub fn load_remote_content(
     app_handle: AppHandle,
-    account_id: i64,
-    limit: i32,
-) -> Result<Vec<crate::models::Contact>, DEmailError> {
+    message_id: i64,
+) -> Result<crate::models::Message, DEmailError> {
+    let mut message = get_message_details(app_handle.clone(), message_id)?;
+
     let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
     let pool = &app_state.db_pool;
-    crate::core::contacts::get_frequent_contacts(pool, account_id, limit)
+    let conn = pool
+        .get()
+        .map_err(|_| DEmailError::Database(rusqlite::Error::InvalidQuery))?;
+    message.body_html = conn.query_row(
+        "SELECT body_html FROM messages WHERE id = ?1",
+        [message_id],
+        |row| row.get(0),
+    )?;
+    crate::core::remote_content::render_message(&conn, &mut message, true)?;
+    Ok(message)
+}
+
+#[tauri::command]
+pub fn add_remote_content_exception(
+    app_handle: AppHandle,
+    kind: String,
+    value: String,
+) -> Result<crate::models::RemoteContentException, DEmailError> {
+    let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
+    let pool = &app_state.db_pool;
+    let conn = pool
+        .get()
+        .map_err(|_| DEmailError::Database(rusqlite::Error::InvalidQuery))?;
+    crate::core::remote_content::add_exception(&conn, &kind, &value)
+}
+
+#[tauri::command]
+pub fn list_remote_content_exceptions(
+    app_handle: AppHandle,
+) -> Result<Vec<crate::models::RemoteContentException>, DEmailError> {
+    let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
+    let pool = &app_state.db_pool;
+    let conn = pool
+        .get()
+        .map_err(|_| DEmailError::Database(rusqlite::Error::InvalidQuery))?;
+    crate::core::remote_content::list_exceptions(&conn)
+}
+
+#[tauri::command]
+pub fn delete_remote_content_exception(
+    app_handle: AppHandle,
+    exception_id: i64,
+) -> Result<(), DEmailError> {
+    let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
+    let pool = &app_state.db_pool;
+    let conn = pool
+        .get()
+        .map_err(|_| DEmailError::Database(rusqlite::Error::InvalidQuery))?;
+    crate::core::remote_content::delete_exception(&conn, exception_id)
 }
 
 // ============================================================================
-// THREADING OPERATIONS
+// DATABASE ENCRYPTION OPERATIONS
 // ============================================================================
 
 #[tauri::command]
-pub fn get_thread_messages(
+pub fn get_database_encryption_status(
     app_handle: AppHandle,
-    thread_id: i64,
-) -> Result<Vec<crate::models::Message>, DEmailError> {
+) -> Result<crate::core::cache::encryption::EncryptionStatus, DEmailError> {
+    let app_state = app_handle.state::<AppState>();
+    let app_config = app_state.app_config.lock().unwrap();
+    let db_path = crate::core::cache::db::get_db_path(&app_config)?;
+    crate::core::cache::encryption::get_status(&db_path)
+}
+
+#[tauri::command]
+pub fn enable_database_encryption(
+    app_handle: AppHandle,
+    mode: String,
+    passphrase: Option<String>,
+) -> Result<crate::core::cache::encryption::EncryptionStatus, DEmailError> {
+    let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
+    let app_config = app_state.app_config.lock().unwrap();
+    let db_path = crate::core::cache::db::get_db_path(&app_config)?;
+    crate::core::cache::encryption::enable(&db_path, &mode, passphrase.as_deref())
+}
+
+#[tauri::command]
+pub fn unlock_database(app_handle: AppHandle, passphrase: String) -> Result<(), DEmailError> {
+    let app_state = app_handle.state::<AppState>();
+    let db_path = {
+        let app_config = app_state.app_config.lock().unwrap();
+        crate::core::cache::db::get_db_path(&app_config)?
+    };
+    crate::core::cache::encryption::unlock(&db_path, &passphrase)?;
+    crate::core::cache::db::prepare_schema(&app_state.db_pool)?;
+    // The database passphrase was just entered, so the app lock starts unlocked
+    app_state.app_lock.load(&app_state.db_pool, false)
+}
+
+#[tauri::command]
+pub fn change_database_passphrase(
+    app_handle: AppHandle,
+    current_passphrase: Option<String>,
+    new_passphrase: String,
+) -> Result<(), DEmailError> {
     let app_state = app_handle.state::<AppState>();
+    ensure_unlocked(&app_state)?;
     let pool = &app_state.db_pool;
-    crate::core::threading::get_thread_messages(pool, thread_id)
+    let db_path = {
+        let app_config = app_state.app_config.lock().unwrap();
+        crate::core::cache::db::get_db_path(&app_config)?
+    };
+    let conn = pool
+        .get()
+        .map_err(|_| DEmailError::Database(rusqlite::Error::InvalidQuery))?;
+    crate::core::cache::encryption::change_passphrase(
+        &conn,
+        &db_path,
+        current_passphrase.as_deref(),
+        &new_passphrase,
+    )
+}
+
+// ============================================================================
+// APP LOCK OPERATIONS
+// ============================================================================
+
+#[tauri::command]
+pub fn get_app_lock_status(app_handle: AppHandle) -> crate::core::app_lock::AppLockStatus {
+    let app_state = app_handle.state::<AppState>();
+    app_state.app_lock.status()
 }
 
 #[tauri::command]
-pub fn get_thread(
+pub fn set_app_lock_passphrase(
     app_handle: AppHandle,
-    thread_id: i64,
-) -> Result<crate::models::Thread, DEmailError> {
+    current_passphrase: Option<String>,
+    new_passphrase: String,
+) -> Result<(), DEmailError> {
     let app_state = app_handle.state::<AppState>();
     let pool = &app_state.db_pool;
-    crate::core::threading::get_thread(pool, thread_id)
+    app_state
+        .app_lock
+        .set_passphrase(pool, current_passphrase.as_deref(), &new_passphrase)
+}
+
+#[tauri::command]
+pub fn disable_app_lock(app_handle: AppHandle, passphrase: String) -> Result<(), DEmailError> {
+    let app_state = app_handle.state::<AppState>();
+    let pool = &app_state.db_pool;
+    app_state.app_lock.disable(pool, &passphrase)
+}
+
+#[tauri::command]
+pub fn set_app_lock_idle_timeout(app_handle: AppHandle, minutes: u64) -> Result<(), DEmailError> {
+    let app_state = app_handle.state::<AppState>();
+    let pool = &app_state.db_pool;
+    app_state.app_lock.set_idle_timeout(pool, minutes)
+}
+
+#[tauri::command]
+pub fn lock_app(app_handle: AppHandle) {
+    let app_state = app_handle.state::<AppState>();
+    app_state.app_lock.lock();
+}
+
+#[tauri::command]
+pub fn unlock_app(app_handle: AppHandle, passphrase: String) -> Result<(), DEmailError> {
+    let app_state = app_handle.state::<AppState>();
+    let pool = &app_state.db_pool;
+    app_state.app_lock.unlock(pool, &passphrase)
+}
+
+#[tauri::command]
+pub fn record_user_activity(app_handle: AppHandle) {
+    let app_state = app_handle.state::<AppState>();
+    app_state.app_lock.record_activity();
 }
@@ -23,6 +23,7 @@ pub fn create_account(
         email_address: email_address.to_string(),
         display_name: display_name.to_string(),
         provider_type: provider_type.to_string(),
+        needs_reauth: false,
     })
 }
 
@@ -31,14 +32,16 @@ pub fn get_accounts(pool: &Pool) -> Result<Vec<Account>, DEmailError> {
         .get()
         .map_err(|e| DEmailError::Database(rusqlite::Error::InvalidQuery))?;
 
-    let mut stmt =
-        conn.prepare("SELECT id, email_address, display_name, provider_type FROM accounts")?;
+    let mut stmt = conn.prepare(
+        "SELECT id, email_address, display_name, provider_type, needs_reauth FROM accounts",
+    )?;
     let account_iter = stmt.query_map([], |row| {
         Ok(Account {
             id: row.get(0)?,
             email_address: row.get(1)?,
             display_name: row.get(2)?,
             provider_type: row.get(3)?,
+            needs_reauth: row.get(4)?,
         })
     })?;
 
@@ -59,6 +62,26 @@ pub fn delete_account(pool: &Pool, account_id: i64) -> Result<(), DEmailError> {
     Ok(())
 }
 
+/// Finds an existing account, so that signing in again re-authenticates it
+pub fn find_account_by_email(pool: &Pool, email_address: &str) -> Result<Option<Account>, DEmailError> {
+    Ok(get_accounts(pool)?
+        .into_iter()
+        .find(|account| account.email_address.eq_ignore_ascii_case(email_address)))
+}
+
+/// Records whether the account's refresh token was rejected by the provider
+pub fn set_needs_reauth(pool: &Pool, account_id: i64, needs_reauth: bool) -> Result<(), DEmailError> {
+    let conn = pool
+        .get()
+        .map_err(|_| DEmailError::Database(rusqlite::Error::InvalidQuery))?;
+
+    conn.execute(
+        "UPDATE accounts SET needs_reauth = ?1 WHERE id = ?2 AND needs_reauth != ?1",
+        rusqlite::params![needs_reauth, account_id],
+    )?;
+    Ok(())
+}
+
 pub fn store_refresh_token(account_id: i64, refresh_token: &str) -> Result<(), DEmailError> {
     let entry = keyring::Entry::new("demail", &format!("account_{}", account_id));
     entry.set_password(refresh_token)?;
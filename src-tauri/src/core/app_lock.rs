@@ -0,0 +1,404 @@
+// App lock module - Passphrase lock for the UI with idle and suspend auto-lock
+
+use crate::core::cache::db::{get_setting, save_setting, Pool};
+use crate::core::validation;
+use crate::error::DEmailError;
+use argon2::password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, SaltString};
+use argon2::{Argon2, PasswordVerifier};
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+use tauri::{AppHandle, Manager};
+use tracing::info;
+
+/// Settings keys, hidden from the generic settings commands
+const SETTING_HASH: &str = "app_lock_hash";
+const SETTING_IDLE_MINUTES: &str = "app_lock_idle_minutes";
+const RESERVED_PREFIX: &str = "app_lock_";
+
+pub const DEFAULT_IDLE_MINUTES: u64 = 15;
+/// Event emitted to the frontend when the app locks itself
+pub const LOCKED_EVENT: &str = "app-locked";
+
+const WATCH_INTERVAL: Duration = Duration::from_secs(5);
+/// Wall-clock time that may pass beyond monotonic time before a tick counts as a suspend
+const SUSPEND_THRESHOLD: Duration = Duration::from_secs(30);
+
+/// Wrong passphrases allowed before further attempts have to wait
+const FREE_ATTEMPTS: u32 = 3;
+/// Longest wait between attempts; the delay doubles with each failure up to this
+const MAX_ATTEMPT_DELAY: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AppLockStatus {
+    pub enabled: bool,
+    pub locked: bool,
+    /// 0 disables auto-lock on idle
+    pub idle_timeout_minutes: u64,
+}
+
+/// Lock state shared through `AppState`. Commands returning mail data call
+/// `ensure_unlocked` before touching the database.
+pub struct AppLock {
+    enabled: AtomicBool,
+    locked: AtomicBool,
+    idle_timeout_minutes: AtomicU64,
+    last_activity: Mutex<Instant>,
+    attempts: Mutex<AttemptThrottle>,
+}
+
+/// Slows down passphrase guessing: after `FREE_ATTEMPTS` failures every further attempt
+/// must wait, twice as long each time
+#[derive(Debug, Default)]
+struct AttemptThrottle {
+    failures: u32,
+    retry_at: Option<Instant>,
+}
+
+impl AttemptThrottle {
+    /// Time left before the next attempt is allowed
+    fn wait(&self, now: Instant) -> Option<Duration> {
+        self.retry_at
+            .and_then(|retry_at| retry_at.checked_duration_since(now))
+            .filter(|wait| !wait.is_zero())
+    }
+
+    fn record_failure(&mut self, now: Instant) {
+        self.failures = self.failures.saturating_add(1);
+        if self.failures >= FREE_ATTEMPTS {
+            let doublings = (self.failures - FREE_ATTEMPTS).min(16);
+            let delay = Duration::from_secs(1 << doublings).min(MAX_ATTEMPT_DELAY);
+            self.retry_at = Some(now + delay);
+        }
+    }
+
+    fn reset(&mut self) {
+        *self = Self::default();
+    }
+}
+
+impl Default for AppLock {
+    fn default() -> Self {
+        Self {
+            enabled: AtomicBool::new(false),
+            locked: AtomicBool::new(false),
+            idle_timeout_minutes: AtomicU64::new(DEFAULT_IDLE_MINUTES),
+            last_activity: Mutex::new(Instant::now()),
+            attempts: Mutex::new(AttemptThrottle::default()),
+        }
+    }
+}
+
+impl AppLock {
+    /// Reads the lock settings. When a passphrase is set the app starts locked.
+    pub fn load(&self, pool: &Pool, start_locked: bool) -> Result<(), DEmailError> {
+        let enabled = get_setting(pool, SETTING_HASH)?.is_some();
+        let idle_minutes = get_setting(pool, SETTING_IDLE_MINUTES)?
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(DEFAULT_IDLE_MINUTES);
+
+        self.enabled.store(enabled, Ordering::SeqCst);
+        self.locked.store(enabled && start_locked, Ordering::SeqCst);
+        self.idle_timeout_minutes
+            .store(idle_minutes, Ordering::SeqCst);
+        self.record_activity();
+        Ok(())
+    }
+
+    pub fn status(&self) -> AppLockStatus {
+        AppLockStatus {
+            enabled: self.enabled.load(Ordering::SeqCst),
+            locked: self.is_locked(),
+            idle_timeout_minutes: self.idle_timeout_minutes.load(Ordering::SeqCst),
+        }
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::SeqCst)
+    }
+
+    pub fn ensure_unlocked(&self) -> Result<(), DEmailError> {
+        if self.is_locked() {
+            Err(DEmailError::Auth("DEmail is locked".to_string()))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Resets the idle timer; called by the frontend on user input
+    pub fn record_activity(&self) {
+        *self.last_activity.lock().unwrap() = Instant::now();
+    }
+
+    /// Locks immediately. Returns whether the app was unlocked before.
+    pub fn lock(&self) -> bool {
+        self.enabled.load(Ordering::SeqCst) && !self.locked.swap(true, Ordering::SeqCst)
+    }
+
+    pub fn unlock(&self, pool: &Pool, passphrase: &str) -> Result<(), DEmailError> {
+        if !self.is_locked() {
+            return Ok(());
+        }
+        self.check_passphrase(pool, passphrase)?;
+        self.locked.store(false, Ordering::SeqCst);
+        self.record_activity();
+        info!("App unlocked");
+        Ok(())
+    }
+
+    /// Sets or replaces the lock passphrase. The current passphrase is required to
+    /// replace an existing one.
+    pub fn set_passphrase(
+        &self,
+        pool: &Pool,
+        current_passphrase: Option<&str>,
+        new_passphrase: &str,
+    ) -> Result<(), DEmailError> {
+        self.ensure_unlocked()?;
+        if self.enabled.load(Ordering::SeqCst) {
+            self.check_passphrase(pool, current_passphrase.unwrap_or_default())?;
+        }
+        validation::validate_passphrase(new_passphrase)?;
+
+        let salt = SaltString::generate(&mut OsRng);
+        let hash = Argon2::default()
+            .hash_password(new_passphrase.as_bytes(), &salt)
+            .map_err(|e| DEmailError::Crypto(format!("Failed to hash passphrase: {}", e)))?
+            .to_string();
+        save_setting(pool, SETTING_HASH, &hash)?;
+
+        self.enabled.store(true, Ordering::SeqCst);
+        self.record_activity();
+        info!("App lock passphrase set");
+        Ok(())
+    }
+
+    /// Turns the app lock off after checking the current passphrase
+    pub fn disable(&self, pool: &Pool, passphrase: &str) -> Result<(), DEmailError> {
+        self.ensure_unlocked()?;
+        self.check_passphrase(pool, passphrase)?;
+
+        let conn = pool
+            .get()
+            .map_err(|_| DEmailError::Database(rusqlite::Error::InvalidQuery))?;
+        conn.execute("DELETE FROM settings WHERE key = ?1", [SETTING_HASH])?;
+
+        self.enabled.store(false, Ordering::SeqCst);
+        info!("App lock disabled");
+        Ok(())
+    }
+
+    pub fn set_idle_timeout(&self, pool: &Pool, minutes: u64) -> Result<(), DEmailError> {
+        self.ensure_unlocked()?;
+        if minutes > 24 * 60 {
+            return Err(DEmailError::Validation(
+                "Idle timeout must be at most 24 hours".to_string(),
+            ));
+        }
+        save_setting(pool, SETTING_IDLE_MINUTES, &minutes.to_string())?;
+        self.idle_timeout_minutes.store(minutes, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Verifies the passphrase, refusing to even try while a wait after repeated
+    /// failures is still running
+    fn check_passphrase(&self, pool: &Pool, passphrase: &str) -> Result<(), DEmailError> {
+        let mut attempts = self.attempts.lock().unwrap();
+        let now = Instant::now();
+        if let Some(wait) = attempts.wait(now) {
+            return Err(DEmailError::Auth(format!(
+                "Too many wrong passphrases, try again in {} seconds",
+                wait.as_secs().max(1)
+            )));
+        }
+        match verify_passphrase(pool, passphrase) {
+            Ok(()) => {
+                attempts.reset();
+                Ok(())
+            }
+            Err(e) => {
+                attempts.record_failure(now);
+                Err(e)
+            }
+        }
+    }
+
+    /// Whether the idle timeout has passed since the last recorded activity
+    fn is_idle(&self, now: Instant) -> bool {
+        let minutes = self.idle_timeout_minutes.load(Ordering::SeqCst);
+        minutes > 0
+            && now.duration_since(*self.last_activity.lock().unwrap())
+                >= Duration::from_secs(minutes * 60)
+    }
+}
+
+/// Setting keys managed here cannot be read or written through the generic settings
+/// commands, so the passphrase hash never reaches the frontend
+pub fn is_reserved_setting(key: &str) -> bool {
+    key.starts_with(RESERVED_PREFIX)
+}
+
+fn verify_passphrase(pool: &Pool, passphrase: &str) -> Result<(), DEmailError> {
+    let hash = get_setting(pool, SETTING_HASH)?
+        .ok_or_else(|| DEmailError::Auth("No app lock passphrase is set".to_string()))?;
+    let parsed = PasswordHash::new(&hash)
+        .map_err(|e| DEmailError::Crypto(format!("Invalid stored passphrase hash: {}", e)))?;
+    Argon2::default()
+        .verify_password(passphrase.as_bytes(), &parsed)
+        .map_err(|_| DEmailError::Auth("Wrong passphrase".to_string()))
+}
+
+/// Whether the wall clock moved on noticeably further than the monotonic clock, which
+/// stops while the system is suspended
+fn resumed_from_suspend(wall_elapsed: Duration, monotonic_elapsed: Duration) -> bool {
+    wall_elapsed.saturating_sub(monotonic_elapsed) > SUSPEND_THRESHOLD
+}
+
+/// Locks the app after the idle timeout and after a system suspend, and notifies the
+/// frontend with the `app-locked` event
+pub fn start_idle_watcher(app_handle: AppHandle) {
+    std::thread::spawn(move || {
+        let mut last_tick = (Instant::now(), SystemTime::now());
+        loop {
+            std::thread::sleep(WATCH_INTERVAL);
+            let now = (Instant::now(), SystemTime::now());
+            let monotonic_elapsed = now.0.duration_since(last_tick.0);
+            let wall_elapsed = now.1.duration_since(last_tick.1).unwrap_or_default();
+            last_tick = now;
+
+            let app_state = app_handle.state::<crate::AppState>();
+            let app_lock = &app_state.app_lock;
+            let reason = if resumed_from_suspend(wall_elapsed, monotonic_elapsed) {
+                "suspend"
+            } else if app_lock.is_idle(now.0) {
+                "idle"
+            } else {
+                continue;
+            };
+
+            if app_lock.lock() {
+                info!("App locked after {}", reason);
+                let _ = app_handle.emit_all(LOCKED_EVENT, reason);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::cache::db::test_pool;
+
+    #[test]
+    fn test_lock_and_unlock_with_passphrase() {
+        let pool = test_pool();
+        let app_lock = AppLock::default();
+        app_lock.load(&pool, true).unwrap();
+        assert!(!app_lock.status().enabled);
+        // Locking without a passphrase does nothing
+        assert!(!app_lock.lock());
+
+        app_lock
+            .set_passphrase(&pool, None, "correct horse battery")
+            .unwrap();
+        assert!(get_setting(&pool, SETTING_HASH)
+            .unwrap()
+            .unwrap()
+            .starts_with("$argon2id$"));
+
+        assert!(app_lock.lock());
+        assert!(app_lock.ensure_unlocked().is_err());
+        assert!(app_lock.unlock(&pool, "wrong horse battery").is_err());
+        assert!(app_lock.is_locked());
+        app_lock.unlock(&pool, "correct horse battery").unwrap();
+        assert!(app_lock.ensure_unlocked().is_ok());
+
+        // A restart with a passphrase set starts locked
+        let restarted = AppLock::default();
+        restarted.load(&pool, true).unwrap();
+        assert!(restarted.is_locked());
+    }
+
+    #[test]
+    fn test_change_and_disable_passphrase() {
+        let pool = test_pool();
+        let app_lock = AppLock::default();
+        app_lock
+            .set_passphrase(&pool, None, "correct horse battery")
+            .unwrap();
+        assert!(app_lock
+            .set_passphrase(&pool, Some("wrong horse battery"), "new passphrase!")
+            .is_err());
+        app_lock
+            .set_passphrase(&pool, Some("correct horse battery"), "new passphrase!")
+            .unwrap();
+
+        assert!(app_lock.disable(&pool, "correct horse battery").is_err());
+        app_lock.disable(&pool, "new passphrase!").unwrap();
+        assert!(!app_lock.status().enabled);
+        assert!(get_setting(&pool, SETTING_HASH).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_idle_timeout() {
+        let pool = test_pool();
+        let app_lock = AppLock::default();
+        app_lock.set_idle_timeout(&pool, 5).unwrap();
+        let now = Instant::now();
+        assert!(!app_lock.is_idle(now));
+        assert!(app_lock.is_idle(now + Duration::from_secs(5 * 60)));
+
+        app_lock.set_idle_timeout(&pool, 0).unwrap();
+        assert!(!app_lock.is_idle(now + Duration::from_secs(24 * 60 * 60)));
+        assert!(app_lock.set_idle_timeout(&pool, 24 * 60 + 1).is_err());
+    }
+
+    #[test]
+    fn test_wrong_passphrases_back_off() {
+        let now = Instant::now();
+        let mut attempts = AttemptThrottle::default();
+        for _ in 1..FREE_ATTEMPTS {
+            attempts.record_failure(now);
+            assert!(attempts.wait(now).is_none());
+        }
+        attempts.record_failure(now);
+        assert_eq!(attempts.wait(now), Some(Duration::from_secs(1)));
+        assert!(attempts.wait(now + Duration::from_secs(1)).is_none());
+        attempts.record_failure(now);
+        assert_eq!(attempts.wait(now), Some(Duration::from_secs(2)));
+        for _ in 0..40 {
+            attempts.record_failure(now);
+        }
+        assert_eq!(attempts.wait(now), Some(MAX_ATTEMPT_DELAY));
+        attempts.reset();
+        assert!(attempts.wait(now).is_none());
+
+        // Once the wait starts even the right passphrase is refused until it is over
+        let pool = test_pool();
+        let app_lock = AppLock::default();
+        app_lock
+            .set_passphrase(&pool, None, "correct horse battery")
+            .unwrap();
+        app_lock.lock();
+        for _ in 0..FREE_ATTEMPTS {
+            assert!(app_lock.unlock(&pool, "wrong horse battery").is_err());
+        }
+        assert!(app_lock.unlock(&pool, "correct horse battery").is_err());
+        assert!(app_lock.is_locked());
+    }
+
+    #[test]
+    fn test_suspend_detection_and_reserved_keys() {
+        assert!(!resumed_from_suspend(
+            Duration::from_secs(5),
+            Duration::from_secs(5)
+        ));
+        assert!(resumed_from_suspend(
+            Duration::from_secs(3600),
+            Duration::from_secs(5)
+        ));
+        assert!(is_reserved_setting(SETTING_HASH));
+        assert!(!is_reserved_setting("theme"));
+    }
+}
@@ -1,13 +1,11 @@
-use crate::core::cache::db::{get_attachment_data, save_attachment_data};
+use crate::core::cache::db::{get_attachment_data, save_attachment_data, Pool};
 use crate::error::DEmailError;
 use crate::models::Attachment;
-use r2d2::Pool;
-use r2d2_sqlite::SqliteConnectionManager;
 use std::fs;
 use std::path::Path;
 
 pub fn save_attachment_to_disk(
-    pool: &Pool<SqliteConnectionManager>,
+    pool: &Pool,
     attachment: &Attachment,
     destination_path: &str,
 ) -> Result<(), DEmailError> {
@@ -24,7 +22,7 @@ pub fn save_attachment_to_disk(
 }
 
 pub fn store_attachment_data(
-    pool: &Pool<SqliteConnectionManager>,
+    pool: &Pool,
     attachment_id: i64,
     data: Vec<u8>,
 ) -> Result<(), DEmailError> {
@@ -69,3 +67,17 @@ pub fn read_file_for_attachment(file_path: &str) -> Result<Vec<u8>, DEmailError>
 
     Ok(data)
 }
+
+/// Reads a file to attach, returning its name, guessed MIME type and contents
+pub fn read_attachment_file(file_path: &str) -> Result<(String, String, Vec<u8>), DEmailError> {
+    let data = read_file_for_attachment(file_path)?;
+    let filename = Path::new(file_path)
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or("attachment")
+        .to_string();
+    let mime_type = mime_guess::from_path(file_path)
+        .first_or_octet_stream()
+        .to_string();
+    Ok((filename, mime_type, data))
+}
@@ -1,75 +1,81 @@
-use crate::core::accounts::{create_account, store_refresh_token};
+use crate::core::accounts::{
+    create_account, find_account_by_email, set_needs_reauth, store_refresh_token,
+};
+use crate::core::app_lock::AppLock;
 use crate::core::cache::db;
-use crate::core::config::load_config;
+use crate::core::device_flow::{self, DeviceAuthorization};
+use crate::core::oauth_loopback::{LoopbackListener, RedirectResponse, REDIRECT_TIMEOUT};
+use crate::core::providers::{
+    credentials_for_provider, oauth_client, ProviderClient, ProviderDefinition, ProviderRegistry,
+};
+use crate::core::sync::draft_sync::DraftSyncState;
+use crate::core::sync::imap_pool::ImapPool;
+use crate::core::sync::scheduler::SyncScheduler;
+use crate::core::tokens::TokenManager;
 use crate::error::DEmailError;
 use crate::models::Account;
-use oauth2::basic::BasicClient;
+use oauth2::basic::BasicTokenType;
 use oauth2::reqwest::async_http_client;
 use oauth2::{
-    AuthUrl, AuthorizationCode, ClientId, ClientSecret, CsrfToken, PkceCodeChallenge,
-    PkceCodeVerifier, RedirectUrl, Scope, TokenResponse, TokenUrl,
+    AuthorizationCode, CsrfToken, ExtraTokenFields, PkceCodeChallenge, PkceCodeVerifier,
+    RedirectUrl, Scope, StandardTokenResponse, TokenResponse,
 };
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::Instant;
 use tauri::{AppHandle, Config, Manager, State};
+use tracing::{info, warn};
 
 pub struct AppState {
     pub db_pool: Arc<db::Pool>,
     pub app_config: Arc<std::sync::Mutex<Config>>,
+    pub app_lock: Arc<AppLock>,
+    pub token_manager: Arc<TokenManager>,
+    pub sync_scheduler: Arc<SyncScheduler>,
+    pub imap_pool: Arc<ImapPool>,
+    pub draft_sync: Arc<DraftSyncState>,
+}
+
+/// Token endpoint fields beyond the standard ones that sign-in needs
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SignInTokenFields {
+    /// OpenID Connect ID token, read for the account address when there is no userinfo
+    /// endpoint
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id_token: Option<String>,
 }
 
+impl ExtraTokenFields for SignInTokenFields {}
+
+pub type SignInClient = ProviderClient<StandardTokenResponse<SignInTokenFields, BasicTokenType>>;
+
 #[derive(Debug)]
 pub struct OAuth2State {
-    pub client: BasicClient,
+    pub client: SignInClient,
     pub pkce_verifier: PkceCodeVerifier,
     pub csrf_token: CsrfToken,
-    pub provider: String,
+    pub provider: ProviderDefinition,
+    /// Address the user entered, used when the provider has no userinfo endpoint
+    pub email_address: String,
+    pub created_at: Instant,
 }
 
-pub type OAuth2StateMap = std::sync::Mutex<HashMap<String, OAuth2State>>;
-
-pub fn get_google_client(
-    client_id: String,
-    client_secret: String,
-) -> Result<BasicClient, DEmailError> {
-    let auth_url = AuthUrl::new("https://accounts.google.com/o/oauth2/v2/auth".to_string())
-        .map_err(|e| DEmailError::OAuth(e.to_string()))?;
-    let token_url = TokenUrl::new("https://oauth2.googleapis.com/token".to_string())
-        .map_err(|e| DEmailError::OAuth(e.to_string()))?;
+/// Emitted with the new or re-authenticated account when a sign-in completes
+pub const OAUTH_COMPLETED_EVENT: &str = "oauth-completed";
+/// Emitted with an error message when a sign-in fails, is denied or times out
+pub const OAUTH_FAILED_EVENT: &str = "oauth-failed";
 
-    Ok(BasicClient::new(
-        ClientId::new(client_id),
-        Some(ClientSecret::new(client_secret)),
-        auth_url,
-        Some(token_url),
-    )
-    .set_redirect_uri(
-        RedirectUrl::new("http://localhost:1420/callback".to_string())
-            .map_err(|e| DEmailError::OAuth(e.to_string()))?,
-    ))
-}
+pub type OAuth2StateMap = std::sync::Mutex<HashMap<String, OAuth2State>>;
 
-pub fn get_microsoft_client(
-    client_id: String,
-    client_secret: String,
-) -> Result<BasicClient, DEmailError> {
-    let auth_url =
-        AuthUrl::new("https://login.microsoftonline.com/common/oauth2/v2.0/authorize".to_string())
-            .map_err(|e| DEmailError::OAuth(e.to_string()))?;
-    let token_url =
-        TokenUrl::new("https://login.microsoftonline.com/common/oauth2/v2.0/token".to_string())
-            .map_err(|e| DEmailError::OAuth(e.to_string()))?;
-
-    Ok(BasicClient::new(
-        ClientId::new(client_id),
-        Some(ClientSecret::new(client_secret)),
-        auth_url,
-        Some(token_url),
-    )
-    .set_redirect_uri(
-        RedirectUrl::new("http://localhost:1420/callback".to_string())
-            .map_err(|e| DEmailError::OAuth(e.to_string()))?,
-    ))
+/// What the user has to do to finish adding an account
+#[derive(Debug, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum SignInPrompt {
+    /// Sign in on the page opened in the system browser
+    Browser { auth_url: String },
+    /// Enter the user code at the verification URI on any device
+    DeviceCode(DeviceAuthorization),
 }
 
 pub async fn start_oauth_flow(
@@ -77,71 +83,114 @@ pub async fn start_oauth_flow(
     email_address: String,
 ) -> Result<String, DEmailError> {
     let app_state = app_handle.state::<AppState>();
-    let config = app_state.app_config.lock().unwrap();
-    let configs = load_config(&config)?;
-
-    let provider = if email_address.ends_with("@gmail.com") {
-        "google".to_string()
-    } else if email_address.ends_with("@outlook.com") || email_address.ends_with("@hotmail.com") {
-        "microsoft".to_string()
-    } else {
-        return Err(DEmailError::OAuth("Unsupported provider".to_string()));
-    };
+    let config = app_state.app_config.lock().unwrap().clone();
 
-    let provider_config = configs
-        .get(&provider)
-        .ok_or_else(|| DEmailError::Config(format!("OAuth config for {} not found", provider)))?;
-
-    let client = match provider.as_str() {
-        "google" => get_google_client(
-            provider_config.client_id.clone(),
-            provider_config.client_secret.clone(),
-        )?,
-        "microsoft" => get_microsoft_client(
-            provider_config.client_id.clone(),
-            provider_config.client_secret.clone(),
-        )?,
-        _ => return Err(DEmailError::OAuth("Unsupported provider".to_string())),
-    };
+    let registry = ProviderRegistry::load(&config)?;
+    let provider_id = registry.detect(&email_address).await?.id.clone();
+    let (provider, credentials) = credentials_for_provider(&config, &provider_id)?;
+    let client: SignInClient =
+        oauth_client(&provider, credentials.client_id, credentials.client_secret)?;
+
+    // RFC 8252: the authorization response comes back to an ephemeral loopback port
+    let listener = LoopbackListener::bind().await?;
+    let client = client.set_redirect_uri(
+        RedirectUrl::new(listener.redirect_uri()).map_err(|e| DEmailError::OAuth(e.to_string()))?,
+    );
 
     let (pkce_challenge, pkce_verifier) = PkceCodeChallenge::new_random_sha256();
 
-    let (auth_url, csrf_token) = client
+    let mut request = client
         .authorize_url(CsrfToken::new_random)
-        .add_scope(Scope::new("https://mail.google.com/".to_string())) // for google
-        .add_scope(Scope::new("offline_access".to_string()))
-        .add_scope(Scope::new(
-            "https://outlook.office.com/IMAP.AccessAsUser.All".to_string(),
-        ))
-        .add_scope(Scope::new(
-            "https://outlook.office.com/SMTP.Send".to_string(),
-        ))
+        .add_scopes(provider.scopes.iter().cloned().map(Scope::new))
         .set_pkce_challenge(pkce_challenge)
-        .url();
+        .add_extra_param("login_hint", email_address.clone());
+    for (name, value) in &provider.auth_params {
+        request = request.add_extra_param(name.clone(), value.clone());
+    }
+    let (auth_url, csrf_token) = request.url();
 
-    let oauth_state_map = app_handle.state::<OAuth2StateMap>();
-    let mut state_map = oauth_state_map.lock().unwrap();
-    state_map.insert(
+    {
+        let oauth_state_map = app_handle.state::<OAuth2StateMap>();
+        let mut state_map = oauth_state_map.lock().unwrap();
+        purge_expired_states(&mut state_map);
+        state_map.insert(
+            csrf_token.secret().clone(),
+            OAuth2State {
+                client,
+                pkce_verifier,
+                csrf_token: csrf_token.clone(),
+                provider,
+                email_address,
+                created_at: Instant::now(),
+            },
+        );
+    }
+
+    tokio::spawn(complete_oauth_flow(
+        app_handle.clone(),
+        listener,
         csrf_token.secret().clone(),
-        OAuth2State {
-            client,
-            pkce_verifier,
-            csrf_token: csrf_token.clone(),
-            provider,
-        },
-    );
+    ));
+
+    if let Err(e) = tauri::api::shell::open(&app_handle.shell_scope(), auth_url.as_str(), None) {
+        warn!("Could not open the system browser: {}", e);
+    }
 
     Ok(auth_url.to_string())
 }
 
+/// Waits for the browser redirect, exchanges the code and reports the outcome to the
+/// frontend. The pending state is dropped if the user never finishes signing in.
+async fn complete_oauth_flow(app_handle: AppHandle, listener: LoopbackListener, csrf_state: String) {
+    let result = match listener.wait_for_redirect(REDIRECT_TIMEOUT).await {
+        Ok(RedirectResponse::Code { code, state }) if state == csrf_state => {
+            handle_oauth_callback(app_handle.clone(), code, state).await
+        }
+        Ok(RedirectResponse::Code { .. }) => Err(DEmailError::Auth("Invalid OAuth state".to_string())),
+        Ok(RedirectResponse::Error { error, .. }) => Err(DEmailError::OAuth(format!(
+            "Authorization was not granted: {}",
+            error
+        ))),
+        Err(e) => Err(e),
+    };
+
+    if result.is_err() {
+        app_handle
+            .state::<OAuth2StateMap>()
+            .lock()
+            .unwrap()
+            .remove(&csrf_state);
+    }
+    report_sign_in(&app_handle, result);
+}
+
+fn report_sign_in(app_handle: &AppHandle, result: Result<Account, DEmailError>) {
+    match result {
+        Ok(account) => {
+            info!("OAuth sign-in completed for {}", account.email_address);
+            let _ = app_handle.emit_all(OAUTH_COMPLETED_EVENT, account);
+        }
+        Err(e) => {
+            warn!("OAuth sign-in failed: {}", e);
+            let _ = app_handle.emit_all(OAUTH_FAILED_EVENT, e.to_string());
+        }
+    }
+}
+
+/// Drops sign-ins that were abandoned before their redirect timed out
+fn purge_expired_states(state_map: &mut HashMap<String, OAuth2State>) {
+    state_map.retain(|_, state| state.created_at.elapsed() < REDIRECT_TIMEOUT);
+}
+
 pub async fn handle_oauth_callback(
     app_handle: AppHandle,
     code: String,
     state: String,
 ) -> Result<Account, DEmailError> {
-    let oauth_state_map = app_handle.state::<OAuth2StateMap>();
-    let mut state_map = oauth_state_map.lock().unwrap();
-    let oauth_state = state_map
+    let oauth_state = app_handle
+        .state::<OAuth2StateMap>()
+        .lock()
+        .unwrap()
         .remove(&state)
         .ok_or_else(|| DEmailError::Auth("Invalid OAuth state".to_string()))?;
 
@@ -157,59 +206,174 @@ pub async fn handle_oauth_callback(
         .refresh_token()
         .ok_or_else(|| DEmailError::OAuth("No refresh token found".to_string()))?;
 
-    let access_token = token_result.access_token();
-
-    let (email_address, display_name) = match oauth_state.provider.as_str() {
-        "google" => {
-            let client = reqwest::Client::new();
-            let response: serde_json::Value = client
-                .get("https://www.googleapis.com/oauth2/v2/userinfo")
-                .bearer_auth(access_token.secret())
-                .send()
-                .await
-                .map_err(|e| DEmailError::OAuth(e.to_string()))?
-                .json()
-                .await
-                .map_err(|e| DEmailError::OAuth(e.to_string()))?;
-
-            let email = response["email"]
-                .as_str()
-                .ok_or_else(|| DEmailError::OAuth("Email not found in userinfo".to_string()))?
-                .to_string();
-            let name = response["name"].as_str().unwrap_or(&email).to_string();
-            (email, name)
-        }
-        "microsoft" => {
-            let client = reqwest::Client::new();
-            let response: serde_json::Value = client
-                .get("https://graph.microsoft.com/v1.0/me")
-                .bearer_auth(access_token.secret())
-                .send()
-                .await
-                .map_err(|e| DEmailError::OAuth(e.to_string()))?
-                .json()
-                .await
-                .map_err(|e| DEmailError::OAuth(e.to_string()))?;
-
-            let email = response["userPrincipalName"]
-                .as_str()
-                .ok_or_else(|| DEmailError::OAuth("Email not found in userinfo".to_string()))?
-                .to_string();
-            let name = response["displayName"]
-                .as_str()
-                .unwrap_or(&email)
-                .to_string();
-            (email, name)
+    finish_sign_in(
+        &app_handle,
+        &oauth_state.provider,
+        &oauth_state.email_address,
+        token_result.access_token().secret(),
+        refresh_token.secret(),
+        token_result.extra_fields().id_token.as_deref(),
+    )
+    .await
+}
+
+/// Starts a device code sign-in and returns the code for the user to enter. Polling
+/// continues in the background and ends with the same events as the browser flow.
+pub async fn start_device_flow(
+    app_handle: AppHandle,
+    email_address: String,
+) -> Result<DeviceAuthorization, DEmailError> {
+    let app_state = app_handle.state::<AppState>();
+    let config = app_state.app_config.lock().unwrap().clone();
+
+    let registry = ProviderRegistry::load(&config)?;
+    let provider_id = registry.detect(&email_address).await?.id.clone();
+    let (provider, credentials) = credentials_for_provider(&config, &provider_id)?;
+
+    let authorization = device_flow::request_device_authorization(
+        &provider,
+        &credentials.client_id,
+        &credentials.client_secret,
+    )
+    .await?;
+
+    let pending = authorization.clone();
+    tokio::spawn(async move {
+        let result = async {
+            let tokens = device_flow::poll_for_token(
+                &provider,
+                &credentials.client_id,
+                &credentials.client_secret,
+                &pending,
+            )
+            .await?;
+            let refresh_token = tokens
+                .refresh_token
+                .ok_or_else(|| DEmailError::OAuth("No refresh token found".to_string()))?;
+            finish_sign_in(
+                &app_handle,
+                &provider,
+                &email_address,
+                &tokens.access_token,
+                &refresh_token,
+                tokens.id_token.as_deref(),
+            )
+            .await
         }
-        _ => return Err(DEmailError::OAuth("Unsupported provider".to_string())),
+        .await;
+        report_sign_in(&app_handle, result);
+    });
+
+    Ok(authorization)
+}
+
+/// Creates the account for a completed sign-in, or re-authenticates the existing one
+async fn finish_sign_in(
+    app_handle: &AppHandle,
+    provider: &ProviderDefinition,
+    entered_address: &str,
+    access_token: &str,
+    refresh_token: &str,
+    id_token: Option<&str>,
+) -> Result<Account, DEmailError> {
+    let (email_address, display_name) = match (&provider.userinfo_url, id_token) {
+        (Some(userinfo_url), _) => fetch_identity(provider, userinfo_url, access_token).await?,
+        (None, Some(id_token)) => identity_from_claims(provider, &id_token_claims(id_token)?)?,
+        (None, None) => (entered_address.to_string(), entered_address.to_string()),
     };
 
     let app_state = app_handle.state::<AppState>();
     let pool = &app_state.db_pool;
 
-    let account = create_account(pool, &email_address, &display_name, &oauth_state.provider)?;
+    // Signing in to an existing account re-authenticates it
+    let account = match find_account_by_email(pool, &email_address)? {
+        Some(mut account) => {
+            set_needs_reauth(pool, account.id, false)?;
+            app_state.token_manager.invalidate(account.id);
+            account.needs_reauth = false;
+            account
+        }
+        None => create_account(pool, &email_address, &display_name, &provider.id)?,
+    };
 
-    store_refresh_token(account.id, refresh_token.secret())?;
+    store_refresh_token(account.id, refresh_token)?;
 
     Ok(account)
 }
+
+/// Reads the address and display name from the provider's userinfo endpoint
+async fn fetch_identity(
+    provider: &ProviderDefinition,
+    userinfo_url: &str,
+    access_token: &str,
+) -> Result<(String, String), DEmailError> {
+    let response: serde_json::Value = reqwest::Client::new()
+        .get(userinfo_url)
+        .bearer_auth(access_token)
+        .send()
+        .await
+        .map_err(|e| DEmailError::OAuth(e.to_string()))?
+        .json()
+        .await
+        .map_err(|e| DEmailError::OAuth(e.to_string()))?;
+    identity_from_claims(provider, &response)
+}
+
+/// Reads the claims of an ID token. It came straight from the token endpoint over TLS,
+/// so the signature does not need to be checked (OpenID Connect Core 3.1.3.7).
+fn id_token_claims(id_token: &str) -> Result<serde_json::Value, DEmailError> {
+    let invalid = || DEmailError::OAuth("Invalid ID token".to_string());
+    let payload = id_token.split('.').nth(1).ok_or_else(invalid)?;
+    // base64url without padding
+    let mut payload = payload.replace('-', "+").replace('_', "/");
+    while payload.len() % 4 != 0 {
+        payload.push('=');
+    }
+    let json = openssl::base64::decode_block(&payload).map_err(|_| invalid())?;
+    serde_json::from_slice(&json).map_err(|_| invalid())
+}
+
+fn identity_from_claims(
+    provider: &ProviderDefinition,
+    claims: &serde_json::Value,
+) -> Result<(String, String), DEmailError> {
+    let first_claim = |names: &[String]| {
+        names
+            .iter()
+            .find_map(|name| claims[name.as_str()].as_str().filter(|value| !value.is_empty()))
+            .map(|value| value.to_string())
+    };
+    let email = first_claim(&provider.email_claims)
+        .ok_or_else(|| DEmailError::OAuth("Email not found in identity claims".to_string()))?;
+    let name = first_claim(&provider.name_claims).unwrap_or_else(|| email.clone());
+    Ok((email, name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::providers::builtin_providers;
+
+    #[test]
+    fn test_identity_from_id_token() {
+        let microsoft = builtin_providers()
+            .into_iter()
+            .find(|provider| provider.id == "microsoft")
+            .unwrap();
+        let claims =
+            r#"{"aud":"client","preferred_username":"jane@contoso.com","name":"Jane Doe"}"#;
+        let payload = openssl::base64::encode_block(claims.as_bytes())
+            .trim_end_matches('=')
+            .replace('+', "-")
+            .replace('/', "_");
+        let id_token = format!("eyJhbGciOiJSUzI1NiJ9.{}.c2ln", payload);
+
+        let identity = identity_from_claims(&microsoft, &id_token_claims(&id_token).unwrap());
+        assert_eq!(
+            identity.unwrap(),
+            ("jane@contoso.com".to_string(), "Jane Doe".to_string())
+        );
+        assert!(id_token_claims("not-a-token").is_err());
+        assert!(id_token_claims("a.!!!.c").is_err());
+    }
+}
@@ -0,0 +1,492 @@
+// Backup module - Writes and restores passphrase-encrypted, portable profile archives
+
+use crate::core::accounts::{get_accounts, get_refresh_token, store_refresh_token};
+use crate::core::cache::db::{get_db_path, Pool};
+use crate::core::cache::encryption;
+use crate::core::cache::schema::initialize_schema;
+use crate::core::config::{
+    get_client_secret, get_config_path, load_config_info, migrate_plaintext_secrets,
+    store_client_secret,
+};
+use crate::core::migrations;
+use crate::core::providers::get_providers_path;
+use crate::core::validation;
+use crate::error::DEmailError;
+use argon2::Argon2;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use tauri::Config;
+use tracing::{info, warn};
+
+/// Magic bytes identifying a DEmail backup archive
+const BACKUP_MAGIC: &[u8; 8] = b"DEMAILBK";
+/// Version of the encrypted container layout
+const CONTAINER_VERSION: u8 = 1;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 24;
+const HEADER_LEN: usize = BACKUP_MAGIC.len() + 1 + SALT_LEN + NONCE_LEN;
+
+const MANIFEST_ENTRY: &str = "manifest.json";
+const DATABASE_ENTRY: &str = "demail.sqlite";
+const CONFIG_ENTRY: &str = "demail_config.json";
+const PROVIDERS_ENTRY: &str = "demail_providers.json";
+const CREDENTIALS_ENTRY: &str = "credentials.json";
+const CLIENT_SECRETS_ENTRY: &str = "client_secrets.json";
+
+/// Describes the contents of a backup archive
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct BackupManifest {
+    pub app_version: String,
+    pub schema_version: i64,
+    pub created_at: i64,
+    pub account_count: i64,
+    pub includes_config: bool,
+    pub includes_credentials: bool,
+}
+
+/// Creates an encrypted backup of the database, OAuth config and optionally the
+/// refresh tokens stored in the OS keyring
+pub fn create_backup(
+    pool: &Pool,
+    config: &Config,
+    destination_path: &str,
+    passphrase: &str,
+    include_credentials: bool,
+) -> Result<BackupManifest, DEmailError> {
+    validation::validate_passphrase(passphrase)?;
+
+    let conn = pool
+        .get()
+        .map_err(|_| DEmailError::Database(rusqlite::Error::InvalidQuery))?;
+
+    let schema_version = migrations::get_current_version(&conn)?;
+    let database = snapshot_database(&conn, &snapshot_dir(config)?)?;
+    drop(conn);
+
+    let config_path = get_config_path(config)?;
+    let config_data = if config_path.exists() {
+        Some(fs::read(&config_path)?)
+    } else {
+        None
+    };
+    let providers_path = get_providers_path(config)?;
+    let providers_data = if providers_path.exists() {
+        Some(fs::read(&providers_path)?)
+    } else {
+        None
+    };
+
+    let accounts = get_accounts(pool)?;
+    let credentials = if include_credentials {
+        let mut tokens = HashMap::new();
+        for account in &accounts {
+            match get_refresh_token(account.id) {
+                Ok(token) => {
+                    tokens.insert(account.id, token);
+                }
+                Err(e) => warn!(
+                    "Skipping credentials for account {} in backup: {}",
+                    account.id, e
+                ),
+            }
+        }
+        Some(serde_json::to_vec(&tokens)?)
+    } else {
+        None
+    };
+    let client_secrets = if include_credentials {
+        let mut secrets = HashMap::new();
+        for provider in load_config_info(config)?.into_keys() {
+            if let Some(secret) = get_client_secret(&provider)? {
+                secrets.insert(provider, secret);
+            }
+        }
+        Some(serde_json::to_vec(&secrets)?)
+    } else {
+        None
+    };
+
+    let manifest = BackupManifest {
+        app_version: env!("CARGO_PKG_VERSION").to_string(),
+        schema_version,
+        created_at: chrono::Utc::now().timestamp(),
+        account_count: accounts.len() as i64,
+        includes_config: config_data.is_some(),
+        includes_credentials: credentials.is_some(),
+    };
+
+    let mut entries: Vec<(&str, Vec<u8>)> = vec![
+        (MANIFEST_ENTRY, serde_json::to_vec_pretty(&manifest)?),
+        (DATABASE_ENTRY, database),
+    ];
+    if let Some(data) = config_data {
+        entries.push((CONFIG_ENTRY, data));
+    }
+    if let Some(data) = providers_data {
+        entries.push((PROVIDERS_ENTRY, data));
+    }
+    if let Some(data) = credentials {
+        entries.push((CREDENTIALS_ENTRY, data));
+    }
+    if let Some(data) = client_secrets {
+        entries.push((CLIENT_SECRETS_ENTRY, data));
+    }
+
+    let archive = build_archive(&entries)?;
+    let encrypted = encrypt_archive(&archive, passphrase)?;
+
+    let destination = Path::new(destination_path);
+    if let Some(parent) = destination.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(destination, encrypted)?;
+
+    info!(
+        "Backup written to {} (schema v{}, {} accounts)",
+        destination_path, manifest.schema_version, manifest.account_count
+    );
+    Ok(manifest)
+}
+
+/// Restores an encrypted backup over the current profile and re-runs migrations
+pub fn restore_backup(
+    pool: &Pool,
+    config: &Config,
+    source_path: &str,
+    passphrase: &str,
+) -> Result<BackupManifest, DEmailError> {
+    let encrypted = fs::read(source_path)?;
+    let archive = decrypt_archive(&encrypted, passphrase)?;
+    let mut entries = read_archive(&archive)?;
+
+    let manifest: BackupManifest = serde_json::from_slice(
+        &entries
+            .remove(MANIFEST_ENTRY)
+            .ok_or_else(|| DEmailError::Backup("Backup manifest is missing".to_string()))?,
+    )?;
+    let database = entries
+        .remove(DATABASE_ENTRY)
+        .ok_or_else(|| DEmailError::Backup("Backup database is missing".to_string()))?;
+
+    // Validate the snapshot before touching the live database
+    let snapshot = SnapshotFile::create(&snapshot_dir(config)?)?;
+    fs::write(&snapshot.path, &database)?;
+    restore_database(pool, &snapshot.path, &manifest)?;
+    drop(snapshot);
+
+    if let Some(data) = entries.remove(CONFIG_ENTRY) {
+        let config_path = get_config_path(config)?;
+        if let Some(parent) = config_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(config_path, data)?;
+        // Backups from earlier versions carry client secrets in the config file
+        migrate_plaintext_secrets(config)?;
+    }
+
+    if let Some(data) = entries.remove(PROVIDERS_ENTRY) {
+        let providers_path = get_providers_path(config)?;
+        if let Some(parent) = providers_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(providers_path, data)?;
+    }
+
+    if let Some(data) = entries.remove(CLIENT_SECRETS_ENTRY) {
+        let secrets: HashMap<String, String> = serde_json::from_slice(&data)?;
+        for (provider, secret) in secrets {
+            store_client_secret(&provider, &secret)?;
+        }
+    }
+
+    if let Some(data) = entries.remove(CREDENTIALS_ENTRY) {
+        let tokens: HashMap<i64, String> = serde_json::from_slice(&data)?;
+        for (account_id, token) in tokens {
+            store_refresh_token(account_id, &token)?;
+        }
+    }
+
+    info!(
+        "Backup from {} restored (schema v{})",
+        source_path, manifest.schema_version
+    );
+    Ok(manifest)
+}
+
+/// Copies the live database into an in-memory byte buffer. The snapshot is always
+/// plaintext so archives stay portable between encrypted and unencrypted profiles.
+fn snapshot_database(conn: &Connection, dir: &Path) -> Result<Vec<u8>, DEmailError> {
+    let snapshot = SnapshotFile::create(dir)?;
+    encryption::copy_to_plaintext(conn, &snapshot.path)?;
+    Ok(fs::read(&snapshot.path)?)
+}
+
+/// Checks the snapshot's schema version and copies it over the live database
+fn restore_database(
+    pool: &Pool,
+    snapshot_path: &Path,
+    manifest: &BackupManifest,
+) -> Result<(), DEmailError> {
+    let snapshot = Connection::open(snapshot_path)?;
+    let snapshot_version = migrations::get_current_version(&snapshot)?;
+    drop(snapshot);
+
+    check_schema_version(snapshot_version, manifest)?;
+
+    let mut conn = pool
+        .get()
+        .map_err(|_| DEmailError::Database(rusqlite::Error::InvalidQuery))?;
+
+    encryption::restore_from_plaintext(&mut conn, snapshot_path)?;
+
+    // Bring an older snapshot up to the schema this build expects
+    initialize_schema(&conn)?;
+    migrations::apply_migrations(&conn)?;
+
+    Ok(())
+}
+
+/// Rejects snapshots that do not match their manifest or come from a newer schema
+fn check_schema_version(snapshot_version: i64, manifest: &BackupManifest) -> Result<(), DEmailError> {
+    if snapshot_version != manifest.schema_version {
+        return Err(DEmailError::Backup(format!(
+            "Backup database is at schema v{} but the manifest records v{}",
+            snapshot_version, manifest.schema_version
+        )));
+    }
+
+    let latest = migrations::latest_version();
+    if snapshot_version > latest {
+        return Err(DEmailError::Backup(format!(
+            "Backup was created with schema v{} which is newer than this version of DEmail supports (v{})",
+            snapshot_version, latest
+        )));
+    }
+
+    Ok(())
+}
+
+/// Snapshots stay next to the live database rather than in the shared temp directory
+fn snapshot_dir(config: &Config) -> Result<PathBuf, DEmailError> {
+    let db_path = get_db_path(config)?;
+    db_path
+        .parent()
+        .map(Path::to_path_buf)
+        .ok_or_else(|| DEmailError::Backup("Could not determine app data directory".to_string()))
+}
+
+/// A plaintext database snapshot on disk, readable only by the user and removed when
+/// dropped, so an error part way through a backup or restore does not leave it behind
+struct SnapshotFile {
+    path: PathBuf,
+}
+
+impl SnapshotFile {
+    fn create(dir: &Path) -> Result<Self, DEmailError> {
+        fs::create_dir_all(dir)?;
+        let path = dir.join(format!("demail-backup-{}.sqlite", uuid::Uuid::new_v4()));
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        options.open(&path)?;
+        Ok(Self { path })
+    }
+}
+
+impl Drop for SnapshotFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+        let _ = fs::remove_file(self.path.with_extension("sqlite-journal"));
+    }
+}
+
+/// Packs named entries into an uncompressed tar archive
+fn build_archive(entries: &[(&str, Vec<u8>)]) -> Result<Vec<u8>, DEmailError> {
+    let mut builder = tar::Builder::new(Vec::new());
+    for (name, data) in entries {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(data.len() as u64);
+        header.set_mode(0o600);
+        header.set_mtime(chrono::Utc::now().timestamp() as u64);
+        header.set_cksum();
+        builder.append_data(&mut header, name, data.as_slice())?;
+    }
+    Ok(builder.into_inner()?)
+}
+
+/// Unpacks a tar archive into a map of entry name to contents
+fn read_archive(archive: &[u8]) -> Result<HashMap<String, Vec<u8>>, DEmailError> {
+    let mut entries = HashMap::new();
+    let mut reader = tar::Archive::new(archive);
+    for entry in reader.entries()? {
+        let mut entry = entry?;
+        let name = entry.path()?.to_string_lossy().to_string();
+        let mut data = Vec::new();
+        entry.read_to_end(&mut data)?;
+        entries.insert(name, data);
+    }
+    Ok(entries)
+}
+
+/// Derives a 256-bit key from the passphrase with Argon2id
+fn derive_key(passphrase: &str, salt: &[u8]) -> Result<[u8; 32], DEmailError> {
+    let mut key = [0u8; 32];
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| DEmailError::Backup(format!("Key derivation failed: {}", e)))?;
+    Ok(key)
+}
+
+/// Encrypts an archive as `magic | version | salt | nonce | ciphertext`
+fn encrypt_archive(archive: &[u8], passphrase: &str) -> Result<Vec<u8>, DEmailError> {
+    let mut salt = [0u8; SALT_LEN];
+    OsRng.fill_bytes(&mut salt);
+    let key = derive_key(passphrase, &salt)?;
+
+    let cipher = XChaCha20Poly1305::new(&key.into());
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = cipher
+        .encrypt(&nonce, archive)
+        .map_err(|_| DEmailError::Backup("Failed to encrypt backup".to_string()))?;
+
+    let mut output = Vec::with_capacity(HEADER_LEN + ciphertext.len());
+    output.extend_from_slice(BACKUP_MAGIC);
+    output.push(CONTAINER_VERSION);
+    output.extend_from_slice(&salt);
+    output.extend_from_slice(&nonce);
+    output.extend_from_slice(&ciphertext);
+    Ok(output)
+}
+
+/// Decrypts an archive written by `encrypt_archive`
+fn decrypt_archive(data: &[u8], passphrase: &str) -> Result<Vec<u8>, DEmailError> {
+    if data.len() < HEADER_LEN || &data[..BACKUP_MAGIC.len()] != BACKUP_MAGIC {
+        return Err(DEmailError::Backup(
+            "File is not a DEmail backup".to_string(),
+        ));
+    }
+
+    let version = data[BACKUP_MAGIC.len()];
+    if version != CONTAINER_VERSION {
+        return Err(DEmailError::Backup(format!(
+            "Unsupported backup format version: {}",
+            version
+        )));
+    }
+
+    let salt_start = BACKUP_MAGIC.len() + 1;
+    let nonce_start = salt_start + SALT_LEN;
+    let salt = &data[salt_start..nonce_start];
+    let nonce = XNonce::from_slice(&data[nonce_start..HEADER_LEN]);
+
+    let key = derive_key(passphrase, salt)?;
+    let cipher = XChaCha20Poly1305::new(&key.into());
+    cipher.decrypt(nonce, &data[HEADER_LEN..]).map_err(|_| {
+        DEmailError::Backup("Wrong passphrase or corrupted backup".to_string())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manifest_at(schema_version: i64) -> BackupManifest {
+        BackupManifest {
+            app_version: "1.0.0".to_string(),
+            schema_version,
+            created_at: 0,
+            account_count: 0,
+            includes_config: false,
+            includes_credentials: false,
+        }
+    }
+
+    #[test]
+    fn test_encrypt_decrypt_roundtrip() {
+        let data = b"archive contents".to_vec();
+        let encrypted = encrypt_archive(&data, "correct horse battery").unwrap();
+        assert_eq!(&encrypted[..8], BACKUP_MAGIC);
+        assert_eq!(
+            decrypt_archive(&encrypted, "correct horse battery").unwrap(),
+            data
+        );
+    }
+
+    #[test]
+    fn test_decrypt_rejects_wrong_passphrase_and_garbage() {
+        let encrypted = encrypt_archive(b"secret", "correct horse battery").unwrap();
+        assert!(decrypt_archive(&encrypted, "wrong passphrase").is_err());
+        assert!(decrypt_archive(b"not a backup", "correct horse battery").is_err());
+
+        let mut tampered = encrypted.clone();
+        let last = tampered.len() - 1;
+        tampered[last] ^= 0xff;
+        assert!(decrypt_archive(&tampered, "correct horse battery").is_err());
+    }
+
+    #[test]
+    fn test_archive_roundtrip() {
+        let archive = build_archive(&[
+            (MANIFEST_ENTRY, b"{}".to_vec()),
+            (DATABASE_ENTRY, vec![1, 2, 3]),
+        ])
+        .unwrap();
+        let entries = read_archive(&archive).unwrap();
+        assert_eq!(entries.get(MANIFEST_ENTRY).unwrap(), b"{}");
+        assert_eq!(entries.get(DATABASE_ENTRY).unwrap(), &vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn test_snapshot_preserves_schema_version() {
+        let conn = Connection::open_in_memory().unwrap();
+        initialize_schema(&conn).unwrap();
+        migrations::apply_migrations(&conn).unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let bytes = snapshot_database(&conn, dir.path()).unwrap();
+        // The plaintext copy is gone once the snapshot is read
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+
+        let snapshot = SnapshotFile::create(dir.path()).unwrap();
+        fs::write(&snapshot.path, bytes).unwrap();
+        let restored = Connection::open(&snapshot.path).unwrap();
+        assert_eq!(
+            migrations::get_current_version(&restored).unwrap(),
+            migrations::latest_version()
+        );
+        drop(restored);
+        drop(snapshot);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_snapshot_file_is_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let snapshot = SnapshotFile::create(dir.path()).unwrap();
+        let mode = fs::metadata(&snapshot.path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    #[test]
+    fn test_check_schema_version() {
+        let latest = migrations::latest_version();
+        assert!(check_schema_version(latest, &manifest_at(latest)).is_ok());
+        assert!(check_schema_version(1, &manifest_at(1)).is_ok());
+        assert!(check_schema_version(1, &manifest_at(2)).is_err());
+        assert!(check_schema_version(latest + 1, &manifest_at(latest + 1)).is_err());
+    }
+}
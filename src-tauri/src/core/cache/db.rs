@@ -1,3 +1,4 @@
+use crate::core::cache::encryption;
 use crate::core::cache::schema::initialize_schema;
 use crate::core::migrations;
 use crate::error::DEmailError;
@@ -5,7 +6,7 @@ use crate::models::{AppSetting, Attachment, Draft, EmailSignature, Folder, Messa
 use ammonia;
 use r2d2;
 use r2d2_sqlite::SqliteConnectionManager;
-use rusqlite::Connection;
+use rusqlite::{Connection, OptionalExtension};
 use std::fs;
 use std::path::PathBuf;
 use tauri::api::path::app_data_dir;
@@ -13,7 +14,37 @@ use tauri::Config;
 use tracing::{debug, info};
 
 /// Database connection pool type
-pub type Pool = r2d2::Pool<SqliteConnectionManager>;
+pub type Pool = r2d2::Pool<ConnectionManager>;
+
+/// Connection manager that keys every new connection for SQLCipher before use
+#[derive(Debug)]
+pub struct ConnectionManager(SqliteConnectionManager);
+
+impl ConnectionManager {
+    pub fn new(manager: SqliteConnectionManager) -> Self {
+        Self(manager)
+    }
+}
+
+impl r2d2::ManageConnection for ConnectionManager {
+    type Connection = Connection;
+    type Error = rusqlite::Error;
+
+    fn connect(&self) -> Result<Connection, rusqlite::Error> {
+        let conn = self.0.connect()?;
+        encryption::apply_key(&conn)?;
+        Ok(conn)
+    }
+
+    /// Also rejects connections still keyed with a previous passphrase after a rekey
+    fn is_valid(&self, conn: &mut Connection) -> Result<(), rusqlite::Error> {
+        conn.query_row("SELECT count(*) FROM sqlite_master", [], |_| Ok(()))
+    }
+
+    fn has_broken(&self, _conn: &mut Connection) -> bool {
+        false
+    }
+}
 
 /// Gets the path to the SQLite database file
 pub fn get_db_path(config: &Config) -> Result<PathBuf, DEmailError> {
@@ -35,30 +66,41 @@ pub fn initialize_pool(config: &Config) -> Result<Pool, DEmailError> {
 
     info!("Initializing database pool at {:?}", path);
 
+    // Load the SQLCipher key; a passphrase-protected database stays locked until unlocked
+    encryption::prepare(&path)?;
+
     // Create connection manager
-    let manager = SqliteConnectionManager::file(path);
+    let manager = ConnectionManager::new(SqliteConnectionManager::file(path));
 
-    // Build pool with configuration
+    // Build pool with configuration. Connections are opened lazily so a locked
+    // database does not fail pool creation.
     let pool = r2d2::Pool::builder()
         .max_size(15) // Maximum 15 connections in pool
-        .build(manager)
-        .map_err(|e| DEmailError::Init(format!("Failed to create connection pool: {}", e)))?;
+        .min_idle(Some(0))
+        .build_unchecked(manager);
 
-    // Initialize schema and run migrations on a single connection
-    {
-        let conn = pool
-            .get()
-            .map_err(|e| DEmailError::Init(format!("Failed to get connection from pool: {}", e)))?;
-
-        initialize_schema(&conn)?;
-        migrations::apply_migrations(&conn)?;
-
-        info!("Database schema initialized and migrations applied");
+    if encryption::is_locked() {
+        info!("Database is locked; schema initialization deferred until unlock");
+    } else {
+        prepare_schema(&pool)?;
     }
 
     Ok(pool)
 }
 
+/// Initializes schema and runs migrations on a single connection
+pub fn prepare_schema(pool: &Pool) -> Result<(), DEmailError> {
+    let conn = pool
+        .get()
+        .map_err(|e| DEmailError::Init(format!("Failed to get connection from pool: {}", e)))?;
+
+    initialize_schema(&conn)?;
+    migrations::apply_migrations(&conn)?;
+
+    info!("Database schema initialized and migrations applied");
+    Ok(())
+}
+
 /// Legacy function for compatibility - creates a single connection
 /// Used in tests and specific scenarios where pool is not needed
 pub fn initialize_database(config: &Config) -> Result<Connection, DEmailError> {
@@ -75,11 +117,26 @@ pub fn initialize_database(config: &Config) -> Result<Connection, DEmailError> {
 // FOLDER OPERATIONS
 // ============================================================================
 
+/// Stores a folder, or picks up the stored folder with the same path so its messages
+/// and UIDVALIDITY carry over between syncs
 pub fn save_folder(pool: &Pool, folder: &mut Folder) -> Result<(), DEmailError> {
     let conn = pool
         .get()
         .map_err(|e| DEmailError::Database(rusqlite::Error::ToSqlConversionFailure(Box::new(e))))?;
 
+    let existing = conn
+        .query_row(
+            "SELECT id, uid_validity FROM folders WHERE account_id = ?1 AND path = ?2",
+            rusqlite::params![folder.account_id, folder.path],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .optional()?;
+    if let Some((id, uid_validity)) = existing {
+        folder.id = id;
+        folder.uid_validity = uid_validity;
+        return Ok(());
+    }
+
     conn.execute(
         "INSERT INTO folders (account_id, name, path, parent_id, uid_validity) VALUES (?1, ?2, ?3, ?4, ?5)",
         rusqlite::params![
@@ -110,11 +167,11 @@ pub fn update_folder_uid_validity(pool: &Pool, folder_id: i64, uid_validity: Opt
 // MESSAGE OPERATIONS
 // ============================================================================
 
-fn sanitize_html(html: &Option<String>) -> Option<String> {
+pub fn sanitize_html(html: &Option<String>) -> Option<String> {
     html.as_ref().map(|h| ammonia::clean(h))
 }
 
-pub fn save_message(pool: &Pool, message: &Message) -> Result<(), DEmailError> {
+pub fn save_message(pool: &Pool, message: &Message) -> Result<i64, DEmailError> {
     let conn = pool
         .get()
         .map_err(|e| DEmailError::Database(rusqlite::Error::ToSqlConversionFailure(Box::new(e))))?;
@@ -123,7 +180,7 @@ pub fn save_message(pool: &Pool, message: &Message) -> Result<(), DEmailError> {
     conn.execute(
         "INSERT OR REPLACE INTO messages (id, account_id, folder_id, imap_uid, message_id_header, from_header, to_header, cc_header, subject, date, body_plain, body_html, has_attachments, is_read, is_starred, thread_id) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16)",
         rusqlite::params![
-            message.id,
+            (message.id != 0).then_some(message.id), // new messages get a fresh rowid
             message.account_id,
             message.folder_id,
             message.imap_uid,
@@ -141,7 +198,12 @@ pub fn save_message(pool: &Pool, message: &Message) -> Result<(), DEmailError> {
             None::<i64>, // thread_id will be set by threading module
         ],
     )?;
-    Ok(())
+    let message_id = conn.last_insert_rowid();
+    conn.execute(
+        "INSERT OR REPLACE INTO message_locations (message_id, folder_id, imap_uid) VALUES (?1, ?2, ?3)",
+        rusqlite::params![message_id, message.folder_id, message.imap_uid],
+    )?;
+    Ok(message_id)
 }
 
 pub fn update_message_read_status(
@@ -178,6 +240,11 @@ pub fn move_message(
         .get()
         .map_err(|e| DEmailError::Database(rusqlite::Error::ToSqlConversionFailure(Box::new(e))))?;
 
+    conn.execute(
+        "UPDATE OR IGNORE message_locations SET folder_id = ?1
+         WHERE message_id = ?2 AND folder_id = (SELECT folder_id FROM messages WHERE id = ?2)",
+        rusqlite::params![target_folder_id, message_id],
+    )?;
     conn.execute(
         "UPDATE messages SET folder_id = ?1 WHERE id = ?2",
         rusqlite::params![target_folder_id, message_id],
@@ -196,10 +263,12 @@ pub fn get_messages_paginated(
         .map_err(|e| DEmailError::Database(rusqlite::Error::ToSqlConversionFailure(Box::new(e))))?;
 
     let mut stmt = conn.prepare(
-        "SELECT id, subject, from_header, date, is_read, has_attachments, is_starred
-         FROM messages
-         WHERE folder_id = ?1
-         ORDER BY date DESC
+        "SELECT m.id, m.subject, m.from_header, m.date, m.is_read, m.has_attachments, m.is_starred
+         FROM messages m
+         JOIN message_locations l ON l.message_id = m.id
+         WHERE l.folder_id = ?1
+           AND NOT EXISTS (SELECT 1 FROM snoozes s WHERE s.message_id = m.id AND s.kind = 'snooze')
+         ORDER BY MAX(m.date, COALESCE(m.resurfaced_at, 0)) DESC
          LIMIT ?2 OFFSET ?3",
     )?;
 
@@ -228,7 +297,9 @@ pub fn count_messages_in_folder(pool: &Pool, folder_id: i64) -> Result<i64, DEma
         .map_err(|e| DEmailError::Database(rusqlite::Error::ToSqlConversionFailure(Box::new(e))))?;
 
     let count: i64 = conn.query_row(
-        "SELECT COUNT(*) FROM messages WHERE folder_id = ?1",
+        "SELECT COUNT(*) FROM message_locations l
+         WHERE l.folder_id = ?1
+           AND NOT EXISTS (SELECT 1 FROM snoozes s WHERE s.message_id = l.message_id AND s.kind = 'snooze')",
         [folder_id],
         |row| row.get(0),
     )?;
@@ -495,7 +566,7 @@ pub fn search_messages_fts(
 // ATTACHMENT OPERATIONS
 // ============================================================================
 
-pub fn save_attachment(pool: &Pool, attachment: &Attachment) -> Result<(), DEmailError> {
+pub fn save_attachment(pool: &Pool, attachment: &Attachment) -> Result<i64, DEmailError> {
     let conn = pool
         .get()
         .map_err(|e| DEmailError::Database(rusqlite::Error::ToSqlConversionFailure(Box::new(e))))?;
@@ -510,7 +581,7 @@ pub fn save_attachment(pool: &Pool, attachment: &Attachment) -> Result<(), DEmai
             attachment.local_path,
         ],
     )?;
-    Ok(())
+    Ok(conn.last_insert_rowid())
 }
 
 pub fn get_attachments_for_message(
@@ -589,6 +660,11 @@ pub fn save_draft(pool: &Pool, draft: &Draft) -> Result<i64, DEmailError> {
     let conn = pool
         .get()
         .map_err(|e| DEmailError::Database(rusqlite::Error::ToSqlConversionFailure(Box::new(e))))?;
+    write_draft(&conn, draft)
+}
+
+/// `save_draft` on a connection the caller holds, so it can share a transaction
+pub fn write_draft(conn: &Connection, draft: &Draft) -> Result<i64, DEmailError> {
     let now = chrono::Utc::now().timestamp();
 
     if draft.id == 0 {
@@ -610,7 +686,8 @@ pub fn save_draft(pool: &Pool, draft: &Draft) -> Result<i64, DEmailError> {
         Ok(conn.last_insert_rowid())
     } else {
         conn.execute(
-            "UPDATE drafts SET to_addresses = ?1, cc_addresses = ?2, bcc_addresses = ?3, subject = ?4, body_plain = ?5, body_html = ?6, updated_at = ?7 WHERE id = ?8",
+            "UPDATE drafts SET to_addresses = ?1, cc_addresses = ?2, bcc_addresses = ?3, subject = ?4, body_plain = ?5, body_html = ?6, updated_at = ?7,
+                 revision = revision + 1, conflict = 0 WHERE id = ?8",
             rusqlite::params![
                 draft.to_addresses,
                 draft.cc_addresses,
@@ -632,8 +709,9 @@ pub fn get_drafts(pool: &Pool, account_id: i64) -> Result<Vec<Draft>, DEmailErro
         .map_err(|e| DEmailError::Database(rusqlite::Error::ToSqlConversionFailure(Box::new(e))))?;
 
     let mut stmt = conn.prepare(
-        "SELECT id, account_id, to_addresses, cc_addresses, bcc_addresses, subject, body_plain, body_html, created_at, updated_at
-         FROM drafts WHERE account_id = ?1 ORDER BY updated_at DESC",
+        "SELECT id, account_id, to_addresses, cc_addresses, bcc_addresses, subject, body_plain, body_html, created_at, updated_at,
+                revision, conflict
+         FROM drafts WHERE account_id = ?1 AND deleted = 0 ORDER BY updated_at DESC",
     )?;
 
     let draft_iter = stmt.query_map([account_id], |row| {
@@ -648,6 +726,8 @@ pub fn get_drafts(pool: &Pool, account_id: i64) -> Result<Vec<Draft>, DEmailErro
             body_html: row.get(7)?,
             created_at: row.get(8)?,
             updated_at: row.get(9)?,
+            revision: row.get(10)?,
+            conflict: row.get(11)?,
         })
     })?;
 
@@ -663,6 +743,10 @@ pub fn delete_draft(pool: &Pool, draft_id: i64) -> Result<(), DEmailError> {
         .get()
         .map_err(|e| DEmailError::Database(rusqlite::Error::ToSqlConversionFailure(Box::new(e))))?;
 
+    conn.execute(
+        "DELETE FROM draft_attachments WHERE draft_id = ?1",
+        [draft_id],
+    )?;
     conn.execute("DELETE FROM drafts WHERE id = ?1", [draft_id])?;
     Ok(())
 }
@@ -672,19 +756,22 @@ pub fn delete_draft(pool: &Pool, draft_id: i64) -> Result<(), DEmailError> {
 // ============================================================================
 
 pub fn save_signature(pool: &Pool, signature: &EmailSignature) -> Result<i64, DEmailError> {
-    let conn = pool
+    let mut conn = pool
         .get()
         .map_err(|e| DEmailError::Database(rusqlite::Error::ToSqlConversionFailure(Box::new(e))))?;
+    // Clearing the old default and saving the new one happen together, so an account never
+    // has two defaults (the partial unique index from migration v15 would refuse them)
+    let tx = conn.transaction()?;
 
     if signature.is_default {
-        conn.execute(
-            "UPDATE signatures SET is_default = 0 WHERE account_id = ?1",
-            [signature.account_id],
+        tx.execute(
+            "UPDATE signatures SET is_default = 0 WHERE account_id = ?1 AND id != ?2",
+            rusqlite::params![signature.account_id, signature.id],
         )?;
     }
 
-    if signature.id == 0 {
-        conn.execute(
+    let signature_id = if signature.id == 0 {
+        tx.execute(
             "INSERT INTO signatures (account_id, name, content_html, content_plain, is_default)
              VALUES (?1, ?2, ?3, ?4, ?5)",
             rusqlite::params![
@@ -695,9 +782,9 @@ pub fn save_signature(pool: &Pool, signature: &EmailSignature) -> Result<i64, DE
                 signature.is_default,
             ],
         )?;
-        Ok(conn.last_insert_rowid())
+        tx.last_insert_rowid()
     } else {
-        conn.execute(
+        tx.execute(
             "UPDATE signatures SET name = ?1, content_html = ?2, content_plain = ?3, is_default = ?4 WHERE id = ?5",
             rusqlite::params![
                 signature.name,
@@ -707,8 +794,10 @@ pub fn save_signature(pool: &Pool, signature: &EmailSignature) -> Result<i64, DE
                 signature.id,
             ],
         )?;
-        Ok(signature.id)
-    }
+        signature.id
+    };
+    tx.commit()?;
+    Ok(signature_id)
 }
 
 pub fn get_signatures(pool: &Pool, account_id: i64) -> Result<Vec<EmailSignature>, DEmailError> {
@@ -780,6 +869,15 @@ pub fn get_setting(pool: &Pool, key: &str) -> Result<Option<String>, DEmailError
     }
 }
 
+pub fn delete_setting(pool: &Pool, key: &str) -> Result<(), DEmailError> {
+    let conn = pool
+        .get()
+        .map_err(|e| DEmailError::Database(rusqlite::Error::ToSqlConversionFailure(Box::new(e))))?;
+
+    conn.execute("DELETE FROM settings WHERE key = ?1", [key])?;
+    Ok(())
+}
+
 pub fn get_all_settings(pool: &Pool) -> Result<Vec<AppSetting>, DEmailError> {
     let conn = pool
         .get()
@@ -800,3 +898,76 @@ pub fn get_all_settings(pool: &Pool) -> Result<Vec<AppSetting>, DEmailError> {
     }
     Ok(settings)
 }
+
+/// A pool over a freshly initialized database in its own temporary directory. The
+/// directory and the database in it are removed when the pool is dropped.
+#[cfg(test)]
+pub struct TestPool {
+    pool: Pool,
+    _dir: tempfile::TempDir,
+}
+
+#[cfg(test)]
+impl std::ops::Deref for TestPool {
+    type Target = Pool;
+
+    fn deref(&self) -> &Pool {
+        &self.pool
+    }
+}
+
+#[cfg(test)]
+pub fn test_pool() -> TestPool {
+    let dir = tempfile::tempdir().unwrap();
+    let pool = r2d2::Pool::builder()
+        .max_size(2)
+        .build(ConnectionManager::new(SqliteConnectionManager::file(
+            dir.path().join("demail.sqlite"),
+        )))
+        .unwrap();
+    initialize_schema(&pool.get().unwrap()).unwrap();
+    TestPool { pool, _dir: dir }
+}
+
+/// Saves a folder named after its path, as one that has been selected before
+#[cfg(test)]
+pub fn test_folder(pool: &Pool, account_id: i64, path: &str) -> Folder {
+    let mut folder = Folder {
+        id: 0,
+        account_id,
+        name: path.to_string(),
+        path: path.to_string(),
+        parent_id: None,
+        uid_validity: Some(1),
+    };
+    save_folder(pool, &mut folder).unwrap();
+    folder
+}
+
+/// An unread message from alice@example.com with a Message-ID and subject made from its
+/// UID. Tests set the fields they look at with struct update syntax.
+#[cfg(test)]
+pub fn test_message(account_id: i64, folder_id: i64, imap_uid: u32) -> Message {
+    Message {
+        id: 0,
+        account_id,
+        folder_id,
+        imap_uid,
+        message_id_header: format!("<{}@example.com>", imap_uid),
+        from_header: "alice@example.com".to_string(),
+        to_header: "me@example.com".to_string(),
+        cc_header: None,
+        subject: format!("Message {}", imap_uid),
+        date: 0,
+        body_plain: None,
+        body_html: None,
+        has_attachments: false,
+        is_read: false,
+        is_starred: false,
+        thread_id: None,
+        attachments: Vec::new(),
+        security: None,
+        authentication: None,
+        remote_content: None,
+    }
+}
@@ -0,0 +1,594 @@
+// Database encryption module - SQLCipher keys from the OS keyring or a passphrase,
+// in-place encryption of plaintext databases and passphrase changes
+
+use crate::core::validation;
+use crate::error::DEmailError;
+use argon2::Argon2;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::OsRng;
+use once_cell::sync::Lazy;
+use rusqlite::{Connection, DatabaseName};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+use tracing::info;
+
+pub const MODE_NONE: &str = "none";
+pub const MODE_KEYRING: &str = "keyring";
+pub const MODE_PASSPHRASE: &str = "passphrase";
+
+const KEYRING_SERVICE: &str = "demail";
+const KEYRING_DATABASE_KEY: &str = "database_key";
+/// Stored next to the database because the database itself cannot be read without the key
+const SETTINGS_FILE: &str = "demail_encryption.json";
+const KEY_LEN: usize = 32;
+const SALT_LEN: usize = 16;
+const SQLITE_HEADER: &[u8] = b"SQLite format 3\0";
+
+/// How the database key is obtained. Persisted in `demail_encryption.json`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct EncryptionSettings {
+    pub mode: String,
+    /// Argon2id salt (hex) for passphrase mode
+    pub salt: Option<String>,
+    /// SHA-256 of the derived key (hex), to reject a mistyped passphrase before it is
+    /// used to encrypt a plaintext database
+    pub key_check: Option<String>,
+}
+
+impl Default for EncryptionSettings {
+    fn default() -> Self {
+        Self {
+            mode: MODE_NONE.to_string(),
+            salt: None,
+            key_check: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EncryptionStatus {
+    /// Whether this build was compiled with SQLCipher (`sqlcipher` feature)
+    pub supported: bool,
+    pub mode: String,
+    /// Whether the database file on disk is encrypted
+    pub encrypted: bool,
+    /// A passphrase must be entered with `unlock_database` before the database can be used
+    pub locked: bool,
+    /// Encryption is configured but the plaintext database is converted on next start
+    pub restart_required: bool,
+}
+
+enum KeyState {
+    Plaintext,
+    Locked,
+    Unlocked(String),
+}
+
+/// Key used for every pooled connection. Process-wide because connections are opened
+/// lazily by the pool, long after startup.
+static KEY_STATE: Lazy<RwLock<KeyState>> = Lazy::new(|| RwLock::new(KeyState::Plaintext));
+
+pub fn is_supported() -> bool {
+    cfg!(feature = "sqlcipher")
+}
+
+pub fn is_locked() -> bool {
+    matches!(*KEY_STATE.read().unwrap(), KeyState::Locked)
+}
+
+/// Raw key (hex) currently in use, if the database is encrypted and unlocked
+pub fn current_key() -> Option<String> {
+    match &*KEY_STATE.read().unwrap() {
+        KeyState::Unlocked(key) => Some(key.clone()),
+        _ => None,
+    }
+}
+
+fn set_state(state: KeyState) {
+    *KEY_STATE.write().unwrap() = state;
+}
+
+/// Keys a freshly opened connection. Fails while a passphrase-protected database is locked.
+pub fn apply_key(conn: &Connection) -> rusqlite::Result<()> {
+    match &*KEY_STATE.read().unwrap() {
+        KeyState::Plaintext => Ok(()),
+        KeyState::Locked => Err(rusqlite::Error::SqliteFailure(
+            rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_AUTH),
+            Some("Database is locked; enter the passphrase to unlock it".to_string()),
+        )),
+        KeyState::Unlocked(key) => key_connection(conn, key),
+    }
+}
+
+fn key_connection(conn: &Connection, key: &str) -> rusqlite::Result<()> {
+    conn.execute_batch(&format!("PRAGMA key = \"x'{}'\";", key))
+}
+
+// ============================================================================
+// SETTINGS
+// ============================================================================
+
+pub fn settings_path(db_path: &Path) -> PathBuf {
+    db_path.with_file_name(SETTINGS_FILE)
+}
+
+pub fn load_settings(db_path: &Path) -> Result<EncryptionSettings, DEmailError> {
+    let path = settings_path(db_path);
+    if !path.exists() {
+        return Ok(EncryptionSettings::default());
+    }
+    Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+}
+
+fn save_settings(db_path: &Path, settings: &EncryptionSettings) -> Result<(), DEmailError> {
+    fs::write(
+        settings_path(db_path),
+        serde_json::to_string_pretty(settings)?,
+    )?;
+    Ok(())
+}
+
+/// Whether the file is an unencrypted SQLite database
+fn is_plaintext_file(path: &Path) -> Result<bool, DEmailError> {
+    let mut header = [0u8; 16];
+    match fs::File::open(path) {
+        Ok(mut file) => Ok(file.read_exact(&mut header).is_ok() && header == SQLITE_HEADER),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub fn get_status(db_path: &Path) -> Result<EncryptionStatus, DEmailError> {
+    let settings = load_settings(db_path)?;
+    let plaintext = !db_path.exists() || is_plaintext_file(db_path)?;
+    let locked = is_locked();
+    Ok(EncryptionStatus {
+        supported: is_supported(),
+        encrypted: !plaintext,
+        locked,
+        restart_required: settings.mode != MODE_NONE && plaintext && !locked,
+        mode: settings.mode,
+    })
+}
+
+// ============================================================================
+// LIFECYCLE
+// ============================================================================
+
+/// Loads the key before the pool is created. Keyring-protected databases are unlocked
+/// (and converted from plaintext if encryption was just enabled); passphrase-protected
+/// ones stay locked until `unlock` is called.
+pub fn prepare(db_path: &Path) -> Result<(), DEmailError> {
+    let settings = load_settings(db_path)?;
+    match settings.mode.as_str() {
+        MODE_NONE => set_state(KeyState::Plaintext),
+        MODE_KEYRING => {
+            ensure_supported()?;
+            let key = keyring::Entry::new(KEYRING_SERVICE, KEYRING_DATABASE_KEY)?.get_password()?;
+            if is_plaintext_file(db_path)? {
+                encrypt_in_place(db_path, &key)?;
+            }
+            set_state(KeyState::Unlocked(key));
+        }
+        MODE_PASSPHRASE => {
+            ensure_supported()?;
+            set_state(KeyState::Locked);
+        }
+        other => {
+            return Err(DEmailError::Encryption(format!(
+                "Unknown database encryption mode: {}",
+                other
+            )))
+        }
+    }
+    Ok(())
+}
+
+/// Unlocks a passphrase-protected database. On the first unlock after encryption was
+/// enabled, the plaintext database is encrypted in place.
+pub fn unlock(db_path: &Path, passphrase: &str) -> Result<(), DEmailError> {
+    let settings = load_settings(db_path)?;
+    if settings.mode != MODE_PASSPHRASE {
+        return Err(DEmailError::Encryption(
+            "Database is not protected with a passphrase".to_string(),
+        ));
+    }
+    if !is_locked() {
+        return Ok(());
+    }
+
+    let key = key_from_passphrase(&settings, passphrase)?;
+    if is_plaintext_file(db_path)? {
+        encrypt_in_place(db_path, &key)?;
+    }
+    set_state(KeyState::Unlocked(key));
+    info!("Database unlocked");
+    Ok(())
+}
+
+/// Configures encryption for a plaintext database. The conversion itself happens on the
+/// next start (keyring) or unlock (passphrase), before any pooled connection is open.
+pub fn enable(
+    db_path: &Path,
+    mode: &str,
+    passphrase: Option<&str>,
+) -> Result<EncryptionStatus, DEmailError> {
+    ensure_supported()?;
+    if load_settings(db_path)?.mode != MODE_NONE {
+        return Err(DEmailError::Encryption(
+            "Database encryption is already enabled".to_string(),
+        ));
+    }
+
+    let settings = match mode {
+        MODE_KEYRING => {
+            let key = hex::encode(random_bytes::<KEY_LEN>());
+            keyring::Entry::new(KEYRING_SERVICE, KEYRING_DATABASE_KEY)?.set_password(&key)?;
+            EncryptionSettings {
+                mode: MODE_KEYRING.to_string(),
+                salt: None,
+                key_check: None,
+            }
+        }
+        MODE_PASSPHRASE => {
+            let passphrase = passphrase
+                .ok_or_else(|| DEmailError::Validation("A passphrase is required".to_string()))?;
+            validation::validate_passphrase(passphrase)?;
+            passphrase_settings(passphrase)?.0
+        }
+        other => {
+            return Err(DEmailError::Validation(format!(
+                "Unknown database encryption mode: {}",
+                other
+            )))
+        }
+    };
+    save_settings(db_path, &settings)?;
+    info!(
+        "Database encryption enabled ({}); restart to encrypt",
+        settings.mode
+    );
+
+    let mut status = get_status(db_path)?;
+    status.restart_required = true;
+    Ok(status)
+}
+
+/// Re-encrypts the live database under a new passphrase with `PRAGMA rekey`. A database
+/// protected by the OS keyring is switched to passphrase mode. Pooled connections that
+/// still hold the old key fail validation and are replaced.
+pub fn change_passphrase(
+    conn: &Connection,
+    db_path: &Path,
+    current_passphrase: Option<&str>,
+    new_passphrase: &str,
+) -> Result<(), DEmailError> {
+    let settings = load_settings(db_path)?;
+    let Some(key) = current_key() else {
+        return Err(DEmailError::Encryption(
+            "Database is not encrypted or still locked".to_string(),
+        ));
+    };
+    if settings.mode == MODE_PASSPHRASE {
+        let current = current_passphrase.ok_or_else(|| {
+            DEmailError::Validation("The current passphrase is required".to_string())
+        })?;
+        if key_from_passphrase(&settings, current)? != key {
+            return Err(DEmailError::Encryption("Wrong passphrase".to_string()));
+        }
+    }
+    validation::validate_passphrase(new_passphrase)?;
+
+    let (new_settings, new_key) = passphrase_settings(new_passphrase)?;
+    conn.execute_batch(&format!("PRAGMA rekey = \"x'{}'\";", new_key))?;
+    set_state(KeyState::Unlocked(new_key));
+    save_settings(db_path, &new_settings)?;
+
+    if settings.mode == MODE_KEYRING {
+        let _ = keyring::Entry::new(KEYRING_SERVICE, KEYRING_DATABASE_KEY)
+            .and_then(|entry| entry.delete_password());
+    }
+    info!("Database passphrase changed");
+    Ok(())
+}
+
+fn ensure_supported() -> Result<(), DEmailError> {
+    if is_supported() {
+        Ok(())
+    } else {
+        Err(DEmailError::Encryption(
+            "This build of DEmail was compiled without SQLCipher support".to_string(),
+        ))
+    }
+}
+
+// ============================================================================
+// KEYS
+// ============================================================================
+
+/// New salt, key check and derived key for a passphrase
+fn passphrase_settings(passphrase: &str) -> Result<(EncryptionSettings, String), DEmailError> {
+    let salt = random_bytes::<SALT_LEN>();
+    let key = derive_key(passphrase, &salt)?;
+    Ok((
+        EncryptionSettings {
+            mode: MODE_PASSPHRASE.to_string(),
+            salt: Some(hex::encode(salt)),
+            key_check: Some(key_check(&key)),
+        },
+        key,
+    ))
+}
+
+fn key_from_passphrase(
+    settings: &EncryptionSettings,
+    passphrase: &str,
+) -> Result<String, DEmailError> {
+    let salt = settings
+        .salt
+        .as_deref()
+        .and_then(|salt| hex::decode(salt).ok())
+        .ok_or_else(|| DEmailError::Encryption("Missing passphrase salt".to_string()))?;
+    let key = derive_key(passphrase, &salt)?;
+    if settings.key_check.as_deref() != Some(key_check(&key).as_str()) {
+        return Err(DEmailError::Encryption("Wrong passphrase".to_string()));
+    }
+    Ok(key)
+}
+
+/// Derives a 256-bit raw SQLCipher key (hex) from the passphrase with Argon2id
+fn derive_key(passphrase: &str, salt: &[u8]) -> Result<String, DEmailError> {
+    let mut key = [0u8; KEY_LEN];
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| DEmailError::Encryption(format!("Key derivation failed: {}", e)))?;
+    Ok(hex::encode(key))
+}
+
+fn key_check(key: &str) -> String {
+    hex::encode(openssl::sha::sha256(key.as_bytes()))
+}
+
+fn random_bytes<const N: usize>() -> [u8; N] {
+    let mut bytes = [0u8; N];
+    OsRng.fill_bytes(&mut bytes);
+    bytes
+}
+
+// ============================================================================
+// CONVERSION AND COPIES
+// ============================================================================
+
+/// Encrypts a plaintext database file in place: the contents are exported into an
+/// encrypted copy with `sqlcipher_export`, which then atomically replaces the original
+fn encrypt_in_place(db_path: &Path, key: &str) -> Result<(), DEmailError> {
+    let encrypted_path = db_path.with_extension("sqlite.encrypting");
+    let _ = fs::remove_file(&encrypted_path);
+
+    {
+        let conn = Connection::open(db_path)?;
+        export(&conn, &encrypted_path, Some(key))?;
+    }
+    fs::rename(&encrypted_path, db_path)?;
+    for suffix in ["-wal", "-shm", "-journal"] {
+        let mut sidecar = db_path.as_os_str().to_owned();
+        sidecar.push(suffix);
+        let _ = fs::remove_file(PathBuf::from(sidecar));
+    }
+
+    info!("Database encrypted in place at {:?}", db_path);
+    Ok(())
+}
+
+/// Copies the database behind `conn` into a new file at `destination`, encrypted with
+/// `key` or in plaintext when `None`
+fn export(conn: &Connection, destination: &Path, key: Option<&str>) -> Result<(), DEmailError> {
+    let key = key.map(|key| format!("x'{}'", key)).unwrap_or_default();
+    conn.execute(
+        "ATTACH DATABASE ?1 AS export KEY ?2",
+        rusqlite::params![destination.to_string_lossy(), key],
+    )?;
+    let result = conn.query_row("SELECT sqlcipher_export('export')", [], |_| Ok(()));
+    conn.execute("DETACH DATABASE export", [])?;
+    result?;
+    Ok(())
+}
+
+/// Whether the main database of `conn` is an encrypted file on disk
+fn is_encrypted_connection(conn: &Connection) -> Result<bool, DEmailError> {
+    match conn.path().filter(|path| !path.is_empty()) {
+        Some(path) => Ok(!is_plaintext_file(Path::new(path))?),
+        None => Ok(false),
+    }
+}
+
+/// Writes a plaintext copy of the live database, e.g. for a backup snapshot. The SQLite
+/// backup API cannot copy between encrypted and plaintext databases.
+pub fn copy_to_plaintext(conn: &Connection, destination: &Path) -> Result<(), DEmailError> {
+    if is_encrypted_connection(conn)? {
+        export(conn, destination, None)
+    } else {
+        conn.backup(DatabaseName::Main, destination, None)?;
+        Ok(())
+    }
+}
+
+/// Replaces the live database with a plaintext snapshot. For an encrypted database the
+/// snapshot is first re-encrypted with the live key so the backup API can copy it.
+pub fn restore_from_plaintext(
+    conn: &mut Connection,
+    snapshot_path: &Path,
+) -> Result<(), DEmailError> {
+    if !is_encrypted_connection(conn)? {
+        conn.restore(
+            DatabaseName::Main,
+            snapshot_path,
+            None::<fn(rusqlite::backup::Progress)>,
+        )?;
+        return Ok(());
+    }
+    let key =
+        current_key().ok_or_else(|| DEmailError::Encryption("Database is locked".to_string()))?;
+
+    let keyed_path = snapshot_path.with_extension("keyed");
+    let result = (|| {
+        export(&Connection::open(snapshot_path)?, &keyed_path, Some(&key))?;
+        let keyed = Connection::open(&keyed_path)?;
+        key_connection(&keyed, &key)?;
+        let backup = rusqlite::backup::Backup::new(&keyed, conn)?;
+        backup.run_to_completion(100, std::time::Duration::ZERO, None)?;
+        Ok(())
+    })();
+    let _ = fs::remove_file(&keyed_path);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// Serializes tests that change the process-wide key state
+    static KEY_STATE_LOCK: Mutex<()> = Mutex::new(());
+
+    fn temp_db() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("demail-encryption-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("demail.sqlite");
+        let conn = Connection::open(&path).unwrap();
+        conn.execute_batch(
+            "CREATE TABLE notes (id INTEGER PRIMARY KEY, body TEXT);
+             INSERT INTO notes (body) VALUES ('secret body');",
+        )
+        .unwrap();
+        path
+    }
+
+    #[test]
+    fn test_settings_default_to_plaintext() {
+        let path = temp_db();
+        assert_eq!(load_settings(&path).unwrap(), EncryptionSettings::default());
+        assert!(is_plaintext_file(&path).unwrap());
+
+        let status = get_status(&path).unwrap();
+        assert_eq!(status.mode, MODE_NONE);
+        assert!(!status.encrypted && !status.restart_required);
+    }
+
+    #[test]
+    fn test_passphrase_key_check() {
+        let (settings, key) = passphrase_settings("correct horse battery").unwrap();
+        assert_eq!(key.len(), KEY_LEN * 2);
+        assert_eq!(
+            key_from_passphrase(&settings, "correct horse battery").unwrap(),
+            key
+        );
+        assert!(key_from_passphrase(&settings, "wrong horse battery").is_err());
+    }
+
+    #[cfg(not(feature = "sqlcipher"))]
+    #[test]
+    fn test_enable_requires_sqlcipher() {
+        let path = temp_db();
+        assert!(enable(&path, MODE_PASSPHRASE, Some("correct horse battery")).is_err());
+        assert_eq!(load_settings(&path).unwrap().mode, MODE_NONE);
+    }
+
+    #[cfg(feature = "sqlcipher")]
+    #[test]
+    fn test_encrypt_in_place_and_copy_back() {
+        let _guard = KEY_STATE_LOCK.lock().unwrap();
+        let path = temp_db();
+        let key = hex::encode(random_bytes::<KEY_LEN>());
+        encrypt_in_place(&path, &key).unwrap();
+        assert!(!is_plaintext_file(&path).unwrap());
+
+        let conn = Connection::open(&path).unwrap();
+        assert!(conn
+            .query_row("SELECT count(*) FROM notes", [], |row| row.get::<_, i64>(0))
+            .is_err());
+        drop(conn);
+
+        let conn = Connection::open(&path).unwrap();
+        key_connection(&conn, &key).unwrap();
+        let body: String = conn
+            .query_row("SELECT body FROM notes", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(body, "secret body");
+
+        // Plaintext export of the encrypted database, as used for backups
+        let plain = path.with_file_name("plain.sqlite");
+        export(&conn, &plain, None).unwrap();
+        assert!(is_plaintext_file(&plain).unwrap());
+        let copy = Connection::open(&plain).unwrap();
+        let count: i64 = copy
+            .query_row("SELECT count(*) FROM notes", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 1);
+
+        // Restoring the plaintext copy into the encrypted database keeps it encrypted
+        copy.execute("INSERT INTO notes (body) VALUES ('restored')", [])
+            .unwrap();
+        drop(copy);
+        set_state(KeyState::Unlocked(key.clone()));
+        let mut conn = conn;
+        restore_from_plaintext(&mut conn, &plain).unwrap();
+        set_state(KeyState::Plaintext);
+        let count: i64 = conn
+            .query_row("SELECT count(*) FROM notes", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 2);
+        assert!(!is_plaintext_file(&path).unwrap());
+    }
+
+    #[cfg(feature = "sqlcipher")]
+    #[test]
+    fn test_enable_unlock_and_change_passphrase() {
+        let _guard = KEY_STATE_LOCK.lock().unwrap();
+        let path = temp_db();
+        let status = enable(&path, MODE_PASSPHRASE, Some("correct horse battery")).unwrap();
+        assert!(status.restart_required);
+        assert!(enable(&path, MODE_PASSPHRASE, Some("correct horse battery")).is_err());
+
+        prepare(&path).unwrap();
+        assert!(is_locked());
+        assert!(apply_key(&Connection::open_in_memory().unwrap()).is_err());
+        assert!(unlock(&path, "wrong horse battery").is_err());
+        assert!(is_plaintext_file(&path).unwrap());
+
+        unlock(&path, "correct horse battery").unwrap();
+        assert!(!is_plaintext_file(&path).unwrap());
+        let conn = Connection::open(&path).unwrap();
+        apply_key(&conn).unwrap();
+        assert!(conn
+            .query_row("SELECT count(*) FROM notes", [], |_| Ok(()))
+            .is_ok());
+
+        assert!(
+            change_passphrase(&conn, &path, Some("wrong horse battery"), "new passphrase!")
+                .is_err()
+        );
+        change_passphrase(
+            &conn,
+            &path,
+            Some("correct horse battery"),
+            "new passphrase!",
+        )
+        .unwrap();
+        drop(conn);
+
+        set_state(KeyState::Locked);
+        assert!(unlock(&path, "correct horse battery").is_err());
+        unlock(&path, "new passphrase!").unwrap();
+        let conn = Connection::open(&path).unwrap();
+        apply_key(&conn).unwrap();
+        let body: String = conn
+            .query_row("SELECT body FROM notes", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(body, "secret body");
+        set_state(KeyState::Plaintext);
+    }
+}
@@ -0,0 +1,291 @@
+// Message locations - A message is stored once per account however many folders hold
+// it; `message_locations` maps each (folder, UID) to the stored message. The row's own
+// `folder_id`/`imap_uid` name its primary location, which is always one of these.
+//
+// On Gmail copies are recognised by X-GM-MSGID, which is the same in every label folder.
+// Other servers have no such id, so there they are recognised by Message-ID plus a
+// SHA-256 of the raw message.
+
+use crate::error::DEmailError;
+use rusqlite::{params, Connection, OptionalExtension};
+use std::collections::HashSet;
+
+/// Hex SHA-256 of a raw RFC 822 message
+pub fn content_hash(raw: &[u8]) -> String {
+    hex::encode(openssl::sha::sha256(raw))
+}
+
+pub fn set_content_hash(conn: &Connection, message_id: i64, hash: &str) -> Result<(), DEmailError> {
+    conn.execute(
+        "UPDATE messages SET content_hash = ?1 WHERE id = ?2",
+        params![hash, message_id],
+    )?;
+    Ok(())
+}
+
+pub fn set_gmail_message_id(
+    conn: &Connection,
+    message_id: i64,
+    gm_msgid: &str,
+) -> Result<(), DEmailError> {
+    conn.execute(
+        "UPDATE messages SET gm_msgid = ?1 WHERE id = ?2",
+        params![gm_msgid, message_id],
+    )?;
+    Ok(())
+}
+
+/// The stored message a newly fetched one is a copy of, if any. `gm_msgid` is the
+/// message's X-GM-MSGID on Gmail and `None` elsewhere.
+pub fn find_copy(
+    conn: &Connection,
+    account_id: i64,
+    gm_msgid: Option<&str>,
+    message_id_header: &str,
+    hash: &str,
+) -> Result<Option<i64>, DEmailError> {
+    let Some(gm_msgid) = gm_msgid else {
+        return find_copy_by_hash(conn, account_id, message_id_header, hash, false);
+    };
+    let id = conn
+        .query_row(
+            "SELECT id FROM messages WHERE account_id = ?1 AND gm_msgid = ?2 LIMIT 1",
+            params![account_id, gm_msgid],
+            |row| row.get(0),
+        )
+        .optional()?;
+    if id.is_some() {
+        return Ok(id);
+    }
+    // Messages stored before their X-GM-MSGID was recorded
+    let id = find_copy_by_hash(conn, account_id, message_id_header, hash, true)?;
+    if let Some(id) = id {
+        set_gmail_message_id(conn, id, gm_msgid)?;
+    }
+    Ok(id)
+}
+
+fn find_copy_by_hash(
+    conn: &Connection,
+    account_id: i64,
+    message_id_header: &str,
+    hash: &str,
+    without_gm_msgid: bool,
+) -> Result<Option<i64>, DEmailError> {
+    let id = conn
+        .query_row(
+            "SELECT id FROM messages
+             WHERE account_id = ?1 AND content_hash = ?2 AND COALESCE(message_id_header, '') = ?3
+               AND (?4 = 0 OR gm_msgid IS NULL)
+             LIMIT 1",
+            params![account_id, hash, message_id_header, without_gm_msgid],
+            |row| row.get(0),
+        )
+        .optional()?;
+    Ok(id)
+}
+
+pub fn add_location(
+    conn: &Connection,
+    message_id: i64,
+    folder_id: i64,
+    imap_uid: u32,
+) -> Result<(), DEmailError> {
+    conn.execute(
+        "INSERT OR REPLACE INTO message_locations (message_id, folder_id, imap_uid)
+         VALUES (?1, ?2, ?3)",
+        params![message_id, folder_id, imap_uid],
+    )?;
+    Ok(())
+}
+
+/// UIDs in the folder that are already stored
+pub fn folder_uids(conn: &Connection, folder_id: i64) -> Result<HashSet<u32>, DEmailError> {
+    let mut stmt = conn.prepare("SELECT imap_uid FROM message_locations WHERE folder_id = ?1")?;
+    let uids = stmt
+        .query_map([folder_id], |row| row.get(0))?
+        .collect::<Result<_, _>>()?;
+    Ok(uids)
+}
+
+/// Forgets that a folder holds the message. The message is deleted once no folder
+/// holds it, otherwise its primary location moves to another folder.
+pub fn remove_location(
+    conn: &Connection,
+    message_id: i64,
+    folder_id: i64,
+    imap_uid: u32,
+) -> Result<(), DEmailError> {
+    conn.execute(
+        "DELETE FROM message_locations WHERE folder_id = ?1 AND imap_uid = ?2",
+        params![folder_id, imap_uid],
+    )?;
+    settle(conn, "id = ?1", message_id)
+}
+
+/// Forgets every message location in a folder, as when its UIDVALIDITY changes or
+/// the folder goes away
+pub fn clear_folder(conn: &Connection, folder_id: i64) -> Result<(), DEmailError> {
+    conn.execute(
+        "DELETE FROM message_locations WHERE folder_id = ?1",
+        [folder_id],
+    )?;
+    settle(conn, "folder_id = ?1", folder_id)
+}
+
+/// Deletes the selected messages that no folder holds any more and points the others
+/// at a location they still have
+fn settle(conn: &Connection, selection: &str, value: i64) -> Result<(), DEmailError> {
+    conn.execute(
+        &format!(
+            "DELETE FROM messages WHERE {} AND NOT EXISTS
+                (SELECT 1 FROM message_locations l WHERE l.message_id = messages.id)",
+            selection
+        ),
+        [value],
+    )?;
+    conn.execute(
+        &format!(
+            "UPDATE messages SET (folder_id, imap_uid) =
+                (SELECT l.folder_id, l.imap_uid FROM message_locations l
+                 WHERE l.message_id = messages.id ORDER BY l.folder_id LIMIT 1)
+             WHERE {} AND NOT EXISTS
+                (SELECT 1 FROM message_locations l
+                 WHERE l.message_id = messages.id
+                   AND l.folder_id = messages.folder_id AND l.imap_uid = messages.imap_uid)",
+            selection
+        ),
+        [value],
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::accounts::create_account;
+    use crate::core::cache::db::{save_message, test_folder, test_message, test_pool, Pool};
+    use crate::models::Message;
+
+    fn store(
+        pool: &Pool,
+        account_id: i64,
+        folder_id: i64,
+        imap_uid: u32,
+        message_id_header: &str,
+    ) -> i64 {
+        save_message(
+            pool,
+            &Message {
+                message_id_header: message_id_header.to_string(),
+                ..test_message(account_id, folder_id, imap_uid)
+            },
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_copies_share_one_message() {
+        let pool = test_pool();
+        let account_id = create_account(&pool, "me@example.com", "Me", "google")
+            .unwrap()
+            .id;
+        let inbox = test_folder(&pool, account_id, "INBOX").id;
+        let all_mail = test_folder(&pool, account_id, "[Gmail]/All Mail").id;
+        let raw = b"Message-ID: <1@example.com>\r\nSubject: Hi\r\n\r\nHello";
+        let hash = content_hash(raw);
+
+        let message_id = store(&pool, account_id, inbox, 7, "<1@example.com>");
+        let conn = pool.get().unwrap();
+        set_content_hash(&conn, message_id, &hash).unwrap();
+
+        // The All Mail copy is recognised and only mapped
+        assert_eq!(
+            find_copy(&conn, account_id, None, "<1@example.com>", &hash).unwrap(),
+            Some(message_id)
+        );
+        assert_eq!(
+            find_copy(&conn, account_id, None, "<2@example.com>", &hash).unwrap(),
+            None
+        );
+        add_location(&conn, message_id, all_mail, 42).unwrap();
+        assert_eq!(folder_uids(&conn, inbox).unwrap(), HashSet::from([7]));
+        assert_eq!(folder_uids(&conn, all_mail).unwrap(), HashSet::from([42]));
+
+        // Leaving the inbox keeps the message, now at home in All Mail
+        remove_location(&conn, message_id, inbox, 7).unwrap();
+        let primary: (i64, u32) = conn
+            .query_row(
+                "SELECT folder_id, imap_uid FROM messages WHERE id = ?1",
+                [message_id],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!(primary, (all_mail, 42));
+
+        // Once no folder holds it, it is gone
+        clear_folder(&conn, all_mail).unwrap();
+        let count: i64 = conn
+            .query_row("SELECT COUNT(*) FROM messages", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 0);
+    }
+
+    #[test]
+    fn test_gmail_copies_found_by_msgid() {
+        let pool = test_pool();
+        let account_id = create_account(&pool, "me@gmail.com", "Me", "google")
+            .unwrap()
+            .id;
+        let inbox = test_folder(&pool, account_id, "INBOX").id;
+        let conn = pool.get().unwrap();
+
+        let message_id = store(&pool, account_id, inbox, 7, "<1@example.com>");
+        set_content_hash(&conn, message_id, "hash").unwrap();
+        set_gmail_message_id(&conn, message_id, "1278455344230334865").unwrap();
+
+        // The id alone identifies the copy, whatever the bytes
+        assert_eq!(
+            find_copy(
+                &conn,
+                account_id,
+                Some("1278455344230334865"),
+                "<1@example.com>",
+                "other"
+            )
+            .unwrap(),
+            Some(message_id)
+        );
+        // Same bytes, different Gmail message
+        assert_eq!(
+            find_copy(
+                &conn,
+                account_id,
+                Some("1278455344230334866"),
+                "<1@example.com>",
+                "hash"
+            )
+            .unwrap(),
+            None
+        );
+
+        // A message stored before its id was recorded is found by hash and given the id
+        let older = store(&pool, account_id, inbox, 8, "<2@example.com>");
+        set_content_hash(&conn, older, "older").unwrap();
+        assert_eq!(
+            find_copy(
+                &conn,
+                account_id,
+                Some("1278455344230334867"),
+                "<2@example.com>",
+                "older"
+            )
+            .unwrap(),
+            Some(older)
+        );
+        assert_eq!(
+            find_copy(&conn, account_id, Some("1278455344230334867"), "", "").unwrap(),
+            Some(older)
+        );
+    }
+}
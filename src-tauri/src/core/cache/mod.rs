@@ -1,2 +1,4 @@
 pub mod db;
+pub mod encryption;
+pub mod locations;
 pub mod schema;
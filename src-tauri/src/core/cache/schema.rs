@@ -7,6 +7,7 @@ pub fn initialize_schema(conn: &Connection) -> Result<()> {
     create_accounts_table(conn)?;
     create_folders_table(conn)?;
     create_messages_table(conn)?;
+    create_message_locations_table(conn)?;
     create_attachments_table(conn)?;
     create_message_flags_table(conn)?;
 
@@ -16,6 +17,7 @@ pub fn initialize_schema(conn: &Connection) -> Result<()> {
 
     // Feature tables
     create_drafts_table(conn)?;
+    create_draft_attachments_table(conn)?;
     create_signatures_table(conn)?;
     create_settings_table(conn)?;
     create_attachment_data_table(conn)?;
@@ -25,6 +27,37 @@ pub fn initialize_schema(conn: &Connection) -> Result<()> {
     create_threads_table(conn)?;
     create_contacts_table(conn)?;
 
+    // Message security (OpenPGP / S/MIME)
+    create_message_security_table(conn)?;
+    create_pgp_keys_table(conn)?;
+    create_smime_certificates_table(conn)?;
+
+    // Sender authentication (DKIM/SPF/DMARC)
+    create_message_authentication_table(conn)?;
+    create_dkim_keys_table(conn)?;
+
+    // Remote content blocking
+    create_remote_content_exceptions_table(conn)?;
+    create_remote_content_cache_table(conn)?;
+
+    // Per-account sync state
+    create_sync_status_table(conn)?;
+
+    // Message rules
+    create_rules_table(conn)?;
+    create_rule_pending_actions_table(conn)?;
+    create_message_headers_table(conn)?;
+    create_rule_moves_table(conn)?;
+
+    // Snoozes and follow-up reminders
+    create_snoozes_table(conn)?;
+
+    // Sending identities
+    create_identities_table(conn)?;
+
+    // Message templates
+    create_templates_tables(conn)?;
+
     // Indexes
     create_indexes(conn)?;
 
@@ -37,7 +70,8 @@ fn create_accounts_table(conn: &Connection) -> Result<()> {
             id INTEGER PRIMARY KEY AUTOINCREMENT,
             email_address TEXT NOT NULL UNIQUE,
             display_name TEXT NOT NULL,
-            provider_type TEXT NOT NULL
+            provider_type TEXT NOT NULL,
+            needs_reauth INTEGER NOT NULL DEFAULT 0
         )",
         [],
     )?;
@@ -80,6 +114,9 @@ fn create_messages_table(conn: &Connection) -> Result<()> {
             is_read INTEGER NOT NULL DEFAULT 0,
             is_starred INTEGER NOT NULL DEFAULT 0,
             thread_id INTEGER,
+            content_hash TEXT,
+            resurfaced_at INTEGER,
+            gm_msgid TEXT,
             FOREIGN KEY (account_id) REFERENCES accounts (id) ON DELETE CASCADE,
             FOREIGN KEY (folder_id) REFERENCES folders (id) ON DELETE CASCADE,
             FOREIGN KEY (thread_id) REFERENCES threads (id) ON DELETE SET NULL,
@@ -90,6 +127,20 @@ fn create_messages_table(conn: &Connection) -> Result<()> {
     Ok(())
 }
 
+/// Every folder and UID a stored message is found under
+fn create_message_locations_table(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS message_locations (
+            message_id INTEGER NOT NULL REFERENCES messages(id) ON DELETE CASCADE,
+            folder_id INTEGER NOT NULL REFERENCES folders(id) ON DELETE CASCADE,
+            imap_uid INTEGER NOT NULL,
+            PRIMARY KEY (folder_id, imap_uid)
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
 fn create_attachments_table(conn: &Connection) -> Result<()> {
     conn.execute(
         "CREATE TABLE IF NOT EXISTS attachments (
@@ -175,6 +226,14 @@ fn create_drafts_table(conn: &Connection) -> Result<()> {
             body_html TEXT,
             created_at INTEGER NOT NULL,
             updated_at INTEGER NOT NULL,
+            message_id_header TEXT,
+            remote_uid INTEGER,
+            remote_uid_validity INTEGER,
+            revision INTEGER NOT NULL DEFAULT 1,
+            synced_revision INTEGER,
+            imported_revision INTEGER,
+            conflict INTEGER NOT NULL DEFAULT 0,
+            deleted INTEGER NOT NULL DEFAULT 0,
             FOREIGN KEY (account_id) REFERENCES accounts (id) ON DELETE CASCADE
         )",
         [],
@@ -182,6 +241,20 @@ fn create_drafts_table(conn: &Connection) -> Result<()> {
     Ok(())
 }
 
+fn create_draft_attachments_table(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS draft_attachments (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            draft_id INTEGER NOT NULL REFERENCES drafts(id) ON DELETE CASCADE,
+            filename TEXT NOT NULL,
+            mime_type TEXT NOT NULL,
+            data BLOB NOT NULL
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
 fn create_signatures_table(conn: &Connection) -> Result<()> {
     conn.execute(
         "CREATE TABLE IF NOT EXISTS signatures (
@@ -268,6 +341,264 @@ fn create_contacts_table(conn: &Connection) -> Result<()> {
     Ok(())
 }
 
+fn create_message_security_table(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS message_security (
+            message_id INTEGER PRIMARY KEY,
+            protocol TEXT NOT NULL,
+            is_signed INTEGER NOT NULL DEFAULT 0,
+            signature_status TEXT NOT NULL,
+            signer TEXT,
+            signer_key_id TEXT,
+            is_encrypted INTEGER NOT NULL DEFAULT 0,
+            decryption_status TEXT NOT NULL,
+            details TEXT,
+            FOREIGN KEY (message_id) REFERENCES messages (id) ON DELETE CASCADE
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+fn create_pgp_keys_table(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS pgp_keys (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            fingerprint TEXT NOT NULL UNIQUE,
+            key_id TEXT NOT NULL,
+            user_ids TEXT NOT NULL,
+            primary_email TEXT,
+            armored_public TEXT NOT NULL,
+            armored_secret TEXT,
+            created_at INTEGER NOT NULL
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+fn create_smime_certificates_table(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS smime_certificates (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            fingerprint TEXT NOT NULL UNIQUE,
+            email TEXT,
+            subject TEXT NOT NULL,
+            issuer TEXT NOT NULL,
+            not_after INTEGER NOT NULL,
+            certificate_pem TEXT NOT NULL,
+            pkcs12 BLOB,
+            is_trust_anchor INTEGER NOT NULL DEFAULT 0,
+            is_trusted INTEGER NOT NULL DEFAULT 0,
+            created_at INTEGER NOT NULL
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+fn create_message_authentication_table(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS message_authentication (
+            message_id INTEGER PRIMARY KEY,
+            authserv_id TEXT,
+            spf TEXT,
+            dkim TEXT,
+            dmarc TEXT,
+            arc TEXT,
+            dkim_local TEXT,
+            dkim_domain TEXT,
+            verdict TEXT NOT NULL,
+            warnings TEXT NOT NULL DEFAULT '[]',
+            FOREIGN KEY (message_id) REFERENCES messages (id) ON DELETE CASCADE
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+fn create_dkim_keys_table(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS dkim_keys (
+            domain TEXT NOT NULL,
+            selector TEXT NOT NULL,
+            record TEXT NOT NULL,
+            created_at INTEGER NOT NULL,
+            PRIMARY KEY (domain, selector)
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+fn create_remote_content_exceptions_table(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS remote_content_exceptions (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            kind TEXT NOT NULL,
+            value TEXT NOT NULL,
+            created_at INTEGER NOT NULL,
+            UNIQUE (kind, value)
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+fn create_remote_content_cache_table(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS remote_content_cache (
+            url_hash TEXT PRIMARY KEY,
+            url TEXT NOT NULL,
+            mime_type TEXT NOT NULL,
+            data BLOB NOT NULL,
+            fetched_at INTEGER NOT NULL
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+fn create_sync_status_table(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS sync_status (
+            account_id INTEGER PRIMARY KEY REFERENCES accounts(id) ON DELETE CASCADE,
+            state TEXT NOT NULL DEFAULT 'idle',
+            last_attempt_at INTEGER,
+            last_success_at INTEGER,
+            last_error TEXT,
+            last_error_at INTEGER,
+            messages_fetched INTEGER NOT NULL DEFAULT 0,
+            consecutive_failures INTEGER NOT NULL DEFAULT 0
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+fn create_rules_table(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS rules (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            account_id INTEGER REFERENCES accounts(id) ON DELETE CASCADE,
+            name TEXT NOT NULL,
+            enabled INTEGER NOT NULL DEFAULT 1,
+            position INTEGER NOT NULL DEFAULT 0,
+            match_all INTEGER NOT NULL DEFAULT 1,
+            stop_processing INTEGER NOT NULL DEFAULT 0,
+            conditions TEXT NOT NULL,
+            actions TEXT NOT NULL,
+            created_at INTEGER NOT NULL
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+fn create_rule_pending_actions_table(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS rule_pending_actions (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            message_id INTEGER NOT NULL REFERENCES messages(id) ON DELETE CASCADE,
+            action TEXT NOT NULL,
+            run_at INTEGER NOT NULL
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+fn create_message_headers_table(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS message_headers (
+            message_id INTEGER PRIMARY KEY REFERENCES messages(id) ON DELETE CASCADE,
+            headers TEXT NOT NULL,
+            size_bytes INTEGER NOT NULL
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+/// Messages a rule moved, so they are recognised when synced in the target folder
+fn create_rule_moves_table(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS rule_moves (
+            account_id INTEGER NOT NULL REFERENCES accounts(id) ON DELETE CASCADE,
+            folder_path TEXT NOT NULL,
+            message_id_header TEXT NOT NULL,
+            labels TEXT NOT NULL,
+            moved_at INTEGER NOT NULL,
+            PRIMARY KEY (account_id, folder_path, message_id_header)
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+/// Snoozed messages and follow-up reminders, one of each kind per message
+fn create_snoozes_table(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS snoozes (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            message_id INTEGER NOT NULL REFERENCES messages(id) ON DELETE CASCADE,
+            kind TEXT NOT NULL,
+            due_at INTEGER NOT NULL,
+            created_at INTEGER NOT NULL,
+            UNIQUE (message_id, kind)
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+fn create_identities_table(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS identities (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            account_id INTEGER NOT NULL REFERENCES accounts(id) ON DELETE CASCADE,
+            email_address TEXT NOT NULL,
+            display_name TEXT NOT NULL DEFAULT '',
+            reply_to TEXT,
+            signature_id INTEGER REFERENCES signatures(id) ON DELETE SET NULL,
+            smtp_host TEXT,
+            smtp_port INTEGER,
+            smtp_security TEXT,
+            smtp_username TEXT,
+            is_default INTEGER NOT NULL DEFAULT 0,
+            UNIQUE (account_id, email_address)
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+fn create_templates_tables(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS templates (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            account_id INTEGER REFERENCES accounts(id) ON DELETE CASCADE,
+            name TEXT NOT NULL,
+            subject TEXT NOT NULL DEFAULT '',
+            body TEXT NOT NULL DEFAULT '',
+            created_at INTEGER NOT NULL,
+            updated_at INTEGER NOT NULL
+        )",
+        [],
+    )?;
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS template_attachments (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            template_id INTEGER NOT NULL REFERENCES templates(id) ON DELETE CASCADE,
+            filename TEXT NOT NULL,
+            mime_type TEXT NOT NULL,
+            data BLOB NOT NULL
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
 fn create_indexes(conn: &Connection) -> Result<()> {
     // Message indexes
     conn.execute(
@@ -317,5 +648,45 @@ fn create_indexes(conn: &Connection) -> Result<()> {
         [],
     )?;
 
+    // PGP key indexes
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS idx_pgp_keys_primary_email ON pgp_keys(primary_email)",
+        [],
+    )?;
+
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS idx_smime_certificates_email ON smime_certificates(email)",
+        [],
+    )?;
+
+    // Message location indexes
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS idx_message_locations_message ON message_locations(message_id)",
+        [],
+    )?;
+
+    // Tag indexes
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS idx_message_flags_flag ON message_flags(flag)",
+        [],
+    )?;
+
+    // Reminder indexes
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS idx_snoozes_due_at ON snoozes(due_at)",
+        [],
+    )?;
+
+    // Template and draft attachment indexes
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS idx_template_attachments_template
+         ON template_attachments(template_id)",
+        [],
+    )?;
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS idx_draft_attachments_draft ON draft_attachments(draft_id)",
+        [],
+    )?;
+
     Ok(())
 }
@@ -1,18 +1,98 @@
 use crate::error::DEmailError;
-use crate::models::OAuthProviderConfig;
+use crate::models::{OAuthProviderConfig, OAuthProviderInfo};
 use serde_json;
 use std::collections::HashMap;
 use std::fs;
 use std::path::PathBuf;
 use tauri::Config;
+use tracing::info;
 
-fn get_config_path(config: &Config) -> Result<PathBuf, DEmailError> {
+pub fn get_config_path(config: &Config) -> Result<PathBuf, DEmailError> {
     let path = tauri::api::path::app_config_dir(config)
         .ok_or_else(|| DEmailError::Init("Could not determine app config directory".to_string()))?;
     Ok(path.join("demail_config.json"))
 }
 
+/// Loads the provider configs, with client secrets filled in from the OS keyring
 pub fn load_config(config: &Config) -> Result<HashMap<String, OAuthProviderConfig>, DEmailError> {
+    let mut configs = read_config_file(config)?;
+    for (provider, provider_config) in configs.iter_mut() {
+        provider_config.client_secret = get_client_secret(provider)?.unwrap_or_default();
+    }
+    Ok(configs)
+}
+
+/// Provider configs as shown to the frontend, without the client secrets
+pub fn load_config_info(
+    config: &Config,
+) -> Result<HashMap<String, OAuthProviderInfo>, DEmailError> {
+    Ok(read_config_file(config)?
+        .into_iter()
+        .map(|(provider, provider_config)| {
+            let has_client_secret = matches!(get_client_secret(&provider), Ok(Some(_)));
+            (
+                provider,
+                OAuthProviderInfo {
+                    client_id: provider_config.client_id,
+                    has_client_secret,
+                },
+            )
+        })
+        .collect())
+}
+
+/// Writes the provider configs. Client secrets go to the OS keyring only; an empty
+/// secret keeps the one already stored.
+pub fn save_config(
+    config: &Config,
+    configs: &HashMap<String, OAuthProviderConfig>,
+) -> Result<(), DEmailError> {
+    for (provider, provider_config) in configs {
+        if !provider_config.client_secret.is_empty() {
+            store_client_secret(provider, &provider_config.client_secret)?;
+        }
+    }
+    write_config_file(config, configs)
+}
+
+/// Moves client secrets left in `demail_config.json` by earlier versions into the OS
+/// keyring and rewrites the file without them
+pub fn migrate_plaintext_secrets(config: &Config) -> Result<(), DEmailError> {
+    let path = get_config_path(config)?;
+    if !path.exists() {
+        return Ok(());
+    }
+    let raw: HashMap<String, serde_json::Value> =
+        serde_json::from_str(&fs::read_to_string(&path)?)?;
+    let plaintext: Vec<(&String, &str)> = raw
+        .iter()
+        .filter_map(|(provider, value)| {
+            value
+                .get("client_secret")
+                .and_then(|secret| secret.as_str())
+                .map(|secret| (provider, secret))
+        })
+        .collect();
+    if plaintext.is_empty() {
+        return Ok(());
+    }
+
+    for (provider, secret) in &plaintext {
+        if !secret.is_empty() {
+            store_client_secret(provider, secret)?;
+        }
+    }
+    let configs = read_config_file(config)?;
+    write_config_file(config, &configs)?;
+
+    info!(
+        "Moved {} OAuth client secret(s) from the config file into the keyring",
+        plaintext.len()
+    );
+    Ok(())
+}
+
+fn read_config_file(config: &Config) -> Result<HashMap<String, OAuthProviderConfig>, DEmailError> {
     let path = get_config_path(config)?;
     if !path.exists() {
         return Ok(HashMap::new());
@@ -22,7 +102,7 @@ pub fn load_config(config: &Config) -> Result<HashMap<String, OAuthProviderConfi
     Ok(configs)
 }
 
-pub fn save_config(
+fn write_config_file(
     config: &Config,
     configs: &HashMap<String, OAuthProviderConfig>,
 ) -> Result<(), DEmailError> {
@@ -34,3 +114,46 @@ pub fn save_config(
     fs::write(path, content)?;
     Ok(())
 }
+
+fn client_secret_entry(provider: &str) -> Result<keyring::Entry, DEmailError> {
+    Ok(keyring::Entry::new(
+        "demail",
+        &format!("oauth_client_secret_{}", provider),
+    )?)
+}
+
+pub fn store_client_secret(provider: &str, client_secret: &str) -> Result<(), DEmailError> {
+    client_secret_entry(provider)?.set_password(client_secret)?;
+    Ok(())
+}
+
+pub fn get_client_secret(provider: &str) -> Result<Option<String>, DEmailError> {
+    match client_secret_entry(provider)?.get_password() {
+        Ok(secret) => Ok(Some(secret)),
+        Err(keyring::Error::NoEntry) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_client_secret_is_never_serialized() {
+        let provider_config = OAuthProviderConfig {
+            client_id: "id.apps.example".to_string(),
+            client_secret: "hunter2".to_string(),
+        };
+        let json = serde_json::to_string(&provider_config).unwrap();
+        assert!(!json.contains("hunter2"));
+        assert!(!json.contains("client_secret"));
+
+        // Secrets are still accepted from the frontend and from old config files
+        let parsed: OAuthProviderConfig =
+            serde_json::from_str(r#"{"client_id":"id","client_secret":"hunter2"}"#).unwrap();
+        assert_eq!(parsed.client_secret, "hunter2");
+        let parsed: OAuthProviderConfig = serde_json::from_str(r#"{"client_id":"id"}"#).unwrap();
+        assert!(parsed.client_secret.is_empty());
+    }
+}
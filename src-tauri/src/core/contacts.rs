@@ -2,6 +2,7 @@
 
 use crate::core::validation;
 use crate::error::DEmailError;
+use mail_parser::MessageParser;
 use rusqlite::Connection;
 use serde::{Deserialize, Serialize};
 
@@ -182,7 +183,7 @@ fn save_or_update_contact(
 
 /// Parses an email address string to extract email and optional name
 /// Supports formats like "John Doe <john@example.com>" and "john@example.com"
-fn parse_email_address(addr: &str) -> Option<(String, Option<String>)> {
+pub fn parse_email_address(addr: &str) -> Option<(String, Option<String>)> {
     let trimmed = addr.trim();
 
     // Check for "Name <email>" format
@@ -211,6 +212,24 @@ fn parse_email_address(addr: &str) -> Option<(String, Option<String>)> {
     None
 }
 
+/// The addresses in an address header such as To or Cc, parsed as RFC 5322 does so that
+/// quoted names, comments and groups are read correctly
+pub fn parse_address_list(header: &str) -> Vec<String> {
+    let raw = format!("To: {}\r\n\r\n", header.replace(['\r', '\n'], " "));
+    let Some(message) = MessageParser::default().parse(raw.as_bytes()) else {
+        return Vec::new();
+    };
+    message
+        .to()
+        .map(|to| {
+            to.iter()
+                .filter_map(|addr| addr.address())
+                .map(str::to_string)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
 /// Splits a comma or semicolon-separated list of email addresses
 fn split_email_addresses(addresses: &str) -> Vec<String> {
     addresses
@@ -246,6 +265,19 @@ mod tests {
         assert!(parse_email_address("").is_none());
     }
 
+    #[test]
+    fn test_parse_address_list() {
+        assert_eq!(
+            parse_address_list("\"Doe, Jane\" <jane@example.com>, bob@example.com (Bob)"),
+            vec!["jane@example.com", "bob@example.com"]
+        );
+        assert_eq!(
+            parse_address_list("Team: alice@example.com, carol@example.com;"),
+            vec!["alice@example.com", "carol@example.com"]
+        );
+        assert!(parse_address_list("").is_empty());
+    }
+
     #[test]
     fn test_split_email_addresses() {
         let addrs = split_email_addresses("john@example.com, jane@example.com");
@@ -0,0 +1,319 @@
+// Message security module - Shared types and storage for OpenPGP / S/MIME status
+
+pub mod openpgp;
+pub mod smime;
+
+use crate::core::cache::db::sanitize_html;
+use crate::error::DEmailError;
+use crate::models::MessageSecurity;
+use lettre::message::header::ContentType;
+use lettre::message::{MessageBuilder, MultiPart, SinglePart};
+use mail_parser::{Message as ParsedMessage, MessageParser};
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+
+pub const PROTOCOL_OPENPGP: &str = "openpgp";
+pub const PROTOCOL_SMIME: &str = "smime";
+
+pub const SIGNATURE_NONE: &str = "none";
+pub const SIGNATURE_VALID: &str = "valid";
+pub const SIGNATURE_INVALID: &str = "invalid";
+pub const SIGNATURE_UNKNOWN_KEY: &str = "unknown_key";
+/// Signature is intact but the signer's certificate does not chain to a trusted root
+pub const SIGNATURE_UNTRUSTED: &str = "untrusted";
+/// Signature is intact but the signing key or certificate belongs to someone other than
+/// the sender
+pub const SIGNATURE_SIGNER_MISMATCH: &str = "signer_mismatch";
+
+pub const DECRYPTION_NONE: &str = "none";
+pub const DECRYPTION_DECRYPTED: &str = "decrypted";
+pub const DECRYPTION_NEEDS_PASSPHRASE: &str = "needs_passphrase";
+pub const DECRYPTION_NO_KEY: &str = "no_key";
+pub const DECRYPTION_FAILED: &str = "failed";
+
+/// Set to "true" to keep decrypted bodies in the message cache. Off by default, so
+/// decrypted text is not written to a database that may not be encrypted at rest.
+pub const SETTING_CACHE_DECRYPTED_BODIES: &str = "crypto_cache_decrypted_bodies";
+
+/// Result of processing a signed and/or encrypted incoming message
+#[derive(Debug, Clone)]
+pub struct IncomingSecurity {
+    pub security: MessageSecurity,
+    /// Replacement bodies when the message was decrypted
+    pub body_plain: Option<String>,
+    pub body_html: Option<String>,
+}
+
+/// Signing/encryption options chosen in compose
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct OutgoingSecurity {
+    pub protocol: String,
+    pub sign: bool,
+    pub encrypt: bool,
+    pub passphrase: Option<String>,
+}
+
+/// Protected body of an outgoing message. S/MIME enveloped data is a single part,
+/// everything else is a multipart/signed or multipart/encrypted structure.
+#[derive(Debug, Clone)]
+pub enum ProtectedBody {
+    MultiPart(MultiPart),
+    SinglePart(SinglePart),
+}
+
+impl ProtectedBody {
+    /// Finishes a message builder with the protected body
+    pub fn build(self, builder: MessageBuilder) -> Result<lettre::Message, lettre::error::Error> {
+        match self {
+            ProtectedBody::MultiPart(part) => builder.multipart(part),
+            ProtectedBody::SinglePart(part) => builder.singlepart(part),
+        }
+    }
+}
+
+impl MessageSecurity {
+    pub fn new(protocol: &str) -> Self {
+        Self {
+            message_id: 0,
+            protocol: protocol.to_string(),
+            is_signed: false,
+            signature_status: SIGNATURE_NONE.to_string(),
+            signer: None,
+            signer_key_id: None,
+            is_encrypted: false,
+            decryption_status: DECRYPTION_NONE.to_string(),
+            details: None,
+        }
+    }
+}
+
+/// Detects, verifies and decrypts signed/encrypted content while a message is synced.
+/// Returns `None` for ordinary messages.
+pub fn process_incoming(
+    conn: &Connection,
+    raw: &[u8],
+    parsed: &ParsedMessage,
+) -> Option<IncomingSecurity> {
+    smime::process_incoming(conn, raw, parsed, None)
+        .or_else(|| openpgp::process_incoming(conn, raw, parsed, None))
+}
+
+/// Decrypts a stored message on open with the passphrase entered by the user. The
+/// decrypted bodies are returned, and only cached when the user opted in.
+pub fn decrypt_stored_message(
+    conn: &Connection,
+    message_id: i64,
+    passphrase: &str,
+) -> Result<IncomingSecurity, DEmailError> {
+    let protocol = get_message_security(conn, message_id)?.map(|security| security.protocol);
+    let decrypted = match protocol.as_deref() {
+        Some(PROTOCOL_SMIME) => smime::decrypt_stored_message(conn, message_id, passphrase)?,
+        _ => openpgp::decrypt_stored_message(conn, message_id, passphrase)?,
+    };
+    if cache_decrypted_bodies(conn) {
+        update_decrypted_bodies(
+            conn,
+            message_id,
+            decrypted.body_plain.as_deref(),
+            decrypted.body_html.as_deref(),
+        )?;
+    }
+    Ok(decrypted)
+}
+
+/// Whether decrypted bodies may be stored in the message cache
+pub fn cache_decrypted_bodies(conn: &Connection) -> bool {
+    conn.query_row(
+        "SELECT value FROM settings WHERE key = ?1",
+        [SETTING_CACHE_DECRYPTED_BODIES],
+        |row| row.get::<_, String>(0),
+    )
+    .map(|value| value == "true")
+    .unwrap_or(false)
+}
+
+/// Builds the protected MIME body for an outgoing message
+pub fn protect_outgoing(
+    conn: &Connection,
+    from: &str,
+    recipients: &[String],
+    body: &str,
+    security: &OutgoingSecurity,
+) -> Result<ProtectedBody, DEmailError> {
+    match security.protocol.as_str() {
+        PROTOCOL_OPENPGP => openpgp::protect_outgoing(conn, from, recipients, body, security)
+            .map(ProtectedBody::MultiPart),
+        PROTOCOL_SMIME => smime::protect_outgoing(conn, from, recipients, body, security),
+        other => Err(DEmailError::Crypto(format!(
+            "Unsupported security protocol: {}",
+            other
+        ))),
+    }
+}
+
+/// Saves (or replaces) the security status of a message
+pub fn save_message_security(
+    conn: &Connection,
+    message_id: i64,
+    security: &MessageSecurity,
+) -> Result<(), DEmailError> {
+    conn.execute(
+        "INSERT OR REPLACE INTO message_security (message_id, protocol, is_signed, signature_status, signer, signer_key_id, is_encrypted, decryption_status, details)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+        rusqlite::params![
+            message_id,
+            security.protocol,
+            security.is_signed,
+            security.signature_status,
+            security.signer,
+            security.signer_key_id,
+            security.is_encrypted,
+            security.decryption_status,
+            security.details,
+        ],
+    )?;
+    Ok(())
+}
+
+/// Loads the security status of a message, if it was signed or encrypted
+pub fn get_message_security(
+    conn: &Connection,
+    message_id: i64,
+) -> Result<Option<MessageSecurity>, DEmailError> {
+    let result = conn.query_row(
+        "SELECT message_id, protocol, is_signed, signature_status, signer, signer_key_id, is_encrypted, decryption_status, details
+         FROM message_security WHERE message_id = ?1",
+        [message_id],
+        |row| {
+            Ok(MessageSecurity {
+                message_id: row.get(0)?,
+                protocol: row.get(1)?,
+                is_signed: row.get(2)?,
+                signature_status: row.get(3)?,
+                signer: row.get(4)?,
+                signer_key_id: row.get(5)?,
+                is_encrypted: row.get(6)?,
+                decryption_status: row.get(7)?,
+                details: row.get(8)?,
+            })
+        },
+    );
+
+    match result {
+        Ok(security) => Ok(Some(security)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(DEmailError::from(e)),
+    }
+}
+
+/// Replaces the stored bodies of a message after on-demand decryption
+fn update_decrypted_bodies(
+    conn: &Connection,
+    message_id: i64,
+    body_plain: Option<&str>,
+    body_html: Option<&str>,
+) -> Result<(), DEmailError> {
+    let sanitized_html = sanitize_html(&body_html.map(str::to_string));
+    conn.execute(
+        "UPDATE messages SET body_plain = COALESCE(?1, body_plain), body_html = COALESCE(?2, body_html) WHERE id = ?3",
+        rusqlite::params![body_plain, sanitized_html, message_id],
+    )?;
+    Ok(())
+}
+
+// ============================================================================
+// MIME HELPERS
+// ============================================================================
+
+fn content_type(value: &str) -> Result<ContentType, DEmailError> {
+    ContentType::parse(value)
+        .map_err(|e| DEmailError::Crypto(format!("Invalid content type {}: {}", value, e)))
+}
+
+fn is_mime_type(ct: &mail_parser::ContentType, mime_type: &str) -> bool {
+    let full = match ct.subtype() {
+        Some(sub) => format!("{}/{}", ct.ctype(), sub),
+        None => ct.ctype().to_string(),
+    };
+    full.eq_ignore_ascii_case(mime_type)
+}
+
+fn sender_email(parsed: &ParsedMessage) -> Option<String> {
+    parsed
+        .from()
+        .and_then(|from| from.first())
+        .and_then(|addr| addr.address())
+        .map(|address| address.to_lowercase())
+}
+
+/// Bytes of an outgoing part as covered by a multipart/signed signature. The line break
+/// before the next boundary delimiter belongs to the delimiter (RFC 2046), so it is not signed.
+fn signed_content(part: &SinglePart) -> Vec<u8> {
+    let mut content = part.formatted();
+    if content.ends_with(b"\r\n") {
+        content.truncate(content.len() - 2);
+    }
+    content
+}
+
+/// Returns the bytes of the first body part of a multipart/signed message, exactly as
+/// they were signed: from after the first delimiter line up to the CRLF that precedes
+/// the next delimiter (RFC 1847, RFC 3156 section 5)
+fn split_multipart_signed<'a>(raw: &'a [u8], boundary: &str) -> Option<&'a [u8]> {
+    let delimiter = format!("--{}", boundary);
+    let delimiter = delimiter.as_bytes();
+
+    let first = find_delimiter_line(raw, delimiter, 0)?;
+    let start = first + delimiter.len();
+    let start = start + line_break_len(&raw[start..])?;
+
+    let mut search = start;
+    loop {
+        let next = find_subslice(&raw[search..], delimiter)? + search;
+        if next >= start + 2 && &raw[next - 2..next] == b"\r\n" {
+            return Some(&raw[start..next - 2]);
+        }
+        if next > start && raw[next - 1] == b'\n' {
+            return Some(&raw[start..next - 1]);
+        }
+        search = next + delimiter.len();
+    }
+}
+
+fn find_delimiter_line(raw: &[u8], delimiter: &[u8], from: usize) -> Option<usize> {
+    let mut search = from;
+    loop {
+        let pos = find_subslice(&raw[search..], delimiter)? + search;
+        if pos == 0 || raw[pos - 1] == b'\n' {
+            return Some(pos);
+        }
+        search = pos + delimiter.len();
+    }
+}
+
+fn line_break_len(data: &[u8]) -> Option<usize> {
+    if data.starts_with(b"\r\n") {
+        Some(2)
+    } else if data.starts_with(b"\n") {
+        Some(1)
+    } else {
+        None
+    }
+}
+
+fn find_subslice(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+/// Extracts text and HTML bodies from a decrypted MIME entity
+fn bodies_from_mime(content: &[u8]) -> (Option<String>, Option<String>) {
+    match MessageParser::default().parse(content) {
+        Some(entity) => (
+            entity.body_text(0).map(|body| body.to_string()),
+            entity.body_html(0).map(|body| body.to_string()),
+        ),
+        None => (Some(String::from_utf8_lossy(content).to_string()), None),
+    }
+}
@@ -0,0 +1,1051 @@
+// S/MIME module - Certificate store, PKCS#7 verification/decryption and signing/encryption
+
+use super::{
+    bodies_from_mime, content_type, get_message_security, is_mime_type, save_message_security,
+    sender_email, signed_content, split_multipart_signed, IncomingSecurity, OutgoingSecurity,
+    ProtectedBody, DECRYPTION_DECRYPTED, DECRYPTION_FAILED, DECRYPTION_NEEDS_PASSPHRASE,
+    DECRYPTION_NO_KEY, PROTOCOL_SMIME, SIGNATURE_INVALID, SIGNATURE_SIGNER_MISMATCH,
+    SIGNATURE_UNTRUSTED, SIGNATURE_VALID,
+};
+use crate::core::contacts::parse_email_address;
+use crate::error::DEmailError;
+use crate::models::MessageSecurity;
+use lettre::message::header::ContentDisposition;
+use lettre::message::{MultiPart, SinglePart};
+use mail_parser::{Message as ParsedMessage, MessageParser, MimeHeaders};
+use openssl::asn1::Asn1Time;
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkcs12::Pkcs12;
+use openssl::pkcs7::{Pkcs7, Pkcs7Flags};
+use openssl::pkey::{PKey, Private};
+use openssl::stack::Stack;
+use openssl::symm::Cipher;
+use openssl::x509::store::{X509Store, X509StoreBuilder};
+use openssl::x509::{X509NameRef, X509Ref, X509};
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+const SMIME_SIGNATURE_TYPES: [&str; 2] = [
+    "application/pkcs7-signature",
+    "application/x-pkcs7-signature",
+];
+const SMIME_MIME_TYPES: [&str; 2] = ["application/pkcs7-mime", "application/x-pkcs7-mime"];
+
+/// Setting key controlling whether the operating system's root certificates are trusted
+/// in addition to the trust anchors imported into the app ("true" by default)
+pub const SETTING_USE_SYSTEM_ROOTS: &str = "smime_use_system_roots";
+
+/// How deep signed-inside-encrypted structures are unwrapped
+const MAX_NESTING: usize = 2;
+
+/// Public information about a certificate in the S/MIME store
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SmimeCertificateInfo {
+    pub id: i64,
+    pub fingerprint: String,
+    pub email: Option<String>,
+    pub subject: String,
+    pub issuer: String,
+    pub not_after: i64,
+    pub has_private_key: bool,
+    pub is_trust_anchor: bool,
+    pub created_at: i64,
+}
+
+/// Kind of S/MIME structure found on an incoming message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SmimeKind {
+    DetachedSigned,
+    OpaqueSigned,
+    Enveloped,
+}
+
+/// Certificate and private key unlocked from a PKCS#12 file
+struct Identity {
+    certificate: X509,
+    private_key: PKey<Private>,
+    chain: Vec<X509>,
+}
+
+fn ssl_error(e: openssl::error::ErrorStack) -> DEmailError {
+    DEmailError::Crypto(e.to_string())
+}
+
+// ============================================================================
+// CERTIFICATE STORE OPERATIONS
+// ============================================================================
+
+/// Imports a PKCS#12 identity (certificate + private key) used for signing and decryption.
+/// The file is stored as-is, still protected by its password.
+pub fn import_identity(
+    conn: &Connection,
+    pkcs12_der: &[u8],
+    password: &str,
+) -> Result<SmimeCertificateInfo, DEmailError> {
+    let identity = parse_identity(pkcs12_der, password)?;
+    store_certificate(conn, &identity.certificate, Some(pkcs12_der), false)
+}
+
+/// Imports a PEM or DER certificate, either a correspondent's certificate (for encryption)
+/// or a trust anchor used to validate signatures
+pub fn import_certificate(
+    conn: &Connection,
+    data: &[u8],
+    trust_anchor: bool,
+) -> Result<SmimeCertificateInfo, DEmailError> {
+    let certificate = if data.starts_with(b"-----BEGIN") {
+        X509::from_pem(data)
+    } else {
+        X509::from_der(data)
+    }
+    .map_err(ssl_error)?;
+
+    store_certificate(conn, &certificate, None, trust_anchor)
+}
+
+fn store_certificate(
+    conn: &Connection,
+    certificate: &X509Ref,
+    pkcs12: Option<&[u8]>,
+    trust_anchor: bool,
+) -> Result<SmimeCertificateInfo, DEmailError> {
+    let fingerprint = certificate_fingerprint(certificate)?;
+    let pem = String::from_utf8(certificate.to_pem().map_err(ssl_error)?)
+        .map_err(|e| DEmailError::Crypto(e.to_string()))?;
+
+    // Certificates are only stored when imported by the user or taken from a signature
+    // whose chain verified, so every stored certificate may be encrypted to
+    conn.execute(
+        "INSERT INTO smime_certificates (fingerprint, email, subject, issuer, not_after, certificate_pem, pkcs12, is_trust_anchor, is_trusted, created_at)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, 1, ?9)
+         ON CONFLICT(fingerprint) DO UPDATE SET
+            pkcs12 = COALESCE(excluded.pkcs12, smime_certificates.pkcs12),
+            is_trust_anchor = MAX(excluded.is_trust_anchor, smime_certificates.is_trust_anchor),
+            is_trusted = 1",
+        rusqlite::params![
+            fingerprint,
+            certificate_email(certificate),
+            name_to_string(certificate.subject_name()),
+            name_to_string(certificate.issuer_name()),
+            not_after_timestamp(certificate)?,
+            pem,
+            pkcs12,
+            trust_anchor,
+            chrono::Utc::now().timestamp(),
+        ],
+    )?;
+
+    get_certificate(conn, &fingerprint)
+}
+
+/// Gets a certificate from the store by SHA-256 fingerprint
+pub fn get_certificate(
+    conn: &Connection,
+    fingerprint: &str,
+) -> Result<SmimeCertificateInfo, DEmailError> {
+    conn.query_row(
+        "SELECT id, fingerprint, email, subject, issuer, not_after, pkcs12 IS NOT NULL, is_trust_anchor, created_at
+         FROM smime_certificates WHERE fingerprint = ?1",
+        [fingerprint],
+        certificate_info_from_row,
+    )
+    .map_err(|e| match e {
+        rusqlite::Error::QueryReturnedNoRows => {
+            DEmailError::NotFound(format!("S/MIME certificate {} not found", fingerprint))
+        }
+        e => DEmailError::Database(e),
+    })
+}
+
+/// Lists all certificates, identities and trust anchors in the store
+pub fn list_certificates(conn: &Connection) -> Result<Vec<SmimeCertificateInfo>, DEmailError> {
+    let mut stmt = conn.prepare(
+        "SELECT id, fingerprint, email, subject, issuer, not_after, pkcs12 IS NOT NULL, is_trust_anchor, created_at
+         FROM smime_certificates ORDER BY is_trust_anchor, email, not_after DESC",
+    )?;
+
+    let certificate_iter = stmt.query_map([], certificate_info_from_row)?;
+
+    let mut certificates = Vec::new();
+    for certificate in certificate_iter {
+        certificates.push(certificate?);
+    }
+    Ok(certificates)
+}
+
+fn certificate_info_from_row(row: &rusqlite::Row) -> rusqlite::Result<SmimeCertificateInfo> {
+    Ok(SmimeCertificateInfo {
+        id: row.get(0)?,
+        fingerprint: row.get(1)?,
+        email: row.get(2)?,
+        subject: row.get(3)?,
+        issuer: row.get(4)?,
+        not_after: row.get(5)?,
+        has_private_key: row.get(6)?,
+        is_trust_anchor: row.get(7)?,
+        created_at: row.get(8)?,
+    })
+}
+
+/// Removes a certificate (and its private key, if any) from the store
+pub fn delete_certificate(conn: &Connection, fingerprint: &str) -> Result<(), DEmailError> {
+    conn.execute(
+        "DELETE FROM smime_certificates WHERE fingerprint = ?1",
+        [fingerprint],
+    )?;
+    Ok(())
+}
+
+/// Newest unexpired certificate for an email address, preferring our own identities
+fn load_certificate_for(conn: &Connection, email: &str) -> Result<Option<X509>, DEmailError> {
+    let result = conn.query_row(
+        "SELECT certificate_pem FROM smime_certificates
+         WHERE LOWER(email) = LOWER(?1) AND is_trust_anchor = 0 AND is_trusted = 1
+           AND not_after > ?2
+         ORDER BY pkcs12 IS NOT NULL DESC, not_after DESC LIMIT 1",
+        rusqlite::params![email, chrono::Utc::now().timestamp()],
+        |row| row.get::<_, String>(0),
+    );
+
+    match result {
+        Ok(pem) => Ok(Some(X509::from_pem(pem.as_bytes()).map_err(ssl_error)?)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(DEmailError::from(e)),
+    }
+}
+
+/// Unlocks the PKCS#12 identity for an email address
+fn load_identity_for(
+    conn: &Connection,
+    email: &str,
+    password: &str,
+) -> Result<Identity, DEmailError> {
+    let pkcs12: Vec<u8> = conn
+        .query_row(
+            "SELECT pkcs12 FROM smime_certificates
+             WHERE LOWER(email) = LOWER(?1) AND pkcs12 IS NOT NULL
+             ORDER BY not_after DESC LIMIT 1",
+            [email],
+            |row| row.get(0),
+        )
+        .map_err(|e| match e {
+            rusqlite::Error::QueryReturnedNoRows => {
+                DEmailError::Crypto(format!("No S/MIME identity for {}", email))
+            }
+            e => DEmailError::Database(e),
+        })?;
+
+    parse_identity(&pkcs12, password)
+}
+
+fn load_all_pkcs12(conn: &Connection) -> Result<Vec<Vec<u8>>, DEmailError> {
+    let mut stmt =
+        conn.prepare("SELECT pkcs12 FROM smime_certificates WHERE pkcs12 IS NOT NULL")?;
+    let rows = stmt.query_map([], |row| row.get(0))?;
+
+    let mut identities = Vec::new();
+    for row in rows {
+        identities.push(row?);
+    }
+    Ok(identities)
+}
+
+fn parse_identity(pkcs12_der: &[u8], password: &str) -> Result<Identity, DEmailError> {
+    let parsed = Pkcs12::from_der(pkcs12_der)
+        .map_err(ssl_error)?
+        .parse2(password)
+        .map_err(|_| DEmailError::Crypto("Wrong password or damaged PKCS#12 file".to_string()))?;
+
+    Ok(Identity {
+        certificate: parsed.cert.ok_or_else(|| {
+            DEmailError::Crypto("PKCS#12 file contains no certificate".to_string())
+        })?,
+        private_key: parsed.pkey.ok_or_else(|| {
+            DEmailError::Crypto("PKCS#12 file contains no private key".to_string())
+        })?,
+        chain: parsed
+            .ca
+            .map(|ca| ca.into_iter().collect())
+            .unwrap_or_default(),
+    })
+}
+
+/// Builds the trust store from imported trust anchors and, unless disabled, system roots
+fn trust_store(conn: &Connection) -> Result<X509Store, DEmailError> {
+    let mut builder = X509StoreBuilder::new().map_err(ssl_error)?;
+
+    let use_system_roots = conn
+        .query_row(
+            "SELECT value FROM settings WHERE key = ?1",
+            [SETTING_USE_SYSTEM_ROOTS],
+            |row| row.get::<_, String>(0),
+        )
+        .map(|value| value != "false")
+        .unwrap_or(true);
+    if use_system_roots {
+        builder.set_default_paths().map_err(ssl_error)?;
+    }
+
+    let mut stmt =
+        conn.prepare("SELECT certificate_pem FROM smime_certificates WHERE is_trust_anchor = 1")?;
+    let rows = stmt.query_map([], |row| row.get::<_, String>(0))?;
+    for pem in rows {
+        let anchor = X509::from_pem(pem?.as_bytes()).map_err(ssl_error)?;
+        builder.add_cert(anchor).map_err(ssl_error)?;
+    }
+
+    Ok(builder.build())
+}
+
+/// Stored certificates of a sender, used when a signature does not embed its certificate
+fn known_certificates(conn: &Connection, sender: Option<&str>) -> Result<Stack<X509>, DEmailError> {
+    let mut certificates = Stack::new().map_err(ssl_error)?;
+    if let Some(email) = sender {
+        let mut stmt = conn.prepare(
+            "SELECT certificate_pem FROM smime_certificates WHERE LOWER(email) = LOWER(?1)",
+        )?;
+        let rows = stmt.query_map([email], |row| row.get::<_, String>(0))?;
+        for pem in rows {
+            let certificate = X509::from_pem(pem?.as_bytes()).map_err(ssl_error)?;
+            certificates.push(certificate).map_err(ssl_error)?;
+        }
+    }
+    Ok(certificates)
+}
+
+// ============================================================================
+// INCOMING MESSAGES
+// ============================================================================
+
+/// Verifies and decrypts an incoming S/MIME message (RFC 8551).
+/// Returns `None` when the message carries no S/MIME content.
+pub fn process_incoming(
+    conn: &Connection,
+    raw: &[u8],
+    parsed: &ParsedMessage,
+    passphrase: Option<&str>,
+) -> Option<IncomingSecurity> {
+    detect_kind(parsed)?;
+    let sender = sender_email(parsed);
+
+    let mut security = MessageSecurity::new(PROTOCOL_SMIME);
+    let content = process_entity(
+        conn,
+        &mut security,
+        raw,
+        parsed,
+        passphrase,
+        sender.as_deref(),
+        0,
+    );
+    let (body_plain, body_html) = match content {
+        Some(content) => bodies_from_mime(&content),
+        None => (None, None),
+    };
+
+    debug!(
+        "S/MIME message processed: signature={}, decryption={}",
+        security.signature_status, security.decryption_status
+    );
+
+    Some(IncomingSecurity {
+        security,
+        body_plain,
+        body_html,
+    })
+}
+
+/// Verifies/decrypts one MIME entity. Returns the inner entity when it differs from
+/// the one passed in (opaque signed or enveloped data).
+fn process_entity(
+    conn: &Connection,
+    security: &mut MessageSecurity,
+    raw: &[u8],
+    parsed: &ParsedMessage,
+    passphrase: Option<&str>,
+    sender: Option<&str>,
+    depth: usize,
+) -> Option<Vec<u8>> {
+    match detect_kind(parsed)? {
+        SmimeKind::DetachedSigned => {
+            security.is_signed = true;
+            let signed_part = parsed
+                .root_part()
+                .content_type()
+                .and_then(|ct| ct.attribute("boundary"))
+                .and_then(|boundary| split_multipart_signed(raw, boundary));
+            let signature = parsed.attachments().find_map(|part| {
+                part.content_type()
+                    .filter(|ct| SMIME_SIGNATURE_TYPES.iter().any(|t| is_mime_type(ct, t)))
+                    .map(|_| part.contents())
+            });
+
+            match (signed_part, signature.map(Pkcs7::from_der)) {
+                (Some(signed_part), Some(Ok(pkcs7))) => {
+                    verify_pkcs7(conn, security, &pkcs7, Some(signed_part), sender);
+                }
+                _ => {
+                    security.signature_status = SIGNATURE_INVALID.to_string();
+                    security.details = Some("Malformed S/MIME signed message".to_string());
+                }
+            }
+
+            // The signed entity is already readable as-is
+            (depth > 0).then(|| raw.to_vec())
+        }
+        SmimeKind::OpaqueSigned => {
+            security.is_signed = true;
+            match Pkcs7::from_der(parsed.root_part().contents()) {
+                Ok(pkcs7) => verify_pkcs7(conn, security, &pkcs7, None, sender),
+                Err(e) => {
+                    security.signature_status = SIGNATURE_INVALID.to_string();
+                    security.details = Some(format!("Unreadable S/MIME signature: {}", e));
+                    None
+                }
+            }
+        }
+        SmimeKind::Enveloped => {
+            security.is_encrypted = true;
+            match Pkcs7::from_der(parsed.root_part().contents()) {
+                Ok(pkcs7) => open_enveloped(conn, security, &pkcs7, passphrase, sender, depth),
+                Err(e) => {
+                    security.decryption_status = DECRYPTION_FAILED.to_string();
+                    security.details = Some(format!("Unreadable S/MIME envelope: {}", e));
+                    None
+                }
+            }
+        }
+    }
+}
+
+/// Decrypts enveloped data and verifies a signed entity inside it
+fn open_enveloped(
+    conn: &Connection,
+    security: &mut MessageSecurity,
+    pkcs7: &Pkcs7,
+    passphrase: Option<&str>,
+    sender: Option<&str>,
+    depth: usize,
+) -> Option<Vec<u8>> {
+    let content = decrypt_pkcs7(conn, security, pkcs7, passphrase)?;
+
+    let nested = if depth < MAX_NESTING {
+        MessageParser::default()
+            .parse(&content)
+            .filter(|inner| detect_kind(inner).is_some())
+            .and_then(|inner| {
+                process_entity(
+                    conn,
+                    security,
+                    &content,
+                    &inner,
+                    passphrase,
+                    sender,
+                    depth + 1,
+                )
+            })
+    } else {
+        None
+    };
+
+    Some(nested.unwrap_or(content))
+}
+
+/// Decrypts a stored message when it is opened, using the password of the PKCS#12 identity.
+/// The enveloped data is read from the `smime.p7m` attachment saved during sync.
+pub fn decrypt_stored_message(
+    conn: &Connection,
+    message_id: i64,
+    passphrase: &str,
+) -> Result<IncomingSecurity, DEmailError> {
+    let from_header: String = conn.query_row(
+        "SELECT from_header FROM messages WHERE id = ?1",
+        [message_id],
+        |row| row.get(0),
+    )?;
+    let sender = parse_email_address(&from_header).map(|(email, _)| email.to_lowercase());
+
+    let mut stmt = conn.prepare(
+        "SELECT d.data FROM attachments a
+         INNER JOIN attachment_data d ON d.attachment_id = a.id
+         WHERE a.message_id = ?1",
+    )?;
+    let rows = stmt.query_map([message_id], |row| row.get::<_, Vec<u8>>(0))?;
+
+    let mut envelope = None;
+    for data in rows {
+        if let Ok(pkcs7) = Pkcs7::from_der(&data?) {
+            envelope = Some(pkcs7);
+            break;
+        }
+    }
+    let envelope = envelope.ok_or_else(|| {
+        DEmailError::NotFound(format!(
+            "No S/MIME enveloped data in message {}",
+            message_id
+        ))
+    })?;
+
+    let mut security = get_message_security(conn, message_id)?
+        .unwrap_or_else(|| MessageSecurity::new(PROTOCOL_SMIME));
+    security.message_id = message_id;
+    security.is_encrypted = true;
+
+    let content = open_enveloped(
+        conn,
+        &mut security,
+        &envelope,
+        Some(passphrase),
+        sender.as_deref(),
+        0,
+    );
+    save_message_security(conn, message_id, &security)?;
+
+    let content = content.ok_or_else(|| {
+        DEmailError::Crypto(
+            security
+                .details
+                .clone()
+                .unwrap_or_else(|| "Decryption failed".to_string()),
+        )
+    })?;
+
+    let (plain, html) = bodies_from_mime(&content);
+
+    Ok(IncomingSecurity {
+        security,
+        body_plain: plain,
+        body_html: html,
+    })
+}
+
+/// Verifies a PKCS#7 signature, first against the trust store and then without chain
+/// validation to tell an untrusted signer from a broken signature. Returns the signed
+/// content.
+fn verify_pkcs7(
+    conn: &Connection,
+    security: &mut MessageSecurity,
+    pkcs7: &Pkcs7,
+    content: Option<&[u8]>,
+    sender: Option<&str>,
+) -> Option<Vec<u8>> {
+    let (store, certificates) = match (trust_store(conn), known_certificates(conn, sender)) {
+        (Ok(store), Ok(certificates)) => (store, certificates),
+        (Err(e), _) | (_, Err(e)) => {
+            security.signature_status = SIGNATURE_INVALID.to_string();
+            security.details = Some(e.to_string());
+            return None;
+        }
+    };
+
+    let mut output = Vec::new();
+    let trusted = pkcs7
+        .verify(
+            &certificates,
+            &store,
+            content,
+            Some(&mut output),
+            Pkcs7Flags::empty(),
+        )
+        .is_ok();
+
+    if !trusted {
+        output.clear();
+        if let Err(e) = pkcs7.verify(
+            &certificates,
+            &store,
+            content,
+            Some(&mut output),
+            Pkcs7Flags::NOVERIFY,
+        ) {
+            security.signature_status = SIGNATURE_INVALID.to_string();
+            security.details = Some(format!("Signature verification failed: {}", e));
+            return None;
+        }
+    }
+
+    let mut warnings = Vec::new();
+    if trusted {
+        security.signature_status = SIGNATURE_VALID.to_string();
+    } else {
+        security.signature_status = SIGNATURE_UNTRUSTED.to_string();
+        warnings.push("Signer certificate is not issued by a trusted authority".to_string());
+    }
+
+    if let Ok(signers) = pkcs7.signers(&certificates, Pkcs7Flags::empty()) {
+        if let Some(certificate) = signers.iter().next() {
+            let email = certificate_email(certificate);
+            security.signer = Some(match (common_name(certificate), &email) {
+                (Some(name), Some(email)) => format!("{} <{}>", name, email),
+                (Some(name), None) => name,
+                (None, Some(email)) => email.clone(),
+                (None, None) => name_to_string(certificate.subject_name()),
+            });
+            security.signer_key_id = certificate_fingerprint(certificate).ok();
+
+            if let (Some(sender), Some(email)) = (sender, &email) {
+                if !sender.eq_ignore_ascii_case(email) {
+                    security.signature_status = SIGNATURE_SIGNER_MISMATCH.to_string();
+                    warnings.push(format!(
+                        "Signer certificate belongs to {}, not the sender",
+                        email
+                    ));
+                }
+            }
+
+            // Remember a verified signer's certificate so replies can be encrypted to them.
+            // An unverified one could be anyone's, so it must not replace a stored key.
+            if trusted {
+                if let Err(e) = store_certificate(conn, certificate, None, false) {
+                    warn!("Failed to store signer certificate: {}", e);
+                }
+            }
+        }
+    }
+
+    if !warnings.is_empty() {
+        security.details = Some(warnings.join("; "));
+    }
+    Some(output)
+}
+
+/// Tries every stored identity against the enveloped data
+fn decrypt_pkcs7(
+    conn: &Connection,
+    security: &mut MessageSecurity,
+    pkcs7: &Pkcs7,
+    passphrase: Option<&str>,
+) -> Option<Vec<u8>> {
+    let identities = load_all_pkcs12(conn).unwrap_or_default();
+    if identities.is_empty() {
+        security.decryption_status = DECRYPTION_NO_KEY.to_string();
+        security.details = Some("No S/MIME identity available".to_string());
+        return None;
+    }
+
+    let password = passphrase.unwrap_or_default();
+    let mut locked = false;
+    for pkcs12 in &identities {
+        let identity = match parse_identity(pkcs12, password) {
+            Ok(identity) => identity,
+            Err(_) => {
+                locked = true;
+                continue;
+            }
+        };
+
+        if let Ok(content) = pkcs7.decrypt(
+            &identity.private_key,
+            &identity.certificate,
+            Pkcs7Flags::empty(),
+        ) {
+            security.decryption_status = DECRYPTION_DECRYPTED.to_string();
+            return Some(content);
+        }
+    }
+
+    // During sync there is no password; protected identities are unlocked on open
+    if locked && passphrase.is_none() {
+        security.decryption_status = DECRYPTION_NEEDS_PASSPHRASE.to_string();
+        security.details = Some("S/MIME identity is password protected".to_string());
+    } else if locked {
+        security.decryption_status = DECRYPTION_FAILED.to_string();
+        security.details = Some("Wrong password for S/MIME identity".to_string());
+    } else {
+        security.decryption_status = DECRYPTION_NO_KEY.to_string();
+        security.details = Some("No S/MIME identity matches the message recipients".to_string());
+    }
+    None
+}
+
+// ============================================================================
+// OUTGOING MESSAGES
+// ============================================================================
+
+/// Signs (multipart/signed) and/or encrypts (application/pkcs7-mime) an outgoing plain
+/// text body. Signed-and-encrypted mail is signed first, then encrypted.
+pub fn protect_outgoing(
+    conn: &Connection,
+    from: &str,
+    recipients: &[String],
+    body: &str,
+    security: &OutgoingSecurity,
+) -> Result<ProtectedBody, DEmailError> {
+    if !security.sign && !security.encrypt {
+        return Err(DEmailError::Crypto(
+            "Neither signing nor encryption was requested".to_string(),
+        ));
+    }
+
+    let inner = SinglePart::plain(body.to_string());
+
+    let signed = if security.sign {
+        let password = security.passphrase.clone().unwrap_or_default();
+        let identity = load_identity_for(conn, from, &password)?;
+
+        let mut chain = Stack::new().map_err(ssl_error)?;
+        for certificate in &identity.chain {
+            chain.push(certificate.clone()).map_err(ssl_error)?;
+        }
+
+        let signature = Pkcs7::sign(
+            &identity.certificate,
+            &identity.private_key,
+            &chain,
+            &signed_content(&inner),
+            Pkcs7Flags::DETACHED | Pkcs7Flags::BINARY,
+        )
+        .map_err(ssl_error)?
+        .to_der()
+        .map_err(ssl_error)?;
+
+        Some(
+            MultiPart::signed(SMIME_SIGNATURE_TYPES[0].to_string(), "sha-256".to_string())
+                .singlepart(inner.clone())
+                .singlepart(
+                    SinglePart::builder()
+                        .header(content_type(
+                            "application/pkcs7-signature; name=\"smime.p7s\"",
+                        )?)
+                        .header(ContentDisposition::attachment("smime.p7s"))
+                        .body(signature),
+                ),
+        )
+    } else {
+        None
+    };
+
+    if !security.encrypt {
+        return signed
+            .map(ProtectedBody::MultiPart)
+            .ok_or_else(|| DEmailError::Crypto("Signing did not produce a signature".to_string()));
+    }
+
+    let content = match &signed {
+        Some(multipart) => multipart.formatted(),
+        None => inner.formatted(),
+    };
+
+    // Always encrypt to ourselves too so the sent copy stays readable
+    let mut certificates = Stack::new().map_err(ssl_error)?;
+    for address in recipients
+        .iter()
+        .map(String::as_str)
+        .chain(std::iter::once(from))
+    {
+        let certificate = load_certificate_for(conn, address)?
+            .ok_or_else(|| DEmailError::Crypto(format!("No S/MIME certificate for {}", address)))?;
+        certificates.push(certificate).map_err(ssl_error)?;
+    }
+
+    let enveloped = Pkcs7::encrypt(
+        &certificates,
+        &content,
+        Cipher::aes_256_cbc(),
+        Pkcs7Flags::BINARY,
+    )
+    .map_err(ssl_error)?
+    .to_der()
+    .map_err(ssl_error)?;
+
+    Ok(ProtectedBody::SinglePart(
+        SinglePart::builder()
+            .header(content_type(
+                "application/pkcs7-mime; smime-type=enveloped-data; name=\"smime.p7m\"",
+            )?)
+            .header(ContentDisposition::attachment("smime.p7m"))
+            .body(enveloped),
+    ))
+}
+
+// ============================================================================
+// HELPERS
+// ============================================================================
+
+fn detect_kind(parsed: &ParsedMessage) -> Option<SmimeKind> {
+    let ct = parsed.root_part().content_type()?;
+
+    if ct.ctype().eq_ignore_ascii_case("multipart") {
+        let is_signed = ct
+            .subtype()
+            .is_some_and(|sub| sub.eq_ignore_ascii_case("signed"));
+        let protocol = ct.attribute("protocol").unwrap_or_default();
+        return (is_signed
+            && SMIME_SIGNATURE_TYPES
+                .iter()
+                .any(|t| protocol.eq_ignore_ascii_case(t)))
+        .then_some(SmimeKind::DetachedSigned);
+    }
+
+    if SMIME_MIME_TYPES.iter().any(|t| is_mime_type(ct, t)) {
+        return match ct.attribute("smime-type") {
+            Some(smime_type) if smime_type.eq_ignore_ascii_case("signed-data") => {
+                Some(SmimeKind::OpaqueSigned)
+            }
+            _ => Some(SmimeKind::Enveloped),
+        };
+    }
+
+    None
+}
+
+fn certificate_fingerprint(certificate: &X509Ref) -> Result<String, DEmailError> {
+    let digest = certificate
+        .digest(MessageDigest::sha256())
+        .map_err(ssl_error)?;
+    Ok(hex::encode_upper(digest))
+}
+
+/// Email address from the subjectAltName extension, falling back to the subject's
+/// emailAddress attribute
+fn certificate_email(certificate: &X509Ref) -> Option<String> {
+    certificate
+        .subject_alt_names()
+        .and_then(|names| {
+            names
+                .iter()
+                .find_map(|name| name.email().map(|email| email.to_lowercase()))
+        })
+        .or_else(|| {
+            certificate
+                .subject_name()
+                .entries_by_nid(Nid::PKCS9_EMAILADDRESS)
+                .next()
+                .and_then(|entry| entry.data().to_string().ok())
+                .map(|email| email.to_lowercase())
+        })
+}
+
+fn common_name(certificate: &X509Ref) -> Option<String> {
+    certificate
+        .subject_name()
+        .entries_by_nid(Nid::COMMONNAME)
+        .next()
+        .and_then(|entry| entry.data().to_string().ok())
+}
+
+fn name_to_string(name: &X509NameRef) -> String {
+    name.entries()
+        .map(|entry| {
+            format!(
+                "{}={}",
+                entry.object().nid().short_name().unwrap_or("?"),
+                entry.data().to_string().unwrap_or_default()
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn not_after_timestamp(certificate: &X509Ref) -> Result<i64, DEmailError> {
+    let epoch = Asn1Time::from_unix(0).map_err(ssl_error)?;
+    let diff = epoch.diff(certificate.not_after()).map_err(ssl_error)?;
+    Ok(diff.days as i64 * 86_400 + diff.secs as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::cache::schema::initialize_schema;
+
+    const CA_PEM: &[u8] = include_bytes!("../../../../tests/fixtures/smime/ca.pem");
+    const ALICE_P12: &[u8] = include_bytes!("../../../../tests/fixtures/smime/alice.p12");
+    const BOB_P12: &[u8] = include_bytes!("../../../../tests/fixtures/smime/bob.p12");
+    const BOB_PEM: &[u8] = include_bytes!("../../../../tests/fixtures/smime/bob.pem");
+    const SIGNED: &[u8] = include_bytes!("../../../../tests/fixtures/smime/smime_signed.eml");
+    const ENCRYPTED: &[u8] = include_bytes!("../../../../tests/fixtures/smime/smime_encrypted.eml");
+    const SIGNED_ENCRYPTED: &[u8] =
+        include_bytes!("../../../../tests/fixtures/smime/smime_signed_encrypted.eml");
+
+    fn store() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        initialize_schema(&conn).unwrap();
+        conn.execute(
+            "INSERT INTO settings (key, value) VALUES (?1, 'false')",
+            [SETTING_USE_SYSTEM_ROOTS],
+        )
+        .unwrap();
+        conn
+    }
+
+    fn process(conn: &Connection, raw: &[u8]) -> IncomingSecurity {
+        let parsed = MessageParser::default().parse(raw).unwrap();
+        process_incoming(conn, raw, &parsed, None).unwrap()
+    }
+
+    #[test]
+    fn test_detect_kind() {
+        let parse = |raw| detect_kind(&MessageParser::default().parse(raw).unwrap());
+        assert_eq!(parse(SIGNED), Some(SmimeKind::DetachedSigned));
+        assert_eq!(parse(ENCRYPTED), Some(SmimeKind::Enveloped));
+        assert_eq!(parse(SIGNED_ENCRYPTED), Some(SmimeKind::Enveloped));
+        assert_eq!(
+            parse(b"From: a@example.com\r\nSubject: hi\r\n\r\nplain text\r\n"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_import_and_list_certificates() {
+        let conn = store();
+        assert!(import_identity(&conn, ALICE_P12, "wrong password").is_err());
+
+        let alice = import_identity(&conn, ALICE_P12, "").unwrap();
+        assert_eq!(alice.email.as_deref(), Some("alice@example.com"));
+        assert!(alice.has_private_key);
+        assert!(alice.not_after > chrono::Utc::now().timestamp());
+
+        let ca = import_certificate(&conn, CA_PEM, true).unwrap();
+        assert!(ca.is_trust_anchor);
+        assert!(!ca.has_private_key);
+
+        // Re-importing a public certificate keeps the stored identity
+        let bob = import_identity(&conn, BOB_P12, "").unwrap();
+        let bob_again = import_certificate(&conn, BOB_PEM, false).unwrap();
+        assert_eq!(bob.fingerprint, bob_again.fingerprint);
+        assert!(bob_again.has_private_key);
+
+        assert_eq!(list_certificates(&conn).unwrap().len(), 3);
+        delete_certificate(&conn, &ca.fingerprint).unwrap();
+        assert_eq!(list_certificates(&conn).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_verify_signed_message() {
+        let conn = store();
+
+        let untrusted = process(&conn, SIGNED);
+        assert!(untrusted.security.is_signed);
+        assert_eq!(untrusted.security.signature_status, SIGNATURE_UNTRUSTED);
+        assert_eq!(
+            untrusted.security.signer.as_deref(),
+            Some("Alice Example <alice@example.com>")
+        );
+        // An unverified signer's certificate is not kept for encryption
+        assert!(load_certificate_for(&conn, "alice@example.com")
+            .unwrap()
+            .is_none());
+
+        import_certificate(&conn, CA_PEM, true).unwrap();
+        let trusted = process(&conn, SIGNED);
+        assert_eq!(trusted.security.signature_status, SIGNATURE_VALID);
+        assert!(trusted.security.details.is_none());
+        // A verified signer's certificate is collected for later encryption
+        assert!(load_certificate_for(&conn, "alice@example.com")
+            .unwrap()
+            .is_some());
+
+        // Certificates stored without verification are never encrypted to
+        conn.execute("UPDATE smime_certificates SET is_trusted = 0", [])
+            .unwrap();
+        assert!(load_certificate_for(&conn, "alice@example.com")
+            .unwrap()
+            .is_none());
+
+        // A signer who is not the sender keeps both warnings
+        let spoofed =
+            String::from_utf8_lossy(SIGNED).replacen("alice@example.com", "mallory@example.com", 1);
+        let mismatched = process(&store(), spoofed.as_bytes());
+        assert_eq!(
+            mismatched.security.signature_status,
+            SIGNATURE_SIGNER_MISMATCH
+        );
+        let details = mismatched.security.details.unwrap_or_default();
+        assert!(details.contains("not issued by a trusted authority"));
+        assert!(details.contains("not the sender"));
+
+        // A trusted chain does not make a signer mismatch valid
+        let trusting = store();
+        import_certificate(&trusting, CA_PEM, true).unwrap();
+        let mismatched = process(&trusting, spoofed.as_bytes());
+        assert_eq!(
+            mismatched.security.signature_status,
+            SIGNATURE_SIGNER_MISMATCH
+        );
+        assert!(mismatched
+            .security
+            .details
+            .unwrap_or_default()
+            .contains("not the sender"));
+
+        let tampered = String::from_utf8_lossy(SIGNED).replace("is signed", "is forged");
+        let forged = process(&conn, tampered.as_bytes());
+        assert_eq!(forged.security.signature_status, SIGNATURE_INVALID);
+    }
+
+    #[test]
+    fn test_decrypt_enveloped_message() {
+        let conn = store();
+
+        let locked = process(&conn, ENCRYPTED);
+        assert!(locked.security.is_encrypted);
+        assert_eq!(locked.security.decryption_status, DECRYPTION_NO_KEY);
+
+        import_identity(&conn, BOB_P12, "").unwrap();
+        import_certificate(&conn, CA_PEM, true).unwrap();
+
+        let decrypted = process(&conn, ENCRYPTED);
+        assert_eq!(decrypted.security.decryption_status, DECRYPTION_DECRYPTED);
+        assert!(decrypted
+            .body_plain
+            .unwrap()
+            .contains("this S/MIME message is secret"));
+
+        let nested = process(&conn, SIGNED_ENCRYPTED);
+        assert_eq!(nested.security.decryption_status, DECRYPTION_DECRYPTED);
+        assert!(nested.security.is_signed);
+        assert_eq!(nested.security.signature_status, SIGNATURE_VALID);
+        assert!(nested
+            .body_plain
+            .unwrap()
+            .contains("this S/MIME message is signed"));
+    }
+
+    #[test]
+    fn test_protect_outgoing_roundtrip() {
+        let conn = store();
+        import_identity(&conn, ALICE_P12, "").unwrap();
+        import_identity(&conn, BOB_P12, "").unwrap();
+        import_certificate(&conn, CA_PEM, true).unwrap();
+
+        let security = OutgoingSecurity {
+            protocol: PROTOCOL_SMIME.to_string(),
+            sign: true,
+            encrypt: true,
+            passphrase: None,
+        };
+        let protected = protect_outgoing(
+            &conn,
+            "alice@example.com",
+            &["bob@example.com".to_string()],
+            "Hello Bob, signed and sealed",
+            &security,
+        )
+        .unwrap();
+
+        let builder = lettre::Message::builder()
+            .from("alice@example.com".parse().unwrap())
+            .to("bob@example.com".parse().unwrap())
+            .subject("Roundtrip");
+        let raw = protected.build(builder).unwrap().formatted();
+
+        let incoming = process(&conn, &raw);
+        assert_eq!(incoming.security.decryption_status, DECRYPTION_DECRYPTED);
+        assert_eq!(incoming.security.signature_status, SIGNATURE_VALID);
+        assert!(incoming
+            .body_plain
+            .unwrap()
+            .contains("Hello Bob, signed and sealed"));
+
+        let missing = protect_outgoing(
+            &conn,
+            "alice@example.com",
+            &["carol@example.com".to_string()],
+            "Hello Carol",
+            &security,
+        );
+        assert!(missing.is_err());
+    }
+}
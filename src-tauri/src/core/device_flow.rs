@@ -0,0 +1,390 @@
+// Device authorization grant - Sign-in on machines where a browser redirect to the
+// app is impractical: the user enters a short code on any other device (RFC 8628)
+
+use crate::core::providers::ProviderDefinition;
+use crate::error::DEmailError;
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::time::Duration;
+use tracing::debug;
+
+const DEVICE_CODE_GRANT: &str = "urn:ietf:params:oauth:grant-type:device_code";
+/// Polling interval when the provider does not specify one (RFC 8628 section 3.2)
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(5);
+/// Added to the interval for every `slow_down` answer (RFC 8628 section 3.5)
+const SLOW_DOWN_INCREMENT: Duration = Duration::from_secs(5);
+
+/// Device authorization response; `device_code` stays in the backend
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeviceAuthorization {
+    #[serde(skip_serializing)]
+    pub device_code: String,
+    pub user_code: String,
+    /// Google still sends the pre-RFC `verification_url`
+    #[serde(alias = "verification_url")]
+    pub verification_uri: String,
+    #[serde(default)]
+    pub verification_uri_complete: Option<String>,
+    pub expires_in: u64,
+    #[serde(default, skip_serializing)]
+    pub interval: Option<u64>,
+}
+
+/// Tokens issued once the user has approved the device
+#[derive(Debug, Deserialize)]
+pub struct DeviceTokens {
+    pub access_token: String,
+    #[serde(default)]
+    pub refresh_token: Option<String>,
+    /// OpenID Connect ID token, when `openid` was among the scopes
+    #[serde(default)]
+    pub id_token: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ErrorResponse {
+    error: String,
+    #[serde(default)]
+    error_description: Option<String>,
+}
+
+/// Asks the provider for a device code and the user code to show
+pub async fn request_device_authorization(
+    provider: &ProviderDefinition,
+    client_id: &str,
+    client_secret: &str,
+) -> Result<DeviceAuthorization, DEmailError> {
+    let device_auth_url = provider.device_auth_url.as_deref().ok_or_else(|| {
+        DEmailError::OAuth(format!(
+            "{} does not support signing in with a device code",
+            provider.name
+        ))
+    })?;
+
+    let scope = provider.scopes.join(" ");
+    let mut form = vec![("client_id", client_id), ("scope", scope.as_str())];
+    if !client_secret.is_empty() {
+        form.push(("client_secret", client_secret));
+    }
+
+    let response = reqwest::Client::new()
+        .post(device_auth_url)
+        .form(&form)
+        .send()
+        .await
+        .map_err(|e| DEmailError::OAuth(e.to_string()))?;
+    if !response.status().is_success() {
+        let status = response.status();
+        let error = response.json::<ErrorResponse>().await.ok();
+        return Err(DEmailError::OAuth(match error {
+            Some(error) => describe_error(&error),
+            None => format!("Device authorization failed with status {}", status),
+        }));
+    }
+    response
+        .json()
+        .await
+        .map_err(|e| DEmailError::OAuth(e.to_string()))
+}
+
+/// Polls the token endpoint until the user approves or denies the sign-in, or the
+/// device code expires
+pub async fn poll_for_token(
+    provider: &ProviderDefinition,
+    client_id: &str,
+    client_secret: &str,
+    authorization: &DeviceAuthorization,
+) -> Result<DeviceTokens, DEmailError> {
+    poll_with(
+        &provider.token_url,
+        client_id,
+        client_secret,
+        authorization,
+        tokio::time::sleep,
+    )
+    .await
+}
+
+async fn poll_with<S, F>(
+    token_url: &str,
+    client_id: &str,
+    client_secret: &str,
+    authorization: &DeviceAuthorization,
+    mut sleep: S,
+) -> Result<DeviceTokens, DEmailError>
+where
+    S: FnMut(Duration) -> F,
+    F: Future<Output = ()>,
+{
+    let http = reqwest::Client::new();
+    let mut form = vec![
+        ("grant_type", DEVICE_CODE_GRANT),
+        ("device_code", authorization.device_code.as_str()),
+        ("client_id", client_id),
+    ];
+    if !client_secret.is_empty() {
+        form.push(("client_secret", client_secret));
+    }
+
+    let lifetime = Duration::from_secs(authorization.expires_in);
+    let mut interval = authorization
+        .interval
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_INTERVAL);
+    let mut waited = Duration::ZERO;
+
+    loop {
+        sleep(interval).await;
+        waited += interval;
+        if waited > lifetime {
+            return Err(expired());
+        }
+
+        let response = match http.post(token_url).form(&form).send().await {
+            Ok(response) => response,
+            Err(e) => {
+                // Back off on connection problems as well (RFC 8628 section 3.5)
+                debug!("Device token request failed: {}", e);
+                interval += SLOW_DOWN_INCREMENT;
+                continue;
+            }
+        };
+        if response.status().is_success() {
+            return response
+                .json()
+                .await
+                .map_err(|e| DEmailError::OAuth(e.to_string()));
+        }
+
+        let error: ErrorResponse = response
+            .json()
+            .await
+            .map_err(|e| DEmailError::OAuth(e.to_string()))?;
+        match error.error.as_str() {
+            "authorization_pending" => {}
+            // The increase applies to all later requests, not just the next one
+            "slow_down" => interval += SLOW_DOWN_INCREMENT,
+            "access_denied" => {
+                return Err(DEmailError::OAuth(
+                    "Authorization was not granted: access_denied".to_string(),
+                ))
+            }
+            "expired_token" => return Err(expired()),
+            _ => return Err(DEmailError::OAuth(describe_error(&error))),
+        }
+    }
+}
+
+fn expired() -> DEmailError {
+    DEmailError::OAuth("The device code expired before the sign-in was completed".to_string())
+}
+
+fn describe_error(error: &ErrorResponse) -> String {
+    match &error.error_description {
+        Some(description) => format!("{}: {}", error.error, description),
+        None => error.error.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Token endpoint answering with the given statuses and bodies in order and
+    /// recording the request bodies it received
+    async fn mock_endpoint(
+        responses: Vec<(u16, &'static str)>,
+    ) -> (String, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let url = format!("http://{}/token", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let recorded = requests.clone();
+
+        tokio::spawn(async move {
+            for (status, body) in responses {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let request = read_request(&mut stream).await;
+                recorded.lock().unwrap().push(request);
+                let response = format!(
+                    "HTTP/1.1 {} X\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                );
+                stream.write_all(response.as_bytes()).await.unwrap();
+                stream.shutdown().await.unwrap();
+            }
+        });
+        (url, requests)
+    }
+
+    /// Reads one HTTP request and returns its body
+    async fn read_request(stream: &mut tokio::net::TcpStream) -> String {
+        let mut buffer = Vec::new();
+        let mut chunk = [0u8; 1024];
+        loop {
+            let read = stream.read(&mut chunk).await.unwrap();
+            buffer.extend_from_slice(&chunk[..read]);
+            let text = String::from_utf8_lossy(&buffer).to_string();
+            if let Some((headers, body)) = text.split_once("\r\n\r\n") {
+                let length = headers
+                    .lines()
+                    .find_map(|line| {
+                        let (name, value) = line.split_once(':')?;
+                        name.eq_ignore_ascii_case("content-length")
+                            .then(|| value.trim().parse::<usize>().ok())?
+                    })
+                    .unwrap_or(0);
+                if body.len() >= length {
+                    return body.to_string();
+                }
+            }
+            if read == 0 {
+                return String::new();
+            }
+        }
+    }
+
+    fn authorization(expires_in: u64, interval: Option<u64>) -> DeviceAuthorization {
+        DeviceAuthorization {
+            device_code: "device-123".to_string(),
+            user_code: "ABCD-EFGH".to_string(),
+            verification_uri: "https://example.org/device".to_string(),
+            verification_uri_complete: None,
+            expires_in,
+            interval,
+        }
+    }
+
+    fn recording_sleep(
+        sleeps: &Arc<Mutex<Vec<u64>>>,
+    ) -> impl FnMut(Duration) -> std::future::Ready<()> {
+        let sleeps = sleeps.clone();
+        move |duration| {
+            sleeps.lock().unwrap().push(duration.as_secs());
+            std::future::ready(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_request_device_authorization() {
+        let (url, requests) = mock_endpoint(vec![(
+            200,
+            r#"{"device_code":"dev","user_code":"WXYZ-1234","verification_url":"https://www.google.com/device","expires_in":1800,"interval":5}"#,
+        )])
+        .await;
+        let mut provider = crate::core::providers::builtin_providers().remove(1);
+        provider.device_auth_url = Some(url);
+
+        let authorization = request_device_authorization(&provider, "client", "")
+            .await
+            .unwrap();
+        assert_eq!(authorization.user_code, "WXYZ-1234");
+        assert_eq!(
+            authorization.verification_uri,
+            "https://www.google.com/device"
+        );
+        assert_eq!(authorization.interval, Some(5));
+
+        let body = requests.lock().unwrap()[0].clone();
+        assert!(body.contains("client_id=client"));
+        assert!(body.contains("offline_access"));
+        assert!(!body.contains("client_secret"));
+
+        // The device code is never handed to the frontend
+        let json = serde_json::to_string(&authorization).unwrap();
+        assert!(!json.contains("device_code"));
+
+        provider.device_auth_url = None;
+        assert!(request_device_authorization(&provider, "client", "")
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_poll_handles_pending_and_slow_down() {
+        let (url, requests) = mock_endpoint(vec![
+            (400, r#"{"error":"authorization_pending"}"#),
+            (400, r#"{"error":"slow_down"}"#),
+            (400, r#"{"error":"authorization_pending"}"#),
+            (
+                200,
+                r#"{"access_token":"at","refresh_token":"rt","token_type":"Bearer","expires_in":3600}"#,
+            ),
+        ])
+        .await;
+        let sleeps = Arc::new(Mutex::new(Vec::new()));
+
+        let tokens = poll_with(
+            &url,
+            "client",
+            "secret",
+            &authorization(600, Some(3)),
+            recording_sleep(&sleeps),
+        )
+        .await
+        .unwrap();
+        assert_eq!(tokens.access_token, "at");
+        assert_eq!(tokens.refresh_token.as_deref(), Some("rt"));
+
+        // slow_down raises the interval for every later request
+        assert_eq!(*sleeps.lock().unwrap(), vec![3, 3, 8, 8]);
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 4);
+        assert!(requests[0]
+            .contains("grant_type=urn%3Aietf%3Aparams%3Aoauth%3Agrant-type%3Adevice_code"));
+        assert!(requests[0].contains("device_code=device-123"));
+        assert!(requests[0].contains("client_secret=secret"));
+    }
+
+    #[tokio::test]
+    async fn test_poll_stops_on_denial_and_expiry() {
+        let (url, _) = mock_endpoint(vec![(400, r#"{"error":"access_denied"}"#)]).await;
+        let sleeps = Arc::new(Mutex::new(Vec::new()));
+        let error = poll_with(
+            &url,
+            "client",
+            "",
+            &authorization(600, None),
+            recording_sleep(&sleeps),
+        )
+        .await
+        .unwrap_err();
+        assert!(error.to_string().contains("access_denied"));
+        assert_eq!(*sleeps.lock().unwrap(), vec![5]);
+
+        // Stops polling once the device code's lifetime has passed
+        let (url, requests) = mock_endpoint(vec![
+            (400, r#"{"error":"authorization_pending"}"#),
+            (400, r#"{"error":"authorization_pending"}"#),
+        ])
+        .await;
+        let sleeps = Arc::new(Mutex::new(Vec::new()));
+        let error = poll_with(
+            &url,
+            "client",
+            "",
+            &authorization(12, None),
+            recording_sleep(&sleeps),
+        )
+        .await
+        .unwrap_err();
+        assert!(error.to_string().contains("expired"));
+        assert_eq!(requests.lock().unwrap().len(), 2);
+
+        let (url, _) = mock_endpoint(vec![(400, r#"{"error":"expired_token"}"#)]).await;
+        assert!(poll_with(
+            &url,
+            "client",
+            "",
+            &authorization(600, None),
+            recording_sleep(&sleeps)
+        )
+        .await
+        .is_err());
+    }
+}
@@ -0,0 +1,432 @@
+// DNS module - Minimal MX lookups over UDP (TCP for truncated answers) for provider
+// detection
+
+use crate::error::DEmailError;
+use std::net::IpAddr;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::process::Command;
+use tracing::debug;
+
+const QUERY_TIMEOUT: Duration = Duration::from_secs(3);
+const TYPE_MX: u16 = 15;
+const CLASS_IN: u16 = 1;
+/// Header flag set when the answer did not fit into a UDP datagram
+const FLAG_TRUNCATED: u16 = 0x0200;
+
+/// Returns the MX hosts of `domain`, most preferred first. Only the system's configured
+/// nameservers are asked, so lookups never leave for a resolver the user did not choose.
+pub async fn lookup_mx(domain: &str) -> Result<Vec<String>, DEmailError> {
+    let id = rand::random::<u16>();
+    let query = build_query(id, domain, TYPE_MX)?;
+
+    let mut last_error = DEmailError::Api(format!(
+        "No system nameserver is configured to look up {}",
+        domain
+    ));
+    for nameserver in nameservers().await {
+        match query_nameserver(&nameserver, &query).await {
+            Ok(response) => match parse_mx_response(id, domain, &response) {
+                Ok(hosts) => return Ok(hosts),
+                Err(e) => last_error = e,
+            },
+            Err(e) => {
+                debug!("MX query to {} failed: {}", nameserver, e);
+                last_error = e;
+            }
+        }
+    }
+    Err(last_error)
+}
+
+/// Asks over UDP and repeats the query over TCP when the answer came back truncated
+async fn query_nameserver(nameserver: &str, query: &[u8]) -> Result<Vec<u8>, DEmailError> {
+    let response = query_udp(nameserver, query).await?;
+    if !is_truncated(&response) {
+        return Ok(response);
+    }
+    debug!("Truncated answer from {}, retrying over TCP", nameserver);
+    query_tcp(nameserver, query).await
+}
+
+fn socket_address(nameserver: &str) -> String {
+    if nameserver.contains(':') {
+        format!("[{}]:53", nameserver)
+    } else {
+        format!("{}:53", nameserver)
+    }
+}
+
+async fn query_udp(nameserver: &str, query: &[u8]) -> Result<Vec<u8>, DEmailError> {
+    let bind_address = if nameserver.contains(':') {
+        "[::]:0"
+    } else {
+        "0.0.0.0:0"
+    };
+    let socket = UdpSocket::bind(bind_address).await?;
+    socket.connect(socket_address(nameserver)).await?;
+    socket.send(query).await?;
+
+    let mut buffer = vec![0u8; 4096];
+    let read = tokio::time::timeout(QUERY_TIMEOUT, socket.recv(&mut buffer))
+        .await
+        .map_err(|_| DEmailError::Api(format!("DNS query to {} timed out", nameserver)))??;
+    buffer.truncate(read);
+    Ok(buffer)
+}
+
+/// DNS over TCP (RFC 1035 4.2.2): every message is preceded by its length
+async fn query_tcp(nameserver: &str, query: &[u8]) -> Result<Vec<u8>, DEmailError> {
+    let exchange = async {
+        let mut stream = TcpStream::connect(socket_address(nameserver)).await?;
+        let mut message = Vec::with_capacity(query.len() + 2);
+        message.extend_from_slice(&(query.len() as u16).to_be_bytes());
+        message.extend_from_slice(query);
+        stream.write_all(&message).await?;
+
+        let length = stream.read_u16().await? as usize;
+        let mut response = vec![0u8; length];
+        stream.read_exact(&mut response).await?;
+        Ok::<_, std::io::Error>(response)
+    };
+    Ok(tokio::time::timeout(QUERY_TIMEOUT, exchange)
+        .await
+        .map_err(|_| DEmailError::Api(format!("DNS query to {} timed out", nameserver)))??)
+}
+
+fn is_truncated(response: &[u8]) -> bool {
+    response
+        .get(2..4)
+        .is_some_and(|flags| u16::from_be_bytes([flags[0], flags[1]]) & FLAG_TRUNCATED != 0)
+}
+
+/// Nameservers from `/etc/resolv.conf`. Where it is missing or lists none, the
+/// platform's own configuration is asked instead: `scutil --dns` on macOS and
+/// `Get-DnsClientServerAddress` on Windows. An empty list leaves provider detection to
+/// the address domain alone.
+async fn nameservers() -> Vec<String> {
+    let resolv_conf = std::fs::read_to_string("/etc/resolv.conf").ok();
+    let configured = resolv_conf
+        .as_deref()
+        .map(parse_resolv_conf)
+        .unwrap_or_default();
+    if !configured.is_empty() {
+        return configured;
+    }
+    platform_nameservers().await
+}
+
+async fn platform_nameservers() -> Vec<String> {
+    if cfg!(target_os = "macos") {
+        command_output("scutil", &["--dns"])
+            .await
+            .map(|output| parse_scutil_dns(&output))
+            .unwrap_or_default()
+    } else if cfg!(windows) {
+        command_output(
+            "powershell",
+            &[
+                "-NoProfile",
+                "-NonInteractive",
+                "-Command",
+                "Get-DnsClientServerAddress | ForEach-Object { $_.ServerAddresses }",
+            ],
+        )
+        .await
+        .map(|output| parse_address_lines(&output))
+        .unwrap_or_default()
+    } else {
+        Vec::new()
+    }
+}
+
+async fn command_output(program: &str, args: &[&str]) -> Option<String> {
+    let mut command = Command::new(program);
+    command.args(args).kill_on_drop(true);
+    // Keep a console window from flashing up over the app
+    #[cfg(windows)]
+    command.creation_flags(0x0800_0000);
+
+    match tokio::time::timeout(QUERY_TIMEOUT, command.output()).await {
+        Ok(Ok(output)) if output.status.success() => {
+            Some(String::from_utf8_lossy(&output.stdout).into_owned())
+        }
+        Ok(Ok(output)) => {
+            debug!("{} exited with {}", program, output.status);
+            None
+        }
+        Ok(Err(e)) => {
+            debug!("Failed to run {}: {}", program, e);
+            None
+        }
+        Err(_) => {
+            debug!("{} timed out", program);
+            None
+        }
+    }
+}
+
+fn parse_resolv_conf(content: &str) -> Vec<String> {
+    content
+        .lines()
+        .filter_map(|line| {
+            let mut parts = line.split_whitespace();
+            match (parts.next(), parts.next()) {
+                // Drop IPv6 zone ids such as fe80::1%eth0
+                (Some("nameserver"), Some(address)) => {
+                    Some(address.split('%').next().unwrap_or(address).to_string())
+                }
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+/// Parses the `nameserver[n] : address` lines of `scutil --dns`, which repeats the same
+/// servers for each resolver and scope
+fn parse_scutil_dns(output: &str) -> Vec<String> {
+    let addresses = output.lines().filter_map(|line| {
+        let (key, value) = line.trim().split_once(':')?;
+        key.trim_end()
+            .starts_with("nameserver[")
+            .then(|| value.trim())
+    });
+    valid_addresses(addresses)
+}
+
+/// Parses one address per line, as printed for `ServerAddresses` by PowerShell
+fn parse_address_lines(output: &str) -> Vec<String> {
+    valid_addresses(output.lines().map(str::trim))
+}
+
+fn valid_addresses<'a>(addresses: impl Iterator<Item = &'a str>) -> Vec<String> {
+    let mut nameservers: Vec<String> = Vec::new();
+    for address in addresses {
+        let address = address.split('%').next().unwrap_or(address);
+        if address.parse::<IpAddr>().is_ok() && !nameservers.iter().any(|known| known == address) {
+            nameservers.push(address.to_string());
+        }
+    }
+    nameservers
+}
+
+fn build_query(id: u16, domain: &str, record_type: u16) -> Result<Vec<u8>, DEmailError> {
+    let mut query = Vec::with_capacity(domain.len() + 18);
+    query.extend_from_slice(&id.to_be_bytes());
+    // Recursion desired, one question
+    query.extend_from_slice(&[0x01, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0]);
+    for label in domain.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(DEmailError::Validation(format!(
+                "Invalid domain name: {}",
+                domain
+            )));
+        }
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    query.extend_from_slice(&record_type.to_be_bytes());
+    query.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(query)
+}
+
+/// Parses the answer to an MX query for `domain`. The id and the echoed question must
+/// match the query, so a stray or spoofed datagram for another name is rejected.
+fn parse_mx_response(id: u16, domain: &str, response: &[u8]) -> Result<Vec<String>, DEmailError> {
+    let malformed = || DEmailError::Api("Malformed DNS response".to_string());
+    let read_u16 = |offset: usize| -> Result<u16, DEmailError> {
+        response
+            .get(offset..offset + 2)
+            .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+            .ok_or_else(malformed)
+    };
+
+    if read_u16(0)? != id {
+        return Err(DEmailError::Api("DNS response id mismatch".to_string()));
+    }
+    let flags = read_u16(2)?;
+    match flags & 0x000f {
+        0 => {}
+        // NXDOMAIN: the domain has no records at all
+        3 => return Ok(Vec::new()),
+        rcode => {
+            return Err(DEmailError::Api(format!(
+                "DNS query failed (rcode {})",
+                rcode
+            )))
+        }
+    }
+    if read_u16(4)? != 1 {
+        return Err(DEmailError::Api(
+            "DNS response question mismatch".to_string(),
+        ));
+    }
+    let answers = read_u16(6)?;
+
+    let (question, offset) = read_name(response, 12)?;
+    if !question.eq_ignore_ascii_case(domain.trim_end_matches('.'))
+        || read_u16(offset)? != TYPE_MX
+        || read_u16(offset + 2)? != CLASS_IN
+    {
+        return Err(DEmailError::Api(
+            "DNS response question mismatch".to_string(),
+        ));
+    }
+    let mut offset = offset + 4;
+
+    let mut hosts = Vec::new();
+    for _ in 0..answers {
+        offset = read_name(response, offset)?.1;
+        let record_type = read_u16(offset)?;
+        let data_len = read_u16(offset + 8)? as usize;
+        let data_start = offset + 10;
+        if data_start + data_len > response.len() {
+            return Err(malformed());
+        }
+        if record_type == TYPE_MX {
+            let preference = read_u16(data_start)?;
+            let (exchange, _) = read_name(response, data_start + 2)?;
+            hosts.push((preference, exchange));
+        }
+        offset = data_start + data_len;
+    }
+
+    hosts.sort();
+    Ok(hosts.into_iter().map(|(_, host)| host).collect())
+}
+
+/// Reads a possibly compressed domain name; returns it with the offset after it
+fn read_name(message: &[u8], mut offset: usize) -> Result<(String, usize), DEmailError> {
+    let malformed = || DEmailError::Api("Malformed DNS name".to_string());
+    let mut labels = Vec::new();
+    let mut end = None;
+    let mut jumps = 0;
+
+    loop {
+        let len = *message.get(offset).ok_or_else(malformed)? as usize;
+        if len == 0 {
+            end.get_or_insert(offset + 1);
+            break;
+        }
+        if len & 0xc0 == 0xc0 {
+            let pointer =
+                ((len & 0x3f) << 8) | *message.get(offset + 1).ok_or_else(malformed)? as usize;
+            end.get_or_insert(offset + 2);
+            jumps += 1;
+            if jumps > 16 {
+                return Err(malformed());
+            }
+            offset = pointer;
+            continue;
+        }
+        let label = message
+            .get(offset + 1..offset + 1 + len)
+            .ok_or_else(malformed)?;
+        labels.push(String::from_utf8_lossy(label).to_ascii_lowercase());
+        offset += 1 + len;
+    }
+
+    Ok((labels.join("."), end.unwrap_or(offset)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Answer for `example.com MX` with two records, the second using name compression
+    fn mx_response(id: u16) -> Vec<u8> {
+        let mut response = build_query(id, "example.com", TYPE_MX).unwrap();
+        response[2] = 0x81;
+        response[3] = 0x80;
+        response[7] = 2;
+
+        // example.com MX 20 alt1.aspmx.l.google.com
+        response.extend_from_slice(&[0xc0, 12, 0, 15, 0, 1, 0, 0, 0x0e, 0x10]);
+        let mut exchange = Vec::new();
+        for label in ["alt1", "aspmx", "l", "google", "com"] {
+            exchange.push(label.len() as u8);
+            exchange.extend_from_slice(label.as_bytes());
+        }
+        exchange.push(0);
+        let first_exchange = response.len() + 4;
+        response.extend_from_slice(&((exchange.len() + 2) as u16).to_be_bytes());
+        response.extend_from_slice(&20u16.to_be_bytes());
+        response.extend_from_slice(&exchange);
+
+        // example.com MX 10 aspmx.l.google.com (pointer into the first exchange)
+        response.extend_from_slice(&[0xc0, 12, 0, 15, 0, 1, 0, 0, 0x0e, 0x10, 0, 4]);
+        response.extend_from_slice(&10u16.to_be_bytes());
+        response.extend_from_slice(&[0xc0, (first_exchange + 5) as u8]);
+        response
+    }
+
+    #[test]
+    fn test_build_query() {
+        let query = build_query(0x1234, "example.com.", TYPE_MX).unwrap();
+        assert_eq!(&query[..2], &[0x12, 0x34]);
+        assert_eq!(&query[12..25], b"\x07example\x03com\x00");
+        assert_eq!(&query[25..], &[0, 15, 0, 1]);
+        assert!(build_query(1, "bad..domain", TYPE_MX).is_err());
+    }
+
+    #[test]
+    fn test_parse_mx_response() {
+        let hosts = parse_mx_response(7, "example.com", &mx_response(7)).unwrap();
+        assert_eq!(hosts, vec!["aspmx.l.google.com", "alt1.aspmx.l.google.com"]);
+        assert!(parse_mx_response(7, "Example.COM.", &mx_response(7)).is_ok());
+
+        assert!(parse_mx_response(8, "example.com", &mx_response(7)).is_err());
+        assert!(parse_mx_response(7, "example.com", &mx_response(7)[..40]).is_err());
+
+        let mut nxdomain = build_query(9, "missing.example", TYPE_MX).unwrap();
+        nxdomain[3] = 0x83;
+        assert!(parse_mx_response(9, "missing.example", &nxdomain)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_question_must_match_query() {
+        // Right id, but answering for another name or record type
+        assert!(parse_mx_response(7, "example.org", &mx_response(7)).is_err());
+        let mut other_type = mx_response(7);
+        other_type[26] = 1;
+        assert!(parse_mx_response(7, "example.com", &other_type).is_err());
+        let mut two_questions = mx_response(7);
+        two_questions[5] = 2;
+        assert!(parse_mx_response(7, "example.com", &two_questions).is_err());
+    }
+
+    #[test]
+    fn test_truncated_flag() {
+        let mut response = mx_response(7);
+        assert!(!is_truncated(&response));
+        response[2] |= 0x02;
+        assert!(is_truncated(&response));
+        assert!(!is_truncated(&[0, 1]));
+    }
+
+    #[test]
+    fn test_parse_resolv_conf() {
+        let content = "# generated\nsearch lan\nnameserver 192.168.1.1\nnameserver fe80::1%eth0\n";
+        assert_eq!(parse_resolv_conf(content), vec!["192.168.1.1", "fe80::1"]);
+    }
+
+    #[test]
+    fn test_parse_platform_nameservers() {
+        // Without /etc/resolv.conf the servers come from scutil or PowerShell
+        let scutil = "DNS configuration\n\nresolver #1\n  search domain[0] : lan\n  \
+nameserver[0] : 192.168.1.1\n  nameserver[1] : fe80::1%en0\n  if_index : 6 (en0)\n\n\
+DNS configuration (for scoped queries)\n\nresolver #1\n  nameserver[0] : 192.168.1.1\n";
+        assert_eq!(parse_scutil_dns(scutil), vec!["192.168.1.1", "fe80::1"]);
+
+        let powershell = "fec0:0:0:ffff::1\r\n10.0.0.1\r\n\r\n10.0.0.1\r\n8.8.8.8\r\n";
+        assert_eq!(
+            parse_address_lines(powershell),
+            vec!["fec0:0:0:ffff::1", "10.0.0.1", "8.8.8.8"]
+        );
+        assert!(parse_address_lines("Get-DnsClientServerAddress : not found\r\n").is_empty());
+    }
+}
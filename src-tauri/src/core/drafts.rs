@@ -1,20 +1,800 @@
+// Drafts module - Local drafts, their attachments, and their copies in the server's
+// Drafts folder. A draft is uploaded as a MIME message once its `revision` moves past
+// the `synced_revision` last uploaded or imported and its editor has let it rest;
+// `core::sync::draft_sync` talks to the server.
+
 use crate::core::cache::db::{delete_draft, get_drafts, save_draft, Pool};
 use crate::error::DEmailError;
 use crate::models::Draft;
+use lettre::message::header::{self, ContentType, Header, HeaderName, HeaderValue};
+use lettre::message::{Attachment, Mailbox, Mailboxes, MessageBuilder, MultiPart, SinglePart};
+use mail_parser::{MessageParser, MimeHeaders};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+
+/// A file kept with a draft until it is sent
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DraftAttachment {
+    pub id: i64,
+    pub draft_id: i64,
+    pub filename: String,
+    pub mime_type: String,
+    pub size_bytes: i64,
+}
 
+/// Saves a draft from an editor and returns the id it was saved under. When a version
+/// from the server was imported after the editor loaded the draft, both are kept: the
+/// edit becomes a new draft and the server's version is marked as the conflicting one.
 pub fn create_or_update_draft(pool: &Pool, draft: &Draft) -> Result<i64, DEmailError> {
-    save_draft(pool, draft)
+    if draft.id == 0 {
+        return save_draft(pool, draft);
+    }
+    let stored: Option<Option<i64>> = {
+        let conn = pool
+            .get()
+            .map_err(|_| DEmailError::Database(rusqlite::Error::InvalidQuery))?;
+        conn.query_row(
+            "SELECT imported_revision FROM drafts WHERE id = ?1 AND deleted = 0",
+            [draft.id],
+            |row| row.get(0),
+        )
+        .optional()?
+    };
+
+    match stored {
+        // Sent or discarded on another device while open here
+        None => save_draft(pool, &Draft { id: 0, ..draft.clone() }),
+        Some(Some(imported)) if imported > draft.revision => {
+            let id = save_draft(pool, &Draft { id: 0, ..draft.clone() })?;
+            let conn = pool
+                .get()
+                .map_err(|_| DEmailError::Database(rusqlite::Error::InvalidQuery))?;
+            conn.execute("UPDATE drafts SET conflict = 1 WHERE id = ?1", [draft.id])?;
+            conn.execute(
+                "INSERT INTO draft_attachments (draft_id, filename, mime_type, data)
+                 SELECT ?1, filename, mime_type, data FROM draft_attachments
+                 WHERE draft_id = ?2 ORDER BY id",
+                params![id, draft.id],
+            )?;
+            Ok(id)
+        }
+        Some(_) => save_draft(pool, draft),
+    }
 }
 
 pub fn load_drafts_for_account(pool: &Pool, account_id: i64) -> Result<Vec<Draft>, DEmailError> {
     get_drafts(pool, account_id)
 }
 
+/// Deletes a draft. One with a copy on the server is only marked, and goes once the
+/// next draft sync has removed that copy.
 pub fn remove_draft(pool: &Pool, draft_id: i64) -> Result<(), DEmailError> {
-    delete_draft(pool, draft_id)
+    let conn = pool
+        .get()
+        .map_err(|_| DEmailError::Database(rusqlite::Error::InvalidQuery))?;
+    let marked = conn.execute(
+        "UPDATE drafts SET deleted = 1 WHERE id = ?1 AND synced_revision IS NOT NULL",
+        [draft_id],
+    )?;
+    drop(conn);
+    if marked == 0 {
+        delete_draft(pool, draft_id)?;
+    }
+    Ok(())
+}
+
+pub fn draft_account_id(conn: &Connection, draft_id: i64) -> Result<Option<i64>, DEmailError> {
+    Ok(conn
+        .query_row(
+            "SELECT account_id FROM drafts WHERE id = ?1",
+            [draft_id],
+            |row| row.get(0),
+        )
+        .optional()?)
+}
+
+pub fn get_draft(conn: &Connection, draft_id: i64) -> Result<Option<Draft>, DEmailError> {
+    Ok(conn
+        .query_row(
+            "SELECT id, account_id, to_addresses, cc_addresses, bcc_addresses, subject,
+                    body_plain, body_html, created_at, updated_at, revision, conflict
+             FROM drafts WHERE id = ?1",
+            [draft_id],
+            |row| {
+                Ok(Draft {
+                    id: row.get(0)?,
+                    account_id: row.get(1)?,
+                    to_addresses: row.get(2)?,
+                    cc_addresses: row.get(3)?,
+                    bcc_addresses: row.get(4)?,
+                    subject: row.get(5)?,
+                    body_plain: row.get(6)?,
+                    body_html: row.get(7)?,
+                    created_at: row.get(8)?,
+                    updated_at: row.get(9)?,
+                    revision: row.get(10)?,
+                    conflict: row.get(11)?,
+                })
+            },
+        )
+        .optional()?)
+}
+
+/// Records a change that is not saved through `save_draft`, so it gets uploaded
+fn touch_draft(conn: &Connection, draft_id: i64) -> Result<(), DEmailError> {
+    conn.execute(
+        "UPDATE drafts SET revision = revision + 1, updated_at = ?2 WHERE id = ?1",
+        params![draft_id, chrono::Utc::now().timestamp()],
+    )?;
+    Ok(())
 }
 
 pub fn auto_save_draft(pool: &Pool, draft: &Draft) -> Result<i64, DEmailError> {
     tracing::debug!("Auto-saving draft for account {}", draft.account_id);
     save_draft(pool, draft)
 }
+
+pub fn add_draft_attachment(
+    conn: &Connection,
+    draft_id: i64,
+    filename: &str,
+    mime_type: &str,
+    data: &[u8],
+) -> Result<i64, DEmailError> {
+    conn.execute(
+        "INSERT INTO draft_attachments (draft_id, filename, mime_type, data)
+         VALUES (?1, ?2, ?3, ?4)",
+        params![draft_id, filename, mime_type, data],
+    )?;
+    let id = conn.last_insert_rowid();
+    touch_draft(conn, draft_id)?;
+    Ok(id)
+}
+
+pub fn list_draft_attachments(
+    conn: &Connection,
+    draft_id: i64,
+) -> Result<Vec<DraftAttachment>, DEmailError> {
+    let mut stmt = conn.prepare(
+        "SELECT id, draft_id, filename, mime_type, LENGTH(data)
+         FROM draft_attachments WHERE draft_id = ?1 ORDER BY id",
+    )?;
+    let attachments = stmt
+        .query_map([draft_id], |row| {
+            Ok(DraftAttachment {
+                id: row.get(0)?,
+                draft_id: row.get(1)?,
+                filename: row.get(2)?,
+                mime_type: row.get(3)?,
+                size_bytes: row.get(4)?,
+            })
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(attachments)
+}
+
+/// Filename, MIME type and contents of each of a draft's attachments
+pub fn draft_attachment_contents(
+    conn: &Connection,
+    draft_id: i64,
+) -> Result<Vec<(String, String, Vec<u8>)>, DEmailError> {
+    let mut stmt = conn.prepare(
+        "SELECT filename, mime_type, data FROM draft_attachments WHERE draft_id = ?1 ORDER BY id",
+    )?;
+    let attachments = stmt
+        .query_map([draft_id], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(attachments)
+}
+
+/// Removes an attachment and returns the draft it belonged to
+pub fn remove_draft_attachment(
+    conn: &Connection,
+    attachment_id: i64,
+) -> Result<Option<i64>, DEmailError> {
+    let draft_id: Option<i64> = conn
+        .query_row(
+            "SELECT draft_id FROM draft_attachments WHERE id = ?1",
+            [attachment_id],
+            |row| row.get(0),
+        )
+        .optional()?;
+    conn.execute(
+        "DELETE FROM draft_attachments WHERE id = ?1",
+        [attachment_id],
+    )?;
+    if let Some(draft_id) = draft_id {
+        touch_draft(conn, draft_id)?;
+    }
+    Ok(draft_id)
+}
+
+/// A plain-text body followed by the attachments, as a multipart/mixed message body
+pub fn with_attachments(body: String, attachments: Vec<(String, String, Vec<u8>)>) -> MultiPart {
+    attachments.into_iter().fold(
+        MultiPart::mixed().singlepart(SinglePart::plain(body)),
+        |multipart, (filename, mime_type, data)| {
+            let content_type = ContentType::parse(&mime_type)
+                .unwrap_or_else(|_| ContentType::parse("application/octet-stream").unwrap());
+            multipart.singlepart(Attachment::new(filename).body(data, content_type))
+        },
+    )
+}
+
+/// Where a draft stands with its copy in the server's Drafts folder
+#[derive(Debug, Clone, PartialEq)]
+pub struct DraftSyncState {
+    pub id: i64,
+    pub revision: i64,
+    /// The revision last uploaded or imported; `None` if never on the server
+    pub synced_revision: Option<i64>,
+    pub message_id_header: Option<String>,
+    pub remote_uid: Option<u32>,
+    pub remote_uid_validity: Option<u32>,
+    pub deleted: bool,
+}
+
+impl DraftSyncState {
+    /// Whether there are local changes the server has not seen
+    fn is_dirty(&self) -> bool {
+        self.synced_revision != Some(self.revision)
+    }
+}
+
+/// One change to bring the local drafts and the server's Drafts folder in step
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DraftSyncStep {
+    /// Append the local version and expunge the server copy it replaces. Without a
+    /// Message-ID a new one is made, which also keeps a draft apart from a conflicting
+    /// version left on the server.
+    Upload {
+        draft_id: i64,
+        message_id: Option<String>,
+        replaces: Option<u32>,
+    },
+    /// Store a server message over a local draft, or as a new draft
+    Import {
+        uid: u32,
+        into: Option<i64>,
+        conflict: bool,
+    },
+    /// The draft was sent or discarded on another device
+    RemoveLocal { draft_id: i64 },
+    /// The draft was deleted here, so its server copy goes too
+    RemoveRemote { draft_id: i64, uid: Option<u32> },
+}
+
+pub fn sync_states(conn: &Connection, account_id: i64) -> Result<Vec<DraftSyncState>, DEmailError> {
+    let mut stmt = conn.prepare(
+        "SELECT id, revision, synced_revision, message_id_header, remote_uid,
+                remote_uid_validity, deleted
+         FROM drafts WHERE account_id = ?1 ORDER BY id",
+    )?;
+    let states = stmt
+        .query_map([account_id], |row| {
+            Ok(DraftSyncState {
+                id: row.get(0)?,
+                revision: row.get(1)?,
+                synced_revision: row.get(2)?,
+                message_id_header: row.get(3)?,
+                remote_uid: row.get(4)?,
+                remote_uid_validity: row.get(5)?,
+                deleted: row.get(6)?,
+            })
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(states)
+}
+
+/// Works out the sync steps from the local drafts and the Message-ID of each message
+/// in the Drafts folder, by UID.
+///
+/// A server copy that is gone may have been replaced by another client that kept the
+/// Message-ID. The replacement is imported over the draft, or next to it when the draft
+/// also changed here, so neither edit is lost. A copy gone for good means the draft was
+/// sent or discarded elsewhere, unless there are local changes to keep.
+pub fn plan_sync(
+    local: &[DraftSyncState],
+    server: &HashMap<u32, String>,
+    uid_validity: Option<u32>,
+) -> Vec<DraftSyncStep> {
+    let current = |state: &DraftSyncState| {
+        state.remote_uid.filter(|uid| {
+            state.remote_uid_validity.is_some()
+                && state.remote_uid_validity == uid_validity
+                && server.contains_key(uid)
+        })
+    };
+    let mut claimed: HashSet<u32> = local.iter().filter_map(current).collect();
+
+    let mut steps = Vec::new();
+    for state in local {
+        if state.deleted {
+            steps.push(DraftSyncStep::RemoveRemote {
+                draft_id: state.id,
+                uid: current(state),
+            });
+            continue;
+        }
+        if let Some(uid) = current(state) {
+            if state.is_dirty() {
+                steps.push(DraftSyncStep::Upload {
+                    draft_id: state.id,
+                    message_id: state.message_id_header.clone(),
+                    replaces: Some(uid),
+                });
+            }
+            continue;
+        }
+
+        let replacement = state
+            .message_id_header
+            .as_deref()
+            .filter(|message_id| !message_id.is_empty())
+            .and_then(|message_id| {
+                let mut uids: Vec<u32> = server
+                    .iter()
+                    .filter(|(uid, id)| id.as_str() == message_id && !claimed.contains(uid))
+                    .map(|(uid, _)| *uid)
+                    .collect();
+                uids.sort_unstable();
+                uids.pop()
+            });
+        match replacement {
+            Some(uid) => {
+                claimed.insert(uid);
+                let conflict = state.is_dirty();
+                steps.push(DraftSyncStep::Import {
+                    uid,
+                    into: (!conflict).then_some(state.id),
+                    conflict,
+                });
+                if conflict {
+                    steps.push(DraftSyncStep::Upload {
+                        draft_id: state.id,
+                        message_id: None,
+                        replaces: None,
+                    });
+                }
+            }
+            None if state.is_dirty() => steps.push(DraftSyncStep::Upload {
+                draft_id: state.id,
+                message_id: state.message_id_header.clone(),
+                replaces: None,
+            }),
+            None => steps.push(DraftSyncStep::RemoveLocal { draft_id: state.id }),
+        }
+    }
+
+    // Drafts started on other devices
+    let mut new: Vec<u32> = server
+        .keys()
+        .filter(|uid| !claimed.contains(uid))
+        .copied()
+        .collect();
+    new.sort_unstable();
+    steps.extend(new.into_iter().map(|uid| DraftSyncStep::Import {
+        uid,
+        into: None,
+        conflict: false,
+    }));
+    steps
+}
+
+/// The account's Drafts folder: Drafts under [Gmail] on Gmail, otherwise a folder
+/// named Drafts
+pub fn drafts_folder(conn: &Connection, account_id: i64) -> Result<Option<String>, DEmailError> {
+    let mut stmt = conn.prepare("SELECT path, name FROM folders WHERE account_id = ?1")?;
+    let folders = stmt
+        .query_map([account_id], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    let gmail = folders
+        .iter()
+        .find(|(path, _)| path == "[Gmail]/Drafts" || path == "[Google Mail]/Drafts");
+    let named = folders
+        .iter()
+        .find(|(_, name)| name.eq_ignore_ascii_case("Drafts"));
+    Ok(gmail.or(named).map(|(path, _)| path.clone()))
+}
+
+/// A Message-ID for a draft sent from `from`
+pub fn new_message_id(from: &str) -> String {
+    let domain = from
+        .rsplit_once('@')
+        .map(|(_, domain)| domain)
+        .filter(|domain| !domain.is_empty())
+        .unwrap_or("localhost");
+    format!("<{}@{}>", uuid::Uuid::new_v4(), domain)
+}
+
+/// The draft as a MIME message for the Drafts folder. Recipients that do not parse
+/// yet, like a half-typed address, are kept as written.
+pub fn draft_message(
+    draft: &Draft,
+    from: &str,
+    message_id: &str,
+    attachments: Vec<(String, String, Vec<u8>)>,
+) -> Result<Vec<u8>, DEmailError> {
+    let from: Mailbox = from
+        .parse()
+        .map_err(|_| DEmailError::Validation(format!("Invalid from address: {}", from)))?;
+    // Nothing is sent from the Drafts folder; the envelope only satisfies the builder
+    let envelope =
+        lettre::address::Envelope::new(Some(from.email.clone()), vec![from.email.clone()])
+            .map_err(|e| DEmailError::Validation(e.to_string()))?;
+    let mut builder = lettre::Message::builder()
+        .from(from)
+        .subject(draft.subject.clone())
+        .message_id(Some(message_id.to_string()))
+        .envelope(envelope)
+        .keep_bcc();
+    builder = with_recipients::<header::To>(builder, "To", Some(&draft.to_addresses));
+    builder = with_recipients::<header::Cc>(builder, "Cc", draft.cc_addresses.as_deref());
+    builder = with_recipients::<header::Bcc>(builder, "Bcc", draft.bcc_addresses.as_deref());
+
+    let body = draft.body_plain.clone().unwrap_or_default();
+    let message = if attachments.is_empty() {
+        builder.body(body)
+    } else {
+        builder.multipart(with_attachments(body, attachments))
+    }
+    .map_err(|e| DEmailError::Validation(format!("Could not build the draft: {}", e)))?;
+    Ok(message.formatted())
+}
+
+fn with_recipients<H: Header + From<Mailboxes>>(
+    builder: MessageBuilder,
+    name: &'static str,
+    value: Option<&str>,
+) -> MessageBuilder {
+    match value.map(str::trim).filter(|value| !value.is_empty()) {
+        None => builder,
+        Some(value) => match value.parse::<Mailboxes>() {
+            Ok(mailboxes) => builder.header(H::from(mailboxes)),
+            Err(_) => builder.raw_header(HeaderValue::new(
+                HeaderName::new_from_ascii_str(name),
+                value.to_string(),
+            )),
+        },
+    }
+}
+
+/// A draft read from the server's Drafts folder
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ServerDraft {
+    pub message_id_header: String,
+    pub to_addresses: String,
+    pub cc_addresses: Option<String>,
+    pub bcc_addresses: Option<String>,
+    pub subject: String,
+    pub body_plain: Option<String>,
+    pub attachments: Vec<(String, String, Vec<u8>)>,
+}
+
+/// Reads a draft message. Compose is plain text, so an HTML-only draft from another
+/// client comes in as its text rendering.
+pub fn parse_server_draft(raw: &[u8]) -> Option<ServerDraft> {
+    let parsed = MessageParser::default().parse(raw)?;
+    let addresses = |address: Option<&mail_parser::Address>| {
+        let list = address
+            .map(|address| {
+                address
+                    .clone()
+                    .into_list()
+                    .iter()
+                    .filter_map(|addr| {
+                        let email = addr.address()?;
+                        Some(match addr.name().filter(|name| !name.is_empty()) {
+                            Some(name) if name.contains([',', '"', '<', '>', '@']) => {
+                                format!("\"{}\" <{}>", name.replace('"', "'"), email)
+                            }
+                            Some(name) => format!("{} <{}>", name, email),
+                            None => email.to_string(),
+                        })
+                    })
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            })
+            .unwrap_or_default();
+        list
+    };
+
+    Some(ServerDraft {
+        message_id_header: parsed.message_id().unwrap_or_default().to_string(),
+        to_addresses: addresses(parsed.to()),
+        cc_addresses: Some(addresses(parsed.cc())).filter(|cc| !cc.is_empty()),
+        bcc_addresses: Some(addresses(parsed.bcc())).filter(|bcc| !bcc.is_empty()),
+        subject: parsed.subject().unwrap_or_default().to_string(),
+        body_plain: parsed.body_text(0).map(|body| body.to_string()),
+        attachments: parsed
+            .attachments()
+            .map(|attachment| {
+                let mime_type = attachment
+                    .content_type()
+                    .map(|ct| match ct.subtype() {
+                        Some(subtype) => format!("{}/{}", ct.ctype(), subtype),
+                        None => ct.ctype().to_string(),
+                    })
+                    .unwrap_or_else(|| "application/octet-stream".to_string());
+                (
+                    attachment
+                        .attachment_name()
+                        .unwrap_or("unnamed_attachment")
+                        .to_string(),
+                    mime_type,
+                    attachment.contents().to_vec(),
+                )
+            })
+            .collect(),
+    })
+}
+
+/// Records the server copy of an uploaded revision
+pub fn mark_uploaded(
+    conn: &Connection,
+    draft_id: i64,
+    revision: i64,
+    message_id_header: &str,
+    remote_uid: Option<u32>,
+    uid_validity: Option<u32>,
+) -> Result<(), DEmailError> {
+    conn.execute(
+        "UPDATE drafts SET synced_revision = ?2, message_id_header = ?3, remote_uid = ?4,
+             remote_uid_validity = ?5
+         WHERE id = ?1",
+        params![draft_id, revision, message_id_header, remote_uid, uid_validity],
+    )?;
+    Ok(())
+}
+
+/// Stores a server draft over the draft `into`, or as a new one, with its attachments.
+/// Returns the draft's id.
+pub fn import_server_draft(
+    conn: &Connection,
+    account_id: i64,
+    into: Option<i64>,
+    conflict: bool,
+    draft: &ServerDraft,
+    remote_uid: u32,
+    uid_validity: Option<u32>,
+) -> Result<i64, DEmailError> {
+    let now = chrono::Utc::now().timestamp();
+    let tx = conn.unchecked_transaction()?;
+    let id = match into {
+        Some(id) => {
+            tx.execute(
+                "UPDATE drafts SET to_addresses = ?2, cc_addresses = ?3, bcc_addresses = ?4,
+                     subject = ?5, body_plain = ?6, body_html = NULL, updated_at = ?7,
+                     revision = revision + 1, conflict = ?8
+                 WHERE id = ?1",
+                params![
+                    id,
+                    draft.to_addresses,
+                    draft.cc_addresses,
+                    draft.bcc_addresses,
+                    draft.subject,
+                    draft.body_plain,
+                    now,
+                    conflict
+                ],
+            )?;
+            tx.execute("DELETE FROM draft_attachments WHERE draft_id = ?1", [id])?;
+            id
+        }
+        None => {
+            tx.execute(
+                "INSERT INTO drafts (account_id, to_addresses, cc_addresses, bcc_addresses,
+                     subject, body_plain, created_at, updated_at, conflict)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?7, ?8)",
+                params![
+                    account_id,
+                    draft.to_addresses,
+                    draft.cc_addresses,
+                    draft.bcc_addresses,
+                    draft.subject,
+                    draft.body_plain,
+                    now,
+                    conflict
+                ],
+            )?;
+            tx.last_insert_rowid()
+        }
+    };
+    for (filename, mime_type, data) in &draft.attachments {
+        tx.execute(
+            "INSERT INTO draft_attachments (draft_id, filename, mime_type, data)
+             VALUES (?1, ?2, ?3, ?4)",
+            params![id, filename, mime_type, data],
+        )?;
+    }
+    // The imported revision is on the server already, and is newer than what any open
+    // editor loaded
+    tx.execute(
+        "UPDATE drafts SET synced_revision = revision, imported_revision = revision,
+             message_id_header = ?2, remote_uid = ?3, remote_uid_validity = ?4
+         WHERE id = ?1",
+        params![id, draft.message_id_header, remote_uid, uid_validity],
+    )?;
+    tx.commit()?;
+    Ok(id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::accounts::create_account;
+    use crate::core::cache::db::test_pool;
+
+    fn draft(account_id: i64, subject: &str) -> Draft {
+        Draft {
+            id: 0,
+            account_id,
+            to_addresses: "Bob <bob@example.com>".to_string(),
+            cc_addresses: None,
+            bcc_addresses: None,
+            subject: subject.to_string(),
+            body_plain: Some("Hello".to_string()),
+            body_html: None,
+            created_at: 0,
+            updated_at: 0,
+            revision: 0,
+            conflict: false,
+        }
+    }
+
+    fn state(id: i64, revision: i64, synced: Option<i64>, uid: Option<u32>) -> DraftSyncState {
+        DraftSyncState {
+            id,
+            revision,
+            synced_revision: synced,
+            message_id_header: synced.map(|_| format!("<{}@example.com>", id)),
+            remote_uid: uid,
+            remote_uid_validity: uid.map(|_| 7),
+            deleted: false,
+        }
+    }
+
+    #[test]
+    fn test_plan_sync() {
+        let local = vec![
+            // Never uploaded
+            state(1, 1, None, None),
+            // Edited here since the last upload
+            state(2, 3, Some(2), Some(20)),
+            // Unchanged here, replaced on another device under the same Message-ID
+            state(3, 1, Some(1), Some(30)),
+            // Edited here and replaced on another device: a conflict
+            state(4, 2, Some(1), Some(40)),
+            // Sent from another device
+            state(5, 1, Some(1), Some(50)),
+            // Deleted here
+            DraftSyncState {
+                deleted: true,
+                ..state(6, 1, Some(1), Some(60))
+            },
+        ];
+        let server = HashMap::from([
+            (20, "<2@example.com>".to_string()),
+            (31, "<3@example.com>".to_string()),
+            (41, "<4@example.com>".to_string()),
+            (60, "<6@example.com>".to_string()),
+            (70, "<phone@example.com>".to_string()),
+        ]);
+
+        assert_eq!(
+            plan_sync(&local, &server, Some(7)),
+            vec![
+                DraftSyncStep::Upload {
+                    draft_id: 1,
+                    message_id: None,
+                    replaces: None
+                },
+                DraftSyncStep::Upload {
+                    draft_id: 2,
+                    message_id: Some("<2@example.com>".to_string()),
+                    replaces: Some(20)
+                },
+                DraftSyncStep::Import {
+                    uid: 31,
+                    into: Some(3),
+                    conflict: false
+                },
+                DraftSyncStep::Import {
+                    uid: 41,
+                    into: None,
+                    conflict: true
+                },
+                DraftSyncStep::Upload {
+                    draft_id: 4,
+                    message_id: None,
+                    replaces: None
+                },
+                DraftSyncStep::RemoveLocal { draft_id: 5 },
+                DraftSyncStep::RemoveRemote {
+                    draft_id: 6,
+                    uid: Some(60)
+                },
+                DraftSyncStep::Import {
+                    uid: 70,
+                    into: None,
+                    conflict: false
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_draft_message_roundtrip() {
+        let draft = Draft {
+            to_addresses: "Bob <bob@example.com>, carol@".to_string(),
+            bcc_addresses: Some("audit@example.com".to_string()),
+            ..draft(1, "Quarterly numbers")
+        };
+        let raw = draft_message(
+            &draft,
+            "Alice <alice@example.com>",
+            "<d1@example.com>",
+            vec![(
+                "numbers.csv".to_string(),
+                "text/csv".to_string(),
+                b"q,total\n1,42\n".to_vec(),
+            )],
+        )
+        .unwrap();
+
+        let parsed = parse_server_draft(&raw).unwrap();
+        assert_eq!(parsed.message_id_header, "d1@example.com");
+        assert_eq!(parsed.subject, "Quarterly numbers");
+        assert!(parsed.to_addresses.starts_with("Bob <bob@example.com>"));
+        assert_eq!(parsed.bcc_addresses.as_deref(), Some("audit@example.com"));
+        assert_eq!(parsed.body_plain.as_deref().map(str::trim), Some("Hello"));
+        assert_eq!(
+            parsed.attachments,
+            vec![(
+                "numbers.csv".to_string(),
+                "text/csv".to_string(),
+                b"q,total\n1,42\n".to_vec()
+            )]
+        );
+    }
+
+    #[test]
+    fn test_stale_editor_keeps_both_versions() {
+        let pool = test_pool();
+        let account = create_account(&pool, "alice@example.com", "Alice", "google").unwrap();
+        let id = create_or_update_draft(&pool, &draft(account.id, "Mine")).unwrap();
+        let conn = pool.get().unwrap();
+        let loaded = get_draft(&conn, id).unwrap().unwrap();
+
+        let from_phone = ServerDraft {
+            subject: "From the phone".to_string(),
+            ..Default::default()
+        };
+        import_server_draft(&conn, account.id, Some(id), false, &from_phone, 5, Some(1)).unwrap();
+        drop(conn);
+
+        let edited = Draft {
+            subject: "Mine, edited".to_string(),
+            ..loaded
+        };
+        let saved = create_or_update_draft(&pool, &edited).unwrap();
+        assert_ne!(saved, id);
+
+        let drafts = load_drafts_for_account(&pool, account.id).unwrap();
+        let subject = |id| drafts.iter().find(|d| d.id == id).unwrap().subject.clone();
+        assert_eq!(subject(id), "From the phone");
+        assert_eq!(subject(saved), "Mine, edited");
+        assert!(drafts.iter().find(|d| d.id == id).unwrap().conflict);
+
+        // Saving again from the same editor updates its own draft
+        let again = Draft {
+            id: saved,
+            revision: 1,
+            ..edited
+        };
+        assert_eq!(create_or_update_draft(&pool, &again).unwrap(), saved);
+    }
+}
@@ -38,6 +38,9 @@ pub fn export_account(
             is_starred: row.get(15)?,
             thread_id: row.get(16)?,
             attachments: Vec::new(), // Will be loaded separately
+            security: None,
+            authentication: None,
+            remote_content: None,
         })
     })?;
 
@@ -0,0 +1,390 @@
+// Identities module - The addresses an account sends as: aliases such as support@ or sales@
+// on the same mailbox, each with its own display name, Reply-To, default signature and,
+// optionally, its own SMTP server. Passwords for those servers live in the OS keyring.
+
+use crate::core::contacts::parse_address_list;
+use crate::core::providers::{Security, ServerEndpoint};
+use crate::core::validation::validate_email;
+use crate::error::DEmailError;
+use lettre::message::Mailbox;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+
+/// A separate submission server for an identity, signed in to with a password
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct IdentitySmtp {
+    pub host: String,
+    pub port: u16,
+    pub security: Security,
+    /// Login name; the identity's address when empty
+    pub username: String,
+}
+
+impl IdentitySmtp {
+    pub fn endpoint(&self) -> ServerEndpoint {
+        ServerEndpoint {
+            host: self.host.clone(),
+            port: self.port,
+            security: self.security,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Identity {
+    pub id: i64,
+    pub account_id: i64,
+    pub email_address: String,
+    pub display_name: String,
+    pub reply_to: Option<String>,
+    pub signature_id: Option<i64>,
+    /// Sends through the account's provider when unset
+    pub smtp: Option<IdentitySmtp>,
+    pub is_default: bool,
+}
+
+impl Identity {
+    /// The From mailbox, with the display name when there is one
+    pub fn mailbox(&self) -> Result<Mailbox, DEmailError> {
+        let address = self.email_address.parse().map_err(|_| {
+            DEmailError::Validation(format!("Invalid from address: {}", self.email_address))
+        })?;
+        let name = Some(self.display_name.trim())
+            .filter(|name| !name.is_empty())
+            .map(str::to_string);
+        Ok(Mailbox::new(name, address))
+    }
+
+    pub fn reply_to_mailbox(&self) -> Result<Option<Mailbox>, DEmailError> {
+        self.reply_to
+            .as_deref()
+            .filter(|reply_to| !reply_to.trim().is_empty())
+            .map(|reply_to| {
+                reply_to.trim().parse().map_err(|_| {
+                    DEmailError::Validation(format!("Invalid reply-to address: {}", reply_to))
+                })
+            })
+            .transpose()
+    }
+
+    pub fn smtp_username(&self) -> Option<String> {
+        self.smtp.as_ref().map(|smtp| {
+            if smtp.username.trim().is_empty() {
+                self.email_address.clone()
+            } else {
+                smtp.username.trim().to_string()
+            }
+        })
+    }
+}
+
+const IDENTITY_QUERY: &str = "SELECT id, account_id, email_address, display_name, reply_to,
+        signature_id, smtp_host, smtp_port, smtp_security, smtp_username, is_default
+    FROM identities";
+
+fn row_to_identity(row: &rusqlite::Row) -> rusqlite::Result<Identity> {
+    let smtp_host: Option<String> = row.get(6)?;
+    let smtp = match smtp_host {
+        Some(host) => {
+            let security: String = row.get(8)?;
+            Some(IdentitySmtp {
+                host,
+                port: row.get(7)?,
+                security: if security == "tls" {
+                    Security::Tls
+                } else {
+                    Security::StartTls
+                },
+                username: row.get::<_, Option<String>>(9)?.unwrap_or_default(),
+            })
+        }
+        None => None,
+    };
+    Ok(Identity {
+        id: row.get(0)?,
+        account_id: row.get(1)?,
+        email_address: row.get(2)?,
+        display_name: row.get(3)?,
+        reply_to: row.get(4)?,
+        signature_id: row.get(5)?,
+        smtp,
+        is_default: row.get(10)?,
+    })
+}
+
+pub fn list_identities(conn: &Connection, account_id: i64) -> Result<Vec<Identity>, DEmailError> {
+    let mut stmt = conn.prepare(&format!(
+        "{} WHERE account_id = ?1 ORDER BY is_default DESC, email_address",
+        IDENTITY_QUERY
+    ))?;
+    let identities = stmt
+        .query_map([account_id], row_to_identity)?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(identities)
+}
+
+pub fn get_identity(conn: &Connection, identity_id: i64) -> Result<Identity, DEmailError> {
+    conn.query_row(
+        &format!("{} WHERE id = ?1", IDENTITY_QUERY),
+        [identity_id],
+        row_to_identity,
+    )
+    .optional()?
+    .ok_or_else(|| DEmailError::NotFound(format!("Identity {} not found", identity_id)))
+}
+
+fn default_identity(conn: &Connection, account_id: i64) -> Result<Option<Identity>, DEmailError> {
+    Ok(conn
+        .query_row(
+            &format!(
+                "{} WHERE account_id = ?1 AND is_default = 1 LIMIT 1",
+                IDENTITY_QUERY
+            ),
+            [account_id],
+            row_to_identity,
+        )
+        .optional()?)
+}
+
+/// Creates or updates an identity; saving one as default clears the account's other default
+pub fn save_identity(conn: &Connection, identity: &Identity) -> Result<i64, DEmailError> {
+    validate_email(&identity.email_address)?;
+    if let Some(reply_to) = identity
+        .reply_to
+        .as_deref()
+        .filter(|r| !r.trim().is_empty())
+    {
+        validate_email(reply_to.trim())?;
+    }
+    if let Some(smtp) = &identity.smtp {
+        if smtp.host.trim().is_empty() || smtp.port == 0 {
+            return Err(DEmailError::Validation(
+                "An SMTP server needs a host and port".to_string(),
+            ));
+        }
+    }
+
+    // Clearing the old default and saving the new one happen together, so an account never
+    // has two defaults (the partial unique index from migration v20 would refuse them)
+    let tx = conn.unchecked_transaction()?;
+    if identity.is_default {
+        tx.execute(
+            "UPDATE identities SET is_default = 0 WHERE account_id = ?1 AND id != ?2",
+            params![identity.account_id, identity.id],
+        )?;
+    }
+
+    let smtp = identity.smtp.as_ref();
+    let values = params![
+        identity.account_id,
+        identity.email_address.trim(),
+        identity.display_name.trim(),
+        identity
+            .reply_to
+            .as_deref()
+            .map(str::trim)
+            .filter(|r| !r.is_empty()),
+        identity.signature_id,
+        smtp.map(|s| s.host.trim()),
+        smtp.map(|s| s.port),
+        smtp.map(|s| match s.security {
+            Security::Tls => "tls",
+            Security::StartTls => "starttls",
+        }),
+        smtp.map(|s| s.username.trim()),
+        identity.is_default,
+        identity.id,
+    ];
+
+    let identity_id = if identity.id == 0 {
+        tx.execute(
+            "INSERT INTO identities (account_id, email_address, display_name, reply_to,
+                 signature_id, smtp_host, smtp_port, smtp_security, smtp_username, is_default)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+            &values[..10],
+        )?;
+        tx.last_insert_rowid()
+    } else {
+        let updated = tx.execute(
+            "UPDATE identities SET account_id = ?1, email_address = ?2, display_name = ?3,
+                 reply_to = ?4, signature_id = ?5, smtp_host = ?6, smtp_port = ?7,
+                 smtp_security = ?8, smtp_username = ?9, is_default = ?10
+             WHERE id = ?11",
+            values,
+        )?;
+        if updated == 0 {
+            return Err(DEmailError::NotFound(format!(
+                "Identity {} not found",
+                identity.id
+            )));
+        }
+        identity.id
+    };
+    tx.commit()?;
+    Ok(identity_id)
+}
+
+pub fn delete_identity(conn: &Connection, identity_id: i64) -> Result<(), DEmailError> {
+    conn.execute("DELETE FROM identities WHERE id = ?1", [identity_id])?;
+    delete_smtp_password(identity_id)
+}
+
+/// The identity to send as: the one asked for, or else the account's default. `None` means
+/// the account's own address.
+pub fn resolve_identity(
+    conn: &Connection,
+    account_id: i64,
+    identity_id: Option<i64>,
+) -> Result<Option<Identity>, DEmailError> {
+    match identity_id {
+        Some(identity_id) => {
+            let identity = get_identity(conn, identity_id)?;
+            if identity.account_id != account_id {
+                return Err(DEmailError::Validation(format!(
+                    "Identity {} belongs to another account",
+                    identity_id
+                )));
+            }
+            Ok(Some(identity))
+        }
+        None => default_identity(conn, account_id),
+    }
+}
+
+/// Picks the identity a reply should come from: the one the original message was
+/// addressed to (To, then Cc), falling back to the account's default
+pub fn identity_for_reply(
+    conn: &Connection,
+    message_id: i64,
+) -> Result<Option<Identity>, DEmailError> {
+    let (account_id, to_header, cc_header): (i64, String, Option<String>) = conn
+        .query_row(
+            "SELECT account_id, to_header, cc_header FROM messages WHERE id = ?1",
+            [message_id],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )
+        .optional()?
+        .ok_or_else(|| DEmailError::NotFound(format!("Message {} not found", message_id)))?;
+
+    let identities = list_identities(conn, account_id)?;
+    let recipients = parse_address_list(&to_header)
+        .into_iter()
+        .chain(parse_address_list(cc_header.as_deref().unwrap_or("")));
+    for recipient in recipients {
+        if let Some(identity) = identities
+            .iter()
+            .find(|identity| identity.email_address.eq_ignore_ascii_case(&recipient))
+        {
+            return Ok(Some(identity.clone()));
+        }
+    }
+    Ok(identities.into_iter().find(|identity| identity.is_default))
+}
+
+fn smtp_password_entry(identity_id: i64) -> Result<keyring::Entry, DEmailError> {
+    Ok(keyring::Entry::new(
+        "demail",
+        &format!("identity_smtp_{}", identity_id),
+    )?)
+}
+
+pub fn store_smtp_password(identity_id: i64, password: &str) -> Result<(), DEmailError> {
+    smtp_password_entry(identity_id)?.set_password(password)?;
+    Ok(())
+}
+
+pub fn get_smtp_password(identity_id: i64) -> Result<String, DEmailError> {
+    match smtp_password_entry(identity_id)?.get_password() {
+        Ok(password) => Ok(password),
+        Err(keyring::Error::NoEntry) => Err(DEmailError::NotFound(format!(
+            "No SMTP password stored for identity {}",
+            identity_id
+        ))),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn delete_smtp_password(identity_id: i64) -> Result<(), DEmailError> {
+    match smtp_password_entry(identity_id)?.delete_password() {
+        Ok(()) | Err(keyring::Error::NoEntry) => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rusqlite::Connection;
+
+    fn setup() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        crate::core::cache::schema::initialize_schema(&conn).unwrap();
+        conn.execute_batch(
+            "INSERT INTO accounts (id, email_address, display_name, provider_type)
+                 VALUES (1, 'me@example.com', 'Me', 'google');
+             INSERT INTO folders (id, account_id, name, path) VALUES (1, 1, 'INBOX', 'INBOX');
+             INSERT INTO messages (id, account_id, folder_id, imap_uid, message_id_header,
+                 from_header, to_header, cc_header, subject, date)
+                 VALUES (1, 1, 1, 10, '<a@example.com>', 'alice@example.net',
+                         'Support <Support@example.com>', NULL, 'Help', 5),
+                        (2, 1, 1, 11, '<b@example.com>', 'bob@example.net',
+                         'me@example.com', NULL, 'Hi', 6),
+                        (3, 1, 1, 12, '<c@example.com>', 'carol@example.net',
+                         '\"Doe, Jane\" <jane@example.net>',
+                         'Team: Support@example.com, bob@example.net;', 'Re: Help', 7);",
+        )
+        .unwrap();
+        conn
+    }
+
+    fn identity(email: &str, is_default: bool) -> Identity {
+        Identity {
+            id: 0,
+            account_id: 1,
+            email_address: email.to_string(),
+            display_name: "Example".to_string(),
+            reply_to: None,
+            signature_id: None,
+            smtp: None,
+            is_default,
+        }
+    }
+
+    #[test]
+    fn test_reply_identity_follows_original_recipient() {
+        let conn = setup();
+        let personal = save_identity(&conn, &identity("me.personal@example.com", true)).unwrap();
+        let support = save_identity(
+            &conn,
+            &Identity {
+                smtp: Some(IdentitySmtp {
+                    host: "smtp.example.com".to_string(),
+                    port: 587,
+                    security: Security::StartTls,
+                    username: String::new(),
+                }),
+                ..identity("support@example.com", false)
+            },
+        )
+        .unwrap();
+
+        let reply = identity_for_reply(&conn, 1).unwrap().unwrap();
+        assert_eq!(reply.id, support);
+        assert_eq!(reply.smtp_username().unwrap(), "support@example.com");
+        // Nothing matches the second message, so the default is used
+        assert_eq!(identity_for_reply(&conn, 2).unwrap().unwrap().id, personal);
+        // Quoted names and groups are parsed, not split on commas
+        assert_eq!(identity_for_reply(&conn, 3).unwrap().unwrap().id, support);
+
+        // A second default replaces the first
+        let mut saved = get_identity(&conn, support).unwrap();
+        saved.is_default = true;
+        save_identity(&conn, &saved).unwrap();
+        assert!(!get_identity(&conn, personal).unwrap().is_default);
+        assert_eq!(
+            resolve_identity(&conn, 1, None).unwrap().unwrap().id,
+            support
+        );
+        assert!(resolve_identity(&conn, 2, Some(support)).is_err());
+    }
+}
@@ -33,18 +33,7 @@ pub fn apply_migrations(conn: &Connection) -> Result<(), DEmailError> {
     let current_version = get_current_version(conn)?;
     info!("Current database schema version: {}", current_version);
 
-    // Define all migrations
-    let migrations: Vec<(i64, &str, fn(&Connection) -> Result<(), DEmailError>)> = vec![
-        (1, "Initial schema", migration_v1_initial),
-        (
-            2,
-            "Add starring and threading",
-            migration_v2_starring_threading,
-        ),
-        (3, "Add contacts table", migration_v3_contacts),
-    ];
-
-    for (version, description, migration_fn) in migrations {
+    for (version, description, migration_fn) in all_migrations() {
         if version > current_version {
             info!("Applying migration v{}: {}", version, description);
             migration_fn(conn)?;
@@ -56,6 +45,49 @@ pub fn apply_migrations(conn: &Connection) -> Result<(), DEmailError> {
     Ok(())
 }
 
+/// Returns the schema version this build of the application migrates up to
+pub fn latest_version() -> i64 {
+    all_migrations()
+        .iter()
+        .map(|(version, _, _)| *version)
+        .max()
+        .unwrap_or(0)
+}
+
+/// Defines all migrations in the order they must be applied
+fn all_migrations() -> Vec<(i64, &'static str, fn(&Connection) -> Result<(), DEmailError>)> {
+    vec![
+        (1, "Initial schema", migration_v1_initial),
+        (
+            2,
+            "Add starring and threading",
+            migration_v2_starring_threading,
+        ),
+        (3, "Add contacts table", migration_v3_contacts),
+        (
+            4,
+            "Add OpenPGP keyring and message security",
+            migration_v4_openpgp,
+        ),
+        (5, "Add S/MIME certificate store", migration_v5_smime),
+        (6, "Add sender authentication results and DKIM key cache", migration_v6_sender_auth),
+        (7, "Add remote content exceptions and image cache", migration_v7_remote_content),
+        (8, "Add account re-authentication flag", migration_v8_account_reauth),
+        (9, "Add per-account sync status", migration_v9_sync_status),
+        (10, "Add message rules", migration_v10_rules),
+        (11, "Index message tags", migration_v11_tags),
+        (12, "Store each message once across folders", migration_v12_message_locations),
+        (13, "Add snoozes and follow-up reminders", migration_v13_snoozes),
+        (14, "Add sending identities", migration_v14_identities),
+        (15, "Allow one default signature per account", migration_v15_default_signature),
+        (16, "Add message templates and draft attachments", migration_v16_templates),
+        (17, "Mirror drafts to the server Drafts folder", migration_v17_draft_sync),
+        (18, "Track which S/MIME certificates may be encrypted to", migration_v18_smime_trusted),
+        (19, "Identify Gmail copies by X-GM-MSGID", migration_v19_gmail_msgid),
+        (20, "Allow one default identity per account", migration_v20_default_identity),
+    ]
+}
+
 /// Records that a migration was applied
 fn record_migration(conn: &Connection, version: i64, description: &str) -> Result<(), DEmailError> {
     conn.execute(
@@ -169,6 +201,524 @@ fn migration_v3_contacts(conn: &Connection) -> Result<(), DEmailError> {
     Ok(())
 }
 
+/// Migration v4: Add OpenPGP keyring and per-message signature/encryption status
+fn migration_v4_openpgp(conn: &Connection) -> Result<(), DEmailError> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS message_security (
+            message_id INTEGER PRIMARY KEY,
+            protocol TEXT NOT NULL,
+            is_signed INTEGER NOT NULL DEFAULT 0,
+            signature_status TEXT NOT NULL,
+            signer TEXT,
+            signer_key_id TEXT,
+            is_encrypted INTEGER NOT NULL DEFAULT 0,
+            decryption_status TEXT NOT NULL,
+            details TEXT,
+            FOREIGN KEY (message_id) REFERENCES messages (id) ON DELETE CASCADE
+        )",
+        [],
+    )?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS pgp_keys (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            fingerprint TEXT NOT NULL UNIQUE,
+            key_id TEXT NOT NULL,
+            user_ids TEXT NOT NULL,
+            primary_email TEXT,
+            armored_public TEXT NOT NULL,
+            armored_secret TEXT,
+            created_at INTEGER NOT NULL
+        )",
+        [],
+    )?;
+
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS idx_pgp_keys_primary_email ON pgp_keys(primary_email)",
+        [],
+    )?;
+
+    Ok(())
+}
+
+/// Migration v5: Add S/MIME certificates, PKCS#12 identities and trust anchors
+fn migration_v5_smime(conn: &Connection) -> Result<(), DEmailError> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS smime_certificates (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            fingerprint TEXT NOT NULL UNIQUE,
+            email TEXT,
+            subject TEXT NOT NULL,
+            issuer TEXT NOT NULL,
+            not_after INTEGER NOT NULL,
+            certificate_pem TEXT NOT NULL,
+            pkcs12 BLOB,
+            is_trust_anchor INTEGER NOT NULL DEFAULT 0,
+            created_at INTEGER NOT NULL
+        )",
+        [],
+    )?;
+
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS idx_smime_certificates_email ON smime_certificates(email)",
+        [],
+    )?;
+
+    Ok(())
+}
+
+/// Migration v6: Add DKIM/SPF/DMARC results, trust verdicts and the local DKIM key cache
+fn migration_v6_sender_auth(conn: &Connection) -> Result<(), DEmailError> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS message_authentication (
+            message_id INTEGER PRIMARY KEY,
+            authserv_id TEXT,
+            spf TEXT,
+            dkim TEXT,
+            dmarc TEXT,
+            arc TEXT,
+            dkim_local TEXT,
+            dkim_domain TEXT,
+            verdict TEXT NOT NULL,
+            warnings TEXT NOT NULL DEFAULT '[]',
+            FOREIGN KEY (message_id) REFERENCES messages (id) ON DELETE CASCADE
+        )",
+        [],
+    )?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS dkim_keys (
+            domain TEXT NOT NULL,
+            selector TEXT NOT NULL,
+            record TEXT NOT NULL,
+            created_at INTEGER NOT NULL,
+            PRIMARY KEY (domain, selector)
+        )",
+        [],
+    )?;
+
+    Ok(())
+}
+
+/// Migration v7: Add "always load remote content" exceptions and the proxied image cache
+fn migration_v7_remote_content(conn: &Connection) -> Result<(), DEmailError> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS remote_content_exceptions (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            kind TEXT NOT NULL,
+            value TEXT NOT NULL,
+            created_at INTEGER NOT NULL,
+            UNIQUE (kind, value)
+        )",
+        [],
+    )?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS remote_content_cache (
+            url_hash TEXT PRIMARY KEY,
+            url TEXT NOT NULL,
+            mime_type TEXT NOT NULL,
+            data BLOB NOT NULL,
+            fetched_at INTEGER NOT NULL
+        )",
+        [],
+    )?;
+
+    Ok(())
+}
+
+/// Migration v8: Flag accounts whose refresh token was rejected by the provider
+fn migration_v8_account_reauth(conn: &Connection) -> Result<(), DEmailError> {
+    match conn.execute(
+        "ALTER TABLE accounts ADD COLUMN needs_reauth INTEGER NOT NULL DEFAULT 0",
+        [],
+    ) {
+        Ok(_) => info!("Added needs_reauth column to accounts table"),
+        Err(e) => {
+            warn!("Could not add needs_reauth column (may already exist): {}", e);
+        }
+    }
+
+    Ok(())
+}
+
+/// Migration v9: Persist the outcome of each account's last sync
+fn migration_v9_sync_status(conn: &Connection) -> Result<(), DEmailError> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS sync_status (
+            account_id INTEGER PRIMARY KEY REFERENCES accounts(id) ON DELETE CASCADE,
+            state TEXT NOT NULL DEFAULT 'idle',
+            last_attempt_at INTEGER,
+            last_success_at INTEGER,
+            last_error TEXT,
+            last_error_at INTEGER,
+            messages_fetched INTEGER NOT NULL DEFAULT 0,
+            consecutive_failures INTEGER NOT NULL DEFAULT 0
+        )",
+        [],
+    )?;
+
+    Ok(())
+}
+
+/// Migration v10: Message rules, their delayed actions, the headers they match on and
+/// the messages they moved
+fn migration_v10_rules(conn: &Connection) -> Result<(), DEmailError> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS rules (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            account_id INTEGER REFERENCES accounts(id) ON DELETE CASCADE,
+            name TEXT NOT NULL,
+            enabled INTEGER NOT NULL DEFAULT 1,
+            position INTEGER NOT NULL DEFAULT 0,
+            match_all INTEGER NOT NULL DEFAULT 1,
+            stop_processing INTEGER NOT NULL DEFAULT 0,
+            conditions TEXT NOT NULL,
+            actions TEXT NOT NULL,
+            created_at INTEGER NOT NULL
+        )",
+        [],
+    )?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS rule_pending_actions (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            message_id INTEGER NOT NULL REFERENCES messages(id) ON DELETE CASCADE,
+            action TEXT NOT NULL,
+            run_at INTEGER NOT NULL
+        )",
+        [],
+    )?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS message_headers (
+            message_id INTEGER PRIMARY KEY REFERENCES messages(id) ON DELETE CASCADE,
+            headers TEXT NOT NULL,
+            size_bytes INTEGER NOT NULL
+        )",
+        [],
+    )?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS rule_moves (
+            account_id INTEGER NOT NULL REFERENCES accounts(id) ON DELETE CASCADE,
+            folder_path TEXT NOT NULL,
+            message_id_header TEXT NOT NULL,
+            labels TEXT NOT NULL,
+            moved_at INTEGER NOT NULL,
+            PRIMARY KEY (account_id, folder_path, message_id_header)
+        )",
+        [],
+    )?;
+
+    Ok(())
+}
+
+/// Migration v11: Tags live in `message_flags`, looked up by tag for counts and filters
+fn migration_v11_tags(conn: &Connection) -> Result<(), DEmailError> {
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS idx_message_flags_flag ON message_flags(flag)",
+        [],
+    )?;
+
+    Ok(())
+}
+
+/// Migration v12: Map folders and UIDs to messages stored once, and merge the copies
+/// stored per folder before. Raw messages were not kept, so earlier copies are matched
+/// on Message-ID, sender, subject and date rather than a content hash.
+fn migration_v12_message_locations(conn: &Connection) -> Result<(), DEmailError> {
+    match conn.execute("ALTER TABLE messages ADD COLUMN content_hash TEXT", []) {
+        Ok(_) => info!("Added content_hash column to messages table"),
+        Err(e) => {
+            warn!("Could not add content_hash column (may already exist): {}", e);
+        }
+    }
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS message_locations (
+            message_id INTEGER NOT NULL REFERENCES messages(id) ON DELETE CASCADE,
+            folder_id INTEGER NOT NULL REFERENCES folders(id) ON DELETE CASCADE,
+            imap_uid INTEGER NOT NULL,
+            PRIMARY KEY (folder_id, imap_uid)
+        )",
+        [],
+    )?;
+    conn.execute(
+        "INSERT OR IGNORE INTO message_locations (message_id, folder_id, imap_uid)
+         SELECT id, folder_id, imap_uid FROM messages",
+        [],
+    )?;
+
+    // Each copy with the message it is merged into
+    conn.execute(
+        "CREATE TEMP TABLE message_copies AS
+         SELECT m.id AS id, g.keeper AS keeper
+         FROM messages m
+         JOIN (SELECT account_id, message_id_header, from_header,
+                      COALESCE(subject, '') AS subject, date, MIN(id) AS keeper
+               FROM messages
+               WHERE COALESCE(message_id_header, '') != ''
+               GROUP BY account_id, message_id_header, from_header, COALESCE(subject, ''), date
+               HAVING COUNT(*) > 1) g
+           ON g.account_id = m.account_id AND g.message_id_header = m.message_id_header
+          AND g.from_header = m.from_header AND g.subject = COALESCE(m.subject, '')
+          AND g.date = m.date
+         WHERE m.id != g.keeper",
+        [],
+    )?;
+    conn.execute(
+        "UPDATE message_locations
+         SET message_id = (SELECT keeper FROM message_copies c WHERE c.id = message_locations.message_id)
+         WHERE message_id IN (SELECT id FROM message_copies)",
+        [],
+    )?;
+    conn.execute(
+        "INSERT OR IGNORE INTO message_flags (message_id, flag)
+         SELECT c.keeper, f.flag FROM message_flags f JOIN message_copies c ON c.id = f.message_id",
+        [],
+    )?;
+    conn.execute(
+        "UPDATE messages SET
+            is_read = MAX(is_read, (SELECT MAX(d.is_read) FROM message_copies c
+                                    JOIN messages d ON d.id = c.id WHERE c.keeper = messages.id)),
+            is_starred = MAX(is_starred, (SELECT MAX(d.is_starred) FROM message_copies c
+                                          JOIN messages d ON d.id = c.id WHERE c.keeper = messages.id))
+         WHERE id IN (SELECT keeper FROM message_copies)",
+        [],
+    )?;
+    // Threads must not go with the copies they start or end with
+    conn.execute(
+        "UPDATE threads SET first_message_id =
+            (SELECT keeper FROM message_copies c WHERE c.id = threads.first_message_id)
+         WHERE first_message_id IN (SELECT id FROM message_copies)",
+        [],
+    )?;
+    conn.execute(
+        "UPDATE threads SET last_message_id =
+            (SELECT keeper FROM message_copies c WHERE c.id = threads.last_message_id)
+         WHERE last_message_id IN (SELECT id FROM message_copies)",
+        [],
+    )?;
+    conn.execute(
+        "DELETE FROM messages WHERE id IN (SELECT id FROM message_copies)",
+        [],
+    )?;
+    conn.execute(
+        "UPDATE threads SET message_count = MAX(1,
+            (SELECT COUNT(*) FROM messages m WHERE m.thread_id = threads.id))",
+        [],
+    )?;
+    conn.execute("DROP TABLE message_copies", [])?;
+
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS idx_message_locations_message ON message_locations(message_id)",
+        [],
+    )?;
+    // Not in `create_indexes`, which runs before this migration adds the column
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS idx_messages_content_hash ON messages(account_id, content_hash)",
+        [],
+    )?;
+
+    Ok(())
+}
+
+/// Migration v13: Snoozed messages and follow-up reminders
+fn migration_v13_snoozes(conn: &Connection) -> Result<(), DEmailError> {
+    match conn.execute("ALTER TABLE messages ADD COLUMN resurfaced_at INTEGER", []) {
+        Ok(_) => info!("Added resurfaced_at column to messages table"),
+        Err(e) => {
+            warn!("Could not add resurfaced_at column (may already exist): {}", e);
+        }
+    }
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS snoozes (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            message_id INTEGER NOT NULL REFERENCES messages(id) ON DELETE CASCADE,
+            kind TEXT NOT NULL,
+            due_at INTEGER NOT NULL,
+            created_at INTEGER NOT NULL,
+            UNIQUE (message_id, kind)
+        )",
+        [],
+    )?;
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS idx_snoozes_due_at ON snoozes(due_at)",
+        [],
+    )?;
+
+    Ok(())
+}
+
+fn migration_v14_identities(conn: &Connection) -> Result<(), DEmailError> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS identities (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            account_id INTEGER NOT NULL REFERENCES accounts(id) ON DELETE CASCADE,
+            email_address TEXT NOT NULL,
+            display_name TEXT NOT NULL DEFAULT '',
+            reply_to TEXT,
+            signature_id INTEGER REFERENCES signatures(id) ON DELETE SET NULL,
+            smtp_host TEXT,
+            smtp_port INTEGER,
+            smtp_security TEXT,
+            smtp_username TEXT,
+            is_default INTEGER NOT NULL DEFAULT 0,
+            UNIQUE (account_id, email_address)
+        )",
+        [],
+    )?;
+
+    Ok(())
+}
+
+fn migration_v15_default_signature(conn: &Connection) -> Result<(), DEmailError> {
+    // Keep the newest of any defaults saved side by side before this was enforced
+    conn.execute(
+        "UPDATE signatures SET is_default = 0
+         WHERE is_default = 1 AND id NOT IN (
+             SELECT MAX(id) FROM signatures WHERE is_default = 1 GROUP BY account_id
+         )",
+        [],
+    )?;
+    conn.execute(
+        "CREATE UNIQUE INDEX IF NOT EXISTS idx_signatures_one_default
+         ON signatures(account_id) WHERE is_default = 1",
+        [],
+    )?;
+
+    Ok(())
+}
+
+fn migration_v16_templates(conn: &Connection) -> Result<(), DEmailError> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS templates (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            account_id INTEGER REFERENCES accounts(id) ON DELETE CASCADE,
+            name TEXT NOT NULL,
+            subject TEXT NOT NULL DEFAULT '',
+            body TEXT NOT NULL DEFAULT '',
+            created_at INTEGER NOT NULL,
+            updated_at INTEGER NOT NULL
+        )",
+        [],
+    )?;
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS template_attachments (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            template_id INTEGER NOT NULL REFERENCES templates(id) ON DELETE CASCADE,
+            filename TEXT NOT NULL,
+            mime_type TEXT NOT NULL,
+            data BLOB NOT NULL
+        )",
+        [],
+    )?;
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS draft_attachments (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            draft_id INTEGER NOT NULL REFERENCES drafts(id) ON DELETE CASCADE,
+            filename TEXT NOT NULL,
+            mime_type TEXT NOT NULL,
+            data BLOB NOT NULL
+        )",
+        [],
+    )?;
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS idx_template_attachments_template
+         ON template_attachments(template_id)",
+        [],
+    )?;
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS idx_draft_attachments_draft ON draft_attachments(draft_id)",
+        [],
+    )?;
+
+    Ok(())
+}
+
+/// Migration v17: Server copies of drafts. `revision` counts local changes; a draft
+/// whose `synced_revision` lags behind it still has to be uploaded.
+fn migration_v17_draft_sync(conn: &Connection) -> Result<(), DEmailError> {
+    for column in [
+        "message_id_header TEXT",
+        "remote_uid INTEGER",
+        "remote_uid_validity INTEGER",
+        "revision INTEGER NOT NULL DEFAULT 1",
+        "synced_revision INTEGER",
+        "imported_revision INTEGER",
+        "conflict INTEGER NOT NULL DEFAULT 0",
+        "deleted INTEGER NOT NULL DEFAULT 0",
+    ] {
+        match conn.execute(&format!("ALTER TABLE drafts ADD COLUMN {}", column), []) {
+            Ok(_) => info!("Added {} column to drafts table", column),
+            Err(e) => {
+                warn!("Could not add {} column (may already exist): {}", column, e);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Migration v18: Signer certificates were stored whether or not their chain verified.
+/// Only identities and trust anchors are known to be trusted; other certificates are
+/// no longer encrypted to until they are imported again or arrive on a verified signature.
+fn migration_v18_smime_trusted(conn: &Connection) -> Result<(), DEmailError> {
+    match conn.execute(
+        "ALTER TABLE smime_certificates ADD COLUMN is_trusted INTEGER NOT NULL DEFAULT 0",
+        [],
+    ) {
+        Ok(_) => info!("Added is_trusted column to smime_certificates table"),
+        Err(e) => {
+            warn!("Could not add is_trusted column (may already exist): {}", e);
+        }
+    }
+    conn.execute(
+        "UPDATE smime_certificates SET is_trusted = 1
+         WHERE pkcs12 IS NOT NULL OR is_trust_anchor = 1",
+        [],
+    )?;
+
+    Ok(())
+}
+
+/// Migration v19: Gmail gives each message an X-GM-MSGID shared by all its label
+/// folders, which identifies copies better than the content hash
+fn migration_v19_gmail_msgid(conn: &Connection) -> Result<(), DEmailError> {
+    match conn.execute("ALTER TABLE messages ADD COLUMN gm_msgid TEXT", []) {
+        Ok(_) => info!("Added gm_msgid column to messages table"),
+        Err(e) => {
+            warn!("Could not add gm_msgid column (may already exist): {}", e);
+        }
+    }
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS idx_messages_gm_msgid ON messages(account_id, gm_msgid)",
+        [],
+    )?;
+
+    Ok(())
+}
+
+fn migration_v20_default_identity(conn: &Connection) -> Result<(), DEmailError> {
+    // Keep the newest of any defaults saved side by side before this was enforced
+    conn.execute(
+        "UPDATE identities SET is_default = 0
+         WHERE is_default = 1 AND id NOT IN (
+             SELECT MAX(id) FROM identities WHERE is_default = 1 GROUP BY account_id
+         )",
+        [],
+    )?;
+    conn.execute(
+        "CREATE UNIQUE INDEX IF NOT EXISTS idx_identities_one_default
+         ON identities(account_id) WHERE is_default = 1",
+        [],
+    )?;
+
+    Ok(())
+}
+
 /// Rolls back the last applied migration (for development/testing)
 /// WARNING: This can cause data loss!
 pub fn rollback_last_migration(conn: &Connection) -> Result<(), DEmailError> {
@@ -233,4 +783,114 @@ mod tests {
         let version = get_current_version(&conn).unwrap();
         assert!(version >= 1);
     }
+
+    #[test]
+    fn test_apply_migrations_reaches_latest_version() {
+        let conn = Connection::open_in_memory().unwrap();
+
+        crate::core::cache::schema::initialize_schema(&conn).unwrap();
+        apply_migrations(&conn).unwrap();
+
+        assert_eq!(get_current_version(&conn).unwrap(), latest_version());
+    }
+
+    #[test]
+    fn test_v12_merges_copies_stored_per_folder() {
+        let conn = Connection::open_in_memory().unwrap();
+        crate::core::cache::schema::initialize_schema(&conn).unwrap();
+        conn.execute_batch(
+            "INSERT INTO accounts (id, email_address, display_name, provider_type)
+                 VALUES (1, 'me@example.com', 'Me', 'google');
+             INSERT INTO folders (id, account_id, name, path) VALUES (1, 1, 'INBOX', 'INBOX');
+             INSERT INTO folders (id, account_id, name, path) VALUES (2, 1, 'Work', 'Work');
+             INSERT INTO messages (id, account_id, folder_id, imap_uid, message_id_header,
+                 from_header, to_header, subject, date, is_starred)
+                 VALUES (1, 1, 1, 10, '<a@example.com>', 'alice', 'me', 'Hi', 5, 0),
+                        (2, 1, 2, 20, '<a@example.com>', 'alice', 'me', 'Hi', 5, 1),
+                        (3, 1, 2, 21, '<b@example.com>', 'bob', 'me', 'Hi', 5, 0);
+             INSERT INTO message_flags (message_id, flag) VALUES (2, 'work');",
+        )
+        .unwrap();
+
+        apply_migrations(&conn).unwrap();
+
+        let ids: Vec<i64> = conn
+            .prepare("SELECT id FROM messages ORDER BY id")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(ids, vec![1, 3]);
+        let locations: Vec<(i64, i64, u32)> = conn
+            .prepare("SELECT message_id, folder_id, imap_uid FROM message_locations ORDER BY folder_id, imap_uid")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(locations, vec![(1, 1, 10), (1, 2, 20), (3, 2, 21)]);
+        let (flag, starred): (String, bool) = conn
+            .query_row(
+                "SELECT f.flag, m.is_starred FROM message_flags f JOIN messages m ON m.id = f.message_id",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!((flag.as_str(), starred), ("work", true));
+    }
+
+    #[test]
+    fn test_v15_keeps_one_default_signature() {
+        let conn = Connection::open_in_memory().unwrap();
+        crate::core::cache::schema::initialize_schema(&conn).unwrap();
+        conn.execute_batch(
+            "INSERT INTO accounts (id, email_address, display_name, provider_type)
+                 VALUES (1, 'me@example.com', 'Me', 'google');
+             INSERT INTO signatures (id, account_id, name, content_html, content_plain, is_default)
+                 VALUES (1, 1, 'Old', '', '', 1), (2, 1, 'New', '', '', 1);",
+        )
+        .unwrap();
+
+        apply_migrations(&conn).unwrap();
+
+        let defaults: Vec<i64> = conn
+            .prepare("SELECT id FROM signatures WHERE is_default = 1")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(defaults, vec![2]);
+        assert!(conn
+            .execute("UPDATE signatures SET is_default = 1 WHERE id = 1", [])
+            .is_err());
+    }
+
+    #[test]
+    fn test_v20_keeps_one_default_identity() {
+        let conn = Connection::open_in_memory().unwrap();
+        crate::core::cache::schema::initialize_schema(&conn).unwrap();
+        conn.execute_batch(
+            "INSERT INTO accounts (id, email_address, display_name, provider_type)
+                 VALUES (1, 'me@example.com', 'Me', 'google');
+             INSERT INTO identities (id, account_id, email_address, display_name, is_default)
+                 VALUES (1, 1, 'old@example.com', '', 1), (2, 1, 'new@example.com', '', 1);",
+        )
+        .unwrap();
+
+        apply_migrations(&conn).unwrap();
+
+        let defaults: Vec<i64> = conn
+            .prepare("SELECT id FROM identities WHERE is_default = 1")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(defaults, vec![2]);
+        assert!(conn
+            .execute("UPDATE identities SET is_default = 1 WHERE id = 1", [])
+            .is_err());
+    }
 }
@@ -1,15 +1,33 @@
 pub mod accounts;
+pub mod app_lock;
 pub mod attachments;
 pub mod auth;
+pub mod backup;
 pub mod cache;
 pub mod config;
 pub mod contacts;
+pub mod crypto;
+pub mod device_flow;
+pub mod dns;
 pub mod drafts;
 pub mod export;
+pub mod identities;
 pub mod migrations;
+pub mod notifications;
+pub mod oauth_loopback;
+pub mod providers;
+pub mod remote_content;
 pub mod reply_forward;
+pub mod rules;
+pub mod sender_auth;
+pub mod sieve;
+pub mod signatures;
+pub mod snooze;
 pub mod sync;
+pub mod tags;
+pub mod templates;
 pub mod threading;
+pub mod tokens;
 pub mod validation;
 
 #[cfg(test)]
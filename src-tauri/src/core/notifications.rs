@@ -0,0 +1,548 @@
+// Notifications - Native notifications for mail that arrives during a sync, grouped per
+// account and filtered by the per-account and per-folder settings, VIP senders and
+// quiet hours. Tauri 1 notifications cannot carry buttons, so each notification is also
+// emitted as a `new-mail` event for the window to offer its actions.
+
+use crate::core::cache::db::{get_setting, save_setting, Pool};
+use crate::core::contacts::parse_email_address;
+use crate::core::rules::RuleAction;
+use crate::core::sync::rule_actions;
+use crate::error::DEmailError;
+use crate::models::Account;
+use crate::AppState;
+use chrono::Timelike;
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use tauri::{AppHandle, Manager};
+
+const SETTING_NOTIFICATIONS: &str = "notification_settings";
+
+/// Emitted with a `MailNotification` for each notification raised
+pub const NEW_MAIL_EVENT: &str = "new-mail";
+/// Emitted with the `UnreadCount` after each sync
+pub const UNREAD_COUNT_EVENT: &str = "unread-count";
+
+/// Messages listed in the body of a grouped notification
+const GROUP_PREVIEW: usize = 3;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NotifyMode {
+    #[default]
+    All,
+    /// Only mail from VIP senders
+    VipOnly,
+    Off,
+}
+
+/// Local times, in minutes after midnight; the range wraps past midnight when `start`
+/// is later than `end`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QuietHours {
+    pub start: u32,
+    pub end: u32,
+}
+
+impl QuietHours {
+    pub fn contains(&self, minute: u32) -> bool {
+        if self.start <= self.end {
+            (self.start..self.end).contains(&minute)
+        } else {
+            minute >= self.start || minute < self.end
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NotificationSettings {
+    pub enabled: bool,
+    /// Per-account modes; accounts not listed notify for all mail
+    #[serde(default)]
+    pub accounts: HashMap<i64, NotifyMode>,
+    /// Per-folder switches; folders not listed notify only when they are an inbox
+    #[serde(default)]
+    pub folders: HashMap<i64, bool>,
+    /// Addresses whose mail notifies even in quiet hours and `vip_only` accounts
+    #[serde(default)]
+    pub vip_senders: Vec<String>,
+    /// Only VIP mail notifies during these hours
+    #[serde(default)]
+    pub quiet_hours: Option<QuietHours>,
+}
+
+impl Default for NotificationSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            accounts: HashMap::new(),
+            folders: HashMap::new(),
+            vip_senders: Vec::new(),
+            quiet_hours: None,
+        }
+    }
+}
+
+impl NotificationSettings {
+    /// Whether the sender's address is one of the VIP addresses, ignoring case
+    fn is_vip(&self, from_header: &str) -> bool {
+        let Some((from, _)) = parse_email_address(from_header) else {
+            return false;
+        };
+        self.vip_senders
+            .iter()
+            .any(|vip| vip.trim().eq_ignore_ascii_case(&from))
+    }
+}
+
+pub fn get_settings(pool: &Pool) -> Result<NotificationSettings, DEmailError> {
+    match get_setting(pool, SETTING_NOTIFICATIONS)? {
+        Some(value) => Ok(serde_json::from_str(&value)?),
+        None => Ok(NotificationSettings::default()),
+    }
+}
+
+pub fn save_settings(pool: &Pool, settings: &NotificationSettings) -> Result<(), DEmailError> {
+    if let Some(quiet) = &settings.quiet_hours {
+        if quiet.start >= 24 * 60 || quiet.end >= 24 * 60 {
+            return Err(DEmailError::Validation(
+                "Quiet hours must be times of day".to_string(),
+            ));
+        }
+    }
+    save_setting(
+        pool,
+        SETTING_NOTIFICATIONS,
+        &serde_json::to_string(settings)?,
+    )
+}
+
+/// A message that arrived during a sync and is still unread where it landed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Arrival {
+    pub id: i64,
+    pub folder_id: i64,
+    pub folder_path: String,
+    pub from_header: String,
+    pub subject: String,
+    pub vip: bool,
+}
+
+/// The arrivals that should raise a notification at `minute` (local time, minutes
+/// after midnight). Messages that rules marked read, moved or deleted, or that were
+/// snoozed meanwhile, are left out.
+pub fn notifiable(
+    conn: &Connection,
+    settings: &NotificationSettings,
+    account_id: i64,
+    message_ids: &[i64],
+    minute: u32,
+) -> Result<Vec<Arrival>, DEmailError> {
+    let mode = settings
+        .accounts
+        .get(&account_id)
+        .copied()
+        .unwrap_or_default();
+    if !settings.enabled || mode == NotifyMode::Off {
+        return Ok(Vec::new());
+    }
+    let quiet = settings
+        .quiet_hours
+        .is_some_and(|quiet| quiet.contains(minute));
+
+    // A message found in several folders, like Gmail's Inbox and All Mail, notifies
+    // when any of them does
+    let mut stmt = conn.prepare(
+        "SELECT m.id, f.id, f.path, m.from_header, COALESCE(m.subject, '')
+         FROM messages m
+         JOIN message_locations l ON l.message_id = m.id
+         JOIN folders f ON f.id = l.folder_id
+         WHERE m.id = ?1 AND m.account_id = ?2 AND m.is_read = 0
+           AND NOT EXISTS (SELECT 1 FROM snoozes s WHERE s.message_id = m.id AND s.kind = 'snooze')
+         ORDER BY f.id",
+    )?;
+    let mut arrivals = Vec::new();
+    for &message_id in message_ids {
+        let locations = stmt
+            .query_map(params![message_id, account_id], |row| {
+                Ok(Arrival {
+                    id: row.get(0)?,
+                    folder_id: row.get(1)?,
+                    folder_path: row.get(2)?,
+                    from_header: row.get(3)?,
+                    subject: row.get(4)?,
+                    vip: false,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        let Some(mut arrival) = locations.into_iter().find(|location| {
+            settings
+                .folders
+                .get(&location.folder_id)
+                .copied()
+                .unwrap_or_else(|| location.folder_path.eq_ignore_ascii_case("INBOX"))
+        }) else {
+            continue;
+        };
+        arrival.vip = settings.is_vip(&arrival.from_header);
+        if arrival.vip || (mode == NotifyMode::All && !quiet) {
+            arrivals.push(arrival);
+        }
+    }
+    Ok(arrivals)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MailNotification {
+    pub account_id: i64,
+    pub title: String,
+    pub body: String,
+    pub message_ids: Vec<i64>,
+}
+
+/// One notification per VIP message, then one for the rest. `private` leaves out
+/// senders and subjects, as while the app is locked.
+pub fn group(account: &Account, arrivals: &[Arrival], private: bool) -> Vec<MailNotification> {
+    let (vips, others): (Vec<_>, Vec<_>) = arrivals.iter().partition(|arrival| arrival.vip);
+    let mut notifications = Vec::new();
+    if private {
+        if !arrivals.is_empty() {
+            notifications.push(MailNotification {
+                account_id: account.id,
+                title: account.email_address.clone(),
+                body: count_text(arrivals.len()),
+                message_ids: arrivals.iter().map(|arrival| arrival.id).collect(),
+            });
+        }
+        return notifications;
+    }
+
+    for vip in vips {
+        notifications.push(single(account, vip));
+    }
+    match others.as_slice() {
+        [] => {}
+        [arrival] => notifications.push(single(account, arrival)),
+        others => {
+            let mut lines: Vec<String> = others
+                .iter()
+                .take(GROUP_PREVIEW)
+                .map(|arrival| {
+                    format!(
+                        "{}: {}",
+                        sender_name(&arrival.from_header),
+                        subject(arrival)
+                    )
+                })
+                .collect();
+            if others.len() > GROUP_PREVIEW {
+                lines.push(format!("and {} more", others.len() - GROUP_PREVIEW));
+            }
+            notifications.push(MailNotification {
+                account_id: account.id,
+                title: format!("{} – {}", count_text(others.len()), account.email_address),
+                body: lines.join("\n"),
+                message_ids: others.iter().map(|arrival| arrival.id).collect(),
+            });
+        }
+    }
+    notifications
+}
+
+fn single(account: &Account, arrival: &Arrival) -> MailNotification {
+    MailNotification {
+        account_id: account.id,
+        title: sender_name(&arrival.from_header),
+        body: subject(arrival),
+        message_ids: vec![arrival.id],
+    }
+}
+
+fn subject(arrival: &Arrival) -> String {
+    if arrival.subject.is_empty() {
+        "(No Subject)".to_string()
+    } else {
+        arrival.subject.clone()
+    }
+}
+
+fn count_text(count: usize) -> String {
+    if count == 1 {
+        "1 new message".to_string()
+    } else {
+        format!("{} new messages", count)
+    }
+}
+
+/// The display name of a From header, or the address when there is none
+fn sender_name(from_header: &str) -> String {
+    let name = from_header
+        .split('<')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .trim_matches('"')
+        .trim();
+    if name.is_empty() {
+        from_header
+            .trim()
+            .trim_start_matches('<')
+            .trim_end_matches('>')
+            .to_string()
+    } else {
+        name.to_string()
+    }
+}
+
+/// What can be done from a new-mail notification
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NotificationAction {
+    MarkRead,
+    Archive,
+}
+
+/// The account's archive folder: All Mail on Gmail, otherwise a folder named Archive
+pub fn archive_folder(conn: &Connection, account_id: i64) -> Result<Option<String>, DEmailError> {
+    let mut stmt = conn.prepare("SELECT path, name FROM folders WHERE account_id = ?1")?;
+    let folders = stmt
+        .query_map([account_id], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    let gmail = folders
+        .iter()
+        .find(|(path, _)| path == "[Gmail]/All Mail" || path == "[Google Mail]/All Mail");
+    let named = folders
+        .iter()
+        .find(|(_, name)| name.eq_ignore_ascii_case("Archive"));
+    Ok(gmail.or(named).map(|(path, _)| path.clone()))
+}
+
+/// Carries out a notification action on the server and locally
+pub async fn run_action(
+    app_state: &AppState,
+    account_id: i64,
+    message_ids: &[i64],
+    action: NotificationAction,
+) -> Result<(), DEmailError> {
+    let rule_action = match action {
+        NotificationAction::MarkRead => RuleAction::MarkRead,
+        NotificationAction::Archive => {
+            let conn = app_state
+                .db_pool
+                .get()
+                .map_err(|_| DEmailError::Database(rusqlite::Error::InvalidQuery))?;
+            let folder = archive_folder(&conn, account_id)?.ok_or_else(|| {
+                DEmailError::Validation("The account has no archive folder".to_string())
+            })?;
+            RuleAction::Move { folder }
+        }
+    };
+    rule_actions::run_on_messages(app_state, account_id, message_ids, &rule_action).await
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AccountUnread {
+    pub account_id: i64,
+    pub unread: i64,
+}
+
+/// Unread mail in the inboxes, for the window title and badge
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UnreadCount {
+    pub total: i64,
+    pub accounts: Vec<AccountUnread>,
+}
+
+pub fn unread_count(pool: &Pool) -> Result<UnreadCount, DEmailError> {
+    let conn = pool
+        .get()
+        .map_err(|_| DEmailError::Database(rusqlite::Error::InvalidQuery))?;
+    let mut stmt = conn.prepare(
+        "SELECT a.id, COUNT(DISTINCT m.id)
+         FROM accounts a
+         LEFT JOIN folders f ON f.account_id = a.id AND f.path = 'INBOX' COLLATE NOCASE
+         LEFT JOIN message_locations l ON l.folder_id = f.id
+         LEFT JOIN messages m ON m.id = l.message_id AND m.is_read = 0
+             AND NOT EXISTS (SELECT 1 FROM snoozes s WHERE s.message_id = m.id AND s.kind = 'snooze')
+         GROUP BY a.id
+         ORDER BY a.id",
+    )?;
+    let accounts = stmt
+        .query_map([], |row| {
+            Ok(AccountUnread {
+                account_id: row.get(0)?,
+                unread: row.get(1)?,
+            })
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(UnreadCount {
+        total: accounts.iter().map(|account| account.unread).sum(),
+        accounts,
+    })
+}
+
+/// Raises the notifications for mail that arrived in one sync of an account, and
+/// emits the new unread count
+pub fn notify_arrivals(app_handle: &AppHandle, account: &Account, message_ids: &[i64]) {
+    let app_state = app_handle.state::<AppState>();
+    let pool = &app_state.db_pool;
+
+    if !message_ids.is_empty() {
+        let minute = {
+            let now = chrono::Local::now();
+            now.hour() * 60 + now.minute()
+        };
+        let arrivals = get_settings(pool).and_then(|settings| {
+            let conn = pool
+                .get()
+                .map_err(|_| DEmailError::Database(rusqlite::Error::InvalidQuery))?;
+            notifiable(&conn, &settings, account.id, message_ids, minute)
+        });
+        match arrivals {
+            Ok(arrivals) => {
+                let private = app_state.app_lock.is_locked();
+                let identifier = app_handle.config().tauri.bundle.identifier.clone();
+                for notification in group(account, &arrivals, private) {
+                    if let Err(e) = tauri::api::notification::Notification::new(&identifier)
+                        .title(&notification.title)
+                        .body(&notification.body)
+                        .show()
+                    {
+                        log::warn!("Could not show notification: {}", e);
+                    }
+                    if !private {
+                        let _ = app_handle.emit_all(NEW_MAIL_EVENT, notification);
+                    }
+                }
+            }
+            Err(e) => log::warn!("Could not check new mail for notifications: {}", e),
+        }
+    }
+
+    match unread_count(pool) {
+        Ok(count) => {
+            let _ = app_handle.emit_all(UNREAD_COUNT_EVENT, count);
+        }
+        Err(e) => log::warn!("Could not count unread mail: {}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::accounts::create_account;
+    use crate::core::cache::db::{save_message, test_folder, test_message, test_pool};
+    use crate::models::Message;
+
+    fn store(pool: &Pool, account_id: i64, folder_id: i64, uid: u32, from: &str) -> i64 {
+        save_message(
+            pool,
+            &Message {
+                from_header: from.to_string(),
+                ..test_message(account_id, folder_id, uid)
+            },
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_quiet_hours_wrap_past_midnight() {
+        let night = QuietHours {
+            start: 22 * 60,
+            end: 7 * 60,
+        };
+        assert!(night.contains(23 * 60));
+        assert!(night.contains(60));
+        assert!(!night.contains(7 * 60));
+        assert!(!night.contains(12 * 60));
+        let lunch = QuietHours {
+            start: 12 * 60,
+            end: 13 * 60,
+        };
+        assert!(lunch.contains(12 * 60 + 30));
+        assert!(!lunch.contains(13 * 60));
+    }
+
+    #[test]
+    fn test_notifiable_and_grouping() {
+        let pool = test_pool();
+        let account = create_account(&pool, "me@example.com", "Me", "google").unwrap();
+        let inbox = test_folder(&pool, account.id, "INBOX").id;
+        let lists = test_folder(&pool, account.id, "Lists").id;
+        let boss = store(&pool, account.id, inbox, 1, "The Boss <boss@example.com>");
+        let alice = store(&pool, account.id, inbox, 2, "\"Alice\" <alice@example.com>");
+        let bob = store(&pool, account.id, inbox, 3, "bob@example.com");
+        let list = store(&pool, account.id, lists, 4, "list@example.com");
+        let lookalike = store(&pool, account.id, inbox, 5, "notboss@example.com.evil");
+        let ids = [boss, alice, bob, list, lookalike];
+        let conn = pool.get().unwrap();
+
+        let mut settings = NotificationSettings {
+            vip_senders: vec!["Boss@example.com".to_string()],
+            quiet_hours: Some(QuietHours {
+                start: 22 * 60,
+                end: 7 * 60,
+            }),
+            ..Default::default()
+        };
+        let noon = 12 * 60;
+        let arrivals = notifiable(&conn, &settings, account.id, &ids, noon).unwrap();
+        // Only inboxes notify unless a folder is switched on
+        assert_eq!(
+            arrivals.iter().map(|a| a.id).collect::<Vec<_>>(),
+            vec![boss, alice, bob, lookalike]
+        );
+
+        let arrivals = &arrivals[..3];
+        let notifications = group(&account, arrivals, false);
+        assert_eq!(notifications.len(), 2);
+        assert_eq!(notifications[0].title, "The Boss");
+        assert_eq!(notifications[0].message_ids, vec![boss]);
+        assert_eq!(notifications[1].title, "2 new messages – me@example.com");
+        assert_eq!(
+            notifications[1].body,
+            "Alice: Message 2\nbob@example.com: Message 3"
+        );
+        let private = group(&account, arrivals, true);
+        assert_eq!(private.len(), 1);
+        assert_eq!(private[0].body, "3 new messages");
+
+        // Quiet hours and VIP-only accounts let VIP mail through
+        let night = 23 * 60;
+        let arrivals = notifiable(&conn, &settings, account.id, &ids, night).unwrap();
+        assert_eq!(
+            arrivals.iter().map(|a| a.id).collect::<Vec<_>>(),
+            vec![boss]
+        );
+        settings.accounts.insert(account.id, NotifyMode::VipOnly);
+        let arrivals = notifiable(&conn, &settings, account.id, &ids, noon).unwrap();
+        assert_eq!(
+            arrivals.iter().map(|a| a.id).collect::<Vec<_>>(),
+            vec![boss]
+        );
+
+        // Folder switches, and mail read meanwhile stays quiet
+        settings.accounts.clear();
+        settings.folders.insert(lists, true);
+        settings.folders.insert(inbox, false);
+        conn.execute("UPDATE messages SET is_read = 1 WHERE id = ?1", [boss])
+            .unwrap();
+        let arrivals = notifiable(&conn, &settings, account.id, &ids, noon).unwrap();
+        assert_eq!(
+            arrivals.iter().map(|a| a.id).collect::<Vec<_>>(),
+            vec![list]
+        );
+
+        assert_eq!(unread_count(&pool).unwrap().total, 3);
+        assert_eq!(archive_folder(&conn, account.id).unwrap(), None);
+        test_folder(&pool, account.id, "Archive");
+        assert_eq!(
+            archive_folder(&conn, account.id).unwrap(),
+            Some("Archive".to_string())
+        );
+        save_settings(&pool, &settings).unwrap();
+        assert_eq!(get_settings(&pool).unwrap(), settings);
+    }
+}
@@ -0,0 +1,291 @@
+// OAuth loopback redirect - Receives the authorization response on an ephemeral
+// 127.0.0.1 port, as recommended for native apps by RFC 8252
+
+use crate::error::DEmailError;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+use tracing::debug;
+
+pub const CALLBACK_PATH: &str = "/callback";
+/// How long the user has to finish signing in before the flow is abandoned
+pub const REDIRECT_TIMEOUT: Duration = Duration::from_secs(300);
+const MAX_REQUEST_LEN: usize = 16 * 1024;
+
+const SUCCESS_PAGE: &str =
+    "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><title>DEmail</title></head>\
+<body style=\"font-family: sans-serif; text-align: center; padding-top: 4em\">\
+<h1>Sign-in complete</h1><p>You can close this window and return to DEmail.</p></body></html>";
+const ERROR_PAGE: &str =
+    "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><title>DEmail</title></head>\
+<body style=\"font-family: sans-serif; text-align: center; padding-top: 4em\">\
+<h1>Sign-in failed</h1><p>Return to DEmail and try again.</p></body></html>";
+
+/// Authorization response delivered to the redirect URI
+#[derive(Debug, PartialEq)]
+pub enum RedirectResponse {
+    Code {
+        code: String,
+        state: String,
+    },
+    Error {
+        error: String,
+        state: Option<String>,
+    },
+}
+
+/// Loopback listener bound to an ephemeral port for a single authorization request
+pub struct LoopbackListener {
+    listener: TcpListener,
+    port: u16,
+}
+
+impl LoopbackListener {
+    /// Binds to 127.0.0.1 rather than `localhost` so the redirect cannot be served by
+    /// another interface or resolve to IPv6 unexpectedly (RFC 8252 section 8.3)
+    pub async fn bind() -> Result<Self, DEmailError> {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await?;
+        let port = listener.local_addr()?.port();
+        Ok(Self { listener, port })
+    }
+
+    pub fn redirect_uri(&self) -> String {
+        format!("http://127.0.0.1:{}{}", self.port, CALLBACK_PATH)
+    }
+
+    /// Waits for the browser to hit the redirect URI, answering unrelated requests
+    /// (such as favicon lookups) with 404. Gives up after `timeout`.
+    ///
+    /// Each connection is served on its own task, since browsers open speculative
+    /// connections that may never send a request and must not hold up the redirect.
+    pub async fn wait_for_redirect(
+        self,
+        timeout: Duration,
+    ) -> Result<RedirectResponse, DEmailError> {
+        tokio::time::timeout(timeout, async {
+            let (sender, mut receiver) = mpsc::channel(1);
+            // Dropping the set on return aborts any connection still being served
+            let mut connections = JoinSet::new();
+            loop {
+                tokio::select! {
+                    accepted = self.listener.accept() => {
+                        let (stream, _) = accepted?;
+                        connections.spawn(serve_connection(stream, sender.clone()));
+                    }
+                    Some(response) = receiver.recv() => return Ok(response),
+                    Some(_) = connections.join_next() => {}
+                }
+            }
+        })
+        .await
+        .map_err(|_| {
+            DEmailError::OAuth("Timed out waiting for the sign-in to complete".to_string())
+        })?
+    }
+}
+
+/// Answers one loopback connection, passing the authorization response on once the
+/// browser has been shown the result page
+async fn serve_connection(mut stream: TcpStream, sender: mpsc::Sender<RedirectResponse>) {
+    let request_line = match read_request_line(&mut stream).await {
+        Ok(line) => line,
+        Err(e) => {
+            debug!("Ignoring malformed loopback request: {}", e);
+            return;
+        }
+    };
+
+    match parse_redirect(&request_line) {
+        Some(response) => {
+            let page = match response {
+                RedirectResponse::Code { .. } => SUCCESS_PAGE,
+                RedirectResponse::Error { .. } => ERROR_PAGE,
+            };
+            let _ = write_response(&mut stream, "200 OK", page).await;
+            let _ = sender.send(response).await;
+        }
+        None => {
+            let _ = write_response(&mut stream, "404 Not Found", "").await;
+        }
+    }
+}
+
+async fn read_request_line(stream: &mut TcpStream) -> Result<String, DEmailError> {
+    let mut buffer = Vec::new();
+    let mut chunk = [0u8; 1024];
+    while !buffer.windows(2).any(|window| window == b"\r\n") {
+        let read = stream.read(&mut chunk).await?;
+        if read == 0 || buffer.len() + read > MAX_REQUEST_LEN {
+            return Err(DEmailError::OAuth(
+                "Incomplete redirect request".to_string(),
+            ));
+        }
+        buffer.extend_from_slice(&chunk[..read]);
+    }
+    let text = String::from_utf8_lossy(&buffer);
+    Ok(text.lines().next().unwrap_or_default().to_string())
+}
+
+async fn write_response(
+    stream: &mut TcpStream,
+    status: &str,
+    body: &str,
+) -> Result<(), DEmailError> {
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// Parses `GET /callback?code=...&state=... HTTP/1.1` into the authorization response
+fn parse_redirect(request_line: &str) -> Option<RedirectResponse> {
+    let mut parts = request_line.split_whitespace();
+    if parts.next()? != "GET" {
+        return None;
+    }
+    let url = reqwest::Url::parse(&format!("http://127.0.0.1{}", parts.next()?)).ok()?;
+    if url.path() != CALLBACK_PATH {
+        return None;
+    }
+
+    let param = |name: &str| {
+        url.query_pairs()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.into_owned())
+    };
+    let state = param("state");
+    if let Some(error) = param("error") {
+        return Some(RedirectResponse::Error { error, state });
+    }
+    Some(RedirectResponse::Code {
+        code: param("code")?,
+        state: state?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_redirect() {
+        assert_eq!(
+            parse_redirect("GET /callback?state=abc%3D&code=4%2F0Ab HTTP/1.1"),
+            Some(RedirectResponse::Code {
+                code: "4/0Ab".to_string(),
+                state: "abc=".to_string(),
+            })
+        );
+        assert_eq!(
+            parse_redirect("GET /callback?error=access_denied&state=abc HTTP/1.1"),
+            Some(RedirectResponse::Error {
+                error: "access_denied".to_string(),
+                state: Some("abc".to_string()),
+            })
+        );
+        assert_eq!(parse_redirect("GET /favicon.ico HTTP/1.1"), None);
+        assert_eq!(parse_redirect("GET /callback?code=x HTTP/1.1"), None);
+        assert_eq!(
+            parse_redirect("POST /callback?code=x&state=y HTTP/1.1"),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_redirect() {
+        let listener = LoopbackListener::bind().await.unwrap();
+        let redirect_uri = listener.redirect_uri();
+        assert!(redirect_uri.starts_with("http://127.0.0.1:"));
+        let address = redirect_uri
+            .trim_start_matches("http://")
+            .trim_end_matches(CALLBACK_PATH)
+            .to_string();
+
+        let browser = tokio::spawn(async move {
+            let mut favicon = TcpStream::connect(&address).await.unwrap();
+            favicon
+                .write_all(b"GET /favicon.ico HTTP/1.1\r\nHost: 127.0.0.1\r\n\r\n")
+                .await
+                .unwrap();
+            let mut response = String::new();
+            favicon.read_to_string(&mut response).await.unwrap();
+            assert!(response.starts_with("HTTP/1.1 404"));
+
+            let mut redirect = TcpStream::connect(&address).await.unwrap();
+            redirect
+                .write_all(b"GET /callback?code=abc&state=xyz HTTP/1.1\r\nHost: 127.0.0.1\r\n\r\n")
+                .await
+                .unwrap();
+            let mut response = String::new();
+            redirect.read_to_string(&mut response).await.unwrap();
+            assert!(response.contains("Sign-in complete"));
+        });
+
+        let response = listener
+            .wait_for_redirect(Duration::from_secs(10))
+            .await
+            .unwrap();
+        browser.await.unwrap();
+        assert_eq!(
+            response,
+            RedirectResponse::Code {
+                code: "abc".to_string(),
+                state: "xyz".to_string(),
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_idle_connection_does_not_block_redirect() {
+        let listener = LoopbackListener::bind().await.unwrap();
+        let address = listener
+            .redirect_uri()
+            .trim_start_matches("http://")
+            .trim_end_matches(CALLBACK_PATH)
+            .to_string();
+
+        let browser = tokio::spawn(async move {
+            // A preconnected socket that never sends a request
+            let idle = TcpStream::connect(&address).await.unwrap();
+
+            let mut redirect = TcpStream::connect(&address).await.unwrap();
+            redirect
+                .write_all(b"GET /callback?code=abc&state=xyz HTTP/1.1\r\nHost: 127.0.0.1\r\n\r\n")
+                .await
+                .unwrap();
+            let mut response = String::new();
+            redirect.read_to_string(&mut response).await.unwrap();
+            assert!(response.contains("Sign-in complete"));
+            idle
+        });
+
+        let response = listener
+            .wait_for_redirect(Duration::from_secs(10))
+            .await
+            .unwrap();
+        drop(browser.await.unwrap());
+        assert_eq!(
+            response,
+            RedirectResponse::Code {
+                code: "abc".to_string(),
+                state: "xyz".to_string(),
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_redirect_times_out() {
+        let listener = LoopbackListener::bind().await.unwrap();
+        assert!(listener
+            .wait_for_redirect(Duration::from_millis(50))
+            .await
+            .is_err());
+    }
+}
@@ -0,0 +1,586 @@
+// Provider registry - OAuth endpoints, scopes, mail servers and domain detection for
+// built-in and user-defined providers
+
+use crate::core::config::load_config;
+use crate::core::dns;
+use crate::error::DEmailError;
+use crate::models::OAuthProviderConfig;
+use oauth2::basic::{
+    BasicClient, BasicErrorResponse, BasicRevocationErrorResponse, BasicTokenIntrospectionResponse,
+    BasicTokenType,
+};
+use oauth2::{
+    AuthUrl, Client, ClientId, ClientSecret, StandardRevocableToken, TokenResponse, TokenUrl,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use tauri::Config;
+use tracing::{debug, info};
+
+/// Mail server connection security
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Security {
+    /// Implicit TLS (IMAPS 993, SMTPS 465)
+    Tls,
+    /// STARTTLS upgrade (submission 587)
+    StartTls,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServerEndpoint {
+    pub host: String,
+    pub port: u16,
+    pub security: Security,
+}
+
+/// Everything needed to sign in to and sync with one provider
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProviderDefinition {
+    /// Stored as the account's `provider_type` and used as the key in `demail_config.json`
+    pub id: String,
+    pub name: String,
+    /// OpenID Connect issuer; endpoints left empty are discovered from it
+    #[serde(default)]
+    pub issuer: Option<String>,
+    #[serde(default)]
+    pub auth_url: String,
+    #[serde(default)]
+    pub token_url: String,
+    #[serde(default)]
+    pub userinfo_url: Option<String>,
+    /// Device authorization endpoint for sign-in without a browser redirect (RFC 8628)
+    #[serde(default)]
+    pub device_auth_url: Option<String>,
+    /// Userinfo claims holding the address and display name, first match wins
+    #[serde(default = "default_email_claims")]
+    pub email_claims: Vec<String>,
+    #[serde(default = "default_name_claims")]
+    pub name_claims: Vec<String>,
+    pub scopes: Vec<String>,
+    /// Extra authorization request parameters, e.g. Google's `access_type=offline`
+    #[serde(default)]
+    pub auth_params: HashMap<String, String>,
+    pub imap: ServerEndpoint,
+    pub smtp: ServerEndpoint,
+    /// ManageSieve server (RFC 5804) for server-side filters; `None` when there is none
+    #[serde(default)]
+    pub sieve: Option<ServerEndpoint>,
+    /// Address domains served by the provider; subdomains match too
+    #[serde(default)]
+    pub domains: Vec<String>,
+    /// MX host suffixes that identify custom domains hosted by the provider
+    #[serde(default)]
+    pub mx_patterns: Vec<String>,
+    /// authserv-ids the provider's receiving servers put in Authentication-Results
+    /// (RFC 8601); subdomains match too. Results from anyone else are not trusted.
+    #[serde(default)]
+    pub authserv_ids: Vec<String>,
+    #[serde(default)]
+    pub builtin: bool,
+}
+
+fn default_email_claims() -> Vec<String> {
+    vec!["email".to_string()]
+}
+
+fn default_name_claims() -> Vec<String> {
+    vec!["name".to_string()]
+}
+
+fn strings(values: &[&str]) -> Vec<String> {
+    values.iter().map(|value| value.to_string()).collect()
+}
+
+fn endpoint(host: &str, port: u16, security: Security) -> ServerEndpoint {
+    ServerEndpoint {
+        host: host.to_string(),
+        port,
+        security,
+    }
+}
+
+/// Providers shipped with DEmail. Each becomes usable once a client id is configured.
+pub fn builtin_providers() -> Vec<ProviderDefinition> {
+    vec![
+        ProviderDefinition {
+            id: "google".to_string(),
+            name: "Google".to_string(),
+            issuer: None,
+            auth_url: "https://accounts.google.com/o/oauth2/v2/auth".to_string(),
+            token_url: "https://oauth2.googleapis.com/token".to_string(),
+            userinfo_url: Some("https://openidconnect.googleapis.com/v1/userinfo".to_string()),
+            // Google's device flow does not grant the Gmail scope
+            device_auth_url: None,
+            email_claims: default_email_claims(),
+            name_claims: default_name_claims(),
+            scopes: strings(&["https://mail.google.com/", "openid", "email", "profile"]),
+            // Google only issues refresh tokens for offline access
+            auth_params: HashMap::from([
+                ("access_type".to_string(), "offline".to_string()),
+                ("prompt".to_string(), "consent".to_string()),
+            ]),
+            imap: endpoint("imap.gmail.com", 993, Security::Tls),
+            smtp: endpoint("smtp.gmail.com", 587, Security::StartTls),
+            sieve: None,
+            domains: strings(&["gmail.com", "googlemail.com"]),
+            mx_patterns: strings(&["google.com", "googlemail.com"]),
+            authserv_ids: strings(&["mx.google.com"]),
+            builtin: true,
+        },
+        ProviderDefinition {
+            id: "microsoft".to_string(),
+            name: "Microsoft".to_string(),
+            issuer: None,
+            auth_url: "https://login.microsoftonline.com/common/oauth2/v2.0/authorize".to_string(),
+            token_url: "https://login.microsoftonline.com/common/oauth2/v2.0/token".to_string(),
+            // Microsoft Graph rejects tokens issued for the Outlook scopes, so the address
+            // is read from the ID token instead
+            userinfo_url: None,
+            device_auth_url: Some(
+                "https://login.microsoftonline.com/common/oauth2/v2.0/devicecode".to_string(),
+            ),
+            email_claims: strings(&["email", "preferred_username"]),
+            name_claims: default_name_claims(),
+            scopes: strings(&[
+                "openid",
+                "email",
+                "profile",
+                "https://outlook.office.com/IMAP.AccessAsUser.All",
+                "https://outlook.office.com/SMTP.Send",
+                "offline_access",
+            ]),
+            auth_params: HashMap::new(),
+            imap: endpoint("outlook.office365.com", 993, Security::Tls),
+            smtp: endpoint("smtp.office365.com", 587, Security::StartTls),
+            sieve: None,
+            domains: strings(&["outlook.com", "hotmail.com", "live.com", "msn.com"]),
+            mx_patterns: strings(&["mail.protection.outlook.com", "olc.protection.outlook.com"]),
+            authserv_ids: strings(&["mx.microsoft.com", "outlook.com"]),
+            builtin: true,
+        },
+        ProviderDefinition {
+            id: "yahoo".to_string(),
+            name: "Yahoo".to_string(),
+            issuer: None,
+            auth_url: "https://api.login.yahoo.com/oauth2/request_auth".to_string(),
+            token_url: "https://api.login.yahoo.com/oauth2/get_token".to_string(),
+            userinfo_url: Some("https://api.login.yahoo.com/openid/v1/userinfo".to_string()),
+            device_auth_url: None,
+            email_claims: default_email_claims(),
+            name_claims: default_name_claims(),
+            scopes: strings(&["openid", "mail-w"]),
+            auth_params: HashMap::new(),
+            imap: endpoint("imap.mail.yahoo.com", 993, Security::Tls),
+            smtp: endpoint("smtp.mail.yahoo.com", 465, Security::Tls),
+            sieve: None,
+            domains: strings(&["yahoo.com", "ymail.com", "rocketmail.com"]),
+            mx_patterns: strings(&["yahoodns.net"]),
+            authserv_ids: strings(&["mail.yahoo.com"]),
+            builtin: true,
+        },
+        ProviderDefinition {
+            id: "aol".to_string(),
+            name: "AOL".to_string(),
+            issuer: None,
+            auth_url: "https://api.login.aol.com/oauth2/request_auth".to_string(),
+            token_url: "https://api.login.aol.com/oauth2/get_token".to_string(),
+            userinfo_url: Some("https://api.login.aol.com/openid/v1/userinfo".to_string()),
+            device_auth_url: None,
+            email_claims: default_email_claims(),
+            name_claims: default_name_claims(),
+            scopes: strings(&["openid", "mail-w"]),
+            auth_params: HashMap::new(),
+            imap: endpoint("imap.aol.com", 993, Security::Tls),
+            smtp: endpoint("smtp.aol.com", 465, Security::Tls),
+            sieve: None,
+            domains: strings(&["aol.com", "aim.com"]),
+            mx_patterns: Vec::new(),
+            authserv_ids: strings(&["mail.yahoo.com"]),
+            builtin: true,
+        },
+        ProviderDefinition {
+            id: "fastmail".to_string(),
+            name: "Fastmail".to_string(),
+            issuer: None,
+            auth_url: "https://api.fastmail.com/oauth/authorize".to_string(),
+            token_url: "https://api.fastmail.com/oauth/refresh".to_string(),
+            userinfo_url: None,
+            device_auth_url: None,
+            email_claims: default_email_claims(),
+            name_claims: default_name_claims(),
+            scopes: strings(&[
+                "https://www.fastmail.com/dev/protocol-imap",
+                "https://www.fastmail.com/dev/protocol-smtp",
+            ]),
+            auth_params: HashMap::new(),
+            imap: endpoint("imap.fastmail.com", 993, Security::Tls),
+            smtp: endpoint("smtp.fastmail.com", 465, Security::Tls),
+            sieve: Some(endpoint("imap.fastmail.com", 4190, Security::StartTls)),
+            domains: strings(&["fastmail.com", "fastmail.fm"]),
+            mx_patterns: strings(&["messagingengine.com"]),
+            authserv_ids: strings(&["messagingengine.com"]),
+            builtin: true,
+        },
+    ]
+}
+
+/// Built-in providers overlaid with the user's providers from `demail_providers.json`
+pub struct ProviderRegistry {
+    providers: Vec<ProviderDefinition>,
+}
+
+impl ProviderRegistry {
+    pub fn new(custom: Vec<ProviderDefinition>) -> Self {
+        let mut providers = builtin_providers();
+        for mut provider in custom {
+            provider.builtin = false;
+            match providers
+                .iter_mut()
+                .find(|existing| existing.id == provider.id)
+            {
+                Some(existing) => *existing = provider,
+                None => providers.push(provider),
+            }
+        }
+        Self { providers }
+    }
+
+    pub fn load(config: &Config) -> Result<Self, DEmailError> {
+        Ok(Self::new(load_custom_providers(config)?))
+    }
+
+    pub fn providers(&self) -> &[ProviderDefinition] {
+        &self.providers
+    }
+
+    pub fn get(&self, id: &str) -> Result<&ProviderDefinition, DEmailError> {
+        self.providers
+            .iter()
+            .find(|provider| provider.id == id)
+            .ok_or_else(|| DEmailError::Config(format!("Unknown provider: {}", id)))
+    }
+
+    /// Matches the address domain against the providers' domain patterns
+    pub fn detect_by_domain(&self, email_address: &str) -> Option<&ProviderDefinition> {
+        let domain = email_domain(email_address)?;
+        self.providers.iter().find(|provider| {
+            provider
+                .domains
+                .iter()
+                .any(|pattern| domain_matches(&domain, pattern))
+        })
+    }
+
+    /// Matches MX hosts against the providers' MX patterns, e.g. Google Workspace or
+    /// Microsoft 365 on a custom domain
+    pub fn detect_by_mx(&self, mx_hosts: &[String]) -> Option<&ProviderDefinition> {
+        mx_hosts.iter().find_map(|host| {
+            self.providers.iter().find(|provider| {
+                provider
+                    .mx_patterns
+                    .iter()
+                    .any(|pattern| domain_matches(host, pattern))
+            })
+        })
+    }
+
+    /// Finds the provider for an address, falling back to an MX lookup of its domain
+    pub async fn detect(&self, email_address: &str) -> Result<&ProviderDefinition, DEmailError> {
+        if let Some(provider) = self.detect_by_domain(email_address) {
+            return Ok(provider);
+        }
+        let domain = email_domain(email_address)
+            .ok_or_else(|| DEmailError::Validation("Invalid email address".to_string()))?;
+        let mx_hosts = dns::lookup_mx(&domain).await?;
+        debug!("MX hosts for {}: {:?}", domain, mx_hosts);
+        self.detect_by_mx(&mx_hosts).ok_or_else(|| {
+            DEmailError::OAuth(format!("No supported provider found for {}", domain))
+        })
+    }
+}
+
+fn email_domain(email_address: &str) -> Option<String> {
+    email_address
+        .rsplit_once('@')
+        .map(|(_, domain)| domain.trim().trim_end_matches('.').to_ascii_lowercase())
+        .filter(|domain| !domain.is_empty())
+}
+
+/// Exact match or subdomain of `pattern`
+fn domain_matches(domain: &str, pattern: &str) -> bool {
+    let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+    let pattern = pattern.trim_end_matches('.').to_ascii_lowercase();
+    domain == pattern || domain.ends_with(&format!(".{}", pattern))
+}
+
+/// An OAuth client whose token responses are read as `TR`; `BasicClient` everywhere except
+/// the sign-in code exchange, which keeps the OpenID Connect `id_token`
+pub type ProviderClient<TR> = Client<
+    BasicErrorResponse,
+    TR,
+    BasicTokenType,
+    BasicTokenIntrospectionResponse,
+    StandardRevocableToken,
+    BasicRevocationErrorResponse,
+>;
+
+/// Builds the OAuth client for a provider. An empty secret makes a public client.
+pub fn oauth_client<TR: TokenResponse<BasicTokenType>>(
+    provider: &ProviderDefinition,
+    client_id: String,
+    client_secret: String,
+) -> Result<ProviderClient<TR>, DEmailError> {
+    let auth_url =
+        AuthUrl::new(provider.auth_url.clone()).map_err(|e| DEmailError::OAuth(e.to_string()))?;
+    let token_url =
+        TokenUrl::new(provider.token_url.clone()).map_err(|e| DEmailError::OAuth(e.to_string()))?;
+    let client_secret = Some(client_secret)
+        .filter(|secret| !secret.is_empty())
+        .map(ClientSecret::new);
+
+    Ok(Client::new(
+        ClientId::new(client_id),
+        client_secret,
+        auth_url,
+        Some(token_url),
+    ))
+}
+
+/// Looks up a provider together with its configured client credentials
+pub fn credentials_for_provider(
+    config: &Config,
+    provider_id: &str,
+) -> Result<(ProviderDefinition, OAuthProviderConfig), DEmailError> {
+    let provider = ProviderRegistry::load(config)?.get(provider_id)?.clone();
+    let provider_config = load_config(config)?.remove(provider_id).ok_or_else(|| {
+        DEmailError::Config(format!("OAuth config for {} not found", provider_id))
+    })?;
+    Ok((provider, provider_config))
+}
+
+/// Looks up a provider and builds its OAuth client from the configured credentials
+pub fn client_for_provider(
+    config: &Config,
+    provider_id: &str,
+) -> Result<(ProviderDefinition, BasicClient), DEmailError> {
+    let (provider, provider_config) = credentials_for_provider(config, provider_id)?;
+    let client = oauth_client(
+        &provider,
+        provider_config.client_id,
+        provider_config.client_secret,
+    )?;
+    Ok((provider, client))
+}
+
+// ============================================================================
+// CUSTOM PROVIDERS
+// ============================================================================
+
+pub fn get_providers_path(config: &Config) -> Result<PathBuf, DEmailError> {
+    let path = tauri::api::path::app_config_dir(config)
+        .ok_or_else(|| DEmailError::Init("Could not determine app config directory".to_string()))?;
+    Ok(path.join("demail_providers.json"))
+}
+
+pub fn load_custom_providers(config: &Config) -> Result<Vec<ProviderDefinition>, DEmailError> {
+    let path = get_providers_path(config)?;
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+}
+
+fn save_custom_providers(
+    config: &Config,
+    providers: &[ProviderDefinition],
+) -> Result<(), DEmailError> {
+    let path = get_providers_path(config)?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, serde_json::to_string_pretty(providers)?)?;
+    Ok(())
+}
+
+/// Adds or replaces a custom provider, filling missing endpoints from OpenID Connect
+/// discovery when an issuer is given
+pub async fn save_custom_provider(
+    config: &Config,
+    mut provider: ProviderDefinition,
+) -> Result<ProviderDefinition, DEmailError> {
+    if provider.id.trim().is_empty()
+        || !provider
+            .id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(DEmailError::Validation(
+            "Provider id may only contain letters, digits, '-' and '_'".to_string(),
+        ));
+    }
+
+    if let Some(issuer) = provider.issuer.clone() {
+        if provider.auth_url.is_empty() || provider.token_url.is_empty() {
+            discover_endpoints(&mut provider, &issuer).await?;
+        }
+    }
+    if provider.auth_url.is_empty() || provider.token_url.is_empty() {
+        return Err(DEmailError::Validation(
+            "Authorization and token URLs are required".to_string(),
+        ));
+    }
+    provider.builtin = false;
+
+    let mut providers = load_custom_providers(config)?;
+    providers.retain(|existing| existing.id != provider.id);
+    providers.push(provider.clone());
+    save_custom_providers(config, &providers)?;
+
+    info!("Saved custom provider {}", provider.id);
+    Ok(provider)
+}
+
+pub fn delete_custom_provider(config: &Config, provider_id: &str) -> Result<(), DEmailError> {
+    let mut providers = load_custom_providers(config)?;
+    providers.retain(|provider| provider.id != provider_id);
+    save_custom_providers(config, &providers)
+}
+
+/// Fills endpoints from `{issuer}/.well-known/openid-configuration`
+async fn discover_endpoints(
+    provider: &mut ProviderDefinition,
+    issuer: &str,
+) -> Result<(), DEmailError> {
+    let url = format!(
+        "{}/.well-known/openid-configuration",
+        issuer.trim_end_matches('/')
+    );
+    let document: serde_json::Value = reqwest::get(&url)
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| DEmailError::OAuth(format!("OpenID discovery failed: {}", e)))?
+        .json()
+        .await
+        .map_err(|e| DEmailError::OAuth(format!("OpenID discovery failed: {}", e)))?;
+    apply_discovery_document(provider, &document);
+    Ok(())
+}
+
+fn apply_discovery_document(provider: &mut ProviderDefinition, document: &serde_json::Value) {
+    let field = |name: &str| document[name].as_str().map(|value| value.to_string());
+    if provider.auth_url.is_empty() {
+        provider.auth_url = field("authorization_endpoint").unwrap_or_default();
+    }
+    if provider.token_url.is_empty() {
+        provider.token_url = field("token_endpoint").unwrap_or_default();
+    }
+    if provider.userinfo_url.is_none() {
+        provider.userinfo_url = field("userinfo_endpoint");
+    }
+    if provider.device_auth_url.is_none() {
+        provider.device_auth_url = field("device_authorization_endpoint");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn custom_provider() -> ProviderDefinition {
+        serde_json::from_value(serde_json::json!({
+            "id": "example",
+            "name": "Example Mail",
+            "issuer": "https://id.example.org",
+            "scopes": ["openid", "email", "offline_access"],
+            "imap": { "host": "imap.example.org", "port": 993, "security": "tls" },
+            "smtp": { "host": "smtp.example.org", "port": 587, "security": "starttls" },
+            "domains": ["example.org"]
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_detect_by_domain() {
+        let registry = ProviderRegistry::new(Vec::new());
+        assert_eq!(
+            registry.detect_by_domain("someone@gmail.com").unwrap().id,
+            "google"
+        );
+        assert_eq!(
+            registry.detect_by_domain("Someone@Hotmail.com").unwrap().id,
+            "microsoft"
+        );
+        assert_eq!(
+            registry.detect_by_domain("someone@ymail.com").unwrap().id,
+            "yahoo"
+        );
+        assert!(registry.detect_by_domain("someone@example.org").is_none());
+        // Suffix matches need a label boundary
+        assert!(registry.detect_by_domain("someone@notgmail.com").is_none());
+    }
+
+    #[test]
+    fn test_detect_by_mx() {
+        let registry = ProviderRegistry::new(Vec::new());
+        let workspace = strings(&["aspmx.l.google.com", "alt1.aspmx.l.google.com"]);
+        assert_eq!(registry.detect_by_mx(&workspace).unwrap().id, "google");
+        let microsoft_365 = strings(&["contoso-com.mail.protection.outlook.com"]);
+        assert_eq!(
+            registry.detect_by_mx(&microsoft_365).unwrap().id,
+            "microsoft"
+        );
+        assert!(registry
+            .detect_by_mx(&strings(&["mx.example.org"]))
+            .is_none());
+    }
+
+    #[test]
+    fn test_custom_providers_extend_and_override() {
+        let mut google = builtin_providers().remove(0);
+        google.scopes = strings(&["https://mail.google.com/"]);
+        let registry = ProviderRegistry::new(vec![custom_provider(), google]);
+
+        let example = registry.detect_by_domain("me@example.org").unwrap();
+        assert_eq!(example.id, "example");
+        assert_eq!(example.email_claims, vec!["email"]);
+        assert_eq!(example.smtp.security, Security::StartTls);
+        assert_eq!(registry.get("google").unwrap().scopes.len(), 1);
+        assert!(!registry.get("google").unwrap().builtin);
+        assert!(registry.get("missing").is_err());
+    }
+
+    #[test]
+    fn test_discovery_and_clients() {
+        let mut provider = custom_provider();
+        apply_discovery_document(
+            &mut provider,
+            &serde_json::json!({
+                "authorization_endpoint": "https://id.example.org/authorize",
+                "token_endpoint": "https://id.example.org/token",
+                "userinfo_endpoint": "https://id.example.org/userinfo",
+                "device_authorization_endpoint": "https://id.example.org/device"
+            }),
+        );
+        assert_eq!(provider.auth_url, "https://id.example.org/authorize");
+        assert_eq!(
+            provider.userinfo_url.as_deref(),
+            Some("https://id.example.org/userinfo")
+        );
+        assert_eq!(
+            provider.device_auth_url.as_deref(),
+            Some("https://id.example.org/device")
+        );
+
+        let client: BasicClient =
+            oauth_client(&provider, "client".to_string(), String::new()).unwrap();
+        assert_eq!(client.client_id().as_str(), "client");
+        for builtin in builtin_providers() {
+            let client: Result<BasicClient, _> =
+                oauth_client(&builtin, "id".to_string(), "secret".to_string());
+            assert!(client.is_ok());
+        }
+    }
+}
@@ -0,0 +1,904 @@
+// Remote content module - Blocks remote images/CSS and tracking pixels in HTML bodies
+// and serves allowed images through a local caching proxy
+
+use crate::core::cache::db::Pool;
+use crate::core::contacts::parse_email_address;
+use crate::error::DEmailError;
+use crate::models::{Message, RemoteContentException, RemoteContentReport};
+use rusqlite::Connection;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+/// URI scheme registered with the webview for the image proxy
+pub const PROXY_SCHEME: &str = "demail-remote";
+
+#[cfg(windows)]
+const PROXY_ORIGIN: &str = "https://demail-remote.localhost/";
+#[cfg(not(windows))]
+const PROXY_ORIGIN: &str = "demail-remote://localhost/";
+
+pub const EXCEPTION_SENDER: &str = "sender";
+pub const EXCEPTION_DOMAIN: &str = "domain";
+
+/// Shown in place of a blocked remote image
+const BLOCKED_IMAGE_PLACEHOLDER: &str = "data:image/svg+xml,%3Csvg xmlns='http://www.w3.org/2000/svg' width='120' height='40'%3E%3Crect fill='%23eee' width='120' height='40'/%3E%3Ctext fill='%23999' x='50%25' y='50%25' font-size='10' text-anchor='middle' dy='.3em'%3ERemote image blocked%3C/text%3E%3C/svg%3E";
+
+const FETCH_TIMEOUT_SECS: u64 = 10;
+const MAX_REMOTE_IMAGE_BYTES: usize = 5 * 1024 * 1024;
+const MAX_REDIRECTS: usize = 5;
+const CACHE_MAX_AGE_SECS: i64 = 30 * 24 * 60 * 60;
+
+/// Hosts that only serve open-tracking beacons
+const TRACKER_DOMAINS: &[&str] = &[
+    "google-analytics.com",
+    "doubleclick.net",
+    "list-manage.com",
+    "mailtrack.io",
+    "mandrillapp.com",
+    "mixpanel.com",
+    "pixel.wp.com",
+    "bat.bing.com",
+    "hubspotlinks.com",
+    "sendgrid.net",
+    "mailgun.org",
+    "sparkpostmail.com",
+    "exct.net",
+];
+
+/// URL path fragments used by common open-tracking endpoints
+const TRACKER_PATHS: &[&str] = &[
+    "/wf/open",
+    "/track/open",
+    "/open.php",
+    "/open.aspx",
+    "/e/o/",
+    "/pixel.gif",
+    "/beacon",
+    "/o.gif",
+];
+
+/// Rewrites the stored (already sanitized) HTML body of a message for display. Remote
+/// images and CSS are replaced with placeholders unless the sender or its domain has an
+/// "always load" exception, or `load_remote` is set for a one-time load. Allowed images
+/// are routed through the local proxy; tracking pixels are always removed.
+pub fn render_message(
+    conn: &Connection,
+    message: &mut Message,
+    load_remote: bool,
+) -> Result<(), DEmailError> {
+    let Some(html) = message.body_html.as_deref() else {
+        return Ok(());
+    };
+    let allow_remote = load_remote || is_remote_allowed(conn, &message.from_header)?;
+    let (html, report) = apply_policy(html, allow_remote);
+    message.body_html = Some(html);
+    message.remote_content = Some(report);
+    Ok(())
+}
+
+/// Applies the remote content policy to sanitized HTML
+pub fn apply_policy(html: &str, allow_remote: bool) -> (String, RemoteContentReport) {
+    let mut report = RemoteContentReport {
+        remote_allowed: allow_remote,
+        ..Default::default()
+    };
+    let mut out = String::with_capacity(html.len());
+    let mut rest = html;
+
+    while let Some(start) = rest.find('<') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let Some(tag) = parse_tag(rest) else {
+            out.push('<');
+            rest = &rest[1..];
+            continue;
+        };
+        rest = &rest[tag.length..];
+        if let Some(rewritten) = rewrite_tag(tag, allow_remote, &mut report) {
+            out.push_str(&rewritten);
+        }
+    }
+    out.push_str(rest);
+
+    (out, report)
+}
+
+/// A start tag as serialized by ammonia: lowercase names, double-quoted values
+struct Tag {
+    name: String,
+    attributes: Vec<(String, String)>,
+    self_closing: bool,
+    length: usize,
+}
+
+fn parse_tag(input: &str) -> Option<Tag> {
+    let bytes = input.as_bytes();
+    if bytes.len() < 2 || !bytes[1].is_ascii_alphabetic() {
+        return None;
+    }
+    let mut pos = 1;
+    while pos < bytes.len() && bytes[pos].is_ascii_alphanumeric() {
+        pos += 1;
+    }
+    let name = input[1..pos].to_ascii_lowercase();
+
+    let mut attributes = Vec::new();
+    loop {
+        while pos < bytes.len() && bytes[pos].is_ascii_whitespace() {
+            pos += 1;
+        }
+        match bytes.get(pos)? {
+            b'>' => {
+                return Some(Tag {
+                    name,
+                    attributes,
+                    self_closing: false,
+                    length: pos + 1,
+                })
+            }
+            b'/' if bytes.get(pos + 1) == Some(&b'>') => {
+                return Some(Tag {
+                    name,
+                    attributes,
+                    self_closing: true,
+                    length: pos + 2,
+                })
+            }
+            _ => {}
+        }
+
+        let name_start = pos;
+        while pos < bytes.len() && !matches!(bytes[pos], b'=' | b'>' | b'/') {
+            if bytes[pos].is_ascii_whitespace() {
+                break;
+            }
+            pos += 1;
+        }
+        if pos == name_start {
+            pos += 1;
+            continue;
+        }
+        let attribute = input[name_start..pos].to_ascii_lowercase();
+
+        let mut value = String::new();
+        if bytes.get(pos) == Some(&b'=') {
+            pos += 1;
+            if bytes.get(pos) == Some(&b'"') {
+                let end = input[pos + 1..].find('"')? + pos + 1;
+                value = input[pos + 1..end].to_string();
+                pos = end + 1;
+            } else {
+                let start = pos;
+                while pos < bytes.len() && !bytes[pos].is_ascii_whitespace() && bytes[pos] != b'>' {
+                    pos += 1;
+                }
+                value = input[start..pos].to_string();
+            }
+        }
+        attributes.push((attribute, value));
+    }
+}
+
+/// Returns the tag to emit, or `None` to drop it
+fn rewrite_tag(tag: Tag, allow_remote: bool, report: &mut RemoteContentReport) -> Option<String> {
+    if tag.name == "img" && is_tracking_pixel(&tag, report) {
+        return None;
+    }
+
+    let mut attributes = Vec::with_capacity(tag.attributes.len());
+    for (name, value) in tag.attributes {
+        match name.as_str() {
+            // Responsive image candidates are not proxied; src is enough
+            "srcset" => continue,
+            "src" | "background" | "poster" if is_remote_url(&unescape(&value)) => {
+                if allow_remote {
+                    attributes.push((name, escape(&proxy_url(&unescape(&value)))));
+                } else if name == "src" && tag.name == "img" {
+                    report.blocked_images += 1;
+                    attributes.push((name, BLOCKED_IMAGE_PLACEHOLDER.to_string()));
+                } else {
+                    report.blocked_images += 1;
+                }
+            }
+            "style" if value.to_ascii_lowercase().contains("url(") => {
+                let (style, blocked) = rewrite_style(&unescape(&value), allow_remote);
+                report.blocked_styles += blocked;
+                attributes.push((name, escape(&style)));
+            }
+            _ => attributes.push((name, value)),
+        }
+    }
+
+    let mut out = format!("<{}", tag.name);
+    for (name, value) in attributes {
+        out.push_str(&format!(" {}=\"{}\"", name, value));
+    }
+    out.push_str(if tag.self_closing { " />" } else { ">" });
+    Some(out)
+}
+
+/// Rewrites `url(...)` references in inline CSS. Returns the new style and how many
+/// remote references were blocked.
+fn rewrite_style(style: &str, allow_remote: bool) -> (String, u32) {
+    let mut out = String::with_capacity(style.len());
+    let mut blocked = 0;
+    let mut rest = style;
+
+    while let Some(start) = rest.to_ascii_lowercase().find("url(") {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 4..];
+        let Some(end) = after.find(')') else {
+            rest = "";
+            break;
+        };
+        let url = after[..end].trim().trim_matches(|c| c == '"' || c == '\'');
+        if !is_remote_url(url) {
+            out.push_str(&rest[start..start + 4 + end + 1]);
+        } else if allow_remote {
+            out.push_str(&format!("url('{}')", proxy_url(url)));
+        } else {
+            blocked += 1;
+            out.push_str("none");
+        }
+        rest = &after[end + 1..];
+    }
+    out.push_str(rest);
+
+    (out, blocked)
+}
+
+fn is_tracking_pixel(tag: &Tag, report: &mut RemoteContentReport) -> bool {
+    let attribute = |name: &str| {
+        tag.attributes
+            .iter()
+            .find(|(attribute, _)| attribute == name)
+            .map(|(_, value)| unescape(value))
+    };
+    let Some(src) = attribute("src").filter(|src| is_remote_url(src)) else {
+        return false;
+    };
+    let tiny = |value: Option<String>| {
+        value.is_some_and(|value| {
+            value
+                .trim()
+                .trim_end_matches("px")
+                .parse::<f32>()
+                .is_ok_and(|size| size <= 1.0)
+        })
+    };
+    let hidden = attribute("style").is_some_and(|style| {
+        let style: String = style
+            .to_ascii_lowercase()
+            .chars()
+            .filter(|c| !c.is_whitespace())
+            .collect();
+        style.contains("display:none") || style.contains("visibility:hidden")
+    });
+
+    let host = url_host(&src).unwrap_or_default();
+    let path = src.to_ascii_lowercase();
+    let tracker = TRACKER_DOMAINS
+        .iter()
+        .any(|domain| host == *domain || host.ends_with(&format!(".{}", domain)))
+        || TRACKER_PATHS.iter().any(|fragment| path.contains(fragment));
+
+    if (tiny(attribute("width")) && tiny(attribute("height"))) || hidden || tracker {
+        if !report.tracking_pixels.contains(&host) {
+            report.tracking_pixels.push(host);
+        }
+        return true;
+    }
+    false
+}
+
+fn is_remote_url(url: &str) -> bool {
+    let url = url.trim().to_ascii_lowercase();
+    url.starts_with("http://") || url.starts_with("https://") || url.starts_with("//")
+}
+
+fn url_host(url: &str) -> Option<String> {
+    let without_scheme = url.trim().split_once("//")?.1;
+    let authority = without_scheme.split(['/', '?', '#']).next()?;
+    let host = authority.rsplit('@').next()?.split(':').next()?;
+    Some(host.to_ascii_lowercase()).filter(|host| !host.is_empty())
+}
+
+fn unescape(value: &str) -> String {
+    value
+        .replace("&quot;", "\"")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&nbsp;", "\u{a0}")
+        .replace("&amp;", "&")
+}
+
+fn escape(value: &str) -> String {
+    value.replace('&', "&amp;").replace('"', "&quot;")
+}
+
+// ============================================================================
+// EXCEPTIONS
+// ============================================================================
+
+/// Whether the sender of a message has an "always load" exception, either for its
+/// address or for its domain (including subdomains)
+pub fn is_remote_allowed(conn: &Connection, from_header: &str) -> Result<bool, DEmailError> {
+    let Some((email, _)) = parse_email_address(from_header) else {
+        return Ok(false);
+    };
+    let email = email.to_lowercase();
+    let domain = email
+        .rsplit_once('@')
+        .map(|(_, domain)| domain)
+        .unwrap_or("");
+
+    let allowed = list_exceptions(conn)?
+        .iter()
+        .any(|exception| match exception.kind.as_str() {
+            EXCEPTION_SENDER => exception.value == email,
+            EXCEPTION_DOMAIN => {
+                domain == exception.value || domain.ends_with(&format!(".{}", exception.value))
+            }
+            _ => false,
+        });
+    Ok(allowed)
+}
+
+/// Adds an "always load remote content" exception for a sender address or a domain
+pub fn add_exception(
+    conn: &Connection,
+    kind: &str,
+    value: &str,
+) -> Result<RemoteContentException, DEmailError> {
+    let value = value.trim().trim_end_matches('.').to_lowercase();
+    let valid = match kind {
+        EXCEPTION_SENDER => value.contains('@'),
+        EXCEPTION_DOMAIN => !value.is_empty() && !value.contains('@'),
+        _ => {
+            return Err(DEmailError::Validation(format!(
+                "Unknown remote content exception type: {}",
+                kind
+            )))
+        }
+    };
+    if !valid {
+        return Err(DEmailError::Validation(format!(
+            "Invalid {} for remote content exception: {}",
+            kind, value
+        )));
+    }
+
+    let created_at = chrono::Utc::now().timestamp();
+    conn.execute(
+        "INSERT OR IGNORE INTO remote_content_exceptions (kind, value, created_at) VALUES (?1, ?2, ?3)",
+        rusqlite::params![kind, value, created_at],
+    )?;
+    let exception = conn.query_row(
+        "SELECT id, kind, value, created_at FROM remote_content_exceptions WHERE kind = ?1 AND value = ?2",
+        rusqlite::params![kind, value],
+        |row| {
+            Ok(RemoteContentException {
+                id: row.get(0)?,
+                kind: row.get(1)?,
+                value: row.get(2)?,
+                created_at: row.get(3)?,
+            })
+        },
+    )?;
+    Ok(exception)
+}
+
+/// Lists all remote content exceptions
+pub fn list_exceptions(conn: &Connection) -> Result<Vec<RemoteContentException>, DEmailError> {
+    let mut stmt = conn.prepare(
+        "SELECT id, kind, value, created_at FROM remote_content_exceptions ORDER BY kind, value",
+    )?;
+    let exceptions = stmt
+        .query_map([], |row| {
+            Ok(RemoteContentException {
+                id: row.get(0)?,
+                kind: row.get(1)?,
+                value: row.get(2)?,
+                created_at: row.get(3)?,
+            })
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(exceptions)
+}
+
+/// Removes a remote content exception
+pub fn delete_exception(conn: &Connection, exception_id: i64) -> Result<(), DEmailError> {
+    let deleted = conn.execute(
+        "DELETE FROM remote_content_exceptions WHERE id = ?1",
+        [exception_id],
+    )?;
+    if deleted == 0 {
+        return Err(DEmailError::NotFound(format!(
+            "Remote content exception {}",
+            exception_id
+        )));
+    }
+    Ok(())
+}
+
+// ============================================================================
+// IMAGE PROXY
+// ============================================================================
+
+/// Local proxy URL for a remote image. The webview never contacts the remote host itself.
+pub fn proxy_url(url: &str) -> String {
+    let url = if url.starts_with("//") {
+        format!("https:{}", url)
+    } else {
+        url.to_string()
+    };
+    format!("{}{}", PROXY_ORIGIN, hex::encode(url.as_bytes()))
+}
+
+/// Remote URL encoded in a proxy request URI
+fn remote_url_from_proxy(uri: &str) -> Result<String, DEmailError> {
+    let encoded = uri
+        .split(['?', '#'])
+        .next()
+        .and_then(|path| path.rsplit('/').next())
+        .unwrap_or("");
+    let url = hex::decode(encoded)
+        .ok()
+        .and_then(|bytes| String::from_utf8(bytes).ok())
+        .ok_or_else(|| DEmailError::Validation(format!("Invalid proxy request: {}", uri)))?;
+    if !is_remote_url(&url) {
+        return Err(DEmailError::Validation(format!(
+            "Only http(s) content can be proxied: {}",
+            url
+        )));
+    }
+    Ok(url)
+}
+
+/// Serves a proxied image from the cache, fetching and caching it on first use.
+/// Returns the MIME type and image bytes. Runs on the webview's URI scheme handler
+/// thread, so the fetch itself is spawned on the async runtime and only awaited here
+/// for a bounded time.
+pub fn serve_proxy_request(pool: &Pool, uri: &str) -> Result<(String, Vec<u8>), DEmailError> {
+    let url = remote_url_from_proxy(uri)?;
+    let url_hash = hex::encode(openssl::sha::sha256(url.as_bytes()));
+
+    {
+        let conn = pool
+            .get()
+            .map_err(|_| DEmailError::Database(rusqlite::Error::InvalidQuery))?;
+        if let Some(cached) = get_cached(&conn, &url_hash)? {
+            return Ok(cached);
+        }
+    }
+
+    let (sender, receiver) = std::sync::mpsc::channel();
+    let pool = pool.clone();
+    tauri::async_runtime::spawn(async move {
+        let result = fetch_image(&url).await.and_then(|(mime_type, data)| {
+            let conn = pool
+                .get()
+                .map_err(|_| DEmailError::Database(rusqlite::Error::InvalidQuery))?;
+            store_cached(&conn, &url_hash, &url, &mime_type, &data)?;
+            Ok((mime_type, data))
+        });
+        // The handler may have given up already; the image is cached for next time
+        let _ = sender.send(result);
+    });
+
+    receiver
+        .recv_timeout(Duration::from_secs(
+            FETCH_TIMEOUT_SECS * (MAX_REDIRECTS as u64 + 1),
+        ))
+        .map_err(|_| DEmailError::Unknown(format!("Timed out fetching remote content: {}", uri)))?
+}
+
+/// Fetches an image without cookies or referrer, refusing anything that is not an image.
+/// Redirects are followed by hand so that every hop goes through `connect_to_public_host`.
+async fn fetch_image(url: &str) -> Result<(String, Vec<u8>), DEmailError> {
+    let mut url = reqwest::Url::parse(url)
+        .map_err(|e| DEmailError::Validation(format!("Invalid remote URL {}: {}", url, e)))?;
+
+    for _ in 0..=MAX_REDIRECTS {
+        let client = connect_to_public_host(&url).await?;
+        let response = client
+            .get(url.clone())
+            .send()
+            .await
+            .map_err(|e| DEmailError::Unknown(format!("Failed to fetch {}: {}", url, e)))?;
+
+        if response.status().is_redirection() {
+            let location = response
+                .headers()
+                .get(reqwest::header::LOCATION)
+                .and_then(|value| value.to_str().ok())
+                .ok_or_else(|| {
+                    DEmailError::Unknown(format!("Redirect without a location: {}", url))
+                })?;
+            url = url.join(location).map_err(|e| {
+                DEmailError::Validation(format!("Invalid redirect {}: {}", location, e))
+            })?;
+            if !is_remote_url(url.as_str()) {
+                return Err(DEmailError::Validation(format!(
+                    "Only http(s) content can be proxied: {}",
+                    url
+                )));
+            }
+            continue;
+        }
+
+        let response = response
+            .error_for_status()
+            .map_err(|e| DEmailError::Unknown(format!("Failed to fetch {}: {}", url, e)))?;
+        return read_image(response, url.as_str()).await;
+    }
+
+    Err(DEmailError::Unknown(format!("Too many redirects: {}", url)))
+}
+
+/// Builds a client for one request to `url`. The host is resolved up front and rejected
+/// unless every address is publicly routable, and the connection is pinned to the
+/// checked address so a second DNS answer cannot point it at the local network.
+async fn connect_to_public_host(url: &reqwest::Url) -> Result<reqwest::Client, DEmailError> {
+    let host = url
+        .host_str()
+        .ok_or_else(|| DEmailError::Validation(format!("Remote URL has no host: {}", url)))?;
+    let port = url
+        .port_or_known_default()
+        .ok_or_else(|| DEmailError::Validation(format!("Remote URL has no port: {}", url)))?;
+
+    let addresses: Vec<SocketAddr> = tokio::net::lookup_host((host.trim_matches(['[', ']']), port))
+        .await
+        .map_err(|e| DEmailError::Unknown(format!("Failed to resolve {}: {}", host, e)))?
+        .collect();
+    let Some(address) = addresses.first().copied() else {
+        return Err(DEmailError::Unknown(format!("Failed to resolve {}", host)));
+    };
+    if let Some(private) = addresses
+        .iter()
+        .find(|address| !is_public_address(address.ip()))
+    {
+        return Err(DEmailError::Validation(format!(
+            "Remote content on a private address is not loaded: {} ({})",
+            host,
+            private.ip()
+        )));
+    }
+
+    let mut builder = reqwest::Client::builder()
+        .timeout(Duration::from_secs(FETCH_TIMEOUT_SECS))
+        .redirect(reqwest::redirect::Policy::none());
+    if url.domain().is_some() {
+        builder = builder.resolve(host, address);
+    }
+    builder
+        .build()
+        .map_err(|e| DEmailError::Unknown(format!("Failed to create HTTP client: {}", e)))
+}
+
+/// Whether an address is reachable on the public internet, i.e. not loopback, private,
+/// link-local, shared, documentation, multicast or otherwise reserved
+fn is_public_address(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_multicast()
+                || a == 0
+                || a >= 240
+                // Shared address space (RFC 6598) and benchmarking (RFC 2544)
+                || (a == 100 && (64..128).contains(&b))
+                || (a == 198 && (18..20).contains(&b))
+                // IETF protocol assignments (RFC 6890)
+                || (a == 192 && b == 0 && ip.octets()[2] == 0))
+        }
+        IpAddr::V6(ip) => {
+            if let Some(mapped) = ip.to_ipv4_mapped() {
+                return is_public_address(IpAddr::V4(mapped));
+            }
+            let first = ip.segments()[0];
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_multicast()
+                // Unique local (fc00::/7) and link-local (fe80::/10)
+                || (first & 0xfe00) == 0xfc00
+                || (first & 0xffc0) == 0xfe80
+                // Documentation (2001:db8::/32)
+                || (first == 0x2001 && ip.segments()[1] == 0x0db8))
+        }
+    }
+}
+
+/// Reads an image response, stopping as soon as it grows past `MAX_REMOTE_IMAGE_BYTES`
+async fn read_image(
+    mut response: reqwest::Response,
+    url: &str,
+) -> Result<(String, Vec<u8>), DEmailError> {
+    let mime_type = response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| {
+            value
+                .split(';')
+                .next()
+                .unwrap_or(value)
+                .trim()
+                .to_lowercase()
+        })
+        .unwrap_or_default();
+    if !mime_type.starts_with("image/") {
+        return Err(DEmailError::Validation(format!(
+            "Remote content is not an image: {}",
+            url
+        )));
+    }
+    if response
+        .content_length()
+        .is_some_and(|length| length as usize > MAX_REMOTE_IMAGE_BYTES)
+    {
+        return Err(DEmailError::Validation(format!(
+            "Remote image too large: {}",
+            url
+        )));
+    }
+
+    let mut data = Vec::new();
+    while let Some(chunk) = response
+        .chunk()
+        .await
+        .map_err(|e| DEmailError::Unknown(format!("Failed to fetch {}: {}", url, e)))?
+    {
+        if data.len() + chunk.len() > MAX_REMOTE_IMAGE_BYTES {
+            return Err(DEmailError::Validation(format!(
+                "Remote image too large: {}",
+                url
+            )));
+        }
+        data.extend_from_slice(&chunk);
+    }
+    Ok((mime_type, data))
+}
+
+fn get_cached(conn: &Connection, url_hash: &str) -> Result<Option<(String, Vec<u8>)>, DEmailError> {
+    let result = conn.query_row(
+        "SELECT mime_type, data FROM remote_content_cache WHERE url_hash = ?1 AND fetched_at >= ?2",
+        rusqlite::params![
+            url_hash,
+            chrono::Utc::now().timestamp() - CACHE_MAX_AGE_SECS
+        ],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    );
+
+    match result {
+        Ok(cached) => Ok(Some(cached)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(DEmailError::from(e)),
+    }
+}
+
+fn store_cached(
+    conn: &Connection,
+    url_hash: &str,
+    url: &str,
+    mime_type: &str,
+    data: &[u8],
+) -> Result<(), DEmailError> {
+    let now = chrono::Utc::now().timestamp();
+    conn.execute(
+        "DELETE FROM remote_content_cache WHERE fetched_at < ?1",
+        [now - CACHE_MAX_AGE_SECS],
+    )?;
+    conn.execute(
+        "INSERT OR REPLACE INTO remote_content_cache (url_hash, url, mime_type, data, fetched_at) VALUES (?1, ?2, ?3, ?4, ?5)",
+        rusqlite::params![url_hash, url, mime_type, data, now],
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::cache::schema::initialize_schema;
+
+    fn store() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        initialize_schema(&conn).unwrap();
+        conn
+    }
+
+    #[test]
+    fn test_blocks_remote_images_by_default() {
+        let html = r#"<p>Hi</p><img alt="logo" src="https://cdn.example.com/logo.png?a=1&amp;b=2" width="200"><img src="cid:part1">"#;
+        let (out, report) = apply_policy(html, false);
+        assert!(!out.contains("cdn.example.com"));
+        assert!(out.contains(r#"<img alt="logo" src="data:image/svg+xml"#));
+        assert!(out.contains(r#"<img src="cid:part1">"#));
+        assert!(out.starts_with("<p>Hi</p>"));
+        assert_eq!(report.blocked_images, 1);
+        assert!(!report.remote_allowed);
+    }
+
+    #[test]
+    fn test_allowed_images_go_through_proxy() {
+        let html = r#"<img src="https://cdn.example.com/logo.png?a=1&amp;b=2" srcset="https://cdn.example.com/logo@2x.png 2x">"#;
+        let (out, report) = apply_policy(html, true);
+        let expected = proxy_url("https://cdn.example.com/logo.png?a=1&b=2");
+        assert_eq!(out, format!(r#"<img src="{}">"#, expected));
+        assert_eq!(report.blocked_images, 0);
+        assert_eq!(
+            remote_url_from_proxy(&expected).unwrap(),
+            "https://cdn.example.com/logo.png?a=1&b=2"
+        );
+    }
+
+    #[test]
+    fn test_strips_tracking_pixels_even_when_allowed() {
+        let html = concat!(
+            r#"<img src="https://news.example.com/p.gif" width="1" height="1">"#,
+            r#"<img src="https://mc.us1.list-manage.com/track/x.png">"#,
+            r#"<img src="https://u123.ct.sendgrid.net/wf/open?upn=abc">"#,
+            r#"<img src="https://news.example.com/banner.png" width="600" height="1">"#,
+        );
+        for allow in [false, true] {
+            let (out, report) = apply_policy(html, allow);
+            assert_eq!(out.matches("<img").count(), 1, "{}", out);
+            assert_eq!(
+                report.tracking_pixels,
+                vec![
+                    "news.example.com",
+                    "mc.us1.list-manage.com",
+                    "u123.ct.sendgrid.net"
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn test_blocks_remote_css() {
+        let html = r#"<td style="background-image: url('https://cdn.example.com/bg.png'); color: red" background="http://cdn.example.com/bg.png">x</td>"#;
+        let (out, report) = apply_policy(html, false);
+        assert_eq!(
+            out,
+            r#"<td style="background-image: none; color: red">x</td>"#
+        );
+        assert_eq!(report.blocked_styles, 1);
+        assert_eq!(report.blocked_images, 1);
+
+        let (out, _) = apply_policy(html, true);
+        assert!(out.contains(&proxy_url("https://cdn.example.com/bg.png")));
+    }
+
+    #[test]
+    fn test_attribute_values_with_angle_brackets() {
+        let html =
+            r#"<a href="https://example.com/?q=a>b" title="x &quot;y&quot;">link</a> 1 &lt; 2"#;
+        let (out, _) = apply_policy(html, false);
+        assert_eq!(out, html);
+    }
+
+    #[test]
+    fn test_policy_on_sanitized_html() {
+        let raw = Some(
+            "<div><img src='https://cdn.example.com/a.png?x=1&y=2' alt=\"A > B\"><img src=https://t.example.com/o WIDTH=1 HEIGHT=1></div>"
+                .to_string(),
+        );
+        let sanitized = crate::core::cache::db::sanitize_html(&raw).unwrap();
+        let (out, report) = apply_policy(&sanitized, true);
+        assert_eq!(
+            out,
+            format!(
+                r#"<div><img src="{}" alt="A &gt; B"></div>"#,
+                proxy_url("https://cdn.example.com/a.png?x=1&y=2")
+            )
+        );
+        assert_eq!(report.tracking_pixels, vec!["t.example.com"]);
+    }
+
+    #[test]
+    fn test_exceptions() {
+        let conn = store();
+        assert!(!is_remote_allowed(&conn, "News <news@shop.example.com>").unwrap());
+
+        add_exception(&conn, EXCEPTION_DOMAIN, "Example.com").unwrap();
+        assert!(is_remote_allowed(&conn, "News <news@shop.example.com>").unwrap());
+        assert!(is_remote_allowed(&conn, "news@example.com").unwrap());
+        assert!(!is_remote_allowed(&conn, "news@badexample.com").unwrap());
+
+        let sender = add_exception(&conn, EXCEPTION_SENDER, "friend@other.org").unwrap();
+        assert!(is_remote_allowed(&conn, "Friend <Friend@other.org>").unwrap());
+        assert!(!is_remote_allowed(&conn, "stranger@other.org").unwrap());
+
+        // Adding twice is idempotent
+        assert_eq!(
+            add_exception(&conn, EXCEPTION_SENDER, "friend@other.org")
+                .unwrap()
+                .id,
+            sender.id
+        );
+        assert_eq!(list_exceptions(&conn).unwrap().len(), 2);
+
+        delete_exception(&conn, sender.id).unwrap();
+        assert!(!is_remote_allowed(&conn, "friend@other.org").unwrap());
+        assert!(delete_exception(&conn, sender.id).is_err());
+
+        assert!(add_exception(&conn, EXCEPTION_SENDER, "other.org").is_err());
+        assert!(add_exception(&conn, EXCEPTION_DOMAIN, "a@other.org").is_err());
+        assert!(add_exception(&conn, "ip", "127.0.0.1").is_err());
+    }
+
+    #[test]
+    fn test_proxy_rejects_non_http_urls() {
+        assert!(remote_url_from_proxy(&format!("{}zz", PROXY_ORIGIN)).is_err());
+        let file = format!("{}{}", PROXY_ORIGIN, hex::encode("file:///etc/passwd"));
+        assert!(remote_url_from_proxy(&file).is_err());
+        assert_eq!(
+            remote_url_from_proxy(&proxy_url("//cdn.example.com/a.png")).unwrap(),
+            "https://cdn.example.com/a.png"
+        );
+    }
+
+    #[test]
+    fn test_public_addresses() {
+        for ip in ["93.184.216.34", "2606:2800:220:1:248:1893:25c8:1946"] {
+            assert!(is_public_address(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "255.255.255.255",
+            "::1",
+            "::",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+        ] {
+            assert!(!is_public_address(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fetch_refuses_local_hosts() {
+        for url in [
+            "http://127.0.0.1/a.png",
+            "http://localhost:8080/a.png",
+            "http://[::1]/a.png",
+        ] {
+            let error = fetch_image(url).await.unwrap_err();
+            assert!(
+                matches!(error, DEmailError::Validation(_)),
+                "{}: {}",
+                url,
+                error
+            );
+        }
+    }
+
+    #[test]
+    fn test_cache_roundtrip() {
+        let conn = store();
+        assert!(get_cached(&conn, "abc").unwrap().is_none());
+        store_cached(
+            &conn,
+            "abc",
+            "https://example.com/a.png",
+            "image/png",
+            &[1, 2, 3],
+        )
+        .unwrap();
+        assert_eq!(
+            get_cached(&conn, "abc").unwrap(),
+            Some(("image/png".to_string(), vec![1, 2, 3]))
+        );
+    }
+}
@@ -1,3 +1,6 @@
+use crate::core::cache::db::Pool;
+use crate::core::identities;
+use crate::core::signatures::{self, SignatureContext};
 use crate::error::DEmailError;
 use crate::models::Message;
 use serde::{Deserialize, Serialize};
@@ -93,6 +96,103 @@ pub fn prepare_forward(message: &Message) -> Result<ForwardData, DEmailError> {
     })
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ComposeMode {
+    New,
+    Reply,
+    ReplyAll,
+    Forward,
+}
+
+/// A compose window's starting point, with the signature already in the body
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ComposeData {
+    pub to: String,
+    pub cc: Option<String>,
+    pub subject: String,
+    pub body: String,
+    /// The identity the signature was chosen for; `None` sends as the account address
+    pub identity_id: Option<i64>,
+}
+
+/// Prepares a new message, reply or forward. Replies are sent as the identity the original
+/// was addressed to unless `identity_id` says otherwise, and the identity's signature is
+/// rendered and placed around the quote as configured.
+pub fn prepare_compose(
+    pool: &Pool,
+    account_id: i64,
+    mode: ComposeMode,
+    original: Option<&Message>,
+    identity_id: Option<i64>,
+) -> Result<ComposeData, DEmailError> {
+    let original = match (mode, original) {
+        (ComposeMode::New, _) => None,
+        (_, Some(original)) => Some(original),
+        (_, None) => {
+            return Err(DEmailError::Validation(
+                "Replies and forwards need the original message".to_string(),
+            ))
+        }
+    };
+    let placement = signatures::get_settings(pool)?.placement;
+    let conn = pool
+        .get()
+        .map_err(|_| DEmailError::Database(rusqlite::Error::InvalidQuery))?;
+
+    let identity = match (identity_id, original) {
+        (None, Some(original)) if mode != ComposeMode::Forward => {
+            identities::identity_for_reply(&conn, original.id)?
+        }
+        _ => identities::resolve_identity(&conn, account_id, identity_id)?,
+    };
+    let (account_email, account_name): (String, String) = conn.query_row(
+        "SELECT email_address, display_name FROM accounts WHERE id = ?1",
+        [account_id],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )?;
+    let context = SignatureContext {
+        name: identity
+            .as_ref()
+            .map(|identity| identity.display_name.clone())
+            .filter(|name| !name.is_empty())
+            .unwrap_or(account_name),
+        email: identity
+            .as_ref()
+            .map(|identity| identity.email_address.clone())
+            .unwrap_or_else(|| account_email.clone()),
+        account: account_email,
+        date: chrono::Local::now().format("%Y-%m-%d").to_string(),
+    };
+    let signature = signatures::signature_for(&conn, account_id, identity.as_ref())?
+        .map(|signature| signatures::render(&signature.content_plain, &context));
+
+    let (to, cc, subject, quote) = match (mode, original) {
+        (ComposeMode::Reply | ComposeMode::ReplyAll, Some(original)) => {
+            let reply = prepare_reply(original, mode == ComposeMode::ReplyAll)?;
+            (reply.to, reply.cc, reply.subject, Some(reply.quoted_body))
+        }
+        (ComposeMode::Forward, Some(original)) => {
+            let forward = prepare_forward(original)?;
+            (
+                String::new(),
+                None,
+                forward.subject,
+                Some(forward.body_with_header),
+            )
+        }
+        _ => (String::new(), None, String::new(), None),
+    };
+
+    Ok(ComposeData {
+        to,
+        cc,
+        subject,
+        body: signatures::compose_body(signature.as_deref(), quote.as_deref(), placement),
+        identity_id: identity.map(|identity| identity.id),
+    })
+}
+
 fn quote_original_message(from: &str, date: i64, body: &str) -> String {
     let datetime = chrono::DateTime::from_timestamp(date, 0)
         .map(|dt| dt.format("%Y-%m-%d %H:%M:%S").to_string())
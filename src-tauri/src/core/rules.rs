@@ -0,0 +1,853 @@
+// Rules module - Filing rules stored in SQLite: conditions on headers, body, size and
+// attachments, and the actions to take on matching messages. Rules run on newly synced
+// messages and on demand; the IMAP side of the actions lives in `sync::rule_actions`.
+
+use crate::core::cache::db::Pool;
+use crate::core::tags;
+use crate::core::validation;
+use crate::error::DEmailError;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TextOperator {
+    Contains,
+    NotContains,
+    Is,
+    StartsWith,
+    EndsWith,
+}
+
+/// Case-insensitive comparison against a text field
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TextMatch {
+    pub operator: TextOperator,
+    pub value: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SizeOperator {
+    LargerThan,
+    SmallerThan,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "field", rename_all = "snake_case")]
+pub enum Condition {
+    From(TextMatch),
+    To(TextMatch),
+    Cc(TextMatch),
+    Subject(TextMatch),
+    Body(TextMatch),
+    /// Any header by name; matches if one of its occurrences matches
+    Header {
+        name: String,
+        operator: TextOperator,
+        value: String,
+    },
+    ListId(TextMatch),
+    HasAttachment {
+        value: bool,
+    },
+    Size {
+        operator: SizeOperator,
+        bytes: i64,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RuleAction {
+    /// Moves the message to the folder with this path on the server
+    Move {
+        folder: String,
+    },
+    Star,
+    MarkRead,
+    Delete,
+    Label {
+        label: String,
+    },
+    Forward {
+        to: String,
+    },
+    /// Runs the inner action once the delay has passed, at the end of a later sync
+    RunLater {
+        delay_minutes: i64,
+        action: Box<RuleAction>,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Rule {
+    pub id: i64,
+    /// `None` applies the rule to every account
+    pub account_id: Option<i64>,
+    pub name: String,
+    pub enabled: bool,
+    /// Rules run in ascending position
+    pub position: i64,
+    /// Whether all conditions must match, or any one of them
+    pub match_all: bool,
+    /// Skips the rules after this one when it matches
+    pub stop_processing: bool,
+    pub conditions: Vec<Condition>,
+    pub actions: Vec<RuleAction>,
+}
+
+/// A message a rule matches, as reported by a dry run
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RuleMatch {
+    pub message_id: i64,
+    pub from_header: String,
+    pub subject: String,
+    pub date: i64,
+}
+
+/// What conditions are evaluated against
+#[derive(Debug, Clone, Default)]
+pub struct MessageFacts {
+    pub from: String,
+    pub to: String,
+    pub cc: String,
+    pub subject: String,
+    pub body: String,
+    /// Raw header block of the message
+    pub headers: String,
+    pub has_attachments: bool,
+    pub size_bytes: i64,
+}
+
+/// A stored message rules can act on
+#[derive(Debug, Clone)]
+pub struct StoredMessage {
+    pub id: i64,
+    pub account_id: i64,
+    pub imap_uid: u32,
+    pub folder_id: i64,
+    pub folder_path: String,
+    pub message_id_header: String,
+    pub date: i64,
+    pub facts: MessageFacts,
+}
+
+impl TextMatch {
+    fn matches(&self, text: &str) -> bool {
+        text_matches(self.operator, &self.value, text)
+    }
+}
+
+fn text_matches(operator: TextOperator, value: &str, text: &str) -> bool {
+    let text = text.to_lowercase();
+    let value = value.to_lowercase();
+    match operator {
+        TextOperator::Contains => text.contains(&value),
+        TextOperator::NotContains => !text.contains(&value),
+        TextOperator::Is => text.trim() == value.trim(),
+        TextOperator::StartsWith => text.starts_with(&value),
+        TextOperator::EndsWith => text.trim_end().ends_with(&value),
+    }
+}
+
+impl Condition {
+    pub fn matches(&self, facts: &MessageFacts) -> bool {
+        match self {
+            Condition::From(m) => m.matches(&facts.from),
+            Condition::To(m) => m.matches(&facts.to),
+            Condition::Cc(m) => m.matches(&facts.cc),
+            Condition::Subject(m) => m.matches(&facts.subject),
+            Condition::Body(m) => m.matches(&facts.body),
+            Condition::Header {
+                name,
+                operator,
+                value,
+            } => {
+                let values = header_values(&facts.headers, name);
+                match operator {
+                    // A missing header does not contain anything
+                    TextOperator::NotContains => values
+                        .iter()
+                        .all(|header| text_matches(*operator, value, header)),
+                    _ => values
+                        .iter()
+                        .any(|header| text_matches(*operator, value, header)),
+                }
+            }
+            Condition::ListId(m) => header_values(&facts.headers, "List-Id")
+                .iter()
+                .any(|list_id| m.matches(list_id)),
+            Condition::HasAttachment { value } => facts.has_attachments == *value,
+            Condition::Size { operator, bytes } => match operator {
+                SizeOperator::LargerThan => facts.size_bytes > *bytes,
+                SizeOperator::SmallerThan => facts.size_bytes < *bytes,
+            },
+        }
+    }
+}
+
+impl Rule {
+    pub fn matches(&self, facts: &MessageFacts) -> bool {
+        if self.match_all {
+            self.conditions.iter().all(|c| c.matches(facts))
+        } else {
+            self.conditions.iter().any(|c| c.matches(facts))
+        }
+    }
+}
+
+/// Actions of the matching rules, in rule order, honouring `stop_processing`
+pub fn matching_actions(rules: &[Rule], facts: &MessageFacts) -> Vec<RuleAction> {
+    let mut actions = Vec::new();
+    for rule in rules.iter().filter(|rule| rule.enabled) {
+        if rule.matches(facts) {
+            actions.extend(rule.actions.iter().cloned());
+            if rule.stop_processing {
+                break;
+            }
+        }
+    }
+    actions
+}
+
+/// Header block of a raw message, up to the first empty line
+pub fn header_block(raw: &[u8]) -> String {
+    let end = raw
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .or_else(|| raw.windows(2).position(|w| w == b"\n\n"))
+        .unwrap_or(raw.len());
+    String::from_utf8_lossy(&raw[..end]).to_string()
+}
+
+/// Unfolded values of every occurrence of a header, matched case-insensitively
+pub fn header_values(headers: &str, name: &str) -> Vec<String> {
+    let mut values: Vec<String> = Vec::new();
+    let mut current: Option<String> = None;
+    for line in headers.lines() {
+        if line.starts_with(' ') || line.starts_with('\t') {
+            if let Some(value) = current.as_mut() {
+                value.push(' ');
+                value.push_str(line.trim());
+            }
+            continue;
+        }
+        values.extend(current.take());
+        if let Some((header, value)) = line.split_once(':') {
+            if header.trim().eq_ignore_ascii_case(name) {
+                current = Some(value.trim().to_string());
+            }
+        }
+    }
+    values.extend(current);
+    values
+}
+
+pub fn validate_rule(rule: &Rule) -> Result<(), DEmailError> {
+    if rule.name.trim().is_empty() {
+        return Err(DEmailError::Validation("Rule name is empty".to_string()));
+    }
+    if rule.conditions.is_empty() || rule.actions.is_empty() {
+        return Err(DEmailError::Validation(
+            "A rule needs at least one condition and one action".to_string(),
+        ));
+    }
+    for condition in &rule.conditions {
+        if let Condition::Header { name, .. } = condition {
+            if name.trim().is_empty() || name.contains(':') {
+                return Err(DEmailError::Validation(format!(
+                    "Invalid header name: {}",
+                    name
+                )));
+            }
+        }
+    }
+    for action in &rule.actions {
+        validate_action(action, true)?;
+    }
+    Ok(())
+}
+
+fn validate_action(action: &RuleAction, top_level: bool) -> Result<(), DEmailError> {
+    match action {
+        RuleAction::Move { folder } if folder.trim().is_empty() => Err(DEmailError::Validation(
+            "Move needs a target folder".to_string(),
+        )),
+        RuleAction::Label { label } if label.trim().is_empty() => {
+            Err(DEmailError::Validation("Label is empty".to_string()))
+        }
+        RuleAction::Forward { to } => validation::validate_email(to),
+        RuleAction::RunLater {
+            delay_minutes,
+            action,
+        } => {
+            if !top_level {
+                return Err(DEmailError::Validation(
+                    "Delayed actions cannot be nested".to_string(),
+                ));
+            }
+            if *delay_minutes <= 0 {
+                return Err(DEmailError::Validation(
+                    "Delay must be at least one minute".to_string(),
+                ));
+            }
+            validate_action(action, false)
+        }
+        _ => Ok(()),
+    }
+}
+
+/// All rules, or those that apply to one account, in the order they run
+pub fn list_rules(pool: &Pool, account_id: Option<i64>) -> Result<Vec<Rule>, DEmailError> {
+    let conn = pool
+        .get()
+        .map_err(|_| DEmailError::Database(rusqlite::Error::InvalidQuery))?;
+    match account_id {
+        Some(account_id) => query_rules(
+            &conn,
+            "WHERE account_id IS NULL OR account_id = ?1",
+            params![account_id],
+        ),
+        None => query_rules(&conn, "", params![]),
+    }
+}
+
+/// Enabled rules that apply to an account
+pub fn rules_for_account(conn: &Connection, account_id: i64) -> Result<Vec<Rule>, DEmailError> {
+    query_rules(
+        conn,
+        "WHERE enabled = 1 AND (account_id IS NULL OR account_id = ?1)",
+        params![account_id],
+    )
+}
+
+fn query_rules(
+    conn: &Connection,
+    filter: &str,
+    params: &[&dyn rusqlite::ToSql],
+) -> Result<Vec<Rule>, DEmailError> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT id, account_id, name, enabled, position, match_all, stop_processing,
+                conditions, actions
+         FROM rules {} ORDER BY position, id",
+        filter
+    ))?;
+    let rows = stmt
+        .query_map(params, |row| {
+            Ok((
+                Rule {
+                    id: row.get(0)?,
+                    account_id: row.get(1)?,
+                    name: row.get(2)?,
+                    enabled: row.get(3)?,
+                    position: row.get(4)?,
+                    match_all: row.get(5)?,
+                    stop_processing: row.get(6)?,
+                    conditions: Vec::new(),
+                    actions: Vec::new(),
+                },
+                row.get::<_, String>(7)?,
+                row.get::<_, String>(8)?,
+            ))
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    rows.into_iter()
+        .map(|(mut rule, conditions, actions)| {
+            rule.conditions = serde_json::from_str(&conditions)?;
+            rule.actions = serde_json::from_str(&actions)?;
+            Ok(rule)
+        })
+        .collect()
+}
+
+/// Creates the rule when its id is 0, otherwise updates it
+pub fn save_rule(pool: &Pool, rule: &Rule) -> Result<Rule, DEmailError> {
+    validate_rule(rule)?;
+    let conn = pool
+        .get()
+        .map_err(|_| DEmailError::Database(rusqlite::Error::InvalidQuery))?;
+    let conditions = serde_json::to_string(&rule.conditions)?;
+    let actions = serde_json::to_string(&rule.actions)?;
+
+    let mut saved = rule.clone();
+    if rule.id == 0 {
+        conn.execute(
+            "INSERT INTO rules (account_id, name, enabled, position, match_all, stop_processing,
+                                conditions, actions, created_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+            params![
+                rule.account_id,
+                rule.name.trim(),
+                rule.enabled,
+                rule.position,
+                rule.match_all,
+                rule.stop_processing,
+                conditions,
+                actions,
+                chrono::Utc::now().timestamp()
+            ],
+        )?;
+        saved.id = conn.last_insert_rowid();
+    } else {
+        let updated = conn.execute(
+            "UPDATE rules SET account_id = ?1, name = ?2, enabled = ?3, position = ?4,
+                match_all = ?5, stop_processing = ?6, conditions = ?7, actions = ?8
+             WHERE id = ?9",
+            params![
+                rule.account_id,
+                rule.name.trim(),
+                rule.enabled,
+                rule.position,
+                rule.match_all,
+                rule.stop_processing,
+                conditions,
+                actions,
+                rule.id
+            ],
+        )?;
+        if updated == 0 {
+            return Err(DEmailError::NotFound(format!("Rule {} not found", rule.id)));
+        }
+    }
+    saved.name = rule.name.trim().to_string();
+    Ok(saved)
+}
+
+pub fn delete_rule(pool: &Pool, rule_id: i64) -> Result<(), DEmailError> {
+    let conn = pool
+        .get()
+        .map_err(|_| DEmailError::Database(rusqlite::Error::InvalidQuery))?;
+    conn.execute("DELETE FROM rules WHERE id = ?1", [rule_id])?;
+    Ok(())
+}
+
+/// Keeps the raw headers and size of a synced message for later rule runs
+pub fn save_message_headers(
+    conn: &Connection,
+    message_id: i64,
+    headers: &str,
+    size_bytes: i64,
+) -> Result<(), DEmailError> {
+    conn.execute(
+        "INSERT OR REPLACE INTO message_headers (message_id, headers, size_bytes)
+         VALUES (?1, ?2, ?3)",
+        params![message_id, headers, size_bytes],
+    )?;
+    Ok(())
+}
+
+/// Messages at one of their locations: filter on `l` to pick which
+const STORED_MESSAGE_QUERY: &str = "SELECT m.id, m.account_id, l.imap_uid, l.folder_id, f.path,
+        COALESCE(m.message_id_header, ''), m.date,
+        m.from_header, m.to_header, COALESCE(m.cc_header, ''), COALESCE(m.subject, ''),
+        COALESCE(m.body_plain, m.body_html, ''), m.has_attachments,
+        COALESCE(h.headers, ''),
+        COALESCE(h.size_bytes, LENGTH(COALESCE(m.body_plain, '')) + LENGTH(COALESCE(m.body_html, '')))
+    FROM messages m
+    JOIN message_locations l ON l.message_id = m.id
+    JOIN folders f ON f.id = l.folder_id
+    LEFT JOIN message_headers h ON h.message_id = m.id";
+
+fn row_to_stored_message(row: &rusqlite::Row) -> rusqlite::Result<StoredMessage> {
+    Ok(StoredMessage {
+        id: row.get(0)?,
+        account_id: row.get(1)?,
+        imap_uid: row.get(2)?,
+        folder_id: row.get(3)?,
+        folder_path: row.get(4)?,
+        message_id_header: row.get(5)?,
+        date: row.get(6)?,
+        facts: MessageFacts {
+            from: row.get(7)?,
+            to: row.get(8)?,
+            cc: row.get(9)?,
+            subject: row.get(10)?,
+            body: row.get(11)?,
+            has_attachments: row.get(12)?,
+            headers: row.get(13)?,
+            size_bytes: row.get(14)?,
+        },
+    })
+}
+
+pub fn get_stored_message(
+    conn: &Connection,
+    message_id: i64,
+) -> Result<Option<StoredMessage>, DEmailError> {
+    Ok(conn
+        .query_row(
+            &format!(
+                "{} WHERE m.id = ?1 AND l.folder_id = m.folder_id AND l.imap_uid = m.imap_uid",
+                STORED_MESSAGE_QUERY
+            ),
+            [message_id],
+            row_to_stored_message,
+        )
+        .optional()?)
+}
+
+pub fn folder_messages(pool: &Pool, folder_id: i64) -> Result<Vec<StoredMessage>, DEmailError> {
+    let conn = pool
+        .get()
+        .map_err(|_| DEmailError::Database(rusqlite::Error::InvalidQuery))?;
+    let mut stmt = conn.prepare(&format!(
+        "{} WHERE l.folder_id = ?1 ORDER BY m.date DESC",
+        STORED_MESSAGE_QUERY
+    ))?;
+    let messages = stmt
+        .query_map([folder_id], row_to_stored_message)?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(messages)
+}
+
+/// Messages in a folder the rule would act on; nothing is changed
+pub fn dry_run(pool: &Pool, rule: &Rule, folder_id: i64) -> Result<Vec<RuleMatch>, DEmailError> {
+    if rule.conditions.is_empty() {
+        return Err(DEmailError::Validation(
+            "A rule needs at least one condition".to_string(),
+        ));
+    }
+    Ok(folder_messages(pool, folder_id)?
+        .into_iter()
+        .filter(|message| rule.matches(&message.facts))
+        .map(|message| RuleMatch {
+            message_id: message.id,
+            from_header: message.facts.from,
+            subject: message.facts.subject,
+            date: message.date,
+        })
+        .collect())
+}
+
+pub fn schedule_action(
+    conn: &Connection,
+    message_id: i64,
+    action: &RuleAction,
+    run_at: i64,
+) -> Result<(), DEmailError> {
+    conn.execute(
+        "INSERT INTO rule_pending_actions (message_id, action, run_at) VALUES (?1, ?2, ?3)",
+        params![message_id, serde_json::to_string(action)?, run_at],
+    )?;
+    Ok(())
+}
+
+/// Delayed actions of an account that are due, oldest first
+pub fn due_actions(
+    conn: &Connection,
+    account_id: i64,
+    now: i64,
+) -> Result<Vec<(i64, i64, RuleAction)>, DEmailError> {
+    let mut stmt = conn.prepare(
+        "SELECT p.id, p.message_id, p.action FROM rule_pending_actions p
+         JOIN messages m ON m.id = p.message_id
+         WHERE m.account_id = ?1 AND p.run_at <= ?2
+         ORDER BY p.run_at, p.id",
+    )?;
+    let rows = stmt
+        .query_map(params![account_id, now], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get::<_, String>(2)?))
+        })?
+        .collect::<Result<Vec<(i64, i64, String)>, _>>()?;
+    rows.into_iter()
+        .map(|(id, message_id, action)| Ok((id, message_id, serde_json::from_str(&action)?)))
+        .collect()
+}
+
+pub fn remove_pending_action(conn: &Connection, pending_id: i64) -> Result<(), DEmailError> {
+    conn.execute(
+        "DELETE FROM rule_pending_actions WHERE id = ?1",
+        [pending_id],
+    )?;
+    Ok(())
+}
+
+/// Remembers a message a rule moved on the server, with its tags, so the copy synced
+/// from the target folder keeps them and is not run through the rules again
+pub fn record_move(
+    conn: &Connection,
+    message: &StoredMessage,
+    target_folder: &str,
+) -> Result<(), DEmailError> {
+    if message.message_id_header.is_empty() {
+        return Ok(());
+    }
+    let labels = tags::message_tags(conn, message.id)?;
+    conn.execute(
+        "INSERT OR REPLACE INTO rule_moves (account_id, folder_path, message_id_header, labels, moved_at)
+         VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![
+            message.account_id,
+            target_folder,
+            message.message_id_header,
+            serde_json::to_string(&labels)?,
+            chrono::Utc::now().timestamp()
+        ],
+    )?;
+    Ok(())
+}
+
+/// Tags of a message a rule moved into this folder, consuming the record
+pub fn take_move(
+    conn: &Connection,
+    account_id: i64,
+    folder_path: &str,
+    message_id_header: &str,
+) -> Result<Option<Vec<String>>, DEmailError> {
+    let labels: Option<String> = conn
+        .query_row(
+            "DELETE FROM rule_moves
+             WHERE account_id = ?1 AND folder_path = ?2 AND message_id_header = ?3
+             RETURNING labels",
+            params![account_id, folder_path, message_id_header],
+            |row| row.get(0),
+        )
+        .optional()?;
+    Ok(match labels {
+        Some(labels) => Some(serde_json::from_str(&labels)?),
+        None => None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::accounts::create_account;
+    use crate::core::cache::db::{save_message, test_folder, test_message, test_pool};
+    use crate::models::Message;
+
+    fn text(operator: TextOperator, value: &str) -> TextMatch {
+        TextMatch {
+            operator,
+            value: value.to_string(),
+        }
+    }
+
+    fn rule(conditions: Vec<Condition>, actions: Vec<RuleAction>) -> Rule {
+        Rule {
+            id: 0,
+            account_id: None,
+            name: "Newsletters".to_string(),
+            enabled: true,
+            position: 0,
+            match_all: true,
+            stop_processing: false,
+            conditions,
+            actions,
+        }
+    }
+
+    fn newsletter() -> MessageFacts {
+        MessageFacts {
+            from: "News <news@shop.example>".to_string(),
+            to: "me@example.org".to_string(),
+            subject: "Weekly Deals".to_string(),
+            body: "Unsubscribe at any time".to_string(),
+            headers: "From: news@shop.example\r\nList-Id: Shop deals\r\n <deals.shop.example>\r\nX-Mailer: Bulk\r\n"
+                .to_string(),
+            size_bytes: 2048,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_conditions() {
+        let facts = newsletter();
+        assert!(Condition::From(text(TextOperator::Contains, "SHOP.example")).matches(&facts));
+        assert!(Condition::Subject(text(TextOperator::StartsWith, "weekly")).matches(&facts));
+        assert!(Condition::Subject(text(TextOperator::Is, "weekly deals")).matches(&facts));
+        assert!(!Condition::Body(text(TextOperator::NotContains, "unsubscribe")).matches(&facts));
+        assert!(Condition::To(text(TextOperator::EndsWith, "@example.org")).matches(&facts));
+        assert!(
+            Condition::ListId(text(TextOperator::Contains, "<deals.shop.example>")).matches(&facts)
+        );
+        assert!(Condition::Header {
+            name: "x-mailer".to_string(),
+            operator: TextOperator::Is,
+            value: "bulk".to_string(),
+        }
+        .matches(&facts));
+        // A header that is absent matches "does not contain" only
+        let missing = |operator| Condition::Header {
+            name: "X-Spam".to_string(),
+            operator,
+            value: "yes".to_string(),
+        };
+        assert!(missing(TextOperator::NotContains).matches(&facts));
+        assert!(!missing(TextOperator::Contains).matches(&facts));
+        assert!(Condition::HasAttachment { value: false }.matches(&facts));
+        assert!(Condition::Size {
+            operator: SizeOperator::LargerThan,
+            bytes: 1024
+        }
+        .matches(&facts));
+        assert!(!Condition::Size {
+            operator: SizeOperator::SmallerThan,
+            bytes: 1024
+        }
+        .matches(&facts));
+    }
+
+    #[test]
+    fn test_matching_actions_order_and_stop() {
+        let facts = newsletter();
+        let mut first = rule(
+            vec![
+                Condition::From(text(TextOperator::Contains, "nobody")),
+                Condition::Subject(text(TextOperator::Contains, "deals")),
+            ],
+            vec![RuleAction::MarkRead],
+        );
+        let second = rule(
+            vec![Condition::ListId(text(TextOperator::Contains, "shop"))],
+            vec![RuleAction::Label {
+                label: "Shopping".to_string(),
+            }],
+        );
+
+        // All conditions must match unless the rule matches on any
+        assert_eq!(
+            matching_actions(&[first.clone(), second.clone()], &facts).len(),
+            1
+        );
+        first.match_all = false;
+        assert_eq!(
+            matching_actions(&[first.clone(), second.clone()], &facts),
+            vec![
+                RuleAction::MarkRead,
+                RuleAction::Label {
+                    label: "Shopping".to_string()
+                }
+            ]
+        );
+
+        first.stop_processing = true;
+        assert_eq!(
+            matching_actions(&[first.clone(), second.clone()], &facts),
+            vec![RuleAction::MarkRead]
+        );
+        first.enabled = false;
+        assert_eq!(matching_actions(&[first, second], &facts).len(), 1);
+    }
+
+    #[test]
+    fn test_header_parsing() {
+        let raw = b"Subject: Hi\r\nList-Id: First\r\n\tfolded <a.example>\r\nlist-id: Second\r\n\r\nList-Id: in body\r\n";
+        let headers = header_block(raw);
+        assert!(!headers.contains("in body"));
+        assert_eq!(
+            header_values(&headers, "LIST-ID"),
+            vec!["First folded <a.example>", "Second"]
+        );
+        assert!(header_values(&headers, "X-Missing").is_empty());
+    }
+
+    #[test]
+    fn test_validation() {
+        let valid = rule(
+            vec![Condition::HasAttachment { value: true }],
+            vec![RuleAction::RunLater {
+                delay_minutes: 60,
+                action: Box::new(RuleAction::Move {
+                    folder: "Archive".to_string(),
+                }),
+            }],
+        );
+        assert!(validate_rule(&valid).is_ok());
+
+        let mut invalid = valid.clone();
+        invalid.actions = vec![RuleAction::RunLater {
+            delay_minutes: 60,
+            action: Box::new(valid.actions[0].clone()),
+        }];
+        assert!(validate_rule(&invalid).is_err());
+        invalid.actions = vec![RuleAction::Forward {
+            to: "not an address".to_string(),
+        }];
+        assert!(validate_rule(&invalid).is_err());
+        invalid.actions.clear();
+        assert!(validate_rule(&invalid).is_err());
+    }
+
+    #[test]
+    fn test_storage_dry_run_and_pending_actions() {
+        let pool = test_pool();
+        let account = create_account(&pool, "me@example.org", "Me", "google").unwrap();
+        let folder = test_folder(&pool, account.id, "INBOX");
+
+        let mut ids = Vec::new();
+        for (uid, from, subject) in [
+            (1, "news@shop.example", "Weekly deals"),
+            (2, "friend@example.org", "Lunch?"),
+        ] {
+            let message = Message {
+                from_header: from.to_string(),
+                to_header: "me@example.org".to_string(),
+                subject: subject.to_string(),
+                date: 1_700_000_000 + uid as i64,
+                body_plain: Some("Hello".to_string()),
+                ..test_message(account.id, folder.id, uid)
+            };
+            ids.push(save_message(&pool, &message).unwrap());
+        }
+        let conn = pool.get().unwrap();
+        save_message_headers(&conn, ids[0], "List-Id: <deals.shop.example>", 4096).unwrap();
+
+        let saved = save_rule(
+            &pool,
+            &rule(
+                vec![Condition::ListId(text(TextOperator::Contains, "shop"))],
+                vec![RuleAction::Star],
+            ),
+        )
+        .unwrap();
+        assert!(saved.id > 0);
+        let mut scoped = saved.clone();
+        scoped.id = 0;
+        scoped.position = 1;
+        let other = create_account(&pool, "other@example.org", "Other", "google").unwrap();
+        scoped.account_id = Some(other.id);
+        save_rule(&pool, &scoped).unwrap();
+
+        assert_eq!(list_rules(&pool, None).unwrap().len(), 2);
+        let for_account = rules_for_account(&conn, account.id).unwrap();
+        assert_eq!(for_account.len(), 1);
+        assert_eq!(for_account[0].conditions, saved.conditions);
+
+        let matches = dry_run(&pool, &saved, folder.id).unwrap();
+        assert_eq!(matches.len(), 1);
+        assert_eq!(matches[0].message_id, ids[0]);
+
+        // A moved message keeps its labels when it shows up in the target folder
+        let stored = get_stored_message(&conn, ids[0]).unwrap().unwrap();
+        assert_eq!(stored.message_id_header, "<1@example.com>");
+        tags::add_tag(&conn, ids[0], "Shopping").unwrap();
+        record_move(&conn, &stored, "Archive").unwrap();
+        assert_eq!(
+            take_move(&conn, account.id, "Archive", "<1@example.com>").unwrap(),
+            Some(vec!["Shopping".to_string()])
+        );
+        assert!(take_move(&conn, account.id, "Archive", "<1@example.com>")
+            .unwrap()
+            .is_none());
+
+        // Delayed actions come back once due and disappear with their message
+        let archive = RuleAction::Move {
+            folder: "Archive".to_string(),
+        };
+        schedule_action(&conn, ids[0], &archive, 100).unwrap();
+        schedule_action(&conn, ids[1], &RuleAction::MarkRead, 500).unwrap();
+        let due = due_actions(&conn, account.id, 200).unwrap();
+        assert_eq!(due.len(), 1);
+        assert_eq!((due[0].1, &due[0].2), (ids[0], &archive));
+        remove_pending_action(&conn, due[0].0).unwrap();
+        conn.execute("DELETE FROM messages WHERE id = ?1", [ids[1]])
+            .unwrap();
+        assert!(due_actions(&conn, account.id, 1000).unwrap().is_empty());
+
+        delete_rule(&pool, saved.id).unwrap();
+        assert_eq!(list_rules(&pool, None).unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,1276 @@
+// Sender authentication module - Authentication-Results/ARC parsing, offline DKIM
+// verification and phishing heuristics
+
+use crate::error::DEmailError;
+use crate::models::{DkimKey, MessageAuthentication, SenderWarning};
+use mail_parser::Message as ParsedMessage;
+use openssl::hash::MessageDigest;
+use openssl::pkey::{Id, PKey, Public};
+use openssl::rsa::Rsa;
+use openssl::sign::Verifier;
+use rusqlite::Connection;
+use std::collections::{HashMap, HashSet};
+
+pub const RESULT_PASS: &str = "pass";
+pub const RESULT_FAIL: &str = "fail";
+pub const RESULT_NEUTRAL: &str = "neutral";
+
+pub const VERDICT_TRUSTED: &str = "trusted";
+pub const VERDICT_UNKNOWN: &str = "unknown";
+pub const VERDICT_SUSPICIOUS: &str = "suspicious";
+pub const VERDICT_DANGEROUS: &str = "dangerous";
+
+pub const WARNING_AUTHENTICATION_FAILED: &str = "authentication_failed";
+pub const WARNING_DISPLAY_NAME_SPOOF: &str = "display_name_spoof";
+pub const WARNING_LOOKALIKE_DOMAIN: &str = "lookalike_domain";
+pub const WARNING_REPLY_TO_MISMATCH: &str = "reply_to_mismatch";
+
+/// Contacts used at least this often count as known correspondents for lookalike checks
+const KNOWN_CONTACT_MIN_USES: i64 = 2;
+
+/// Checks a synced message: reads the receiving server's Authentication-Results and
+/// ARC headers, verifies DKIM signatures against locally cached keys and runs the
+/// phishing heuristics. Only results stamped by one of `authserv_ids` (the account
+/// provider's servers) are trusted. Never fails; missing data simply yields an `unknown`
+/// verdict.
+pub fn analyze_message(
+    conn: &Connection,
+    raw: &[u8],
+    parsed: &ParsedMessage,
+    authserv_ids: &[String],
+) -> MessageAuthentication {
+    let (fields, body) = split_message(raw);
+
+    let mut authentication = MessageAuthentication {
+        message_id: 0,
+        authserv_id: None,
+        spf: None,
+        dkim: None,
+        dmarc: None,
+        arc: None,
+        dkim_local: None,
+        dkim_domain: None,
+        verdict: VERDICT_UNKNOWN.to_string(),
+        warnings: Vec::new(),
+    };
+
+    // Any Authentication-Results header could have been written by the sender, so only
+    // the topmost one stamped by our provider's servers counts. When there is none
+    // (e.g. local delivery), fall back to the newest ARC set if its chain validated and
+    // it was sealed by the provider too. Anything else leaves the results unknown.
+    let arc = arc_chain_status(&fields);
+    let results = fields
+        .iter()
+        .filter(|field| field.name_is("authentication-results"))
+        .filter_map(|field| parse_authentication_results(&field.value()))
+        .find(|results| is_trusted_authserv(&results.authserv_id, authserv_ids))
+        .or_else(|| {
+            (arc.as_deref() == Some(RESULT_PASS))
+                .then(|| newest_arc_results(&fields))
+                .flatten()
+                .filter(|results| is_trusted_authserv(&results.authserv_id, authserv_ids))
+        });
+
+    if let Some(results) = results {
+        authentication.authserv_id = Some(results.authserv_id.clone());
+        authentication.spf = results.result("spf").map(str::to_string);
+        authentication.dmarc = results.result("dmarc").map(str::to_string);
+        if let Some((result, domain)) = results.dkim() {
+            authentication.dkim = Some(result);
+            authentication.dkim_domain = domain;
+        }
+        authentication.arc = results.result("arc").map(str::to_string);
+    }
+    if authentication.arc.is_none() {
+        authentication.arc = arc;
+    }
+
+    if let Some(outcome) = verify_dkim(conn, &fields, &body) {
+        if outcome.result == RESULT_PASS || authentication.dkim_domain.is_none() {
+            authentication.dkim_domain = outcome.domain;
+        }
+        authentication.dkim_local = Some(outcome.result);
+    }
+
+    let from = parsed
+        .from()
+        .and_then(|from| from.first())
+        .and_then(|addr| addr.address())
+        .map(|address| address.to_lowercase());
+    let from_name = parsed
+        .from()
+        .and_then(|from| from.first())
+        .and_then(|addr| addr.name())
+        .map(str::to_string);
+    let reply_to = parsed
+        .reply_to()
+        .and_then(|reply_to| reply_to.first())
+        .and_then(|addr| addr.address())
+        .map(|address| address.to_lowercase());
+
+    let known_domains = known_domains(conn);
+    let mut warnings = Vec::new();
+    if let Some(from) = from.as_deref() {
+        if let Some(warning) = check_display_name(from_name.as_deref(), from) {
+            warnings.push(warning);
+        }
+        if let Some(warning) = check_lookalike_domain(from, &known_domains) {
+            warnings.push(warning);
+        }
+        if let Some(warning) = check_reply_to(from, reply_to.as_deref()) {
+            warnings.push(warning);
+        }
+    }
+
+    let from_domain = from.as_deref().and_then(email_domain);
+    let aligned = |domain: &Option<String>| match (domain, from_domain) {
+        (Some(domain), Some(from_domain)) => {
+            organizational_domain(domain) == organizational_domain(from_domain)
+        }
+        _ => false,
+    };
+
+    let dkim_pass = authentication.dkim.as_deref() == Some(RESULT_PASS)
+        || authentication.dkim_local.as_deref() == Some(RESULT_PASS);
+    let authenticated = authentication.dmarc.as_deref() == Some(RESULT_PASS)
+        || (dkim_pass && aligned(&authentication.dkim_domain));
+    let failed = authentication.dmarc.as_deref() == Some(RESULT_FAIL)
+        || authentication.dkim_local.as_deref() == Some(RESULT_FAIL)
+        || (!authenticated && authentication.spf.as_deref() == Some(RESULT_FAIL));
+
+    authentication.verdict = if failed && !warnings.is_empty() {
+        VERDICT_DANGEROUS
+    } else if failed || !warnings.is_empty() {
+        VERDICT_SUSPICIOUS
+    } else if authenticated {
+        VERDICT_TRUSTED
+    } else {
+        VERDICT_UNKNOWN
+    }
+    .to_string();
+
+    if failed {
+        warnings.insert(
+            0,
+            SenderWarning {
+                kind: WARNING_AUTHENTICATION_FAILED.to_string(),
+                message: "This message failed sender authentication and may be forged".to_string(),
+            },
+        );
+    }
+    authentication.warnings = warnings;
+
+    authentication
+}
+
+/// Saves (or replaces) the authentication results of a message
+pub fn save_message_authentication(
+    conn: &Connection,
+    message_id: i64,
+    authentication: &MessageAuthentication,
+) -> Result<(), DEmailError> {
+    conn.execute(
+        "INSERT OR REPLACE INTO message_authentication (message_id, authserv_id, spf, dkim, dmarc, arc, dkim_local, dkim_domain, verdict, warnings)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+        rusqlite::params![
+            message_id,
+            authentication.authserv_id,
+            authentication.spf,
+            authentication.dkim,
+            authentication.dmarc,
+            authentication.arc,
+            authentication.dkim_local,
+            authentication.dkim_domain,
+            authentication.verdict,
+            serde_json::to_string(&authentication.warnings)?,
+        ],
+    )?;
+    Ok(())
+}
+
+/// Loads the authentication results of a message, if it was analyzed at sync time
+pub fn get_message_authentication(
+    conn: &Connection,
+    message_id: i64,
+) -> Result<Option<MessageAuthentication>, DEmailError> {
+    let result = conn.query_row(
+        "SELECT message_id, authserv_id, spf, dkim, dmarc, arc, dkim_local, dkim_domain, verdict, warnings
+         FROM message_authentication WHERE message_id = ?1",
+        [message_id],
+        |row| {
+            let warnings: String = row.get(9)?;
+            Ok(MessageAuthentication {
+                message_id: row.get(0)?,
+                authserv_id: row.get(1)?,
+                spf: row.get(2)?,
+                dkim: row.get(3)?,
+                dmarc: row.get(4)?,
+                arc: row.get(5)?,
+                dkim_local: row.get(6)?,
+                dkim_domain: row.get(7)?,
+                verdict: row.get(8)?,
+                warnings: serde_json::from_str(&warnings).unwrap_or_default(),
+            })
+        },
+    );
+
+    match result {
+        Ok(authentication) => Ok(Some(authentication)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(DEmailError::from(e)),
+    }
+}
+
+// ============================================================================
+// DKIM KEY CACHE
+// ============================================================================
+
+/// Adds (or replaces) a DKIM public key record for offline verification. `record` is the
+/// TXT record published at `<selector>._domainkey.<domain>`, e.g. `v=DKIM1; k=rsa; p=...`
+pub fn add_dkim_key(
+    conn: &Connection,
+    domain: &str,
+    selector: &str,
+    record: &str,
+) -> Result<DkimKey, DEmailError> {
+    let domain = domain.trim().trim_end_matches('.').to_lowercase();
+    let selector = selector.trim().to_lowercase();
+    let record = record.trim().to_string();
+
+    if domain.is_empty() || selector.is_empty() {
+        return Err(DEmailError::Validation(
+            "DKIM domain and selector are required".to_string(),
+        ));
+    }
+    parse_key_record(&record)?;
+
+    let key = DkimKey {
+        domain,
+        selector,
+        record,
+        created_at: chrono::Utc::now().timestamp(),
+    };
+    conn.execute(
+        "INSERT OR REPLACE INTO dkim_keys (domain, selector, record, created_at) VALUES (?1, ?2, ?3, ?4)",
+        rusqlite::params![key.domain, key.selector, key.record, key.created_at],
+    )?;
+    Ok(key)
+}
+
+/// Lists all cached DKIM keys
+pub fn list_dkim_keys(conn: &Connection) -> Result<Vec<DkimKey>, DEmailError> {
+    let mut stmt = conn.prepare(
+        "SELECT domain, selector, record, created_at FROM dkim_keys ORDER BY domain, selector",
+    )?;
+    let keys = stmt
+        .query_map([], |row| {
+            Ok(DkimKey {
+                domain: row.get(0)?,
+                selector: row.get(1)?,
+                record: row.get(2)?,
+                created_at: row.get(3)?,
+            })
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(keys)
+}
+
+/// Removes a cached DKIM key
+pub fn delete_dkim_key(conn: &Connection, domain: &str, selector: &str) -> Result<(), DEmailError> {
+    let deleted = conn.execute(
+        "DELETE FROM dkim_keys WHERE domain = ?1 AND selector = ?2",
+        rusqlite::params![domain.to_lowercase(), selector.to_lowercase()],
+    )?;
+    if deleted == 0 {
+        return Err(DEmailError::NotFound(format!(
+            "DKIM key {}._domainkey.{}",
+            selector, domain
+        )));
+    }
+    Ok(())
+}
+
+fn find_dkim_key(conn: &Connection, domain: &str, selector: &str) -> Option<String> {
+    conn.query_row(
+        "SELECT record FROM dkim_keys WHERE domain = ?1 AND selector = ?2",
+        rusqlite::params![domain.to_lowercase(), selector.to_lowercase()],
+        |row| row.get(0),
+    )
+    .ok()
+}
+
+// ============================================================================
+// HEADER PARSING
+// ============================================================================
+
+/// A raw header field, including its name, folding and terminating CRLF
+struct HeaderField {
+    name: String,
+    raw: Vec<u8>,
+}
+
+impl HeaderField {
+    fn name_is(&self, name: &str) -> bool {
+        self.name.eq_ignore_ascii_case(name)
+    }
+
+    /// Unfolded field value
+    fn value(&self) -> String {
+        let raw = String::from_utf8_lossy(&self.raw);
+        let value = raw.split_once(':').map(|(_, value)| value).unwrap_or("");
+        value.replace("\r\n", "").trim().to_string()
+    }
+}
+
+/// Splits a message into its header fields (top to bottom) and body, with every line
+/// ending normalized to CRLF
+fn split_message(raw: &[u8]) -> (Vec<HeaderField>, Vec<u8>) {
+    let mut normalized = Vec::with_capacity(raw.len() + raw.len() / 32);
+    for (i, &byte) in raw.iter().enumerate() {
+        if byte == b'\n' && (i == 0 || raw[i - 1] != b'\r') {
+            normalized.push(b'\r');
+        }
+        normalized.push(byte);
+    }
+
+    let mut fields: Vec<HeaderField> = Vec::new();
+    let mut pos = 0;
+    while pos < normalized.len() {
+        let end = find_crlf(&normalized[pos..])
+            .map(|offset| pos + offset + 2)
+            .unwrap_or(normalized.len());
+        let line = &normalized[pos..end];
+        pos = end;
+
+        if line == b"\r\n" {
+            break;
+        }
+        if line.starts_with(b" ") || line.starts_with(b"\t") {
+            if let Some(field) = fields.last_mut() {
+                field.raw.extend_from_slice(line);
+            }
+            continue;
+        }
+        let name = match line.iter().position(|&byte| byte == b':') {
+            Some(colon) => String::from_utf8_lossy(&line[..colon]).trim().to_string(),
+            None => continue,
+        };
+        fields.push(HeaderField {
+            name,
+            raw: line.to_vec(),
+        });
+    }
+
+    (fields, normalized[pos.min(normalized.len())..].to_vec())
+}
+
+fn find_crlf(data: &[u8]) -> Option<usize> {
+    data.windows(2).position(|window| window == b"\r\n")
+}
+
+/// Parsed Authentication-Results (RFC 8601) header
+#[derive(Debug, Clone, PartialEq)]
+struct AuthResults {
+    authserv_id: String,
+    /// (method, result, properties) in header order
+    methods: Vec<(String, String, HashMap<String, String>)>,
+}
+
+impl AuthResults {
+    fn result(&self, method: &str) -> Option<&str> {
+        self.methods
+            .iter()
+            .find(|(name, _, _)| name == method)
+            .map(|(_, result, _)| result.as_str())
+    }
+
+    /// Overall DKIM result: a passing signature wins over failing ones
+    fn dkim(&self) -> Option<(String, Option<String>)> {
+        let mut signatures = self.methods.iter().filter(|(name, _, _)| name == "dkim");
+        let first = signatures.clone().next()?;
+        let chosen = signatures
+            .find(|(_, result, _)| result == RESULT_PASS)
+            .unwrap_or(first);
+        let domain = chosen
+            .2
+            .get("header.d")
+            .cloned()
+            .or_else(|| {
+                chosen
+                    .2
+                    .get("header.i")
+                    .and_then(|i| email_domain(i).map(str::to_string))
+            })
+            .map(|domain| domain.to_lowercase());
+        Some((chosen.1.clone(), domain))
+    }
+}
+
+/// Whether `authserv_id` is one of `trusted` or a host below one of them
+fn is_trusted_authserv(authserv_id: &str, trusted: &[String]) -> bool {
+    trusted.iter().any(|trusted| {
+        let trusted = trusted.to_lowercase();
+        authserv_id == trusted
+            || authserv_id
+                .strip_suffix(trusted.as_str())
+                .is_some_and(|prefix| prefix.ends_with('.'))
+    })
+}
+
+/// Parses an Authentication-Results value: `authserv-id; method=result prop=value; ...`
+fn parse_authentication_results(value: &str) -> Option<AuthResults> {
+    let value = strip_comments(value);
+    let mut statements = value.split(';');
+
+    let authserv_id = statements.next()?.split_whitespace().next()?.to_lowercase();
+    let mut methods = Vec::new();
+    for statement in statements {
+        let mut tokens = statement.split_whitespace();
+        let (method, result) = match tokens.next().and_then(|token| token.split_once('=')) {
+            Some(pair) => pair,
+            None => continue,
+        };
+        // Drop an optional method version ("dkim/1")
+        let method = method.split('/').next().unwrap_or(method).to_lowercase();
+        let properties = tokens
+            .filter_map(|token| token.split_once('='))
+            .map(|(key, value)| (key.to_lowercase(), value.trim_matches('"').to_string()))
+            .collect();
+        methods.push((method, result.to_lowercase(), properties));
+    }
+
+    Some(AuthResults {
+        authserv_id,
+        methods,
+    })
+}
+
+/// Removes RFC 5322 comments, which may carry arbitrary text such as "(pass)"
+fn strip_comments(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    let mut depth = 0usize;
+    let mut quoted = false;
+    let mut escaped = false;
+    for c in value.chars() {
+        if escaped {
+            escaped = false;
+            if depth == 0 {
+                out.push(c);
+            }
+            continue;
+        }
+        match c {
+            '\\' => {
+                escaped = true;
+                if depth == 0 {
+                    out.push(c);
+                }
+            }
+            '"' if depth == 0 => {
+                quoted = !quoted;
+                out.push(c);
+            }
+            '(' if !quoted => depth += 1,
+            ')' if !quoted && depth > 0 => depth -= 1,
+            _ if depth == 0 => out.push(c),
+            _ => {}
+        }
+    }
+    out
+}
+
+/// Splits a `tag=value; tag=value` list (DKIM-Signature, DKIM key records, ARC headers)
+fn parse_tags(value: &str) -> HashMap<String, String> {
+    value
+        .split(';')
+        .filter_map(|tag| tag.split_once('='))
+        .map(|(name, value)| (name.trim().to_lowercase(), value.trim().to_string()))
+        .collect()
+}
+
+fn instance(value: &str) -> Option<u32> {
+    parse_tags(value).get("i").and_then(|i| i.parse().ok())
+}
+
+/// Chain validation status (`cv=`) of the newest ARC-Seal
+fn arc_chain_status(fields: &[HeaderField]) -> Option<String> {
+    fields
+        .iter()
+        .filter(|field| field.name_is("arc-seal"))
+        .map(|field| parse_tags(&field.value()))
+        .max_by_key(|tags| tags.get("i").and_then(|i| i.parse::<u32>().ok()))
+        .and_then(|tags| tags.get("cv").map(|cv| cv.to_lowercase()))
+}
+
+/// Authentication results recorded by the newest ARC intermediary
+fn newest_arc_results(fields: &[HeaderField]) -> Option<AuthResults> {
+    fields
+        .iter()
+        .filter(|field| field.name_is("arc-authentication-results"))
+        .map(|field| field.value())
+        .max_by_key(|value| instance(value))
+        .and_then(|value| {
+            let (_, rest) = value.split_once(';')?;
+            parse_authentication_results(rest)
+        })
+}
+
+// ============================================================================
+// DKIM VERIFICATION (RFC 6376, RFC 8463)
+// ============================================================================
+
+#[derive(Debug, Clone, PartialEq)]
+struct DkimOutcome {
+    result: String,
+    domain: Option<String>,
+}
+
+/// Verifies every DKIM-Signature for which a key is cached. Returns `None` when the
+/// message is unsigned; `neutral` when no signature could be checked.
+fn verify_dkim(conn: &Connection, fields: &[HeaderField], body: &[u8]) -> Option<DkimOutcome> {
+    let signatures: Vec<&HeaderField> = fields
+        .iter()
+        .filter(|field| field.name_is("dkim-signature"))
+        .collect();
+    if signatures.is_empty() {
+        return None;
+    }
+
+    let mut outcome = DkimOutcome {
+        result: RESULT_NEUTRAL.to_string(),
+        domain: None,
+    };
+    for signature in signatures {
+        let tags = parse_tags(&signature.value());
+        let domain = tags.get("d").map(|d| d.to_lowercase());
+        let record = match (domain.as_deref(), tags.get("s")) {
+            (Some(domain), Some(selector)) => find_dkim_key(conn, domain, selector),
+            _ => None,
+        };
+        let Some(record) = record else { continue };
+
+        match verify_signature(fields, body, signature, &tags, &record) {
+            Ok(true) => {
+                return Some(DkimOutcome {
+                    result: RESULT_PASS.to_string(),
+                    domain,
+                });
+            }
+            Ok(false) => {
+                outcome = DkimOutcome {
+                    result: RESULT_FAIL.to_string(),
+                    domain,
+                };
+            }
+            // Unsupported algorithms and malformed signatures cannot be judged
+            Err(_) => {}
+        }
+    }
+    Some(outcome)
+}
+
+/// Verifies one signature. `Ok(false)` means the message does not match the signature,
+/// `Err` that the signature or key cannot be evaluated.
+fn verify_signature(
+    fields: &[HeaderField],
+    body: &[u8],
+    signature: &HeaderField,
+    tags: &HashMap<String, String>,
+    record: &str,
+) -> Result<bool, DEmailError> {
+    let tag = |name: &str| {
+        tags.get(name)
+            .ok_or_else(|| DEmailError::Validation(format!("DKIM-Signature without {}=", name)))
+    };
+
+    if tag("v")? != "1" {
+        return Err(DEmailError::Validation(
+            "Unsupported DKIM version".to_string(),
+        ));
+    }
+    let algorithm = tag("a")?.to_lowercase();
+    let (header_relaxed, body_relaxed) = match tags.get("c").map(|c| c.to_lowercase()) {
+        None => (false, false),
+        Some(c) => match c.split_once('/') {
+            Some((header, body)) => (header == "relaxed", body == "relaxed"),
+            None => (c == "relaxed", false),
+        },
+    };
+    if let Some(expires) = tags.get("x").and_then(|x| x.parse::<i64>().ok()) {
+        if expires < chrono::Utc::now().timestamp() {
+            return Ok(false);
+        }
+    }
+
+    // Body hash
+    let mut canonical_body = canonicalize_body(body, body_relaxed);
+    if let Some(length) = tags.get("l").and_then(|l| l.parse::<usize>().ok()) {
+        canonical_body.truncate(length);
+    }
+    let body_hash = decode_base64(tag("bh")?)?;
+    if openssl::sha::sha256(&canonical_body).as_slice() != body_hash.as_slice() {
+        return Ok(false);
+    }
+
+    // Signed headers are taken bottom-up, each instance at most once
+    let mut data = Vec::new();
+    let mut used = HashSet::new();
+    for name in tag("h")?.split(':').map(str::trim) {
+        let selected = fields
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|(index, field)| field.name_is(name) && !used.contains(index));
+        if let Some((index, field)) = selected {
+            used.insert(index);
+            data.extend(canonicalize_header(&field.raw, header_relaxed));
+        }
+    }
+    let mut own = canonicalize_header(&strip_signature_value(&signature.raw), header_relaxed);
+    if own.ends_with(b"\r\n") {
+        own.truncate(own.len() - 2);
+    }
+    data.extend(own);
+
+    let signature_bytes = decode_base64(tag("b")?)?;
+    let (key_type, public_key) = parse_key_record(record)?;
+    let key = match public_key {
+        Some(key) => key,
+        // An empty p= means the key was revoked
+        None => return Ok(false),
+    };
+
+    let crypto_error = |e: openssl::error::ErrorStack| DEmailError::Crypto(e.to_string());
+    match (algorithm.as_str(), key_type.as_str()) {
+        ("rsa-sha256", "rsa") => {
+            let pkey = rsa_public_key(&key)?;
+            let mut verifier =
+                Verifier::new(MessageDigest::sha256(), &pkey).map_err(crypto_error)?;
+            verifier.update(&data).map_err(crypto_error)?;
+            Ok(verifier.verify(&signature_bytes).unwrap_or(false))
+        }
+        ("ed25519-sha256", "ed25519") => {
+            let pkey = PKey::public_key_from_raw_bytes(&key, Id::ED25519).map_err(crypto_error)?;
+            let mut verifier = Verifier::new_without_digest(&pkey).map_err(crypto_error)?;
+            let digest = openssl::sha::sha256(&data);
+            Ok(verifier
+                .verify_oneshot(&signature_bytes, &digest)
+                .unwrap_or(false))
+        }
+        // rsa-sha1 must not be considered valid (RFC 8301)
+        (algorithm, key_type) => Err(DEmailError::Crypto(format!(
+            "Unsupported DKIM algorithm {} for {} key",
+            algorithm, key_type
+        ))),
+    }
+}
+
+/// Returns the key type and decoded public key (`None` when revoked) of a key record
+fn parse_key_record(record: &str) -> Result<(String, Option<Vec<u8>>), DEmailError> {
+    let tags = parse_tags(record);
+    if let Some(version) = tags.get("v") {
+        if version != "DKIM1" {
+            return Err(DEmailError::Validation(format!(
+                "Unsupported DKIM key record version: {}",
+                version
+            )));
+        }
+    }
+    let key_type = tags
+        .get("k")
+        .map(|k| k.to_lowercase())
+        .unwrap_or_else(|| "rsa".to_string());
+    let public_key = tags
+        .get("p")
+        .ok_or_else(|| DEmailError::Validation("DKIM key record without p=".to_string()))?;
+    if public_key.is_empty() {
+        return Ok((key_type, None));
+    }
+    Ok((key_type, Some(decode_base64(public_key)?)))
+}
+
+/// Accepts both SubjectPublicKeyInfo and bare PKCS#1 RSA keys, which are found in the wild
+fn rsa_public_key(der: &[u8]) -> Result<PKey<Public>, DEmailError> {
+    PKey::public_key_from_der(der)
+        .or_else(|_| Rsa::public_key_from_der_pkcs1(der).and_then(PKey::from_rsa))
+        .map_err(|e| DEmailError::Crypto(format!("Invalid DKIM RSA key: {}", e)))
+}
+
+fn decode_base64(value: &str) -> Result<Vec<u8>, DEmailError> {
+    let compact: String = value.chars().filter(|c| !c.is_whitespace()).collect();
+    openssl::base64::decode_block(&compact)
+        .map_err(|e| DEmailError::Validation(format!("Invalid base64 in DKIM data: {}", e)))
+}
+
+/// Empties the b= tag of a DKIM-Signature field, keeping everything else byte for byte
+fn strip_signature_value(raw: &[u8]) -> Vec<u8> {
+    let field = String::from_utf8_lossy(raw);
+    let Some((name, value)) = field.split_once(':') else {
+        return raw.to_vec();
+    };
+    let mut stripped = String::with_capacity(field.len());
+    stripped.push_str(name);
+    stripped.push(':');
+    for (i, tag) in value.split(';').enumerate() {
+        if i > 0 {
+            stripped.push(';');
+        }
+        match tag.split_once('=') {
+            Some((tag_name, _)) if tag_name.trim() == "b" => {
+                stripped.push_str(tag_name);
+                stripped.push('=');
+            }
+            _ => stripped.push_str(tag),
+        }
+    }
+    stripped.into_bytes()
+}
+
+fn canonicalize_header(raw: &[u8], relaxed: bool) -> Vec<u8> {
+    if !relaxed {
+        return raw.to_vec();
+    }
+    let colon = raw
+        .iter()
+        .position(|&byte| byte == b':')
+        .unwrap_or(raw.len());
+    let name = String::from_utf8_lossy(&raw[..colon]).trim().to_lowercase();
+    let value = raw.get(colon + 1..).unwrap_or_default();
+
+    let mut out = name.into_bytes();
+    out.push(b':');
+    out.extend(compress_whitespace(value));
+    out.extend_from_slice(b"\r\n");
+    out
+}
+
+/// Unfolds, collapses whitespace runs to one space and trims both ends
+fn compress_whitespace(value: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(value.len());
+    let mut pending_space = false;
+    for &byte in value {
+        match byte {
+            b'\r' | b'\n' => {}
+            b' ' | b'\t' => pending_space = true,
+            _ => {
+                if pending_space && !out.is_empty() {
+                    out.push(b' ');
+                }
+                pending_space = false;
+                out.push(byte);
+            }
+        }
+    }
+    out
+}
+
+fn canonicalize_body(body: &[u8], relaxed: bool) -> Vec<u8> {
+    let mut lines: Vec<Vec<u8>> = Vec::new();
+    let mut start = 0;
+    while start < body.len() {
+        let end = find_crlf(&body[start..])
+            .map(|offset| start + offset)
+            .unwrap_or(body.len());
+        let line = &body[start..end];
+        lines.push(if relaxed {
+            relax_body_line(line)
+        } else {
+            line.to_vec()
+        });
+        start = end + 2;
+    }
+
+    while lines.last().is_some_and(|line| line.is_empty()) {
+        lines.pop();
+    }
+    if lines.is_empty() {
+        return if relaxed {
+            Vec::new()
+        } else {
+            b"\r\n".to_vec()
+        };
+    }
+
+    let mut out = Vec::with_capacity(body.len());
+    for line in lines {
+        out.extend(line);
+        out.extend_from_slice(b"\r\n");
+    }
+    out
+}
+
+/// Collapses whitespace runs to one space and removes trailing whitespace
+fn relax_body_line(line: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(line.len());
+    let mut pending_space = false;
+    for &byte in line {
+        if byte == b' ' || byte == b'\t' {
+            pending_space = true;
+        } else {
+            if pending_space {
+                out.push(b' ');
+            }
+            pending_space = false;
+            out.push(byte);
+        }
+    }
+    out
+}
+
+// ============================================================================
+// PHISHING HEURISTICS
+// ============================================================================
+
+/// Flags display names that contain an email address other than the real sender,
+/// e.g. `"support@bank.com" <attacker@example.net>`
+fn check_display_name(name: Option<&str>, from: &str) -> Option<SenderWarning> {
+    let shown = name?
+        .split(|c: char| c.is_whitespace() || "<>\"'()[],;".contains(c))
+        .map(|token| token.trim_matches('.').to_lowercase())
+        .find(|token| {
+            token.contains('@')
+                && email_domain(token).is_some_and(|domain| domain.contains('.'))
+                && token != from
+        })?;
+
+    Some(SenderWarning {
+        kind: WARNING_DISPLAY_NAME_SPOOF.to_string(),
+        message: format!(
+            "The sender name shows {} but the message was sent from {}",
+            shown, from
+        ),
+    })
+}
+
+/// Flags sender domains that imitate the domain of one of your accounts or frequent
+/// contacts (character swaps, digit/letter confusables, punycode)
+fn check_lookalike_domain(from: &str, known_domains: &HashSet<String>) -> Option<SenderWarning> {
+    let domain = email_domain(from)?;
+    if known_domains.contains(domain) {
+        return None;
+    }
+    let sender_org = organizational_domain(domain);
+
+    let imitated = known_domains.iter().find(|known| {
+        let known_org = organizational_domain(known);
+        if known_org == sender_org {
+            return false;
+        }
+        confusable_skeleton(sender_org) == confusable_skeleton(known_org)
+            || (known_org.len() >= 6 && edit_distance(sender_org, known_org) <= 1)
+    });
+
+    let message = match imitated {
+        Some(known) => format!(
+            "The sender domain {} looks similar to {}, which you correspond with",
+            domain, known
+        ),
+        None if domain.split('.').any(|label| label.starts_with("xn--")) => format!(
+            "The sender domain {} uses international characters that can imitate other domains",
+            domain
+        ),
+        None => return None,
+    };
+
+    Some(SenderWarning {
+        kind: WARNING_LOOKALIKE_DOMAIN.to_string(),
+        message,
+    })
+}
+
+/// Flags replies that would go to a different organization than the sender
+fn check_reply_to(from: &str, reply_to: Option<&str>) -> Option<SenderWarning> {
+    let reply_to = reply_to?;
+    let from_domain = email_domain(from)?;
+    let reply_domain = email_domain(reply_to)?;
+    if organizational_domain(from_domain) == organizational_domain(reply_domain) {
+        return None;
+    }
+
+    Some(SenderWarning {
+        kind: WARNING_REPLY_TO_MISMATCH.to_string(),
+        message: format!(
+            "Replies will go to {} instead of the sender {}",
+            reply_to, from
+        ),
+    })
+}
+
+/// Domains of your accounts and of contacts you regularly write to
+fn known_domains(conn: &Connection) -> HashSet<String> {
+    let mut domains = HashSet::new();
+    let queries = [
+        ("SELECT email_address FROM accounts", None),
+        (
+            "SELECT email FROM contacts WHERE use_count >= ?1",
+            Some(KNOWN_CONTACT_MIN_USES),
+        ),
+    ];
+    for (query, min_uses) in queries {
+        let Ok(mut stmt) = conn.prepare(query) else {
+            continue;
+        };
+        let params: Vec<&dyn rusqlite::ToSql> = match &min_uses {
+            Some(min_uses) => vec![min_uses],
+            None => Vec::new(),
+        };
+        let Ok(rows) = stmt.query_map(params.as_slice(), |row| row.get::<_, String>(0)) else {
+            continue;
+        };
+        for email in rows.flatten() {
+            if let Some(domain) = email_domain(&email.trim().to_lowercase()) {
+                domains.insert(domain.to_string());
+            }
+        }
+    }
+    domains
+}
+
+fn email_domain(email: &str) -> Option<&str> {
+    email
+        .rsplit_once('@')
+        .map(|(_, domain)| domain.trim_end_matches('.'))
+        .filter(|domain| !domain.is_empty())
+}
+
+/// Approximates the registrable domain: the last two labels, or three under common
+/// second-level registries such as co.uk or com.au
+fn organizational_domain(domain: &str) -> &str {
+    let labels: Vec<&str> = domain.split('.').collect();
+    let keep = match labels.as_slice() {
+        [.., second, top]
+            if top.len() == 2
+                && labels.len() >= 3
+                && matches!(*second, "co" | "com" | "net" | "org" | "ac" | "gov" | "edu") =>
+        {
+            3
+        }
+        _ => 2,
+    };
+    if labels.len() <= keep {
+        return domain;
+    }
+    let skip: usize = labels[..labels.len() - keep]
+        .iter()
+        .map(|label| label.len() + 1)
+        .sum();
+    &domain[skip..]
+}
+
+/// Maps characters that are easily confused for each other onto one representative
+fn confusable_skeleton(domain: &str) -> String {
+    domain
+        .to_lowercase()
+        .replace("rn", "m")
+        .replace("vv", "w")
+        .replace("cl", "d")
+        .chars()
+        .map(|c| match c {
+            '0' => 'o',
+            '1' | 'i' => 'l',
+            '3' => 'e',
+            '5' => 's',
+            _ => c,
+        })
+        .collect()
+}
+
+/// Optimal string alignment distance: edits plus adjacent transpositions ("exampel")
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let mut rows = vec![vec![0usize; b.len() + 1]; a.len() + 1];
+    for (i, row) in rows.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    for (j, cell) in rows[0].iter_mut().enumerate() {
+        *cell = j;
+    }
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            let mut best = (rows[i - 1][j] + 1)
+                .min(rows[i][j - 1] + 1)
+                .min(rows[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                best = best.min(rows[i - 2][j - 2] + 1);
+            }
+            rows[i][j] = best;
+        }
+    }
+    rows[a.len()][b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::cache::schema::initialize_schema;
+    use mail_parser::MessageParser;
+
+    const RFC8463: &[u8] = include_bytes!("../../../tests/fixtures/dkim/rfc8463_ed25519_rsa.eml");
+    const RFC8463_KEYS: &str =
+        include_str!("../../../tests/fixtures/dkim/rfc8463_ed25519_rsa.keys");
+    const RFC6376: &[u8] = include_bytes!("../../../tests/fixtures/dkim/rfc6376_simple.eml");
+    const RFC6376_KEYS: &str = include_str!("../../../tests/fixtures/dkim/rfc6376_simple.keys");
+
+    fn store(keys: &str) -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        initialize_schema(&conn).unwrap();
+        for line in keys.lines().filter(|line| !line.trim().is_empty()) {
+            let (name, record) = line.split_once(' ').unwrap();
+            let (selector, domain) = name.split_once("._domainkey.").unwrap();
+            add_dkim_key(&conn, domain, selector, record).unwrap();
+        }
+        conn
+    }
+
+    fn analyze(conn: &Connection, raw: &[u8]) -> MessageAuthentication {
+        let parsed = MessageParser::default().parse(raw).unwrap();
+        analyze_message(conn, raw, &parsed, &["example.net".to_string()])
+    }
+
+    fn verify(conn: &Connection, raw: &[u8]) -> Option<DkimOutcome> {
+        let (fields, body) = split_message(raw);
+        verify_dkim(conn, &fields, &body)
+    }
+
+    #[test]
+    fn test_verify_ed25519_and_rsa_signatures() {
+        let conn = store(RFC8463_KEYS);
+        let outcome = verify(&conn, RFC8463).unwrap();
+        assert_eq!(outcome.result, RESULT_PASS);
+        assert_eq!(outcome.domain.as_deref(), Some("football.example.com"));
+
+        // Each signature verifies on its own
+        for selector in ["brisbane", "test"] {
+            let conn = store(RFC8463_KEYS);
+            conn.execute("DELETE FROM dkim_keys WHERE selector = ?1", [selector])
+                .unwrap();
+            assert_eq!(verify(&conn, RFC8463).unwrap().result, RESULT_PASS);
+        }
+    }
+
+    #[test]
+    fn test_verify_simple_canonicalization() {
+        let conn = store(RFC6376_KEYS);
+        let authentication = analyze(&conn, RFC6376);
+        assert_eq!(authentication.dkim_local.as_deref(), Some(RESULT_PASS));
+        assert_eq!(authentication.dkim_domain.as_deref(), Some("example.com"));
+        assert_eq!(authentication.verdict, VERDICT_TRUSTED);
+    }
+
+    #[test]
+    fn test_tampered_message_fails() {
+        let conn = store(RFC6376_KEYS);
+        let tampered = String::from_utf8_lossy(RFC6376).replace("hungry", "thirsty");
+        let authentication = analyze(&conn, tampered.as_bytes());
+        assert_eq!(authentication.dkim_local.as_deref(), Some(RESULT_FAIL));
+        assert_eq!(authentication.verdict, VERDICT_SUSPICIOUS);
+        assert_eq!(
+            authentication.warnings[0].kind,
+            WARNING_AUTHENTICATION_FAILED
+        );
+
+        let tampered = String::from_utf8_lossy(RFC6376).replace("Is dinner ready?", "Urgent");
+        assert_eq!(
+            verify(&conn, tampered.as_bytes()).unwrap().result,
+            RESULT_FAIL
+        );
+    }
+
+    #[test]
+    fn test_missing_key_is_neutral() {
+        let conn = store("");
+        assert_eq!(verify(&conn, RFC8463).unwrap().result, RESULT_NEUTRAL);
+        assert_eq!(verify(&conn, b"From: a@example.com\r\n\r\nHi\r\n"), None);
+    }
+
+    #[test]
+    fn test_add_dkim_key_validates_record() {
+        let conn = store("");
+        assert!(add_dkim_key(&conn, "example.com", "s1", "v=DKIM1; k=rsa").is_err());
+        assert!(add_dkim_key(&conn, "", "s1", "v=DKIM1; p=").is_err());
+        add_dkim_key(&conn, "Example.COM.", "S1", "v=DKIM1; p=").unwrap();
+        let keys = list_dkim_keys(&conn).unwrap();
+        assert_eq!(keys.len(), 1);
+        assert_eq!(
+            (keys[0].domain.as_str(), keys[0].selector.as_str()),
+            ("example.com", "s1")
+        );
+        delete_dkim_key(&conn, "example.com", "s1").unwrap();
+        assert!(delete_dkim_key(&conn, "example.com", "s1").is_err());
+    }
+
+    #[test]
+    fn test_parse_authentication_results() {
+        let results = parse_authentication_results(
+            "mx.google.com;\r\n dkim=fail (bad sig) header.i=@other.org;\r\n dkim=pass header.d=example.com header.s=s1;\r\n spf=pass (google.com: domain of bounce@example.com designates 1.2.3.4 as permitted sender) smtp.mailfrom=bounce@example.com;\r\n dmarc=pass (p=REJECT sp=REJECT dis=NONE) header.from=example.com",
+        )
+        .unwrap();
+        assert_eq!(results.authserv_id, "mx.google.com");
+        assert_eq!(results.result("spf"), Some("pass"));
+        assert_eq!(results.result("dmarc"), Some("pass"));
+        assert_eq!(
+            results.dkim(),
+            Some(("pass".to_string(), Some("example.com".to_string())))
+        );
+
+        let none = parse_authentication_results("example.org 1; none").unwrap();
+        assert!(none.methods.is_empty());
+    }
+
+    #[test]
+    fn test_topmost_authentication_results_wins() {
+        let conn = store("");
+        let raw = b"Authentication-Results: mx.example.net; spf=fail smtp.mailfrom=ceo@bank.com; dmarc=fail header.from=bank.com\r\n\
+Authentication-Results: mx.example.net; spf=pass; dmarc=pass\r\n\
+From: CEO <ceo@bank.com>\r\n\
+Subject: Wire transfer\r\n\r\nPlease pay.\r\n";
+        let authentication = analyze(&conn, raw);
+        assert_eq!(
+            authentication.authserv_id.as_deref(),
+            Some("mx.example.net")
+        );
+        assert_eq!(authentication.dmarc.as_deref(), Some(RESULT_FAIL));
+        assert_eq!(authentication.verdict, VERDICT_SUSPICIOUS);
+    }
+
+    #[test]
+    fn test_untrusted_authserv_id_ignored() {
+        let conn = store("");
+        let raw = b"Authentication-Results: mx.example.net.evil.org; spf=pass; dkim=pass header.d=bank.com; dmarc=pass\r\n\
+Authentication-Results: example.net; dmarc=pass\r\n\
+From: CEO <ceo@bank.com>\r\n\
+Subject: Wire transfer\r\n\r\nPlease pay.\r\n";
+        let authentication = analyze(&conn, raw);
+        assert_eq!(authentication.authserv_id.as_deref(), Some("example.net"));
+
+        let parsed = MessageParser::default().parse(&raw[..]).unwrap();
+        let authentication = analyze_message(&conn, raw, &parsed, &[]);
+        assert!(authentication.authserv_id.is_none());
+        assert!(authentication.dmarc.is_none());
+        assert_eq!(authentication.verdict, VERDICT_UNKNOWN);
+
+        assert!(is_trusted_authserv("mx.google.com", &["mx.google.com".to_string()]));
+        assert!(!is_trusted_authserv("evilmx.google.com", &["mx.google.com".to_string()]));
+    }
+
+    #[test]
+    fn test_arc_results_used_when_chain_passes() {
+        let conn = store("");
+        let raw = b"ARC-Seal: i=1; a=rsa-sha256; cv=none; d=lists.example.org; s=arc; b=AAAA\r\n\
+ARC-Authentication-Results: i=1; lists.example.org; dkim=pass header.d=example.com; dmarc=pass header.from=example.com\r\n\
+ARC-Seal: i=2; a=rsa-sha256; cv=pass; d=relay.example.net; s=arc; b=AAAA\r\n\
+ARC-Authentication-Results: i=2; relay.example.net; dmarc=fail header.from=example.com\r\n\
+From: Jane <jane@example.com>\r\n\r\nHello\r\n";
+        let authentication = analyze(&conn, raw);
+        assert_eq!(authentication.arc.as_deref(), Some(RESULT_PASS));
+        assert_eq!(
+            authentication.authserv_id.as_deref(),
+            Some("relay.example.net")
+        );
+        assert_eq!(authentication.dmarc.as_deref(), Some(RESULT_FAIL));
+    }
+
+    #[test]
+    fn test_display_name_spoof_and_reply_to() {
+        let conn = store("");
+        let raw = b"Authentication-Results: mx.example.net; dmarc=pass header.from=example.net\r\n\
+From: \"support@paypal.com\" <helpdesk@example.net>\r\n\
+Reply-To: collect@another.example.org\r\n\r\nVerify your account\r\n";
+        let authentication = analyze(&conn, raw);
+        let kinds: Vec<&str> = authentication
+            .warnings
+            .iter()
+            .map(|warning| warning.kind.as_str())
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![WARNING_DISPLAY_NAME_SPOOF, WARNING_REPLY_TO_MISMATCH]
+        );
+        assert_eq!(authentication.verdict, VERDICT_SUSPICIOUS);
+
+        assert!(check_display_name(Some("Jane Doe"), "jane@example.com").is_none());
+        assert!(check_display_name(Some("jane@example.com"), "jane@example.com").is_none());
+        assert!(check_reply_to("a@mail.example.com", Some("b@example.com")).is_none());
+    }
+
+    #[test]
+    fn test_lookalike_domains() {
+        let conn = store("");
+        conn.execute(
+            "INSERT INTO accounts (email_address, display_name, provider_type) VALUES ('me@example.com', 'Me', 'custom')",
+            [],
+        )
+        .unwrap();
+        let known = known_domains(&conn);
+        assert!(known.contains("example.com"));
+
+        for sender in [
+            "billing@examp1e.com",
+            "billing@exarnple.com",
+            "billing@exampel.com",
+            "billing@xn--exmple-cua.com",
+        ] {
+            let warning = check_lookalike_domain(sender, &known).unwrap();
+            assert_eq!(warning.kind, WARNING_LOOKALIKE_DOMAIN, "{}", sender);
+        }
+        assert!(check_lookalike_domain("billing@example.com", &known).is_none());
+        assert!(check_lookalike_domain("billing@mail.example.com", &known).is_none());
+        assert!(check_lookalike_domain("billing@unrelated.org", &known).is_none());
+    }
+
+    #[test]
+    fn test_dangerous_when_failed_and_spoofed() {
+        let conn = store("");
+        let raw = b"Authentication-Results: mx.example.net; dmarc=fail header.from=bank.com\r\n\
+From: \"security@bank.com\" <security@bank-alerts.example>\r\n\r\nClick here\r\n";
+        let authentication = analyze(&conn, raw);
+        assert_eq!(authentication.verdict, VERDICT_DANGEROUS);
+    }
+
+    #[test]
+    fn test_save_and_load_authentication() {
+        let conn = store(RFC6376_KEYS);
+        conn.execute_batch(
+            "INSERT INTO accounts (id, email_address, display_name, provider_type) VALUES (1, 'me@example.com', 'Me', 'custom');
+             INSERT INTO folders (id, account_id, name, path) VALUES (1, 1, 'INBOX', 'INBOX');
+             INSERT INTO messages (id, account_id, folder_id, imap_uid, message_id_header, from_header, to_header, subject, date, has_attachments, is_read)
+             VALUES (7, 1, 1, 1, '<m@example.com>', 'joe@football.example.com', 'me@example.com', 'Hi', 0, 0, 0);",
+        )
+        .unwrap();
+        let authentication = analyze(&conn, RFC6376);
+        save_message_authentication(&conn, 7, &authentication).unwrap();
+
+        let loaded = get_message_authentication(&conn, 7).unwrap().unwrap();
+        assert_eq!(loaded.message_id, 7);
+        assert_eq!(loaded.verdict, authentication.verdict);
+        assert_eq!(loaded.warnings, authentication.warnings);
+        assert!(get_message_authentication(&conn, 8).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_canonicalization() {
+        assert_eq!(canonicalize_body(b"", false), b"\r\n");
+        assert_eq!(canonicalize_body(b"", true), b"");
+        assert_eq!(
+            canonicalize_body(b" C \r\nD \t E\r\n\r\n\r\n", true),
+            b" C\r\nD E\r\n"
+        );
+        assert_eq!(
+            canonicalize_body(b" C \r\nD \t E\r\n\r\n\r\n", false),
+            b" C \r\nD \t E\r\n"
+        );
+        assert_eq!(
+            canonicalize_header(b"SUBJect: AbC\r\n", true),
+            b"subject:AbC\r\n".to_vec()
+        );
+        assert_eq!(
+            canonicalize_header(b"A : X \r\n\tY\t\r\n", true),
+            b"a:X Y\r\n".to_vec()
+        );
+        assert_eq!(organizational_domain("mail.example.co.uk"), "example.co.uk");
+        assert_eq!(organizational_domain("a.b.example.com"), "example.com");
+        assert_eq!(organizational_domain("example.com"), "example.com");
+    }
+}
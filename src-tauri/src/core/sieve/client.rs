@@ -0,0 +1,624 @@
+// ManageSieve client (RFC 5804) - Lists, fetches, uploads, checks and activates Sieve
+// scripts on the mail server. Blocking; callers run it on a blocking thread.
+
+use crate::core::providers::{Security, ServerEndpoint};
+use crate::error::DEmailError;
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+/// Read and write timeout of the underlying socket
+const IO_TIMEOUT: Duration = Duration::from_secs(30);
+/// Largest literal accepted from the server. Sieve scripts are a few kilobytes, so a
+/// bigger announcement means a broken or hostile server rather than a real script.
+const MAX_LITERAL_BYTES: usize = 1024 * 1024;
+
+/// Any byte stream a ManageSieve session can run over
+pub trait SieveStream: Read + Write + Send {}
+impl<T: Read + Write + Send> SieveStream for T {}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SieveCapabilities {
+    pub implementation: Option<String>,
+    /// Sieve extensions the server supports, e.g. `fileinto` or `vacation`
+    pub extensions: Vec<String>,
+    pub sasl: Vec<String>,
+    pub starttls: bool,
+    pub version: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SieveScript {
+    pub name: String,
+    pub active: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Atom(String),
+    /// Quoted string or literal
+    String(String),
+}
+
+/// Final line of a command: OK, NO or BYE with its optional code and message
+#[derive(Debug)]
+struct Status {
+    ok: bool,
+    code: Option<String>,
+    message: Option<String>,
+}
+
+impl Status {
+    fn into_error(self, command: &str) -> DEmailError {
+        let detail = match (self.code, self.message) {
+            (Some(code), Some(message)) => format!("{} ({})", message, code),
+            (None, Some(message)) => message,
+            (Some(code), None) => code,
+            (None, None) => "command rejected".to_string(),
+        };
+        DEmailError::Sieve(format!("{} failed: {}", command, detail))
+    }
+}
+
+pub struct SieveClient {
+    reader: BufReader<Box<dyn SieveStream>>,
+    capabilities: SieveCapabilities,
+}
+
+impl SieveClient {
+    /// Opens a session, upgrading with STARTTLS when the endpoint asks for it, and
+    /// signs in with an OAuth access token
+    pub fn connect(
+        server: &ServerEndpoint,
+        user: &str,
+        access_token: &str,
+    ) -> Result<Self, DEmailError> {
+        let tls = native_tls::TlsConnector::builder()
+            .build()
+            .map_err(|e| DEmailError::Sieve(e.to_string()))?;
+        let host = server.host.as_str();
+        let tcp = TcpStream::connect((host, server.port))?;
+        tcp.set_read_timeout(Some(IO_TIMEOUT))?;
+        tcp.set_write_timeout(Some(IO_TIMEOUT))?;
+
+        let mut client = match server.security {
+            Security::Tls => {
+                let stream = tls
+                    .connect(host, tcp)
+                    .map_err(|e| DEmailError::Sieve(e.to_string()))?;
+                Self::new(Box::new(stream))?
+            }
+            Security::StartTls => {
+                let mut plain = Self::new(Box::new(tcp.try_clone()?))?;
+                if !plain.capabilities.starttls {
+                    return Err(DEmailError::Sieve(format!(
+                        "{} does not offer STARTTLS",
+                        host
+                    )));
+                }
+                plain.simple_command("STARTTLS")?;
+                drop(plain);
+                let stream = tls
+                    .connect(host, tcp)
+                    .map_err(|e| DEmailError::Sieve(e.to_string()))?;
+                // The server announces its capabilities again once TLS is up
+                Self::new(Box::new(stream))?
+            }
+        };
+        client.authenticate_oauth(user, access_token)?;
+        Ok(client)
+    }
+
+    /// Starts a session over an established stream by reading the server's capabilities
+    pub fn new(stream: Box<dyn SieveStream>) -> Result<Self, DEmailError> {
+        let mut client = Self {
+            reader: BufReader::new(stream),
+            capabilities: SieveCapabilities::default(),
+        };
+        let (lines, status) = client.read_response()?;
+        if !status.ok {
+            return Err(status.into_error("Greeting"));
+        }
+        client.capabilities = parse_capabilities(&lines);
+        Ok(client)
+    }
+
+    pub fn capabilities(&self) -> &SieveCapabilities {
+        &self.capabilities
+    }
+
+    pub fn supports_extension(&self, extension: &str) -> bool {
+        self.capabilities
+            .extensions
+            .iter()
+            .any(|e| e.eq_ignore_ascii_case(extension))
+    }
+
+    /// Signs in with OAUTHBEARER (RFC 7628) or XOAUTH2, whichever the server offers
+    pub fn authenticate_oauth(
+        &mut self,
+        user: &str,
+        access_token: &str,
+    ) -> Result<(), DEmailError> {
+        let offers = |mechanism: &str| {
+            self.capabilities
+                .sasl
+                .iter()
+                .any(|m| m.eq_ignore_ascii_case(mechanism))
+        };
+        let (mechanism, initial) = if offers("OAUTHBEARER") {
+            (
+                "OAUTHBEARER",
+                format!(
+                    "n,a={},\x01auth=Bearer {}\x01\x01",
+                    saslname(user),
+                    access_token
+                ),
+            )
+        } else if offers("XOAUTH2") {
+            (
+                "XOAUTH2",
+                format!("user={}\x01auth=Bearer {}\x01\x01", user, access_token),
+            )
+        } else {
+            return Err(DEmailError::Auth(
+                "The Sieve server offers no OAuth sign-in".to_string(),
+            ));
+        };
+
+        self.write(&format!(
+            "AUTHENTICATE {} {}\r\n",
+            quote(mechanism),
+            quote(&openssl::base64::encode_block(initial.as_bytes()))
+        ))?;
+        loop {
+            let tokens = self.read_line_tokens()?;
+            if let Some(status) = status_from(&tokens) {
+                if status.ok {
+                    return Ok(());
+                }
+                return Err(DEmailError::Auth(format!(
+                    "Sieve server rejected the sign-in: {}",
+                    status.message.unwrap_or_default()
+                )));
+            }
+            // A challenge after a failed token carries the error details and is
+            // answered with an empty response
+            self.write("\"\"\r\n")?;
+        }
+    }
+
+    pub fn list_scripts(&mut self) -> Result<Vec<SieveScript>, DEmailError> {
+        let lines = self.command("LISTSCRIPTS", "LISTSCRIPTS")?;
+        Ok(lines
+            .into_iter()
+            .filter_map(|tokens| match tokens.as_slice() {
+                [Token::String(name), rest @ ..] => Some(SieveScript {
+                    name: name.clone(),
+                    active: matches!(rest.first(), Some(Token::Atom(a)) if a.eq_ignore_ascii_case("ACTIVE")),
+                }),
+                _ => None,
+            })
+            .collect())
+    }
+
+    pub fn get_script(&mut self, name: &str) -> Result<String, DEmailError> {
+        let lines = self.command(&format!("GETSCRIPT {}", quote(name)), "GETSCRIPT")?;
+        lines
+            .into_iter()
+            .flatten()
+            .find_map(|token| match token {
+                Token::String(script) => Some(script),
+                Token::Atom(_) => None,
+            })
+            .ok_or_else(|| DEmailError::Sieve(format!("Script {} came back empty", name)))
+    }
+
+    /// Uploads a script, replacing one with the same name. The server checks it first
+    /// and rejects it with the error it found.
+    pub fn put_script(&mut self, name: &str, script: &str) -> Result<(), DEmailError> {
+        self.command(
+            &format!("PUTSCRIPT {} {}", quote(name), literal(script)),
+            "PUTSCRIPT",
+        )?;
+        Ok(())
+    }
+
+    /// Validates a script without storing it. Returns the server's warnings.
+    pub fn check_script(&mut self, script: &str) -> Result<Option<String>, DEmailError> {
+        self.write(&format!("CHECKSCRIPT {}\r\n", literal(script)))?;
+        let (_, status) = self.read_response()?;
+        if status.ok {
+            Ok(status.message.filter(|m| !m.is_empty()))
+        } else {
+            Err(status.into_error("CHECKSCRIPT"))
+        }
+    }
+
+    /// Makes a script the active one; `None` deactivates all scripts
+    pub fn set_active(&mut self, name: Option<&str>) -> Result<(), DEmailError> {
+        self.command(
+            &format!("SETACTIVE {}", quote(name.unwrap_or(""))),
+            "SETACTIVE",
+        )?;
+        Ok(())
+    }
+
+    pub fn delete_script(&mut self, name: &str) -> Result<(), DEmailError> {
+        self.command(&format!("DELETESCRIPT {}", quote(name)), "DELETESCRIPT")?;
+        Ok(())
+    }
+
+    pub fn logout(mut self) -> Result<(), DEmailError> {
+        self.command("LOGOUT", "LOGOUT")?;
+        Ok(())
+    }
+
+    fn simple_command(&mut self, command: &str) -> Result<(), DEmailError> {
+        self.command(command, command)?;
+        Ok(())
+    }
+
+    /// Sends a command and returns its response lines, failing on NO or BYE
+    fn command(&mut self, command: &str, name: &str) -> Result<Vec<Vec<Token>>, DEmailError> {
+        self.write(&format!("{}\r\n", command))?;
+        let (lines, status) = self.read_response()?;
+        if status.ok {
+            Ok(lines)
+        } else {
+            Err(status.into_error(name))
+        }
+    }
+
+    fn write(&mut self, data: &str) -> Result<(), DEmailError> {
+        let stream = self.reader.get_mut();
+        stream.write_all(data.as_bytes())?;
+        stream.flush()?;
+        Ok(())
+    }
+
+    /// Reads lines up to and including the final OK, NO or BYE
+    fn read_response(&mut self) -> Result<(Vec<Vec<Token>>, Status), DEmailError> {
+        let mut lines = Vec::new();
+        loop {
+            let tokens = self.read_line_tokens()?;
+            if let Some(status) = status_from(&tokens) {
+                return Ok((lines, status));
+            }
+            lines.push(tokens);
+        }
+    }
+
+    /// Reads one response line, pulling in the literals it announces
+    fn read_line_tokens(&mut self) -> Result<Vec<Token>, DEmailError> {
+        let mut tokens = Vec::new();
+        loop {
+            let mut line = Vec::new();
+            if self.reader.read_until(b'\n', &mut line)? == 0 {
+                return Err(DEmailError::Sieve(
+                    "Connection closed by server".to_string(),
+                ));
+            }
+            let line = String::from_utf8_lossy(&line);
+            let line = line.trim_end_matches(['\r', '\n']);
+            let literal_len = tokenize(line, &mut tokens)?;
+            match literal_len {
+                Some(len) if len > MAX_LITERAL_BYTES => {
+                    return Err(DEmailError::Sieve(format!(
+                        "Server sent a {} byte literal, more than the {} allowed",
+                        len, MAX_LITERAL_BYTES
+                    )));
+                }
+                Some(len) => {
+                    let mut data = vec![0; len];
+                    self.reader.read_exact(&mut data)?;
+                    tokens.push(Token::String(String::from_utf8_lossy(&data).to_string()));
+                    // The line goes on after the literal
+                }
+                None => return Ok(tokens),
+            }
+        }
+    }
+}
+
+/// Splits a line into tokens. Returns the length of the literal it ends with, if any.
+fn tokenize(line: &str, tokens: &mut Vec<Token>) -> Result<Option<usize>, DEmailError> {
+    let mut chars = line.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        match c {
+            ' ' => {
+                chars.next();
+            }
+            '"' => {
+                chars.next();
+                let mut value = String::new();
+                loop {
+                    match chars.next() {
+                        Some('\\') => value.extend(chars.next()),
+                        Some('"') => break,
+                        Some(c) => value.push(c),
+                        None => {
+                            return Err(DEmailError::Sieve(format!(
+                                "Unterminated string in response: {}",
+                                line
+                            )))
+                        }
+                    }
+                }
+                tokens.push(Token::String(value));
+            }
+            '{' => {
+                let rest: String = chars.collect();
+                let len = rest
+                    .trim_start_matches('{')
+                    .trim_end_matches('}')
+                    .trim_end_matches('+')
+                    .parse()
+                    .map_err(|_| DEmailError::Sieve(format!("Malformed literal: {}", line)))?;
+                return Ok(Some(len));
+            }
+            '(' => {
+                // Response codes such as (WARNINGS) or (QUOTA/MAXSIZE) are kept as one atom
+                let mut value = String::new();
+                for c in chars.by_ref() {
+                    value.push(c);
+                    if c == ')' {
+                        break;
+                    }
+                }
+                tokens.push(Token::Atom(value));
+            }
+            _ => {
+                let mut value = String::new();
+                while let Some(&c) = chars.peek() {
+                    if c == ' ' {
+                        break;
+                    }
+                    value.push(c);
+                    chars.next();
+                }
+                tokens.push(Token::Atom(value));
+            }
+        }
+    }
+    Ok(None)
+}
+
+fn status_from(tokens: &[Token]) -> Option<Status> {
+    let ok = match tokens.first() {
+        Some(Token::Atom(atom)) if atom.eq_ignore_ascii_case("OK") => true,
+        Some(Token::Atom(atom))
+            if atom.eq_ignore_ascii_case("NO") || atom.eq_ignore_ascii_case("BYE") =>
+        {
+            false
+        }
+        _ => return None,
+    };
+    let mut status = Status {
+        ok,
+        code: None,
+        message: None,
+    };
+    for token in &tokens[1..] {
+        match token {
+            Token::Atom(code) if code.starts_with('(') => {
+                status.code = Some(code.trim_matches(['(', ')']).to_string());
+            }
+            Token::String(message) => status.message = Some(message.clone()),
+            Token::Atom(_) => {}
+        }
+    }
+    Some(status)
+}
+
+fn parse_capabilities(lines: &[Vec<Token>]) -> SieveCapabilities {
+    let mut capabilities = SieveCapabilities::default();
+    for tokens in lines {
+        let (Some(Token::String(name)), value) = (tokens.first(), tokens.get(1)) else {
+            continue;
+        };
+        let value = match value {
+            Some(Token::String(value)) => Some(value.clone()),
+            _ => None,
+        };
+        let list = |value: &Option<String>| -> Vec<String> {
+            value
+                .as_deref()
+                .unwrap_or_default()
+                .split_whitespace()
+                .map(str::to_string)
+                .collect()
+        };
+        match name.to_ascii_uppercase().as_str() {
+            "IMPLEMENTATION" => capabilities.implementation = value,
+            "SIEVE" => capabilities.extensions = list(&value),
+            "SASL" => capabilities.sasl = list(&value),
+            "STARTTLS" => capabilities.starttls = true,
+            "VERSION" => capabilities.version = value,
+            _ => {}
+        }
+    }
+    capabilities
+}
+
+/// Escapes the authorization identity of a GS2 header (RFC 5801 4): `,` and `=` would
+/// otherwise end the field early
+fn saslname(value: &str) -> String {
+    value.replace('=', "=3D").replace(',', "=2C")
+}
+
+fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Non-synchronizing literal, so the data follows without waiting for the server
+fn literal(value: &str) -> String {
+    format!("{{{}+}}\r\n{}", value.len(), value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::thread;
+
+    const GREETING: &str = "\"IMPLEMENTATION\" \"Stub\"\r\n\"SASL\" \"PLAIN XOAUTH2\"\r\n\"SIEVE\" \"fileinto vacation imap4flags\"\r\n\"VERSION\" \"1.0\"\r\nOK \"Ready\"\r\n";
+
+    /// Local ManageSieve stub: sends the greeting, then answers each command with the
+    /// next scripted reply and reports what it received
+    fn stub(replies: Vec<&'static str>) -> (Box<dyn SieveStream>, mpsc::Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (sent, received) = mpsc::channel();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut writer = stream.try_clone().unwrap();
+            let mut reader = BufReader::new(stream);
+            writer.write_all(GREETING.as_bytes()).unwrap();
+            for reply in replies {
+                let mut command = String::new();
+                reader.read_line(&mut command).unwrap();
+                // Pull in the literal a command ends with
+                if let Some(len) = command
+                    .trim_end()
+                    .strip_suffix("+}")
+                    .and_then(|c| c.rsplit('{').next())
+                    .and_then(|len| len.parse::<usize>().ok())
+                {
+                    let mut data = vec![0; len];
+                    reader.read_exact(&mut data).unwrap();
+                    command.push_str(&String::from_utf8(data).unwrap());
+                    let mut rest = String::new();
+                    reader.read_line(&mut rest).unwrap();
+                }
+                sent.send(command).unwrap();
+                writer.write_all(reply.as_bytes()).unwrap();
+            }
+        });
+        let stream = TcpStream::connect(addr).unwrap();
+        (Box::new(stream), received)
+    }
+
+    #[test]
+    fn test_capabilities_and_authentication() {
+        let (stream, received) = stub(vec![
+            "\"eyJzdGF0dXMiOiI0MDEifQ==\"\r\n",
+            "NO \"Authentication failed\"\r\n",
+        ]);
+        let mut client = SieveClient::new(stream).unwrap();
+        assert_eq!(
+            client.capabilities().implementation.as_deref(),
+            Some("Stub")
+        );
+        assert!(client.supports_extension("VACATION"));
+        assert!(!client.capabilities().starttls);
+
+        let error = client
+            .authenticate_oauth("me@example.org", "token")
+            .unwrap_err();
+        assert!(matches!(error, DEmailError::Auth(_)));
+        let command = received.recv().unwrap();
+        assert!(command.starts_with("AUTHENTICATE \"XOAUTH2\" \""));
+        let initial = command.split('"').nth(3).unwrap();
+        assert_eq!(
+            openssl::base64::decode_block(initial).unwrap(),
+            b"user=me@example.org\x01auth=Bearer token\x01\x01"
+        );
+        // The error challenge is answered with an empty response
+        assert_eq!(received.recv().unwrap(), "\"\"\r\n");
+    }
+
+    #[test]
+    fn test_oauthbearer_escapes_authzid() {
+        assert_eq!(saslname("me@example.org"), "me@example.org");
+        assert_eq!(saslname("a,b=c@example.org"), "a=2Cb=3Dc@example.org");
+
+        let (stream, received) = stub(vec!["OK\r\n"]);
+        let mut client = SieveClient::new(stream).unwrap();
+        client.capabilities.sasl = vec!["OAUTHBEARER".to_string()];
+        client
+            .authenticate_oauth("a,b@example.org", "token")
+            .unwrap();
+        let command = received.recv().unwrap();
+        let initial = command.split('"').nth(3).unwrap();
+        assert_eq!(
+            openssl::base64::decode_block(initial).unwrap(),
+            b"n,a=a=2Cb@example.org,\x01auth=Bearer token\x01\x01"
+        );
+    }
+
+    #[test]
+    fn test_oversized_literal_rejected() {
+        let (stream, _received) = stub(vec!["{2147483647}\r\n"]);
+        let mut client = SieveClient::new(stream).unwrap();
+        let error = client.get_script("demail").unwrap_err();
+        assert!(error.to_string().contains("literal"), "{}", error);
+    }
+
+    #[test]
+    fn test_script_commands() {
+        let (stream, received) = stub(vec![
+            "OK\r\n",
+            "\"vacation\"\r\n\"demail \\\"rules\\\"\" ACTIVE\r\nOK\r\n",
+            "{20}\r\nrequire \"fileinto\";\n\r\nOK\r\n",
+            "OK (WARNINGS) \"line 2: unused\"\r\n",
+            "NO \"line 1: unknown command 'fileint'\"\r\n",
+            "OK\r\n",
+            "OK\r\n",
+            "NO (NONEXISTENT) \"No such script\"\r\n",
+            "OK \"Bye\"\r\n",
+        ]);
+        let mut client = SieveClient::new(stream).unwrap();
+        client
+            .authenticate_oauth("me@example.org", "token")
+            .unwrap();
+        received.recv().unwrap();
+
+        assert_eq!(
+            client.list_scripts().unwrap(),
+            vec![
+                SieveScript {
+                    name: "vacation".to_string(),
+                    active: false
+                },
+                SieveScript {
+                    name: "demail \"rules\"".to_string(),
+                    active: true
+                },
+            ]
+        );
+        assert_eq!(received.recv().unwrap(), "LISTSCRIPTS\r\n");
+
+        assert_eq!(
+            client.get_script("demail").unwrap(),
+            "require \"fileinto\";\n"
+        );
+        assert_eq!(received.recv().unwrap(), "GETSCRIPT \"demail\"\r\n");
+
+        assert_eq!(
+            client.check_script("keep;").unwrap().as_deref(),
+            Some("line 2: unused")
+        );
+        assert_eq!(received.recv().unwrap(), "CHECKSCRIPT {5+}\r\nkeep;");
+        let error = client.check_script("fileint \"x\";").unwrap_err();
+        assert!(error.to_string().contains("unknown command"));
+        received.recv().unwrap();
+
+        client.put_script("demail", "keep;").unwrap();
+        assert_eq!(
+            received.recv().unwrap(),
+            "PUTSCRIPT \"demail\" {5+}\r\nkeep;"
+        );
+        client.set_active(None).unwrap();
+        assert_eq!(received.recv().unwrap(), "SETACTIVE \"\"\r\n");
+
+        let error = client.delete_script("missing").unwrap_err();
+        assert!(error.to_string().contains("No such script (NONEXISTENT)"));
+        received.recv().unwrap();
+
+        client.logout().unwrap();
+        assert_eq!(received.recv().unwrap(), "LOGOUT\r\n");
+    }
+}
@@ -0,0 +1,76 @@
+// Sieve module - Server-side filters that run while the app is closed: a ManageSieve
+// client and generation of Sieve scripts from the app's rules and an out-of-office reply
+
+pub mod client;
+pub mod script;
+
+use crate::core::accounts::get_accounts;
+use crate::core::providers::client_for_provider;
+use crate::error::DEmailError;
+use crate::AppState;
+use client::{SieveCapabilities, SieveClient, SieveScript};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SieveOverview {
+    pub capabilities: SieveCapabilities,
+    pub scripts: Vec<SieveScript>,
+}
+
+/// Signs in to the account's ManageSieve server and runs `command` on a blocking
+/// thread, logging out afterwards
+pub async fn with_client<F, R>(
+    app_state: &AppState,
+    account_id: i64,
+    command: F,
+) -> Result<R, DEmailError>
+where
+    F: FnOnce(&mut SieveClient) -> Result<R, DEmailError> + Send + 'static,
+    R: Send + 'static,
+{
+    let pool = &app_state.db_pool;
+    let account = get_accounts(pool)?
+        .into_iter()
+        .find(|account| account.id == account_id)
+        .ok_or_else(|| DEmailError::NotFound(format!("Account {} not found", account_id)))?;
+    let config = app_state.app_config.lock().unwrap().clone();
+    let (provider, oauth_client) = client_for_provider(&config, &account.provider_type)?;
+    let server = provider.sieve.clone().ok_or_else(|| {
+        DEmailError::Config(format!(
+            "{} has no ManageSieve server configured",
+            provider.name
+        ))
+    })?;
+    let access_token = app_state
+        .token_manager
+        .access_token(pool, account_id, &oauth_client)
+        .await?;
+
+    tokio::task::spawn_blocking(move || {
+        let mut client =
+            SieveClient::connect(&server, &account.email_address, access_token.secret())?;
+        let result = command(&mut client)?;
+        // The work is done; a failed goodbye does not undo it
+        let _ = client.logout();
+        Ok(result)
+    })
+    .await
+    .map_err(|e| DEmailError::Unknown(e.to_string()))?
+}
+
+/// Fails with the extensions a script requires that the server does not support, which
+/// reads better than the server's own parse error
+pub fn check_extensions(client: &SieveClient, script: &str) -> Result<(), DEmailError> {
+    let missing: Vec<String> = script::required_extensions(script)
+        .into_iter()
+        .filter(|extension| !client.supports_extension(extension))
+        .collect();
+    if missing.is_empty() {
+        Ok(())
+    } else {
+        Err(DEmailError::Sieve(format!(
+            "The server does not support the Sieve extensions {}",
+            missing.join(", ")
+        )))
+    }
+}
@@ -0,0 +1,408 @@
+// Sieve generation - Translates the app's rules into a Sieve script (RFC 5228) and
+// builds out-of-office replies with the vacation extension (RFC 5230).
+
+use crate::core::rules::{Condition, Rule, RuleAction, SizeOperator, TextOperator};
+use crate::core::validation;
+use crate::error::DEmailError;
+use chrono::NaiveDate;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+
+/// Out-of-office reply sent at most once per sender every `days` days
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VacationSettings {
+    pub subject: String,
+    pub body: String,
+    pub days: u32,
+    /// Other addresses of the account; mail to them is answered too
+    #[serde(default)]
+    pub addresses: Vec<String>,
+    /// First and last day the reply is sent, inclusive
+    #[serde(default)]
+    pub start: Option<NaiveDate>,
+    #[serde(default)]
+    pub end: Option<NaiveDate>,
+}
+
+/// Builds a script from the enabled rules, in order, with the vacation reply first so
+/// rules that discard or stop do not suppress it
+pub fn build_script(
+    rules: &[Rule],
+    vacation: Option<&VacationSettings>,
+) -> Result<String, DEmailError> {
+    let mut script = Script::default();
+    if let Some(vacation) = vacation {
+        script.vacation(vacation)?;
+    }
+    for rule in rules.iter().filter(|rule| rule.enabled) {
+        script.rule(rule)?;
+    }
+    Ok(script.finish())
+}
+
+/// Extensions required by a script, taken from its `require` line
+pub fn required_extensions(script: &str) -> Vec<String> {
+    script
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("require"))
+        .flat_map(|list| list.split('"').skip(1).step_by(2).map(str::to_string))
+        .collect()
+}
+
+#[derive(Default)]
+struct Script {
+    requires: BTreeSet<&'static str>,
+    body: String,
+}
+
+impl Script {
+    fn finish(self) -> String {
+        let mut script = String::from("# Generated by DEmail; changes made here are overwritten\n");
+        if !self.requires.is_empty() {
+            let list: Vec<String> = self.requires.iter().map(|r| quote(r)).collect();
+            script.push_str(&format!("require [{}];\n", list.join(", ")));
+        }
+        script.push_str(&self.body);
+        script
+    }
+
+    fn rule(&mut self, rule: &Rule) -> Result<(), DEmailError> {
+        let tests = rule
+            .conditions
+            .iter()
+            .map(|condition| self.test(condition))
+            .collect::<Vec<_>>();
+        let mut actions = Vec::new();
+        for action in &rule.actions {
+            actions.push(self.action(rule, action)?);
+        }
+        if rule.stop_processing {
+            actions.push("stop;".to_string());
+        }
+
+        let test = match tests.as_slice() {
+            [test] => test.clone(),
+            tests if rule.match_all => format!("allof ({})", tests.join(",\n       ")),
+            tests => format!("anyof ({})", tests.join(",\n       ")),
+        };
+        self.body.push_str(&format!(
+            "\n# {}\nif {} {{\n{}}}\n",
+            rule.name.replace('\n', " "),
+            test,
+            actions
+                .iter()
+                .map(|action| format!("    {}\n", action))
+                .collect::<String>()
+        ));
+        Ok(())
+    }
+
+    fn test(&mut self, condition: &Condition) -> String {
+        match condition {
+            Condition::From(m) => header_test("from", m.operator, &m.value),
+            Condition::To(m) => header_test("to", m.operator, &m.value),
+            Condition::Cc(m) => header_test("cc", m.operator, &m.value),
+            Condition::Subject(m) => header_test("subject", m.operator, &m.value),
+            Condition::ListId(m) => header_test("list-id", m.operator, &m.value),
+            Condition::Header {
+                name,
+                operator,
+                value,
+            } => header_test(&name.to_lowercase(), *operator, value),
+            Condition::Body(m) => {
+                self.requires.insert("body");
+                let (negate, match_type, value) = match_type(m.operator, &m.value);
+                format!("{}body :text {} {}", negate, match_type, quote(&value))
+            }
+            // Sieve has no attachment test; a multipart/mixed message is the usual sign
+            Condition::HasAttachment { value } => format!(
+                "{}header :contains \"content-type\" \"multipart/mixed\"",
+                if *value { "" } else { "not " }
+            ),
+            Condition::Size { operator, bytes } => match operator {
+                SizeOperator::LargerThan => format!("size :over {}", bytes),
+                SizeOperator::SmallerThan => format!("size :under {}", bytes),
+            },
+        }
+    }
+
+    fn action(&mut self, rule: &Rule, action: &RuleAction) -> Result<String, DEmailError> {
+        Ok(match action {
+            RuleAction::Move { folder } => {
+                self.requires.insert("fileinto");
+                format!("fileinto {};", quote(folder))
+            }
+            RuleAction::Star => {
+                self.requires.insert("imap4flags");
+                "addflag \"\\\\Flagged\";".to_string()
+            }
+            RuleAction::MarkRead => {
+                self.requires.insert("imap4flags");
+                "addflag \"\\\\Seen\";".to_string()
+            }
+            RuleAction::Delete => "discard;".to_string(),
+            // Labels become IMAP keywords, which cannot contain spaces or specials
+            RuleAction::Label { label } => {
+                self.requires.insert("imap4flags");
+                format!("addflag {};", quote(&keyword(label)))
+            }
+            RuleAction::Forward { to } => {
+                self.requires.insert("copy");
+                format!("redirect :copy {};", quote(to))
+            }
+            RuleAction::RunLater { .. } => {
+                return Err(DEmailError::Validation(format!(
+                    "Rule \"{}\" has a delayed action, which Sieve cannot express",
+                    rule.name
+                )))
+            }
+        })
+    }
+
+    fn vacation(&mut self, vacation: &VacationSettings) -> Result<(), DEmailError> {
+        if vacation.body.trim().is_empty() {
+            return Err(DEmailError::Validation(
+                "The out-of-office reply is empty".to_string(),
+            ));
+        }
+        if !(1..=365).contains(&vacation.days) {
+            return Err(DEmailError::Validation(
+                "Replies can repeat every 1 to 365 days".to_string(),
+            ));
+        }
+        for address in &vacation.addresses {
+            validation::validate_email(address)?;
+        }
+        if let (Some(start), Some(end)) = (vacation.start, vacation.end) {
+            if end < start {
+                return Err(DEmailError::Validation(
+                    "The out-of-office period ends before it starts".to_string(),
+                ));
+            }
+        }
+
+        self.requires.insert("vacation");
+        let mut command = format!("vacation :days {}", vacation.days);
+        if !vacation.subject.trim().is_empty() {
+            command.push_str(&format!(" :subject {}", quote(vacation.subject.trim())));
+        }
+        if !vacation.addresses.is_empty() {
+            let list: Vec<String> = vacation.addresses.iter().map(|a| quote(a)).collect();
+            command.push_str(&format!(" :addresses [{}]", list.join(", ")));
+        }
+        command.push_str(&format!(" {};\n", multiline(&vacation.body)));
+
+        let mut dates = Vec::new();
+        if let Some(start) = vacation.start {
+            dates.push(format!(
+                "currentdate :value \"ge\" \"date\" {}",
+                quote(&start.to_string())
+            ));
+        }
+        if let Some(end) = vacation.end {
+            dates.push(format!(
+                "currentdate :value \"le\" \"date\" {}",
+                quote(&end.to_string())
+            ));
+        }
+        self.body.push_str("\n# Out of office\n");
+        if dates.is_empty() {
+            self.body.push_str(&command);
+        } else {
+            self.requires.insert("date");
+            self.requires.insert("relational");
+            self.body.push_str(&format!(
+                "if allof ({}) {{\n    {}}}\n",
+                dates.join(", "),
+                command
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn header_test(header: &str, operator: TextOperator, value: &str) -> String {
+    let (negate, match_type, value) = match_type(operator, value);
+    format!(
+        "{}header {} {} {}",
+        negate,
+        match_type,
+        quote(header),
+        quote(&value)
+    )
+}
+
+/// Sieve's default comparator, i;ascii-casemap, is case-insensitive like the app's rules
+fn match_type(operator: TextOperator, value: &str) -> (&'static str, &'static str, String) {
+    // `*` and `?` are wildcards in :matches and are escaped to match literally
+    let wildcard_safe = || {
+        value
+            .replace('\\', "\\\\")
+            .replace('*', "\\*")
+            .replace('?', "\\?")
+    };
+    match operator {
+        TextOperator::Contains => ("", ":contains", value.to_string()),
+        TextOperator::NotContains => ("not ", ":contains", value.to_string()),
+        TextOperator::Is => ("", ":is", value.to_string()),
+        TextOperator::StartsWith => ("", ":matches", format!("{}*", wildcard_safe())),
+        TextOperator::EndsWith => ("", ":matches", format!("*{}", wildcard_safe())),
+    }
+}
+
+fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Multi-line string, ended by a line holding only a dot; lines starting with a dot
+/// are dot-stuffed
+fn multiline(value: &str) -> String {
+    let mut text = String::from("text:\n");
+    for line in value.lines() {
+        if line.starts_with('.') {
+            text.push('.');
+        }
+        text.push_str(line);
+        text.push('\n');
+    }
+    text.push_str(".\n");
+    text
+}
+
+fn keyword(label: &str) -> String {
+    label
+        .trim()
+        .chars()
+        .map(|c| {
+            if c.is_ascii_graphic() && !"(){%*\"\\]".contains(c) {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::rules::TextMatch;
+
+    fn rule(name: &str, conditions: Vec<Condition>, actions: Vec<RuleAction>) -> Rule {
+        Rule {
+            id: 1,
+            account_id: None,
+            name: name.to_string(),
+            enabled: true,
+            position: 0,
+            match_all: true,
+            stop_processing: false,
+            conditions,
+            actions,
+        }
+    }
+
+    fn text(operator: TextOperator, value: &str) -> TextMatch {
+        TextMatch {
+            operator,
+            value: value.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_rules_to_sieve() {
+        let mut newsletters = rule(
+            "Newsletters",
+            vec![
+                Condition::ListId(text(TextOperator::Contains, "shop")),
+                Condition::Subject(text(TextOperator::StartsWith, "Deals * \"today\"")),
+            ],
+            vec![
+                RuleAction::MarkRead,
+                RuleAction::Label {
+                    label: "Shopping list".to_string(),
+                },
+                RuleAction::Move {
+                    folder: "Archive/News".to_string(),
+                },
+            ],
+        );
+        newsletters.stop_processing = true;
+        let mut large = rule(
+            "Large mail",
+            vec![
+                Condition::Size {
+                    operator: SizeOperator::LargerThan,
+                    bytes: 5_000_000,
+                },
+                Condition::Body(text(TextOperator::NotContains, "invoice")),
+            ],
+            vec![RuleAction::Forward {
+                to: "archive@example.org".to_string(),
+            }],
+        );
+        large.match_all = false;
+        let mut disabled = rule(
+            "Off",
+            vec![Condition::HasAttachment { value: true }],
+            vec![RuleAction::Delete],
+        );
+        disabled.enabled = false;
+
+        let script = build_script(&[newsletters, large, disabled], None).unwrap();
+        assert_eq!(
+            required_extensions(&script),
+            vec!["body", "copy", "fileinto", "imap4flags"]
+        );
+        assert!(script.contains(
+            "if allof (header :contains \"list-id\" \"shop\",\n       header :matches \"subject\" \"Deals \\\\* \\\"today\\\"*\") {"
+        ));
+        assert!(script.contains("    addflag \"\\\\Seen\";\n    addflag \"Shopping_list\";\n    fileinto \"Archive/News\";\n    stop;\n}"));
+        assert!(script.contains(
+            "if anyof (size :over 5000000,\n       not body :text :contains \"invoice\") {\n    redirect :copy \"archive@example.org\";\n}"
+        ));
+        assert!(!script.contains("Off"));
+
+        let delayed = rule(
+            "Later",
+            vec![Condition::HasAttachment { value: false }],
+            vec![RuleAction::RunLater {
+                delay_minutes: 5,
+                action: Box::new(RuleAction::Star),
+            }],
+        );
+        assert!(build_script(&[delayed], None).is_err());
+    }
+
+    #[test]
+    fn test_vacation_script() {
+        let mut vacation = VacationSettings {
+            subject: "Out of office".to_string(),
+            body: "Back on Monday.\n.Regards".to_string(),
+            days: 7,
+            addresses: vec!["alias@example.org".to_string()],
+            start: None,
+            end: None,
+        };
+        let script = build_script(&[], Some(&vacation)).unwrap();
+        assert_eq!(required_extensions(&script), vec!["vacation"]);
+        assert!(script.contains(
+            "vacation :days 7 :subject \"Out of office\" :addresses [\"alias@example.org\"] text:\nBack on Monday.\n..Regards\n.\n;\n"
+        ));
+
+        vacation.start = NaiveDate::from_ymd_opt(2026, 12, 20);
+        vacation.end = NaiveDate::from_ymd_opt(2027, 1, 3);
+        let script = build_script(&[], Some(&vacation)).unwrap();
+        assert_eq!(
+            required_extensions(&script),
+            vec!["date", "relational", "vacation"]
+        );
+        assert!(script.contains("if allof (currentdate :value \"ge\" \"date\" \"2026-12-20\", currentdate :value \"le\" \"date\" \"2027-01-03\") {\n    vacation :days 7"));
+
+        vacation.end = NaiveDate::from_ymd_opt(2026, 12, 1);
+        assert!(build_script(&[], Some(&vacation)).is_err());
+        vacation.end = None;
+        vacation.days = 0;
+        assert!(build_script(&[], Some(&vacation)).is_err());
+    }
+}
@@ -0,0 +1,210 @@
+// Signatures module - Picks the signature for an account or identity, fills in its
+// template variables and places it above or below the quote. The signatures themselves
+// are stored by `cache::db`.
+
+use crate::core::cache::db::{get_setting, save_setting, Pool};
+use crate::core::identities::Identity;
+use crate::error::DEmailError;
+use crate::models::EmailSignature;
+use rusqlite::{Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+
+const SETTING_SIGNATURES: &str = "signature_settings";
+
+/// Separates the signature from the body, per RFC 3676
+const SIGNATURE_DELIMITER: &str = "-- \n";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SignaturePlacement {
+    /// Between the reply and the quoted message
+    #[default]
+    AboveQuote,
+    /// After the quoted or forwarded message
+    BelowQuote,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SignatureSettings {
+    pub placement: SignaturePlacement,
+}
+
+pub fn get_settings(pool: &Pool) -> Result<SignatureSettings, DEmailError> {
+    match get_setting(pool, SETTING_SIGNATURES)? {
+        Some(value) => Ok(serde_json::from_str(&value)?),
+        None => Ok(SignatureSettings::default()),
+    }
+}
+
+pub fn save_settings(pool: &Pool, settings: &SignatureSettings) -> Result<(), DEmailError> {
+    save_setting(pool, SETTING_SIGNATURES, &serde_json::to_string(settings)?)
+}
+
+/// Values for the `{{...}}` variables of a signature
+#[derive(Debug, Clone, PartialEq)]
+pub struct SignatureContext {
+    /// Display name of the identity or account
+    pub name: String,
+    /// The From address
+    pub email: String,
+    /// The account's own address
+    pub account: String,
+    pub date: String,
+}
+
+impl SignatureContext {
+    fn value(&self, variable: &str) -> Option<String> {
+        match variable {
+            "name" => Some(self.name.clone()),
+            "first_name" => Some(
+                self.name
+                    .split_whitespace()
+                    .next()
+                    .unwrap_or("")
+                    .to_string(),
+            ),
+            "email" => Some(self.email.clone()),
+            "identity" if self.name.is_empty() => Some(self.email.clone()),
+            "identity" => Some(format!("{} <{}>", self.name, self.email)),
+            "account" => Some(self.account.clone()),
+            "date" => Some(self.date.clone()),
+            _ => None,
+        }
+    }
+}
+
+/// Replaces each `{{variable}}` that `lookup` knows; unknown ones are left as written
+pub fn fill_placeholders(template: &str, mut lookup: impl FnMut(&str) -> Option<String>) -> String {
+    let mut filled = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        let Some(end) = rest[start + 2..].find("}}") else {
+            break;
+        };
+        let end = start + 2 + end;
+        filled.push_str(&rest[..start]);
+        match lookup(rest[start + 2..end].trim()) {
+            Some(value) => filled.push_str(&value),
+            None => filled.push_str(&rest[start..end + 2]),
+        }
+        rest = &rest[end + 2..];
+    }
+    filled.push_str(rest);
+    filled
+}
+
+pub fn render(template: &str, context: &SignatureContext) -> String {
+    fill_placeholders(template, |variable| context.value(variable))
+}
+
+/// The identity's signature, or else the account's default one
+pub fn signature_for(
+    conn: &Connection,
+    account_id: i64,
+    identity: Option<&Identity>,
+) -> Result<Option<EmailSignature>, DEmailError> {
+    let query = "SELECT id, account_id, name, content_html, content_plain, is_default
+        FROM signatures WHERE account_id = ?1 AND ";
+    let row_to_signature = |row: &rusqlite::Row| {
+        Ok(EmailSignature {
+            id: row.get(0)?,
+            account_id: row.get(1)?,
+            name: row.get(2)?,
+            content_html: row.get(3)?,
+            content_plain: row.get(4)?,
+            is_default: row.get(5)?,
+        })
+    };
+
+    if let Some(signature_id) = identity.and_then(|identity| identity.signature_id) {
+        let signature = conn
+            .query_row(
+                &format!("{} id = ?2", query),
+                [account_id, signature_id],
+                row_to_signature,
+            )
+            .optional()?;
+        if signature.is_some() {
+            return Ok(signature);
+        }
+    }
+    Ok(conn
+        .query_row(
+            &format!("{} is_default = 1 LIMIT 1", query),
+            [account_id],
+            row_to_signature,
+        )
+        .optional()?)
+}
+
+/// Lays out a compose body: room to write, then the signature and the quote in the
+/// configured order
+pub fn compose_body(
+    signature: Option<&str>,
+    quote: Option<&str>,
+    placement: SignaturePlacement,
+) -> String {
+    let signature = signature
+        .map(str::trim_end)
+        .filter(|signature| !signature.is_empty())
+        .map(|signature| format!("{}{}", SIGNATURE_DELIMITER, signature));
+    let quote = quote
+        .map(|quote| quote.trim_start_matches('\n'))
+        .filter(|quote| !quote.is_empty())
+        .map(str::to_string);
+
+    let parts = match placement {
+        SignaturePlacement::AboveQuote => [signature, quote],
+        SignaturePlacement::BelowQuote => [quote, signature],
+    };
+    let mut body = String::from("\n\n");
+    body.push_str(&parts.into_iter().flatten().collect::<Vec<_>>().join("\n\n"));
+    body
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn context() -> SignatureContext {
+        SignatureContext {
+            name: "Jane Doe".to_string(),
+            email: "support@example.com".to_string(),
+            account: "jane@example.com".to_string(),
+            date: "2024-03-01".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_render_variables() {
+        assert_eq!(
+            render(
+                "{{ first_name }} / {{identity}}\n{{date}} {{unknown}} {{account",
+                &context()
+            ),
+            "Jane / Jane Doe <support@example.com>\n2024-03-01 {{unknown}} {{account"
+        );
+    }
+
+    #[test]
+    fn test_compose_body_placement() {
+        let quote = "\n\nOn 2024-03-01, bob wrote:\n> hi";
+        assert_eq!(
+            compose_body(Some("Jane\n"), Some(quote), SignaturePlacement::AboveQuote),
+            "\n\n-- \nJane\n\nOn 2024-03-01, bob wrote:\n> hi"
+        );
+        assert_eq!(
+            compose_body(Some("Jane"), Some(quote), SignaturePlacement::BelowQuote),
+            "\n\nOn 2024-03-01, bob wrote:\n> hi\n\n-- \nJane"
+        );
+        assert_eq!(
+            compose_body(Some("Jane"), None, SignaturePlacement::BelowQuote),
+            "\n\n-- \nJane"
+        );
+        assert_eq!(
+            compose_body(None, None, SignaturePlacement::AboveQuote),
+            "\n\n"
+        );
+    }
+}
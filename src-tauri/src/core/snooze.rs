@@ -0,0 +1,243 @@
+// Snooze module - Messages hidden from their folders until a chosen time, and follow-up
+// reminders on sent mail that fire unless a reply arrives first. Both live in the
+// `snoozes` table; the task that brings them back is `sync::reminders`.
+
+use crate::core::cache::db::Pool;
+use crate::error::DEmailError;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+
+/// Keyword marking a snoozed message on the server, so other clients can tell
+pub const SNOOZE_KEYWORD: &str = "$Snoozed";
+/// Gmail has no custom keywords; snoozed messages get this label instead
+pub const SNOOZE_LABEL: &str = "Snoozed";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReminderKind {
+    /// Hidden until due, then back as unread at the top of its folder
+    Snooze,
+    /// Raised when due unless someone replied
+    FollowUp,
+}
+
+impl ReminderKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            ReminderKind::Snooze => "snooze",
+            ReminderKind::FollowUp => "follow_up",
+        }
+    }
+
+    fn parse(value: &str) -> rusqlite::Result<Self> {
+        match value {
+            "snooze" => Ok(ReminderKind::Snooze),
+            "follow_up" => Ok(ReminderKind::FollowUp),
+            other => Err(rusqlite::Error::InvalidColumnType(
+                0,
+                other.to_string(),
+                rusqlite::types::Type::Text,
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Reminder {
+    pub id: i64,
+    pub message_id: i64,
+    pub account_id: i64,
+    pub kind: ReminderKind,
+    /// Unix timestamp
+    pub due_at: i64,
+    pub created_at: i64,
+    pub subject: String,
+    pub from_header: String,
+    pub to_header: String,
+}
+
+const REMINDER_QUERY: &str = "SELECT s.id, s.message_id, m.account_id, s.kind, s.due_at,
+        s.created_at, COALESCE(m.subject, ''), m.from_header, m.to_header
+    FROM snoozes s JOIN messages m ON m.id = s.message_id";
+
+fn row_to_reminder(row: &rusqlite::Row) -> rusqlite::Result<Reminder> {
+    Ok(Reminder {
+        id: row.get(0)?,
+        message_id: row.get(1)?,
+        account_id: row.get(2)?,
+        kind: ReminderKind::parse(&row.get::<_, String>(3)?)?,
+        due_at: row.get(4)?,
+        created_at: row.get(5)?,
+        subject: row.get(6)?,
+        from_header: row.get(7)?,
+        to_header: row.get(8)?,
+    })
+}
+
+/// Adds a reminder, replacing one of the same kind on the message
+pub fn add_reminder(
+    conn: &Connection,
+    message_id: i64,
+    kind: ReminderKind,
+    due_at: i64,
+) -> Result<Reminder, DEmailError> {
+    let now = chrono::Utc::now().timestamp();
+    if due_at <= now {
+        return Err(DEmailError::Validation(
+            "Pick a time in the future".to_string(),
+        ));
+    }
+    let id: i64 = conn.query_row(
+        "INSERT INTO snoozes (message_id, kind, due_at, created_at) VALUES (?1, ?2, ?3, ?4)
+         ON CONFLICT (message_id, kind) DO UPDATE SET due_at = excluded.due_at,
+             created_at = excluded.created_at
+         RETURNING id",
+        params![message_id, kind.as_str(), due_at, now],
+        |row| row.get(0),
+    )?;
+    get_reminder(conn, id)?
+        .ok_or_else(|| DEmailError::NotFound(format!("Message {} not found", message_id)))
+}
+
+pub fn get_reminder(conn: &Connection, reminder_id: i64) -> Result<Option<Reminder>, DEmailError> {
+    Ok(conn
+        .query_row(
+            &format!("{} WHERE s.id = ?1", REMINDER_QUERY),
+            [reminder_id],
+            row_to_reminder,
+        )
+        .optional()?)
+}
+
+pub fn remove_reminder(conn: &Connection, reminder_id: i64) -> Result<(), DEmailError> {
+    conn.execute("DELETE FROM snoozes WHERE id = ?1", [reminder_id])?;
+    Ok(())
+}
+
+/// Pending reminders, soonest first, for one account or all
+pub fn list_reminders(pool: &Pool, account_id: Option<i64>) -> Result<Vec<Reminder>, DEmailError> {
+    let conn = pool
+        .get()
+        .map_err(|_| DEmailError::Database(rusqlite::Error::InvalidQuery))?;
+    let mut stmt = conn.prepare(&format!(
+        "{} WHERE ?1 IS NULL OR m.account_id = ?1 ORDER BY s.due_at",
+        REMINDER_QUERY
+    ))?;
+    let reminders = stmt
+        .query_map([account_id], row_to_reminder)?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(reminders)
+}
+
+pub fn due_reminders(conn: &Connection, now: i64) -> Result<Vec<Reminder>, DEmailError> {
+    let mut stmt = conn.prepare(&format!(
+        "{} WHERE s.due_at <= ?1 ORDER BY s.due_at",
+        REMINDER_QUERY
+    ))?;
+    let reminders = stmt
+        .query_map([now], row_to_reminder)?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(reminders)
+}
+
+pub fn next_due_at(conn: &Connection) -> Result<Option<i64>, DEmailError> {
+    Ok(conn.query_row("SELECT MIN(due_at) FROM snoozes", [], |row| row.get(0))?)
+}
+
+/// Brings a snoozed message back: unread, and sorted as if it had just arrived
+pub fn resurface(conn: &Connection, message_id: i64, now: i64) -> Result<(), DEmailError> {
+    conn.execute(
+        "UPDATE messages SET is_read = 0, resurfaced_at = ?1 WHERE id = ?2",
+        params![now, message_id],
+    )?;
+    Ok(())
+}
+
+/// Whether someone else answered the message: a later message in the account whose
+/// headers (In-Reply-To, References) name it
+pub fn has_reply(conn: &Connection, message_id: i64) -> Result<bool, DEmailError> {
+    let replied = conn
+        .query_row(
+            "SELECT 1 FROM messages sent
+             JOIN accounts a ON a.id = sent.account_id
+             JOIN messages m ON m.account_id = sent.account_id AND m.id != sent.id
+             JOIN message_headers h ON h.message_id = m.id
+             WHERE sent.id = ?1
+               AND COALESCE(sent.message_id_header, '') != ''
+               AND m.date >= sent.date
+               AND instr(h.headers, sent.message_id_header) > 0
+               AND instr(lower(m.from_header), lower(a.email_address)) = 0
+             LIMIT 1",
+            [message_id],
+            |_| Ok(()),
+        )
+        .optional()?;
+    Ok(replied.is_some())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::accounts::create_account;
+    use crate::core::cache::db::{save_message, test_folder, test_message, test_pool};
+    use crate::core::rules::save_message_headers;
+    use crate::models::Message;
+
+    fn store(pool: &Pool, account_id: i64, folder_id: i64, uid: u32, from: &str, date: i64) -> i64 {
+        save_message(
+            pool,
+            &Message {
+                from_header: from.to_string(),
+                to_header: "someone@example.com".to_string(),
+                date,
+                is_read: true,
+                ..test_message(account_id, folder_id, uid)
+            },
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_reminders_and_replies() {
+        let pool = test_pool();
+        let account = create_account(&pool, "me@example.com", "Me", "google").unwrap();
+        let folder = test_folder(&pool, account.id, "INBOX");
+        let sent = store(&pool, account.id, folder.id, 1, "Me <me@example.com>", 100);
+        let conn = pool.get().unwrap();
+
+        let now = chrono::Utc::now().timestamp();
+        assert!(add_reminder(&conn, sent, ReminderKind::FollowUp, now - 1).is_err());
+        let reminder = add_reminder(&conn, sent, ReminderKind::FollowUp, now + 60).unwrap();
+        // Setting it again moves the existing reminder
+        let moved = add_reminder(&conn, sent, ReminderKind::FollowUp, now + 120).unwrap();
+        assert_eq!(moved.id, reminder.id);
+        assert_eq!(next_due_at(&conn).unwrap(), Some(now + 120));
+        assert!(due_reminders(&conn, now).unwrap().is_empty());
+        assert_eq!(
+            due_reminders(&conn, now + 120).unwrap(),
+            vec![moved.clone()]
+        );
+        assert_eq!(
+            list_reminders(&pool, Some(account.id)).unwrap(),
+            vec![moved]
+        );
+
+        // My own follow-up is not a reply; theirs is
+        let mine = store(&pool, account.id, folder.id, 2, "me@example.com", 200);
+        save_message_headers(&conn, mine, "In-Reply-To: <1@example.com>\r\n", 10).unwrap();
+        assert!(!has_reply(&conn, sent).unwrap());
+        let theirs = store(&pool, account.id, folder.id, 3, "bob@example.com", 300);
+        save_message_headers(&conn, theirs, "References: <1@example.com>\r\n", 10).unwrap();
+        assert!(has_reply(&conn, sent).unwrap());
+
+        resurface(&conn, sent, now).unwrap();
+        let (is_read, resurfaced_at): (bool, i64) = conn
+            .query_row(
+                "SELECT is_read, resurfaced_at FROM messages WHERE id = ?1",
+                [sent],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!((is_read, resurfaced_at), (false, now));
+    }
+}
@@ -1,65 +1,77 @@
-use crate::core::accounts::get_accounts;
-use crate::core::auth::{get_google_client, get_microsoft_client};
-use crate::core::config::load_config;
+use crate::core::notifications;
+use crate::core::providers::client_for_provider;
+use crate::core::sync::draft_sync;
 use crate::core::sync::imap_sync::ImapSync;
+use crate::core::sync::status::{self, SYNC_STATUS_EVENT};
 use crate::error::DEmailError;
+use crate::models::{Account, AccountSyncStatus};
 use crate::AppState;
 
-use std::time::Duration;
+use std::sync::Arc;
 use tauri::{AppHandle, Manager};
-use tokio::time;
 
 pub fn start_background_sync(app_handle: AppHandle) {
-    tokio::spawn(async move {
-        let mut interval = time::interval(Duration::from_secs(300)); // 5 minutes
-        loop {
-            interval.tick().await;
-            if let Err(e) = sync_all_accounts(app_handle.clone()).await {
-                log::error!("Error during background sync: {}", e);
-            }
-        }
-    });
+    let app_state = app_handle.state::<AppState>();
+    if let Err(e) = status::reset_interrupted(&app_state.db_pool) {
+        log::warn!("Could not reset interrupted sync status: {}", e);
+    }
+
+    let scheduler = app_state.sync_scheduler.clone();
+    tokio::spawn(async move { scheduler.run(app_handle).await });
 }
 
-async fn sync_all_accounts(app_handle: AppHandle) -> Result<(), DEmailError> {
-    let app_state = app_handle.state::<AppState>();
-    let accounts = get_accounts(&app_state.db_pool)?;
-    let config = app_state.app_config.lock().unwrap();
-    let configs = load_config(&config)?;
+/// Syncs one account, persisting the outcome and emitting `sync-status` events before
+/// and after. Returns the number of messages fetched. Only the scheduler calls this, so
+/// an account is never synced twice at once.
+pub async fn sync_account(app_handle: &AppHandle, account: &Account) -> Result<usize, DEmailError> {
+    let pool = &app_handle.state::<AppState>().db_pool;
+    emit_status(app_handle, status::mark_started(pool, account.id));
+
+    let result = run_sync(app_handle, account).await;
+    let recorded = match &result {
+        Ok(fetched) => status::record_success(pool, account.id, *fetched),
+        Err(e) => {
+            log::error!("Sync of {} failed: {}", account.email_address, e);
+            status::record_failure(pool, account.id, &e.to_string())
+        }
+    };
+    emit_status(app_handle, recorded);
 
-    for account in accounts {
-        let provider = account.provider_type.as_str();
-        let provider_config = configs.get(provider).ok_or_else(|| {
-            DEmailError::Config(format!("OAuth config for {} not found", provider))
-        })?;
+    result
+}
 
-        let client = match provider {
-            "google" => get_google_client(
-                provider_config.client_id.clone(),
-                provider_config.client_secret.clone(),
-            )?,
-            "microsoft" => get_microsoft_client(
-                provider_config.client_id.clone(),
-                provider_config.client_secret.clone(),
-            )?,
-            _ => continue,
-        };
+async fn run_sync(app_handle: &AppHandle, account: &Account) -> Result<usize, DEmailError> {
+    let app_state = app_handle.state::<AppState>();
+    let config = app_state.app_config.lock().unwrap().clone();
+    let (provider, client) = client_for_provider(&config, &account.provider_type)?;
 
-        let app_state_arc = std::sync::Arc::new(AppState {
-            db_pool: app_state.db_pool.clone(),
-            app_config: app_state.app_config.clone(),
-        });
+    let app_state_arc = Arc::new(AppState {
+        db_pool: app_state.db_pool.clone(),
+        app_config: app_state.app_config.clone(),
+        app_lock: app_state.app_lock.clone(),
+        token_manager: app_state.token_manager.clone(),
+        sync_scheduler: app_state.sync_scheduler.clone(),
+        imap_pool: app_state.imap_pool.clone(),
+        draft_sync: app_state.draft_sync.clone(),
+    });
 
-        let imap_sync = ImapSync::new(app_state_arc);
-        imap_sync
-            .initial_sync(
-                account.id,
-                &account.provider_type,
-                &client,
-                &account.email_address,
-            )
-            .await?;
+    let sync = ImapSync::new(app_state_arc).with_progress_events(app_handle.clone());
+    let fetched = sync
+        .initial_sync(account.id, &provider, &client, &account.email_address)
+        .await?;
+    notifications::notify_arrivals(app_handle, account, &sync.take_arrivals());
+    // Drafts written on other devices come in with each pass
+    if let Err(e) = draft_sync::sync_and_notify(app_handle, account.id).await {
+        log::warn!("Draft sync of {} failed: {}", account.email_address, e);
     }
+    Ok(fetched)
+}
 
-    Ok(())
+fn emit_status(app_handle: &AppHandle, status: Result<AccountSyncStatus, DEmailError>) {
+    match status {
+        Ok(status) => {
+            let _ = app_handle.emit_all(SYNC_STATUS_EVENT, status);
+        }
+        Err(e) => log::warn!("Could not record sync status: {}", e),
+    }
 }
@@ -0,0 +1,362 @@
+// Draft sync - Mirrors local drafts to the account's Drafts folder as MIME messages
+// flagged \Draft, and imports drafts written on other devices. `drafts::plan_sync`
+// decides what has to change; this module carries it out on the server.
+//
+// APPEND does not report the new message's UID, so an uploaded draft is found again
+// by its Message-ID. Edits are uploaded once a draft has gone unchanged for a minute or
+// its editor closes, not on every auto-save.
+
+use crate::core::accounts::get_accounts;
+use crate::core::cache::db::delete_draft;
+use crate::core::drafts::{self, DraftSyncStep};
+use crate::core::identities;
+use crate::core::sync::imap_pool::{FetchedMessage, ImapSession, RawSession};
+use crate::core::sync::tag_sync::{account_session, quote};
+use crate::error::DEmailError;
+use crate::AppState;
+use imap::types::Flag;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tauri::{AppHandle, Manager};
+
+/// Emitted with the `DraftSyncReport` when drafts were imported or removed
+pub const DRAFTS_SYNCED_EVENT: &str = "drafts-synced";
+
+/// How long a draft must go unchanged before an edit is uploaded
+const UPLOAD_DELAY: Duration = Duration::from_secs(60);
+
+/// Draft sync bookkeeping per account, shared through `AppState`
+#[derive(Default)]
+pub struct DraftSyncState {
+    /// One sync per account at a time, so a draft is not uploaded twice by overlapping
+    /// syncs
+    locks: Mutex<HashMap<i64, Arc<tokio::sync::Mutex<()>>>>,
+    /// Drafts being edited, with their account and the time of their latest change.
+    /// Their edits wait for `UPLOAD_DELAY` or for the editor to close.
+    pending_uploads: Mutex<HashMap<i64, (i64, Instant)>>,
+}
+
+impl DraftSyncState {
+    fn account_lock(&self, account_id: i64) -> Arc<tokio::sync::Mutex<()>> {
+        self.locks
+            .lock()
+            .unwrap()
+            .entry(account_id)
+            .or_default()
+            .clone()
+    }
+
+    fn upload_pending(&self, draft_id: i64) -> bool {
+        self.pending_uploads.lock().unwrap().contains_key(&draft_id)
+    }
+
+    /// Drops a removed account's lock and the uploads still waiting for its drafts
+    pub fn discard(&self, account_id: i64) {
+        self.locks.lock().unwrap().remove(&account_id);
+        self.pending_uploads
+            .lock()
+            .unwrap()
+            .retain(|_, (pending_account, _)| *pending_account != account_id);
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DraftSyncReport {
+    pub account_id: i64,
+    pub uploaded: usize,
+    /// Drafts added or updated from the server
+    pub imported: usize,
+    /// Drafts sent or discarded on another device
+    pub removed: usize,
+    /// Server versions kept next to a local edit
+    pub conflicts: usize,
+}
+
+/// Brings the account's local drafts and its Drafts folder in step. Accounts without a
+/// Drafts folder are left alone.
+pub async fn sync_drafts(
+    app_state: &AppState,
+    account_id: i64,
+) -> Result<DraftSyncReport, DEmailError> {
+    let lock = app_state.draft_sync.account_lock(account_id);
+    let _running = lock.lock().await;
+    let mut report = DraftSyncReport {
+        account_id,
+        ..Default::default()
+    };
+    let pool = &app_state.db_pool;
+    let account = get_accounts(pool)?
+        .into_iter()
+        .find(|account| account.id == account_id)
+        .ok_or_else(|| DEmailError::NotFound(format!("Account {} not found", account_id)))?;
+    let (folder, from) = {
+        let conn = pool
+            .get()
+            .map_err(|_| DEmailError::Database(rusqlite::Error::InvalidQuery))?;
+        let from = match identities::resolve_identity(&conn, account_id, None)? {
+            Some(identity) => identity.mailbox()?.to_string(),
+            None => account.email_address.clone(),
+        };
+        (drafts::drafts_folder(&conn, account_id)?, from)
+    };
+    let Some(folder) = folder else {
+        return Ok(report);
+    };
+
+    let mut session = account_session(app_state, account_id).await?;
+    let path = folder.clone();
+    let (uid_validity, server) = session
+        .run(move |s| {
+            let mailbox = s.select(&path)?;
+            let mut server = HashMap::new();
+            if mailbox.exists > 0 {
+                for fetch in s.uid_fetch("1:*", "(UID FLAGS ENVELOPE)")?.iter() {
+                    let Some(uid) = fetch.uid else {
+                        continue;
+                    };
+                    if fetch.flags().contains(&Flag::Deleted) {
+                        continue;
+                    }
+                    let message_id = fetch
+                        .envelope()
+                        .and_then(|envelope| envelope.message_id)
+                        .map(|id| String::from_utf8_lossy(id).trim().to_string())
+                        .unwrap_or_default();
+                    server.insert(uid, message_id);
+                }
+            }
+            Ok((mailbox.uid_validity, server))
+        })
+        .await?;
+
+    let steps = {
+        let conn = pool
+            .get()
+            .map_err(|_| DEmailError::Database(rusqlite::Error::InvalidQuery))?;
+        drafts::plan_sync(
+            &drafts::sync_states(&conn, account_id)?,
+            &server,
+            uid_validity,
+        )
+    };
+    for step in steps {
+        match step {
+            DraftSyncStep::Upload {
+                draft_id,
+                message_id,
+                replaces,
+            } => {
+                if app_state.draft_sync.upload_pending(draft_id) {
+                    continue;
+                }
+                let uploaded = {
+                    let conn = pool
+                        .get()
+                        .map_err(|_| DEmailError::Database(rusqlite::Error::InvalidQuery))?;
+                    drafts::get_draft(&conn, draft_id)?.map(|draft| {
+                        drafts::draft_attachment_contents(&conn, draft_id)
+                            .map(|attachments| (draft, attachments))
+                    })
+                }
+                .transpose()?;
+                let Some((draft, attachments)) = uploaded else {
+                    continue;
+                };
+                let message_id =
+                    message_id.unwrap_or_else(|| drafts::new_message_id(&account.email_address));
+                let raw = drafts::draft_message(&draft, &from, &message_id, attachments)?;
+                let uid = upload(&mut session, &folder, raw, &message_id, replaces).await?;
+
+                let conn = pool
+                    .get()
+                    .map_err(|_| DEmailError::Database(rusqlite::Error::InvalidQuery))?;
+                drafts::mark_uploaded(
+                    &conn,
+                    draft_id,
+                    draft.revision,
+                    &message_id,
+                    uid,
+                    uid_validity,
+                )?;
+                report.uploaded += 1;
+            }
+            DraftSyncStep::Import {
+                uid,
+                into,
+                conflict,
+            } => {
+                let Some(server_draft) = fetch_draft(&mut session, uid).await? else {
+                    log::warn!("Could not read draft {} in {}", uid, folder);
+                    continue;
+                };
+                let conn = pool
+                    .get()
+                    .map_err(|_| DEmailError::Database(rusqlite::Error::InvalidQuery))?;
+                drafts::import_server_draft(
+                    &conn,
+                    account_id,
+                    into,
+                    conflict,
+                    &server_draft,
+                    uid,
+                    uid_validity,
+                )?;
+                report.imported += 1;
+                if conflict {
+                    report.conflicts += 1;
+                }
+            }
+            DraftSyncStep::RemoveLocal { draft_id } => {
+                delete_draft(pool, draft_id)?;
+                report.removed += 1;
+            }
+            DraftSyncStep::RemoveRemote { draft_id, uid } => {
+                if let Some(uid) = uid {
+                    let uidplus = session.has_capability("UIDPLUS");
+                    session.run(move |s| expunge(s, uid, uidplus)).await?;
+                }
+                delete_draft(pool, draft_id)?;
+            }
+        }
+    }
+    Ok(report)
+}
+
+/// Syncs an account's drafts and emits `drafts-synced` when any came from the server
+pub async fn sync_and_notify(
+    app_handle: &AppHandle,
+    account_id: i64,
+) -> Result<DraftSyncReport, DEmailError> {
+    let app_state = app_handle.state::<AppState>();
+    let report = sync_drafts(&app_state, account_id).await?;
+    if report.imported > 0 || report.removed > 0 {
+        let _ = app_handle.emit_all(DRAFTS_SYNCED_EVENT, &report);
+    }
+    Ok(report)
+}
+
+/// Syncs an account's drafts in the background after a local change
+fn spawn_sync(app_handle: AppHandle, account_id: i64) {
+    tauri::async_runtime::spawn(async move {
+        if let Err(e) = sync_and_notify(&app_handle, account_id).await {
+            log::warn!("Draft sync of account {} failed: {}", account_id, e);
+        }
+    });
+}
+
+/// Uploads an edited draft once it has gone unchanged for `UPLOAD_DELAY`. A later edit
+/// restarts the wait, and `flush_upload` ends it early.
+pub fn schedule_upload(app_handle: AppHandle, account_id: i64, draft_id: i64) {
+    let state = app_handle.state::<AppState>().draft_sync.clone();
+    let edited = (account_id, Instant::now());
+    state
+        .pending_uploads
+        .lock()
+        .unwrap()
+        .insert(draft_id, edited);
+    tauri::async_runtime::spawn(async move {
+        tokio::time::sleep(UPLOAD_DELAY).await;
+        {
+            let mut pending = state.pending_uploads.lock().unwrap();
+            if pending.get(&draft_id) != Some(&edited) {
+                return;
+            }
+            pending.remove(&draft_id);
+        }
+        spawn_sync(app_handle, account_id);
+    });
+}
+
+/// Uploads a draft's edits now, as when its editor closes
+pub fn flush_upload(app_handle: AppHandle, account_id: i64, draft_id: i64) {
+    let state = app_handle.state::<AppState>().draft_sync.clone();
+    state.pending_uploads.lock().unwrap().remove(&draft_id);
+    spawn_sync(app_handle, account_id);
+}
+
+/// Appends a draft to the selected Drafts folder and removes the copy it replaces.
+/// Returns the new copy's UID when the server can find it.
+pub async fn upload(
+    session: &mut ImapSession,
+    folder: &str,
+    raw: Vec<u8>,
+    message_id: &str,
+    replaces: Option<u32>,
+) -> Result<Option<u32>, DEmailError> {
+    let folder = folder.to_string();
+    let search = format!("HEADER Message-ID {}", quote(message_id));
+    let uidplus = session.has_capability("UIDPLUS");
+    session
+        .run(move |s| {
+            s.append_with_flags(&folder, &raw, &[Flag::Seen, Flag::Draft])?;
+            let uid = s
+                .uid_search(&search)?
+                .into_iter()
+                .filter(|uid| Some(*uid) != replaces)
+                .max();
+            if let Some(replaces) = replaces {
+                expunge(s, replaces, uidplus)?;
+            }
+            Ok(uid)
+        })
+        .await
+}
+
+async fn fetch_draft(
+    session: &mut ImapSession,
+    uid: u32,
+) -> Result<Option<drafts::ServerDraft>, DEmailError> {
+    // BODY.PEEK leaves \Seen alone where the server supports it
+    let query = if session.has_capability("IMAP4rev1") {
+        "(BODY.PEEK[])"
+    } else {
+        "(RFC822)"
+    };
+    let fetched = session
+        .run(move |s| {
+            let fetches = s.uid_fetch(uid.to_string(), query)?;
+            Ok(fetches.iter().map(FetchedMessage::from).next())
+        })
+        .await?;
+    Ok(fetched
+        .as_ref()
+        .and_then(FetchedMessage::body)
+        .and_then(drafts::parse_server_draft))
+}
+
+/// Removes a draft's server copy. Without UIDPLUS a plain EXPUNGE would also remove
+/// messages other clients marked \Deleted, so the copy is only left flagged then.
+fn expunge(session: &mut RawSession, uid: u32, uidplus: bool) -> imap::error::Result<()> {
+    let uid = uid.to_string();
+    session.uid_store(&uid, "+FLAGS (\\Deleted)")?;
+    if uidplus {
+        session.uid_expunge(&uid)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_discard_forgets_only_that_account() {
+        let state = DraftSyncState::default();
+        let first = state.account_lock(1);
+        state.account_lock(2);
+        {
+            let mut pending = state.pending_uploads.lock().unwrap();
+            pending.insert(10, (1, Instant::now()));
+            pending.insert(20, (2, Instant::now()));
+        }
+
+        state.discard(1);
+        assert!(!state.upload_pending(10));
+        assert!(state.upload_pending(20));
+        // A later sync of the account starts from a fresh lock
+        assert!(!Arc::ptr_eq(&first, &state.account_lock(1)));
+        assert_eq!(state.locks.lock().unwrap().len(), 2);
+    }
+}
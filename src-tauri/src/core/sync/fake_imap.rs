@@ -0,0 +1,413 @@
+// Fake IMAP server - A small in-memory IMAP server on a local port for tests of the
+// session pool, folder sync and rule actions. It understands just the commands those
+// send, with one thread per connection and mailboxes shared between connections.
+
+use crate::core::sync::imap_pool::{Connect, ImapStream, RawSession};
+use crate::error::DEmailError;
+use imap::Client;
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+pub const MESSAGE: &str = "From: a@example.org\r\nSubject: Hi\r\n\r\nHello\r\n";
+const CAPABILITIES: &str = "IMAP4rev1 IDLE UIDPLUS AUTH=XOAUTH2";
+const UID_VALIDITY: u32 = 42;
+
+struct FakeMessage {
+    uid: u32,
+    flags: Vec<String>,
+    body: Vec<u8>,
+}
+
+struct Mailbox {
+    next_uid: u32,
+    messages: Vec<FakeMessage>,
+}
+
+struct State {
+    capabilities: String,
+    mailboxes: HashMap<String, Mailbox>,
+    /// Every command received, without its tag
+    commands: Vec<String>,
+}
+
+pub struct FakeServer {
+    pub port: u16,
+    connections: Arc<AtomicUsize>,
+    streams: Arc<Mutex<Vec<TcpStream>>>,
+    state: Arc<Mutex<State>>,
+}
+
+impl FakeServer {
+    /// A server with UIDPLUS whose INBOX holds `MESSAGE` as UID 7
+    pub fn start() -> Self {
+        Self::start_with(CAPABILITIES, &[("INBOX", &[(7, MESSAGE)])])
+    }
+
+    /// A server with the given capabilities and mailboxes of `(uid, message)` pairs
+    pub fn start_with(capabilities: &str, mailboxes: &[(&str, &[(u32, &str)])]) -> Self {
+        let mailboxes = mailboxes
+            .iter()
+            .map(|(name, messages)| {
+                let messages: Vec<FakeMessage> = messages
+                    .iter()
+                    .map(|(uid, body)| FakeMessage {
+                        uid: *uid,
+                        flags: Vec::new(),
+                        body: body.as_bytes().to_vec(),
+                    })
+                    .collect();
+                let next_uid = messages.iter().map(|message| message.uid + 1).max();
+                let mailbox = Mailbox {
+                    next_uid: next_uid.unwrap_or(1),
+                    messages,
+                };
+                (name.to_string(), mailbox)
+            })
+            .collect();
+        let state = Arc::new(Mutex::new(State {
+            capabilities: capabilities.to_string(),
+            mailboxes,
+            commands: Vec::new(),
+        }));
+
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let connections = Arc::new(AtomicUsize::new(0));
+        let streams = Arc::new(Mutex::new(Vec::new()));
+
+        let (count, open, shared) = (connections.clone(), streams.clone(), state.clone());
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = stream.unwrap();
+                count.fetch_add(1, Ordering::SeqCst);
+                open.lock().unwrap().push(stream.try_clone().unwrap());
+                let state = shared.clone();
+                std::thread::spawn(move || serve(stream, state));
+            }
+        });
+
+        Self {
+            port,
+            connections,
+            streams,
+            state,
+        }
+    }
+
+    pub fn connections(&self) -> usize {
+        self.connections.load(Ordering::SeqCst)
+    }
+
+    /// Closes every open connection from the server side
+    pub fn drop_connections(&self) {
+        for stream in self.streams.lock().unwrap().drain(..) {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+
+    /// UIDs in a mailbox, including messages flagged \Deleted but not yet expunged
+    pub fn uids(&self, mailbox: &str) -> Vec<u32> {
+        let state = self.state.lock().unwrap();
+        state.mailboxes[mailbox]
+            .messages
+            .iter()
+            .map(|message| message.uid)
+            .collect()
+    }
+
+    pub fn flags(&self, mailbox: &str, uid: u32) -> Vec<String> {
+        let state = self.state.lock().unwrap();
+        state.mailboxes[mailbox]
+            .messages
+            .iter()
+            .find(|message| message.uid == uid)
+            .map(|message| message.flags.clone())
+            .unwrap_or_default()
+    }
+
+    /// How many commands starting with `prefix` the server received
+    pub fn count(&self, prefix: &str) -> usize {
+        let state = self.state.lock().unwrap();
+        state
+            .commands
+            .iter()
+            .filter(|command| command.to_ascii_uppercase().starts_with(prefix))
+            .count()
+    }
+}
+
+fn serve(stream: TcpStream, state: Arc<Mutex<State>>) {
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut writer = stream;
+    let _ = writer.write_all(b"* OK Fake IMAP ready\r\n");
+    let mut selected: Option<String> = None;
+
+    let mut line = String::new();
+    while reader.read_line(&mut line).unwrap_or(0) > 0 {
+        let request = line.trim_end().to_string();
+        line.clear();
+        let (tag, command) = request.split_once(' ').unwrap_or((&request, ""));
+        let upper = command.to_ascii_uppercase();
+        state.lock().unwrap().commands.push(command.to_string());
+
+        let response = if upper.starts_with("LOGOUT") {
+            let _ = writer.write_all(format!("* BYE\r\n{} OK done\r\n", tag).as_bytes());
+            return;
+        } else if upper.starts_with("APPEND") {
+            let Some(message) = read_literal(command, &mut reader, &mut writer) else {
+                return;
+            };
+            append(&state, tag, command, message)
+        } else {
+            respond(&state, &mut selected, tag, command, &upper)
+        };
+        if writer.write_all(response.as_bytes()).is_err() {
+            return;
+        }
+    }
+}
+
+fn respond(
+    state: &Mutex<State>,
+    selected: &mut Option<String>,
+    tag: &str,
+    command: &str,
+    upper: &str,
+) -> String {
+    let mut state = state.lock().unwrap();
+    let uidplus = state.capabilities.contains("UIDPLUS");
+
+    if upper.starts_with("LOGIN") {
+        format!("{} OK LOGIN completed\r\n", tag)
+    } else if upper.starts_with("CAPABILITY") {
+        format!("* CAPABILITY {}\r\n{} OK done\r\n", state.capabilities, tag)
+    } else if upper.starts_with("NOOP") {
+        format!("{} OK NOOP completed\r\n", tag)
+    } else if upper.starts_with("LIST") {
+        let mut names: Vec<&String> = state.mailboxes.keys().collect();
+        names.sort();
+        let mut response = String::new();
+        for name in names {
+            response.push_str(&format!("* LIST (\\HasNoChildren) \"/\" \"{}\"\r\n", name));
+        }
+        response + &format!("{} OK done\r\n", tag)
+    } else if upper.starts_with("SELECT") {
+        let name = unquote(&command["SELECT".len()..]);
+        let Some(mailbox) = state.mailboxes.get(&name) else {
+            return format!("{} NO no such mailbox\r\n", tag);
+        };
+        let response = format!(
+            "* {} EXISTS\r\n* OK [UIDVALIDITY {}] UIDs valid\r\n* OK [UIDNEXT {}] next\r\n{} OK [READ-WRITE] done\r\n",
+            mailbox.messages.len(),
+            UID_VALIDITY,
+            mailbox.next_uid,
+            tag
+        );
+        *selected = Some(name);
+        response
+    } else {
+        let Some(mailbox) = selected
+            .as_ref()
+            .and_then(|name| state.mailboxes.get_mut(name))
+        else {
+            return format!("{} BAD no mailbox selected\r\n", tag);
+        };
+
+        if upper.starts_with("UID SEARCH ") {
+            let criteria = &command["UID SEARCH ".len()..];
+            let mut response = "* SEARCH".to_string();
+            for message in &mailbox.messages {
+                if matches_search(message, criteria) {
+                    response.push_str(&format!(" {}", message.uid));
+                }
+            }
+            response + &format!("\r\n{} OK done\r\n", tag)
+        } else if let Some(arguments) = upper.strip_prefix("UID FETCH ") {
+            let (set, items) = arguments.split_once(' ').unwrap_or((arguments, ""));
+            let mut response = String::new();
+            for (index, message) in mailbox.messages.iter().enumerate() {
+                if !in_set(set, message.uid) {
+                    continue;
+                }
+                let mut fetched = format!("UID {}", message.uid);
+                if items.contains("FLAGS") {
+                    fetched.push_str(&format!(" FLAGS ({})", message.flags.join(" ")));
+                }
+                let body = String::from_utf8_lossy(&message.body);
+                if items.contains("BODY.PEEK[]") {
+                    fetched.push_str(&format!(" BODY[] {{{}}}\r\n{}", message.body.len(), body));
+                } else if items.contains("RFC822") {
+                    fetched.push_str(&format!(" RFC822 {{{}}}\r\n{}", message.body.len(), body));
+                }
+                response.push_str(&format!("* {} FETCH ({})\r\n", index + 1, fetched));
+            }
+            response + &format!("{} OK done\r\n", tag)
+        } else if upper.starts_with("UID STORE ") {
+            let mut parts = command["UID STORE ".len()..].splitn(3, ' ');
+            let set = parts.next().unwrap_or("");
+            let operation = parts.next().unwrap_or("").to_ascii_uppercase();
+            let flags: Vec<String> = parts
+                .next()
+                .unwrap_or("")
+                .trim_matches(|c| c == '(' || c == ')')
+                .split_whitespace()
+                .map(str::to_string)
+                .collect();
+            let mut response = String::new();
+            for (index, message) in mailbox.messages.iter_mut().enumerate() {
+                if !in_set(set, message.uid) {
+                    continue;
+                }
+                match operation.as_str() {
+                    "+FLAGS" => {
+                        for flag in &flags {
+                            if !message.flags.contains(flag) {
+                                message.flags.push(flag.clone());
+                            }
+                        }
+                    }
+                    "-FLAGS" => message.flags.retain(|flag| !flags.contains(flag)),
+                    _ => message.flags = flags.clone(),
+                }
+                response.push_str(&format!(
+                    "* {} FETCH (UID {} FLAGS ({}))\r\n",
+                    index + 1,
+                    message.uid,
+                    message.flags.join(" ")
+                ));
+            }
+            response + &format!("{} OK done\r\n", tag)
+        } else if upper.starts_with("UID EXPUNGE") && !uidplus {
+            format!("{} BAD UID EXPUNGE needs UIDPLUS\r\n", tag)
+        } else if upper.starts_with("UID EXPUNGE") || upper.starts_with("EXPUNGE") {
+            let set = upper.strip_prefix("UID EXPUNGE ");
+            let mut response = String::new();
+            let mut index = 0;
+            mailbox.messages.retain(|message| {
+                index += 1;
+                let expunged = message.flags.iter().any(|flag| flag == "\\Deleted")
+                    && set.is_none_or(|set| in_set(set, message.uid));
+                if expunged {
+                    response.push_str(&format!("* {} EXPUNGE\r\n", index));
+                    index -= 1;
+                }
+                !expunged
+            });
+            response + &format!("{} OK done\r\n", tag)
+        } else {
+            format!("{} BAD unknown command\r\n", tag)
+        }
+    }
+}
+
+/// Reads the message of `APPEND "mailbox" (flags) {length}` after asking the client
+/// to go on
+fn read_literal(
+    command: &str,
+    reader: &mut BufReader<TcpStream>,
+    writer: &mut TcpStream,
+) -> Option<Vec<u8>> {
+    let length: usize = command
+        .rsplit_once('{')?
+        .1
+        .trim_end_matches('}')
+        .parse()
+        .ok()?;
+    writer.write_all(b"+ Ready for literal data\r\n").ok()?;
+    let mut message = vec![0u8; length];
+    reader.read_exact(&mut message).ok()?;
+    let mut end = String::new();
+    reader.read_line(&mut end).ok()?;
+    Some(message)
+}
+
+fn append(state: &Mutex<State>, tag: &str, command: &str, body: Vec<u8>) -> String {
+    let mut state = state.lock().unwrap();
+    let uidplus = state.capabilities.contains("UIDPLUS");
+    let arguments = &command["APPEND".len()..];
+    let (name, rest) = match arguments.trim_start().strip_prefix('"') {
+        Some(quoted) => quoted.split_once('"').unwrap_or((quoted, "")),
+        None => arguments
+            .trim_start()
+            .split_once(' ')
+            .unwrap_or((arguments, "")),
+    };
+    let flags = rest
+        .split_once('(')
+        .and_then(|(_, flags)| flags.split_once(')'))
+        .map(|(flags, _)| flags.split_whitespace().map(str::to_string).collect())
+        .unwrap_or_default();
+    let Some(mailbox) = state.mailboxes.get_mut(name) else {
+        return format!("{} NO [TRYCREATE] no such mailbox\r\n", tag);
+    };
+
+    let uid = mailbox.next_uid;
+    mailbox.next_uid += 1;
+    mailbox.messages.push(FakeMessage { uid, flags, body });
+    if uidplus {
+        format!("{} OK [APPENDUID {} {}] done\r\n", tag, UID_VALIDITY, uid)
+    } else {
+        format!("{} OK done\r\n", tag)
+    }
+}
+
+/// Supports `ALL`, `UNDELETED` and `HEADER <name> <value>`
+fn matches_search(message: &FakeMessage, criteria: &str) -> bool {
+    let mut parts = criteria.splitn(3, ' ');
+    match parts.next().unwrap_or("").to_ascii_uppercase().as_str() {
+        "ALL" => true,
+        "UNDELETED" => !message.flags.iter().any(|flag| flag == "\\Deleted"),
+        "HEADER" => {
+            let value = unquote(parts.nth(1).unwrap_or(""));
+            String::from_utf8_lossy(&message.body).contains(&value)
+        }
+        _ => false,
+    }
+}
+
+/// Whether a UID is in a set such as `7`, `1,3` or `2:*`
+fn in_set(set: &str, uid: u32) -> bool {
+    set.split(',').any(|range| match range.split_once(':') {
+        Some((start, end)) => {
+            let start = start.parse().unwrap_or(u32::MAX);
+            let end = if end == "*" {
+                u32::MAX
+            } else {
+                end.parse().unwrap_or(0)
+            };
+            (start.min(end)..=start.max(end)).contains(&uid)
+        }
+        None => range.parse() == Ok(uid),
+    })
+}
+
+fn unquote(value: &str) -> String {
+    value
+        .trim()
+        .trim_matches('"')
+        .replace("\\\"", "\"")
+        .replace("\\\\", "\\")
+}
+
+/// Signs in with LOGIN over plain TCP
+pub struct PlainConnector(pub u16);
+
+impl Connect for PlainConnector {
+    fn connect(&self) -> Result<RawSession, DEmailError> {
+        let tcp = TcpStream::connect(("127.0.0.1", self.0))?;
+        tcp.set_read_timeout(Some(Duration::from_secs(5)))?;
+        let mut client = Client::new(Box::new(tcp) as Box<dyn ImapStream>);
+        client.read_greeting()?;
+        client
+            .login("user@example.org", "secret")
+            .map_err(|(e, _)| DEmailError::Imap(e))
+    }
+}
+
+pub fn connector(server: &FakeServer) -> Arc<dyn Connect> {
+    Arc::new(PlainConnector(server.port))
+}
@@ -0,0 +1,490 @@
+// IMAP connection pool - Async sessions over the blocking imap crate. Commands run on
+// Tokio's blocking threads, and authenticated sessions are kept per account so sync and
+// other IMAP work reuse one login instead of opening a new TLS session every time.
+
+use crate::core::providers::{Security, ServerEndpoint};
+use crate::error::DEmailError;
+use imap::types::Fetch;
+use imap::{Authenticator, Client};
+use std::collections::{HashMap, HashSet};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{debug, warn};
+
+/// Read and write timeout of the underlying socket
+const IO_TIMEOUT: Duration = Duration::from_secs(60);
+/// Idle sessions older than this are checked with NOOP before they are handed out
+const HEALTH_CHECK_AFTER: Duration = Duration::from_secs(60);
+/// Idle sessions older than this are logged out; servers drop them after 30 minutes
+const MAX_IDLE: Duration = Duration::from_secs(20 * 60);
+/// Idle sessions kept per account
+const MAX_IDLE_PER_ACCOUNT: usize = 2;
+
+/// Any byte stream an IMAP session can run over
+pub trait ImapStream: Read + Write + Send {}
+impl<T: Read + Write + Send> ImapStream for T {}
+
+pub type RawSession = imap::Session<Box<dyn ImapStream>>;
+
+/// Opens and authenticates a new session. Called on a blocking thread.
+pub trait Connect: Send + Sync {
+    fn connect(&self) -> Result<RawSession, DEmailError>;
+}
+
+/// Connects over TLS or STARTTLS and signs in with an OAuth access token (XOAUTH2)
+pub struct OAuth2Connector {
+    server: ServerEndpoint,
+    user: String,
+    access_token: String,
+}
+
+impl OAuth2Connector {
+    pub fn new(server: &ServerEndpoint, user: &str, access_token: &str) -> Self {
+        Self {
+            server: server.clone(),
+            user: user.to_string(),
+            access_token: access_token.to_string(),
+        }
+    }
+}
+
+impl Connect for OAuth2Connector {
+    fn connect(&self) -> Result<RawSession, DEmailError> {
+        let tls = native_tls::TlsConnector::builder()
+            .build()
+            .map_err(|e| DEmailError::Imap(imap::Error::Tls(e)))?;
+        let host = self.server.host.as_str();
+        let tcp = TcpStream::connect((host, self.server.port))?;
+        tcp.set_read_timeout(Some(IO_TIMEOUT))?;
+        tcp.set_write_timeout(Some(IO_TIMEOUT))?;
+
+        let client = match self.server.security {
+            Security::Tls => {
+                let stream = tls.connect(host, tcp).map_err(imap::Error::TlsHandshake)?;
+                let mut client = Client::new(Box::new(stream) as Box<dyn ImapStream>);
+                client.read_greeting()?;
+                client
+            }
+            Security::StartTls => {
+                // The server sends no second greeting once TLS is up
+                negotiate_starttls(&tcp)?;
+                let stream = tls.connect(host, tcp).map_err(imap::Error::TlsHandshake)?;
+                Client::new(Box::new(stream) as Box<dyn ImapStream>)
+            }
+        };
+
+        let authenticator = XOAuth2 {
+            user: &self.user,
+            access_token: &self.access_token,
+        };
+        client
+            .authenticate("XOAUTH2", &authenticator)
+            .map_err(|(e, _)| DEmailError::Imap(e))
+    }
+}
+
+/// Reads the plain-text greeting and asks the server to switch to TLS
+fn negotiate_starttls(tcp: &TcpStream) -> Result<(), DEmailError> {
+    let mut reader = BufReader::new(tcp.try_clone()?);
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let mut writer = tcp;
+    writer.write_all(b"a0 STARTTLS\r\n")?;
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(DEmailError::Imap(imap::Error::ConnectionLost));
+        }
+        if let Some(status) = line.strip_prefix("a0 ") {
+            return if status.starts_with("OK") {
+                Ok(())
+            } else {
+                Err(DEmailError::Imap(imap::Error::No(
+                    status.trim().to_string(),
+                )))
+            };
+        }
+    }
+}
+
+struct XOAuth2<'a> {
+    user: &'a str,
+    access_token: &'a str,
+}
+
+impl Authenticator for XOAuth2<'_> {
+    type Response = String;
+
+    fn process(&self, challenge: &[u8]) -> String {
+        // A non-empty challenge carries the error details of a failed attempt and is
+        // answered with an empty response
+        if challenge.is_empty() {
+            format!(
+                "user={}\x01auth=Bearer {}\x01\x01",
+                self.user, self.access_token
+            )
+        } else {
+            String::new()
+        }
+    }
+}
+
+/// A fetched message, owned so it can leave the blocking thread
+#[derive(Debug, Clone)]
+pub struct FetchedMessage {
+    pub uid: Option<u32>,
+    body: Option<Vec<u8>>,
+}
+
+impl FetchedMessage {
+    pub fn body(&self) -> Option<&[u8]> {
+        self.body.as_deref()
+    }
+}
+
+impl From<&Fetch> for FetchedMessage {
+    fn from(fetch: &Fetch) -> Self {
+        Self {
+            uid: fetch.uid,
+            body: fetch.body().map(|body| body.to_vec()),
+        }
+    }
+}
+
+/// An authenticated session. Commands run on a blocking thread; a connection-level
+/// failure marks the session broken until `reconnect` is called.
+pub struct ImapSession {
+    account_id: i64,
+    session: Option<RawSession>,
+    connector: Arc<dyn Connect>,
+    capabilities: HashSet<String>,
+    last_used: Instant,
+}
+
+impl ImapSession {
+    async fn open(account_id: i64, connector: Arc<dyn Connect>) -> Result<Self, DEmailError> {
+        let opener = connector.clone();
+        let (session, capabilities) = run_blocking(move || {
+            let mut session = opener.connect()?;
+            let capabilities = read_capabilities(&mut session)?;
+            Ok((session, capabilities))
+        })
+        .await?;
+        debug!(
+            "Opened IMAP session for account {} ({} capabilities)",
+            account_id,
+            capabilities.len()
+        );
+
+        Ok(Self {
+            account_id,
+            session: Some(session),
+            connector,
+            capabilities,
+            last_used: Instant::now(),
+        })
+    }
+
+    /// Whether the server announced a capability such as `IDLE` or `UIDPLUS`
+    pub fn has_capability(&self, capability: &str) -> bool {
+        self.capabilities.contains(&capability.to_ascii_uppercase())
+    }
+
+    pub fn is_broken(&self) -> bool {
+        self.session.is_none()
+    }
+
+    /// Runs commands against the session on a blocking thread
+    pub async fn run<F, R>(&mut self, command: F) -> Result<R, DEmailError>
+    where
+        F: FnOnce(&mut RawSession) -> imap::error::Result<R> + Send + 'static,
+        R: Send + 'static,
+    {
+        let mut session = self
+            .session
+            .take()
+            .ok_or(DEmailError::Imap(imap::Error::ConnectionLost))?;
+        let (session, result) = tokio::task::spawn_blocking(move || {
+            let result = command(&mut session);
+            (session, result)
+        })
+        .await
+        .map_err(|e| DEmailError::Unknown(e.to_string()))?;
+
+        match result {
+            Err(e) if is_connection_error(&e) => {
+                warn!("IMAP connection of account {} lost: {}", self.account_id, e);
+                Err(DEmailError::Imap(e))
+            }
+            result => {
+                self.session = Some(session);
+                self.last_used = Instant::now();
+                result.map_err(DEmailError::Imap)
+            }
+        }
+    }
+
+    /// Replaces the connection with a new one; the mailbox selection is lost
+    pub async fn reconnect(&mut self) -> Result<(), DEmailError> {
+        *self = Self::open(self.account_id, self.connector.clone()).await?;
+        Ok(())
+    }
+}
+
+/// Idle authenticated sessions per account, shared through `AppState`
+pub struct ImapPool {
+    idle: Mutex<HashMap<i64, Vec<ImapSession>>>,
+    health_check_after: Duration,
+    max_idle: Duration,
+}
+
+impl Default for ImapPool {
+    fn default() -> Self {
+        Self::new(HEALTH_CHECK_AFTER, MAX_IDLE)
+    }
+}
+
+impl ImapPool {
+    pub fn new(health_check_after: Duration, max_idle: Duration) -> Self {
+        Self {
+            idle: Mutex::new(HashMap::new()),
+            health_check_after,
+            max_idle,
+        }
+    }
+
+    /// Hands out an idle session of the account, or opens one with `connector`. The
+    /// session returns to the pool when dropped, unless it broke.
+    pub async fn checkout(
+        self: &Arc<Self>,
+        account_id: i64,
+        connector: Arc<dyn Connect>,
+    ) -> Result<PooledSession, DEmailError> {
+        while let Some(mut session) = self.take_idle(account_id) {
+            // Later reconnects use the caller's credentials, not the ones the session
+            // was opened with
+            session.connector = connector.clone();
+            let idle_for = session.last_used.elapsed();
+            if idle_for >= self.max_idle {
+                logout(session);
+                continue;
+            }
+            if idle_for >= self.health_check_after && session.run(|s| s.noop()).await.is_err() {
+                continue;
+            }
+            return Ok(self.wrap(session));
+        }
+
+        let session = ImapSession::open(account_id, connector).await?;
+        Ok(self.wrap(session))
+    }
+
+    /// Logs out and drops the idle sessions of a removed account
+    pub fn discard(&self, account_id: i64) {
+        let sessions = self.idle.lock().unwrap().remove(&account_id);
+        for session in sessions.into_iter().flatten() {
+            logout(session);
+        }
+    }
+
+    /// Logs out and drops every idle session, as when a restored backup replaced the
+    /// accounts
+    pub fn discard_all(&self) {
+        let sessions = std::mem::take(&mut *self.idle.lock().unwrap());
+        for session in sessions.into_values().flatten() {
+            logout(session);
+        }
+    }
+
+    fn take_idle(&self, account_id: i64) -> Option<ImapSession> {
+        self.idle.lock().unwrap().get_mut(&account_id)?.pop()
+    }
+
+    fn checkin(&self, session: ImapSession) {
+        if session.is_broken() {
+            return;
+        }
+        let mut idle = self.idle.lock().unwrap();
+        let sessions = idle.entry(session.account_id).or_default();
+        if sessions.len() < MAX_IDLE_PER_ACCOUNT {
+            sessions.push(session);
+        } else {
+            drop(idle);
+            logout(session);
+        }
+    }
+
+    fn wrap(self: &Arc<Self>, session: ImapSession) -> PooledSession {
+        PooledSession {
+            pool: self.clone(),
+            session: Some(session),
+        }
+    }
+}
+
+/// A session checked out of the pool; returned to it on drop
+pub struct PooledSession {
+    pool: Arc<ImapPool>,
+    session: Option<ImapSession>,
+}
+
+impl Deref for PooledSession {
+    type Target = ImapSession;
+
+    fn deref(&self) -> &ImapSession {
+        self.session
+            .as_ref()
+            .expect("session is present until drop")
+    }
+}
+
+impl DerefMut for PooledSession {
+    fn deref_mut(&mut self) -> &mut ImapSession {
+        self.session
+            .as_mut()
+            .expect("session is present until drop")
+    }
+}
+
+impl Drop for PooledSession {
+    fn drop(&mut self) {
+        if let Some(session) = self.session.take() {
+            self.pool.checkin(session);
+        }
+    }
+}
+
+async fn run_blocking<F, R>(task: F) -> Result<R, DEmailError>
+where
+    F: FnOnce() -> Result<R, DEmailError> + Send + 'static,
+    R: Send + 'static,
+{
+    tokio::task::spawn_blocking(task)
+        .await
+        .map_err(|e| DEmailError::Unknown(e.to_string()))?
+}
+
+fn logout(mut session: ImapSession) {
+    if let Some(mut raw) = session.session.take() {
+        tokio::task::spawn_blocking(move || {
+            let _ = raw.logout();
+        });
+    }
+}
+
+/// Errors after which the stream can no longer be trusted
+fn is_connection_error(error: &imap::Error) -> bool {
+    matches!(
+        error,
+        imap::Error::Io(_)
+            | imap::Error::ConnectionLost
+            | imap::Error::Tls(_)
+            | imap::Error::Parse(_)
+    )
+}
+
+/// Upper-cased capability names from the untagged CAPABILITY response
+fn read_capabilities(session: &mut RawSession) -> Result<HashSet<String>, DEmailError> {
+    let response = session.run_command_and_read_response("CAPABILITY")?;
+    Ok(String::from_utf8_lossy(&response)
+        .lines()
+        .filter_map(|line| line.trim_end().strip_prefix("* CAPABILITY "))
+        .flat_map(|capabilities| capabilities.split_ascii_whitespace())
+        .map(|capability| capability.to_ascii_uppercase())
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::sync::fake_imap::{connector, FakeServer, MESSAGE};
+
+    #[tokio::test]
+    async fn test_sessions_are_reused_and_capabilities_detected() {
+        let server = FakeServer::start();
+        let pool = Arc::new(ImapPool::default());
+
+        let mut session = pool.checkout(1, connector(&server)).await.unwrap();
+        assert!(session.has_capability("idle"));
+        assert!(session.has_capability("AUTH=XOAUTH2"));
+        assert!(!session.has_capability("CONDSTORE"));
+
+        let (uid_validity, uids) = session
+            .run(|s| {
+                let mailbox = s.select("INBOX")?;
+                Ok((mailbox.uid_validity, s.uid_search("ALL")?))
+            })
+            .await
+            .unwrap();
+        assert_eq!(uid_validity, Some(42));
+        assert!(uids.contains(&7));
+
+        let fetched = session
+            .run(|s| {
+                let fetches = s.uid_fetch("7", "(RFC822)")?;
+                Ok(fetches.iter().map(FetchedMessage::from).collect::<Vec<_>>())
+            })
+            .await
+            .unwrap();
+        assert_eq!(fetched[0].uid, Some(7));
+        assert_eq!(fetched[0].body(), Some(MESSAGE.as_bytes()));
+        drop(session);
+
+        // The second checkout reuses the authenticated session
+        let session = pool.checkout(1, connector(&server)).await.unwrap();
+        assert_eq!(server.connections(), 1);
+        // Another account gets its own connection
+        let other = pool.checkout(2, connector(&server)).await.unwrap();
+        assert_eq!(server.connections(), 2);
+        drop((session, other));
+
+        pool.discard(1);
+        drop(pool.checkout(1, connector(&server)).await.unwrap());
+        assert_eq!(server.connections(), 3);
+
+        pool.discard_all();
+        drop(pool.checkout(2, connector(&server)).await.unwrap());
+        assert_eq!(server.connections(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_lost_connections_are_replaced() {
+        let server = FakeServer::start();
+        // Every idle session is checked before it is handed out again
+        let pool = Arc::new(ImapPool::new(Duration::ZERO, MAX_IDLE));
+
+        let mut session = pool.checkout(1, connector(&server)).await.unwrap();
+        server.drop_connections();
+        assert!(session.run(|s| s.noop()).await.is_err());
+        assert!(session.is_broken());
+
+        session.reconnect().await.unwrap();
+        assert!(!session.is_broken());
+        session.run(|s| s.noop()).await.unwrap();
+        assert_eq!(server.connections(), 2);
+        drop(session);
+
+        // An idle session the server closed fails its health check and is replaced
+        server.drop_connections();
+        let mut session = pool.checkout(1, connector(&server)).await.unwrap();
+        session.run(|s| s.noop()).await.unwrap();
+        assert_eq!(server.connections(), 3);
+    }
+
+    #[test]
+    fn test_xoauth2_response() {
+        let authenticator = XOAuth2 {
+            user: "user@example.org",
+            access_token: "token",
+        };
+        assert_eq!(
+            authenticator.process(b""),
+            "user=user@example.org\x01auth=Bearer token\x01\x01"
+        );
+        // Error details of a rejected token get an empty answer
+        assert_eq!(authenticator.process(b"{\"status\":\"401\"}"), "");
+    }
+}
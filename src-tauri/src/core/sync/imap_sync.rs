@@ -1,129 +1,273 @@
-use crate::core::accounts::get_refresh_token;
 use crate::core::cache::db::{
     save_attachment, save_attachment_data, save_folder, save_message, update_folder_uid_validity, Pool,
 };
+use crate::core::cache::locations;
 use crate::core::contacts;
+use crate::core::crypto;
+use crate::core::providers::ProviderDefinition;
+use crate::core::rules::{self, Rule};
+use crate::core::sender_auth;
 use crate::core::threading;
+use crate::core::sync::imap_pool::{FetchedMessage, ImapSession, OAuth2Connector};
+use crate::core::sync::rule_actions::{RuleEnv, RuleRunner};
+use crate::core::sync::status::SYNC_PROGRESS_EVENT;
+use crate::core::sync::tag_sync;
+use crate::core::tags;
 use crate::error::DEmailError;
-use crate::models::{Attachment, Folder, Message};
-use imap::{
-    types::{Fetch, Uid},
-    Client, Session,
-};
+use crate::models::{Attachment, Folder, Message, SyncProgress};
 use mail_parser::Message as ParsedMessage;
-use native_tls::TlsStream;
-use oauth2::{
-    basic::BasicClient, reqwest::async_http_client, AccessToken, RefreshToken, TokenResponse,
-};
-use std::net::TcpStream;
-use std::sync::Arc;
+use oauth2::basic::BasicClient;
+use rusqlite::OptionalExtension;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use tauri::{AppHandle, Manager};
+use tracing::warn;
 
 use crate::AppState;
 
+/// What every folder of an account is synced with
+#[derive(Clone, Copy)]
+struct FolderSyncEnv<'a> {
+    account_id: i64,
+    /// Gmail's labels when the server has X-GM-LABELS
+    gmail_labels: Option<&'a [String]>,
+    /// Authentication-Results issuers of the account's provider
+    authserv_ids: &'a [String],
+    rules: &'a [Rule],
+    rule_env: &'a RuleEnv,
+}
+
 pub struct ImapSync {
     app_state: Arc<AppState>,
+    /// Receives a `sync-progress` event per folder when set
+    progress: Option<AppHandle>,
+    /// Messages that arrived during the sync, for notifications
+    arrivals: Mutex<Vec<i64>>,
 }
 
 impl ImapSync {
     pub fn new(app_state: Arc<AppState>) -> Self {
-        Self { app_state }
+        Self {
+            app_state,
+            progress: None,
+            arrivals: Mutex::new(Vec::new()),
+        }
     }
 
+    pub fn with_progress_events(mut self, app_handle: AppHandle) -> Self {
+        self.progress = Some(app_handle);
+        self
+    }
+
+    /// Takes the ids of the messages that arrived since the last call. Mail found on
+    /// the first pass over a folder and mail moved by rules does not count.
+    pub fn take_arrivals(&self) -> Vec<i64> {
+        std::mem::take(&mut *self.arrivals.lock().unwrap())
+    }
+
+    /// Syncs every folder, running the account's rules on new messages, and returns
+    /// the number of messages fetched
     pub async fn initial_sync(
         &self,
         account_id: i64,
-        provider: &str,
+        provider: &ProviderDefinition,
         client: &BasicClient,
         user_email: &str,
-    ) -> Result<(), DEmailError> {
-        let refresh_token = get_refresh_token(account_id)?;
-
-        let token_result = client
-            .exchange_refresh_token(&RefreshToken::new(refresh_token))
-            .request_async(async_http_client)
-            .await
-            .map_err(|e| DEmailError::OAuth(e.to_string()))?;
+    ) -> Result<usize, DEmailError> {
+        let access_token = self
+            .app_state
+            .token_manager
+            .access_token(&self.app_state.db_pool, account_id, client)
+            .await?;
 
-        let access_token = token_result.access_token();
+        let connector = OAuth2Connector::new(&provider.imap, user_email, access_token.secret());
+        let mut imap_session = self
+            .app_state
+            .imap_pool
+            .checkout(account_id, Arc::new(connector))
+            .await?;
 
-        let domain = match provider {
-            "google" => "imap.gmail.com",
-            "microsoft" => "outlook.office365.com",
-            _ => {
-                return Err(DEmailError::Imap(imap::Error::Parse(
-                    "Unsupported provider",
-                )))
-            }
+        let pool = &self.app_state.db_pool;
+        let rules = {
+            let conn = pool
+                .get()
+                .map_err(|_| DEmailError::Database(rusqlite::Error::InvalidQuery))?;
+            rules::rules_for_account(&conn, account_id)?
+        };
+        let rule_env = RuleEnv {
+            pool: pool.clone(),
+            smtp: provider.smtp.clone(),
+            user_email: user_email.to_string(),
+            access_token,
         };
 
-        let mut imap_session = self.connect(domain, user_email, access_token).await?;
+        let (folders, gmail_labels) = self.fetch_folders(&mut imap_session, account_id).await?;
+        let gmail_labels = gmail_labels.as_deref();
 
-        let folders = self.fetch_folders(&mut imap_session, account_id).await?;
+        let env = FolderSyncEnv {
+            account_id,
+            gmail_labels,
+            authserv_ids: &provider.authserv_ids,
+            rules: &rules,
+            rule_env: &rule_env,
+        };
 
-        for folder in folders {
-            self.sync_folder(&mut imap_session, account_id, &folder)
-                .await?;
+        let mut fetched = 0;
+        for (index, folder) in folders.iter().enumerate() {
+            fetched += match self.sync_folder(&mut imap_session, folder, env).await {
+                // A dropped connection is reopened once and the folder synced again
+                Err(e) if imap_session.is_broken() => {
+                    warn!("Reconnecting to sync {}: {}", folder.name, e);
+                    imap_session.reconnect().await?;
+                    self.sync_folder(&mut imap_session, folder, env).await?
+                }
+                result => result?,
+            };
+            if let Some(app_handle) = &self.progress {
+                let _ = app_handle.emit_all(
+                    SYNC_PROGRESS_EVENT,
+                    SyncProgress {
+                        account_id,
+                        folder: folder.name.clone(),
+                        folders_done: index + 1,
+                        folders_total: folders.len(),
+                    },
+                );
+            }
         }
 
-        imap_session.logout()?;
-
-        Ok(())
-    }
+        // Delayed rule actions that came due since the last sync
+        if let Err(e) = RuleRunner::new(&rule_env, &mut imap_session)
+            .run_pending(account_id)
+            .await
+        {
+            warn!("Delayed rule actions failed: {}", e);
+        }
 
-    async fn connect(
-        &self,
-        domain: &str,
-        user_email: &str,
-        access_token: &AccessToken,
-    ) -> Result<Session<TlsStream<TcpStream>>, DEmailError> {
-        let tls = native_tls::TlsConnector::builder().build()?;
-        let client = imap::connect((domain, 993), domain, &tls)?;
-        let session = client
-            .login_with_oauth2(user_email, access_token.secret())
-            .map_err(|(e, _)| e)?;
-        Ok(session)
+        // The session goes back to the pool for the next pass
+        Ok(fetched)
     }
 
+    /// Stores the account's folders and returns them. On Gmail, label folders are
+    /// returned separately: their messages are synced as tags on the inbox and system
+    /// folders instead of once more per label.
     async fn fetch_folders(
         &self,
-        session: &mut Session<TlsStream<TcpStream>>,
+        session: &mut ImapSession,
         account_id: i64,
-    ) -> Result<Vec<Folder>, DEmailError> {
-        let mailboxes = session.list(Some(""), Some("*"))?;
+    ) -> Result<(Vec<Folder>, Option<Vec<String>>), DEmailError> {
+        let names = session
+            .run(|s| {
+                let mailboxes = s.list(Some(""), Some("*"))?;
+                Ok(mailboxes
+                    .iter()
+                    .map(|mailbox| mailbox.name().to_string())
+                    .collect::<Vec<_>>())
+            })
+            .await?;
         let pool = &self.app_state.db_pool;
+        let gmail = tag_sync::is_gmail(session);
+        let (labels, names): (Vec<String>, Vec<String>) = names
+            .into_iter()
+            .partition(|name| gmail && tags::is_gmail_label_folder(name));
+        if gmail {
+            // Label folders stored before labels became tags
+            let conn = pool
+                .get()
+                .map_err(|_| DEmailError::Database(rusqlite::Error::InvalidQuery))?;
+            for label in &labels {
+                let folder_id: Option<i64> = conn
+                    .query_row(
+                        "SELECT id FROM folders WHERE account_id = ?1 AND path = ?2",
+                        rusqlite::params![account_id, label],
+                        |row| row.get(0),
+                    )
+                    .optional()?;
+                if let Some(folder_id) = folder_id {
+                    // Messages also held by other folders stay
+                    locations::clear_folder(&conn, folder_id)?;
+                    conn.execute("DELETE FROM folders WHERE id = ?1", [folder_id])?;
+                }
+            }
+        }
+
         let mut folders = Vec::new();
-        for mailbox in mailboxes.iter() {
+        for name in names {
             let mut folder = Folder {
                 id: 0, // Will be set by the database
                 account_id,
-                name: mailbox.name().to_string(),
-                path: mailbox.name().to_string(),
+                name: name.clone(),
+                path: name,
                 parent_id: None,
                 uid_validity: None, // Will be updated when folder is selected
             };
             save_folder(pool, &mut folder)?;
             folders.push(folder);
         }
-        Ok(folders)
+        Ok((folders, gmail.then_some(labels)))
     }
 
+    /// Fetches the messages of a folder that are not stored yet and runs the rules on
+    /// them, and updates the tags of the messages already stored
     async fn sync_folder(
         &self,
-        session: &mut Session<TlsStream<TcpStream>>,
-        account_id: i64,
+        session: &mut ImapSession,
         folder: &Folder,
-    ) -> Result<(), DEmailError> {
-        let mailbox = session.select(&folder.path)?;
+        env: FolderSyncEnv<'_>,
+    ) -> Result<usize, DEmailError> {
+        let FolderSyncEnv {
+            account_id,
+            gmail_labels,
+            authserv_ids,
+            rules,
+            rule_env,
+        } = env;
+        let path = folder.path.clone();
+        let (uid_validity, uids) = session
+            .run(move |s| {
+                let mailbox = s.select(&path)?;
+                // Messages flagged \Deleted only wait for an expunge, which never comes
+                // without UIDPLUS; fetching them again would store them as new mail
+                Ok((mailbox.uid_validity, s.uid_search("UNDELETED")?))
+            })
+            .await?;
 
         // Update folder with uid_validity from mailbox if needed
-        if folder.uid_validity.is_none() || folder.uid_validity != mailbox.uid_validity {
-            if let Err(e) = update_folder_uid_validity(&self.app_state.db_pool, folder.id, mailbox.uid_validity) {
+        if folder.uid_validity.is_none() || folder.uid_validity != uid_validity {
+            if let Err(e) = update_folder_uid_validity(&self.app_state.db_pool, folder.id, uid_validity) {
                 eprintln!("Warning: Failed to update folder uid_validity: {}", e);
             }
         }
-        let uids: Vec<Uid> = session.uid_search("ALL")?.into_iter().collect();
+        // Messages found on the first pass over a folder are its existing mail, not new
+        // arrivals, so rules leave them alone
+        let arriving = folder.uid_validity.is_some() && folder.uid_validity == uid_validity;
+        let pool = &self.app_state.db_pool;
+        let known: HashSet<u32> = {
+            let conn = pool
+                .get()
+                .map_err(|_| DEmailError::Database(rusqlite::Error::InvalidQuery))?;
+            // A new UIDVALIDITY means the stored UIDs no longer name the same messages
+            if folder.uid_validity.is_some() && folder.uid_validity != uid_validity {
+                locations::clear_folder(&conn, folder.id)?;
+            }
+            locations::folder_uids(&conn, folder.id)?
+        };
+        // Tags of every message here, including ones other clients changed since the
+        // last pass
+        let remote_tags = if uids.is_empty() {
+            HashMap::new()
+        } else {
+            tag_sync::remote_tags(session, gmail_labels).await?
+        };
+        {
+            let conn = pool
+                .get()
+                .map_err(|_| DEmailError::Database(rusqlite::Error::InvalidQuery))?;
+            tags::apply_remote_tags(&conn, folder.id, &remote_tags)?;
+        }
+        let uids: Vec<u32> = uids.into_iter().filter(|uid| !known.contains(uid)).collect();
         if uids.is_empty() {
-            return Ok(());
+            return Ok(0);
         }
 
         let seq_set = uids
@@ -131,15 +275,51 @@ impl ImapSync {
             .map(|uid| uid.to_string())
             .collect::<Vec<_>>()
             .join(",");
-        let fetch = session.uid_fetch(seq_set, "(RFC822)")?;
-        let pool = &self.app_state.db_pool;
+        let gmail_message_ids = if tag_sync::is_gmail(session) {
+            tag_sync::gmail_message_ids(session, &seq_set).await?
+        } else {
+            HashMap::new()
+        };
+        // Fetching RFC822 marks messages as read on the server; IMAP4rev1 servers can
+        // return the same content without touching the flags
+        let query = if session.has_capability("IMAP4rev1") {
+            "(BODY.PEEK[])"
+        } else {
+            "(RFC822)"
+        };
+        let fetch = session
+            .run(move |s| {
+                let fetches = s.uid_fetch(seq_set, query)?;
+                Ok(fetches.iter().map(FetchedMessage::from).collect::<Vec<_>>())
+            })
+            .await?;
         let conn = pool
             .get()
             .map_err(|_| DEmailError::Database(rusqlite::Error::InvalidQuery))?;
 
+        let mut new_messages = Vec::new();
         for msg in fetch.iter() {
             if let Some(body) = msg.body() {
                 if let Ok(parsed_message) = ParsedMessage::parse(body) {
+                    // A message already stored from another folder is only mapped here
+                    let hash = locations::content_hash(body);
+                    let gm_msgid = msg
+                        .uid
+                        .and_then(|uid| gmail_message_ids.get(&uid))
+                        .map(String::as_str);
+                    let message_id_header = parsed_message.message_id().unwrap_or_default();
+                    if let Some(existing) = locations::find_copy(
+                        &conn,
+                        account_id,
+                        gm_msgid,
+                        message_id_header,
+                        &hash,
+                    )? {
+                        locations::add_location(&conn, existing, folder.id, msg.uid.unwrap_or(0))?;
+                        rules::take_move(&conn, account_id, &folder.path, message_id_header)?;
+                        continue;
+                    }
+
                     let from_str = parsed_message
                         .from()
                         .map(|f| f.to_string())
@@ -171,11 +351,63 @@ impl ImapSync {
                         is_starred: false,
                         thread_id: None,
                         attachments: Vec::new(),
+                        security: None,
+                        authentication: None,
+                        remote_content: None,
                     };
-                    save_message(pool, &message)?;
 
-                    let message_id = conn.last_insert_rowid();
+                    // OpenPGP: verify signatures and decrypt with the local keyring. Decrypted
+                    // text only replaces the stored bodies when the user opted in to caching it.
+                    let incoming = crypto::process_incoming(&conn, body, &parsed_message);
+                    if let Some(incoming) = &incoming {
+                        let has_bodies =
+                            incoming.body_plain.is_some() || incoming.body_html.is_some();
+                        let cache = !incoming.security.is_encrypted
+                            || crypto::cache_decrypted_bodies(&conn);
+                        if has_bodies && cache {
+                            message.body_plain = incoming.body_plain.clone();
+                            message.body_html = incoming.body_html.clone();
+                        }
+                    }
+
+                    let message_id = save_message(pool, &message)?;
                     message.id = message_id;
+                    locations::set_content_hash(&conn, message_id, &hash)?;
+                    if let Some(gm_msgid) = gm_msgid {
+                        locations::set_gmail_message_id(&conn, message_id, gm_msgid)?;
+                    }
+                    rules::save_message_headers(
+                        &conn,
+                        message_id,
+                        &rules::header_block(body),
+                        body.len() as i64,
+                    )?;
+                    if let Some(message_tags) = remote_tags.get(&message.imap_uid) {
+                        tags::set_tags(&conn, message_id, message_tags)?;
+                    }
+                    // Messages a rule moved here keep their tags and skip the rules
+                    match rules::take_move(
+                        &conn,
+                        account_id,
+                        &folder.path,
+                        &message.message_id_header,
+                    )? {
+                        Some(moved_tags) => {
+                            for tag in moved_tags {
+                                tags::add_tag(&conn, message_id, &tag)?;
+                            }
+                        }
+                        None => new_messages.push(message_id),
+                    }
+
+                    if let Some(incoming) = incoming {
+                        crypto::save_message_security(&conn, message_id, &incoming.security)?;
+                    }
+
+                    // Sender authentication: DKIM/SPF/DMARC results and phishing checks
+                    let authentication =
+                        sender_auth::analyze_message(&conn, body, &parsed_message, authserv_ids);
+                    sender_auth::save_message_authentication(&conn, message_id, &authentication)?;
 
                     // Threading integration: organize messages into conversation threads
                     if let Ok(_thread_id) = threading::create_or_update_thread(&conn, &message) {
@@ -212,8 +444,7 @@ impl ImapSync {
                             local_path: None,
                         };
 
-                        save_attachment(pool, &att)?;
-                        let attachment_id = conn.last_insert_rowid();
+                        let attachment_id = save_attachment(pool, &att)?;
                         att.id = attachment_id;
 
                         save_attachment_data(pool, attachment_id, content)?;
@@ -222,19 +453,188 @@ impl ImapSync {
             }
         }
 
-        Ok(())
+        drop(conn);
+
+        if arriving && !rules.is_empty() {
+            let mut runner = RuleRunner::new(rule_env, session).with_selected(&folder.path);
+            for &message_id in &new_messages {
+                let message = {
+                    let conn = pool
+                        .get()
+                        .map_err(|_| DEmailError::Database(rusqlite::Error::InvalidQuery))?;
+                    rules::get_stored_message(&conn, message_id)?
+                };
+                let Some(message) = message else {
+                    continue;
+                };
+                if let Err(e) = runner.apply(rules, &message).await {
+                    warn!("Rules on message {} failed: {}", message_id, e);
+                    if runner.is_broken() {
+                        break;
+                    }
+                }
+            }
+        }
+
+        if arriving {
+            self.arrivals.lock().unwrap().extend(new_messages);
+        }
+
+        Ok(fetch.len())
     }
 }
 
-pub async fn sync_account_manually(
-    app_state: Arc<AppState>,
-    account_id: i64,
-    provider: &str,
-    client: &BasicClient,
-    user_email: &str,
-) -> Result<(), DEmailError> {
-    let syncer = ImapSync::new(app_state);
-    syncer
-        .initial_sync(account_id, provider, client, user_email)
-        .await
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::accounts::create_account;
+    use crate::core::app_lock::AppLock;
+    use crate::core::cache::db::{test_folder, test_pool};
+    use crate::core::providers::{Security, ServerEndpoint};
+    use crate::core::rules::{Condition, RuleAction, TextMatch, TextOperator};
+    use crate::core::sync::draft_sync::{self, DraftSyncState};
+    use crate::core::sync::fake_imap::{connector, FakeServer, MESSAGE};
+    use crate::core::sync::imap_pool::ImapPool;
+    use crate::core::sync::scheduler::SyncScheduler;
+    use crate::core::tokens::TokenManager;
+    use oauth2::AccessToken;
+
+    fn app_state(pool: &Pool) -> Arc<AppState> {
+        Arc::new(AppState {
+            db_pool: Arc::new(pool.clone()),
+            app_config: Arc::new(std::sync::Mutex::new(tauri::Config::default())),
+            app_lock: Arc::new(AppLock::default()),
+            token_manager: Arc::new(TokenManager::default()),
+            sync_scheduler: Arc::new(SyncScheduler::default()),
+            imap_pool: Arc::new(ImapPool::default()),
+            draft_sync: Arc::new(DraftSyncState::default()),
+        })
+    }
+
+    /// A folder synced before, so new messages in it count as arrivals
+    fn synced_folder(pool: &Pool, account_id: i64, path: &str) -> Folder {
+        let mut folder = test_folder(pool, account_id, path);
+        folder.uid_validity = Some(42);
+        update_folder_uid_validity(pool, folder.id, folder.uid_validity).unwrap();
+        folder
+    }
+
+    fn rule_env(pool: &Pool) -> RuleEnv {
+        RuleEnv {
+            pool: Arc::new(pool.clone()),
+            smtp: ServerEndpoint {
+                host: "127.0.0.1".to_string(),
+                port: 587,
+                security: Security::StartTls,
+            },
+            user_email: "me@example.org".to_string(),
+            access_token: AccessToken::new("token".to_string()),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rules_do_not_run_again_on_messages_awaiting_expunge() {
+        // Without UIDPLUS a deleted message is only flagged \Deleted
+        let server = FakeServer::start_with("IMAP4rev1", &[("INBOX", &[(7, MESSAGE)])]);
+        let pool = test_pool();
+        let account = create_account(&pool, "me@example.org", "Me", "google").unwrap();
+        let folder = synced_folder(&pool, account.id, "INBOX");
+        let rules = vec![Rule {
+            id: 1,
+            account_id: None,
+            name: "Delete".to_string(),
+            enabled: true,
+            position: 0,
+            match_all: true,
+            stop_processing: false,
+            conditions: vec![Condition::From(TextMatch {
+                operator: TextOperator::Contains,
+                value: "a@example.org".to_string(),
+            })],
+            actions: vec![RuleAction::Delete],
+        }];
+        let rule_env = rule_env(&pool);
+        let env = || FolderSyncEnv {
+            account_id: account.id,
+            gmail_labels: None,
+            authserv_ids: &[],
+            rules: &rules,
+            rule_env: &rule_env,
+        };
+
+        let sync = ImapSync::new(app_state(&pool));
+        let imap_pool = Arc::new(ImapPool::default());
+        let mut session = imap_pool
+            .checkout(account.id, connector(&server))
+            .await
+            .unwrap();
+        let fetched = sync.sync_folder(&mut session, &folder, env()).await;
+        assert_eq!(fetched.unwrap(), 1);
+        assert_eq!(server.uids("INBOX"), vec![7]);
+        assert_eq!(server.flags("INBOX", 7), vec!["\\Deleted"]);
+        assert_eq!(server.count("UID STORE"), 1);
+
+        // The next pass neither fetches the message again nor runs the rule on it
+        let fetched = sync.sync_folder(&mut session, &folder, env()).await;
+        assert_eq!(fetched.unwrap(), 0);
+        assert_eq!(server.count("UID STORE"), 1);
+        let conn = pool.get().unwrap();
+        let remaining: i64 = conn
+            .query_row("SELECT COUNT(*) FROM message_locations", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(remaining, 0);
+    }
+
+    #[tokio::test]
+    async fn test_replaced_drafts_are_not_synced_as_messages() {
+        // Without UIDPLUS a replaced draft revision is only flagged \\Deleted
+        let server = FakeServer::start_with("IMAP4rev1", &[("Drafts", &[])]);
+        let pool = test_pool();
+        let account = create_account(&pool, "me@example.org", "Me", "google").unwrap();
+        let folder = synced_folder(&pool, account.id, "Drafts");
+        let rule_env = rule_env(&pool);
+        let env = FolderSyncEnv {
+            account_id: account.id,
+            gmail_labels: None,
+            authserv_ids: &[],
+            rules: &[],
+            rule_env: &rule_env,
+        };
+
+        let imap_pool = Arc::new(ImapPool::default());
+        let mut session = imap_pool
+            .checkout(account.id, connector(&server))
+            .await
+            .unwrap();
+        session
+            .run(|s| s.select("Drafts").map(|_| ()))
+            .await
+            .unwrap();
+        let message_id = "<draft@example.org>";
+        let draft = |text: &str| {
+            format!(
+                "Message-ID: {}\r\nFrom: me@example.org\r\nSubject: Plans\r\n\r\n{}\r\n",
+                message_id, text
+            )
+            .into_bytes()
+        };
+        let first = draft_sync::upload(&mut session, "Drafts", draft("First"), message_id, None)
+            .await
+            .unwrap();
+        let second = draft_sync::upload(&mut session, "Drafts", draft("Second"), message_id, first)
+            .await
+            .unwrap();
+        assert_eq!((first, second), (Some(1), Some(2)));
+        assert_eq!(server.uids("Drafts"), vec![1, 2]);
+
+        // Only the current revision is stored as a message of the Drafts folder
+        let sync = ImapSync::new(app_state(&pool));
+        let fetched = sync.sync_folder(&mut session, &folder, env).await;
+        assert_eq!(fetched.unwrap(), 1);
+        let conn = pool.get().unwrap();
+        let uids = locations::folder_uids(&conn, folder.id).unwrap();
+        assert_eq!(uids, HashSet::from([2]));
+    }
 }
@@ -1,3 +1,12 @@
 pub mod background_sync;
+pub mod draft_sync;
+#[cfg(test)]
+pub mod fake_imap;
+pub mod imap_pool;
 pub mod imap_sync;
+pub mod reminders;
+pub mod rule_actions;
+pub mod scheduler;
 pub mod smtp_send;
+pub mod status;
+pub mod tag_sync;
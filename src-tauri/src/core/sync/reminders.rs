@@ -0,0 +1,174 @@
+// Reminders - Snoozes messages on the server and brings them back when due, and raises
+// follow-up reminders nobody replied to. Runs next to the background sync.
+//
+// A snoozed message stays in its folder on the server, marked with the `$Snoozed`
+// keyword (the "Snoozed" label on Gmail), so other clients still show it. Locally it is
+// hidden from folder listings until due.
+
+use crate::core::snooze::{self, Reminder, ReminderKind, SNOOZE_KEYWORD, SNOOZE_LABEL};
+use crate::core::sync::tag_sync::{account_session, is_gmail, push_tag};
+use crate::core::tags;
+use crate::error::DEmailError;
+use crate::AppState;
+use std::time::Duration;
+use tauri::{AppHandle, Manager};
+
+/// Emitted with the `Reminder` when a snooze ends or a follow-up is due
+pub const REMINDER_DUE_EVENT: &str = "reminder-due";
+
+/// Longest sleep, so reminders added in the meantime are not missed by much
+const POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+pub fn start_reminders(app_handle: AppHandle) {
+    tokio::spawn(async move {
+        loop {
+            let wait = match run_due(&app_handle).await {
+                Ok(Some(next_due)) => {
+                    let secs = (next_due - chrono::Utc::now().timestamp()).max(1) as u64;
+                    POLL_INTERVAL.min(Duration::from_secs(secs))
+                }
+                Ok(None) => POLL_INTERVAL,
+                Err(e) => {
+                    // The database stays locked until the user unlocks the app
+                    log::debug!("Skipped due reminders: {}", e);
+                    POLL_INTERVAL
+                }
+            };
+            tokio::time::sleep(wait).await;
+        }
+    });
+}
+
+/// Handles the reminders that are due and returns when the next one is
+async fn run_due(app_handle: &AppHandle) -> Result<Option<i64>, DEmailError> {
+    let app_state = app_handle.state::<AppState>();
+    let now = chrono::Utc::now().timestamp();
+    let due = {
+        let conn = app_state
+            .db_pool
+            .get()
+            .map_err(|_| DEmailError::Database(rusqlite::Error::InvalidQuery))?;
+        snooze::due_reminders(&conn, now)?
+    };
+
+    for reminder in due {
+        let raise = {
+            let conn = app_state
+                .db_pool
+                .get()
+                .map_err(|_| DEmailError::Database(rusqlite::Error::InvalidQuery))?;
+            snooze::remove_reminder(&conn, reminder.id)?;
+            match reminder.kind {
+                ReminderKind::Snooze => {
+                    snooze::resurface(&conn, reminder.message_id, now)?;
+                    true
+                }
+                ReminderKind::FollowUp => !snooze::has_reply(&conn, reminder.message_id)?,
+            }
+        };
+        if reminder.kind == ReminderKind::Snooze {
+            // The message is back locally either way; the marker can be cleared by hand
+            if let Err(e) = mark_snoozed(&app_state, reminder.message_id, false, true).await {
+                log::warn!(
+                    "Could not unsnooze message {} on the server: {}",
+                    reminder.message_id,
+                    e
+                );
+            }
+        }
+        if raise {
+            let _ = app_handle.emit_all(REMINDER_DUE_EVENT, &reminder);
+        }
+    }
+
+    let conn = app_state
+        .db_pool
+        .get()
+        .map_err(|_| DEmailError::Database(rusqlite::Error::InvalidQuery))?;
+    snooze::next_due_at(&conn)
+}
+
+/// Snoozes a message until `until`, marking it on the server first
+pub async fn snooze_message(
+    app_state: &AppState,
+    message_id: i64,
+    until: i64,
+) -> Result<Reminder, DEmailError> {
+    if until <= chrono::Utc::now().timestamp() {
+        return Err(DEmailError::Validation(
+            "Pick a time in the future".to_string(),
+        ));
+    }
+    mark_snoozed(app_state, message_id, true, false).await?;
+    let conn = app_state
+        .db_pool
+        .get()
+        .map_err(|_| DEmailError::Database(rusqlite::Error::InvalidQuery))?;
+    snooze::add_reminder(&conn, message_id, ReminderKind::Snooze, until)
+}
+
+/// Cancels a reminder. A cancelled snooze brings the message back as it was.
+pub async fn cancel_reminder(app_state: &AppState, reminder_id: i64) -> Result<(), DEmailError> {
+    let reminder = {
+        let conn = app_state
+            .db_pool
+            .get()
+            .map_err(|_| DEmailError::Database(rusqlite::Error::InvalidQuery))?;
+        snooze::get_reminder(&conn, reminder_id)?
+    }
+    .ok_or_else(|| DEmailError::NotFound(format!("Reminder {} not found", reminder_id)))?;
+
+    if reminder.kind == ReminderKind::Snooze {
+        mark_snoozed(app_state, reminder.message_id, false, false).await?;
+    }
+    let conn = app_state
+        .db_pool
+        .get()
+        .map_err(|_| DEmailError::Database(rusqlite::Error::InvalidQuery))?;
+    snooze::remove_reminder(&conn, reminder_id)
+}
+
+/// Sets or clears the snooze marker on the server. `unread` also clears `\Seen`, so a
+/// message coming back shows as new in other clients too.
+async fn mark_snoozed(
+    app_state: &AppState,
+    message_id: i64,
+    snoozed: bool,
+    unread: bool,
+) -> Result<(), DEmailError> {
+    let message = {
+        let conn = app_state
+            .db_pool
+            .get()
+            .map_err(|_| DEmailError::Database(rusqlite::Error::InvalidQuery))?;
+        tags::remote_message(&conn, message_id)?
+    }
+    .ok_or_else(|| DEmailError::NotFound(format!("Message {} not found", message_id)))?;
+    let mut session = account_session(app_state, message.account_id).await?;
+
+    let gmail = is_gmail(&session);
+    let marker = if gmail { SNOOZE_LABEL } else { SNOOZE_KEYWORD };
+    let folder = message.folder_path.clone();
+    session.run(move |s| s.select(&folder).map(|_| ())).await?;
+    push_tag(&mut session, &message, marker, snoozed).await?;
+    if unread {
+        let uid = message.imap_uid.to_string();
+        session
+            .run(move |s| s.uid_store(&uid, "-FLAGS (\\Seen)").map(|_| ()))
+            .await?;
+    }
+
+    if gmail {
+        // The label is a tag like any other on Gmail
+        let conn = app_state
+            .db_pool
+            .get()
+            .map_err(|_| DEmailError::Database(rusqlite::Error::InvalidQuery))?;
+        if snoozed {
+            tags::add_tag(&conn, message_id, SNOOZE_LABEL)?;
+        } else {
+            tags::remove_tag(&conn, message_id, SNOOZE_LABEL)?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,601 @@
+// Rule actions - Carries out the actions of matching rules: moves, flags, tags and
+// deletions on the IMAP server, delayed actions locally, and forwards over SMTP.
+
+use crate::core::cache::db::{ConnectionManager, Pool};
+use crate::core::cache::locations;
+use crate::core::contacts::parse_email_address;
+use crate::core::providers::{client_for_provider, ServerEndpoint};
+use crate::core::rules::{self, Rule, RuleAction, StoredMessage};
+use crate::core::sync::imap_pool::{FetchedMessage, ImapSession, OAuth2Connector, PooledSession};
+use crate::core::sync::{smtp_send, tag_sync};
+use crate::core::tags::{self, RemoteMessage};
+use crate::error::DEmailError;
+use crate::AppState;
+use lettre::message::header::{
+    ContentDisposition, ContentTransferEncoding, ContentType, HeaderName, HeaderValue,
+};
+use lettre::message::{Body, MultiPart, SinglePart};
+use oauth2::AccessToken;
+use rusqlite::{Connection, OptionalExtension};
+use std::sync::Arc;
+use tracing::{info, warn};
+
+/// What rule actions need besides the IMAP session
+pub struct RuleEnv {
+    pub pool: Arc<Pool>,
+    pub smtp: ServerEndpoint,
+    pub user_email: String,
+    pub access_token: AccessToken,
+}
+
+pub struct RuleRunner<'a> {
+    env: &'a RuleEnv,
+    session: &'a mut ImapSession,
+    /// Folder currently selected on the session, if known
+    selected: Option<String>,
+}
+
+impl<'a> RuleRunner<'a> {
+    pub fn new(env: &'a RuleEnv, session: &'a mut ImapSession) -> Self {
+        Self {
+            env,
+            session,
+            selected: None,
+        }
+    }
+
+    /// Skips selecting `folder` again when the caller already has it selected
+    pub fn with_selected(mut self, folder: &str) -> Self {
+        self.selected = Some(folder.to_string());
+        self
+    }
+
+    /// Whether the IMAP connection dropped while running an action
+    pub fn is_broken(&self) -> bool {
+        self.session.is_broken()
+    }
+
+    /// Runs the actions of the rules matching a message. Returns whether any rule
+    /// matched.
+    pub async fn apply(
+        &mut self,
+        rules: &[Rule],
+        message: &StoredMessage,
+    ) -> Result<bool, DEmailError> {
+        let actions = rules::matching_actions(rules, &message.facts);
+        if actions.is_empty() {
+            return Ok(false);
+        }
+
+        // A move or delete takes the message out of the folder, so it runs last and
+        // only the first one counts
+        let (removals, others): (Vec<_>, Vec<_>) = actions
+            .into_iter()
+            .partition(|action| matches!(action, RuleAction::Move { .. } | RuleAction::Delete));
+        for action in others.iter().chain(removals.first()) {
+            self.run(message, action).await?;
+        }
+        Ok(true)
+    }
+
+    /// Runs the delayed actions of an account that are due. Returns how many ran.
+    pub async fn run_pending(&mut self, account_id: i64) -> Result<usize, DEmailError> {
+        let due = {
+            let conn = self.conn()?;
+            rules::due_actions(&conn, account_id, chrono::Utc::now().timestamp())?
+        };
+
+        let mut ran = 0;
+        for (pending_id, message_id, action) in due {
+            let message = {
+                let conn = self.conn()?;
+                rules::remove_pending_action(&conn, pending_id)?;
+                rules::get_stored_message(&conn, message_id)?
+            };
+            let Some(message) = message else {
+                continue;
+            };
+            match self.run(&message, &action).await {
+                Ok(()) => ran += 1,
+                Err(e) if self.is_broken() => return Err(e),
+                Err(e) => warn!(
+                    "Delayed rule action on message {} failed: {}",
+                    message_id, e
+                ),
+            }
+        }
+        Ok(ran)
+    }
+
+    async fn run(
+        &mut self,
+        message: &StoredMessage,
+        action: &RuleAction,
+    ) -> Result<(), DEmailError> {
+        match action {
+            RuleAction::Move { folder } => self.move_message(message, folder).await,
+            RuleAction::Star => {
+                self.store(message, "+FLAGS (\\Flagged)").await?;
+                self.conn()?.execute(
+                    "UPDATE messages SET is_starred = 1 WHERE id = ?1",
+                    [message.id],
+                )?;
+                Ok(())
+            }
+            RuleAction::MarkRead => {
+                self.store(message, "+FLAGS (\\Seen)").await?;
+                self.conn()?.execute(
+                    "UPDATE messages SET is_read = 1 WHERE id = ?1",
+                    [message.id],
+                )?;
+                Ok(())
+            }
+            RuleAction::Delete => {
+                self.store(message, "+FLAGS (\\Deleted)").await?;
+                self.expunge(message.imap_uid).await?;
+                let conn = self.conn()?;
+                locations::remove_location(&conn, message.id, message.folder_id, message.imap_uid)
+            }
+            RuleAction::Label { label } => {
+                let label = tags::validate_tag(label, tag_sync::is_gmail(self.session))?;
+                self.select(&message.folder_path).await?;
+                let remote = RemoteMessage {
+                    account_id: message.account_id,
+                    folder_path: message.folder_path.clone(),
+                    imap_uid: message.imap_uid,
+                    message_id_header: message.message_id_header.clone(),
+                };
+                tag_sync::push_tag(self.session, &remote, &label, true).await?;
+                let conn = self.conn()?;
+                tags::add_tag(&conn, message.id, &label)
+            }
+            RuleAction::Forward { to } => self.forward(message, to).await,
+            RuleAction::RunLater {
+                delay_minutes,
+                action,
+            } => {
+                let conn = self.conn()?;
+                let run_at = chrono::Utc::now().timestamp() + delay_minutes * 60;
+                rules::schedule_action(&conn, message.id, action, run_at)
+            }
+        }
+    }
+
+    async fn move_message(
+        &mut self,
+        message: &StoredMessage,
+        target: &str,
+    ) -> Result<(), DEmailError> {
+        if message.folder_path == target {
+            return Ok(());
+        }
+        self.select(&message.folder_path).await?;
+
+        let uid = message.imap_uid.to_string();
+        let target_path = target.to_string();
+        if self.session.has_capability("MOVE") {
+            self.session
+                .run(move |s| s.uid_mv(&uid, &target_path))
+                .await?;
+        } else {
+            self.session
+                .run(move |s| s.uid_copy(&uid, &target_path))
+                .await?;
+            self.store(message, "+FLAGS (\\Deleted)").await?;
+            self.expunge(message.imap_uid).await?;
+        }
+
+        // The copy in the target folder is picked up when that folder syncs
+        let conn = self.conn()?;
+        rules::record_move(&conn, message, target)?;
+        locations::remove_location(&conn, message.id, message.folder_id, message.imap_uid)
+    }
+
+    /// Forwards the original message as an attachment. Messages sent automatically or
+    /// from one of the account's own addresses are skipped, so two accounts forwarding
+    /// to each other cannot loop.
+    async fn forward(&mut self, message: &StoredMessage, to: &str) -> Result<(), DEmailError> {
+        let from_self = {
+            let conn = self.conn()?;
+            is_own_address(
+                &conn,
+                message.account_id,
+                &self.env.user_email,
+                &message.facts.from,
+            )?
+        };
+        if from_self {
+            info!(
+                "Not forwarding message {} sent from this account",
+                message.id
+            );
+            return Ok(());
+        }
+
+        self.select(&message.folder_path).await?;
+        let uid = message.imap_uid;
+        // BODY.PEEK leaves \Seen alone where the server supports it
+        let query = if self.session.has_capability("IMAP4rev1") {
+            "(BODY.PEEK[])"
+        } else {
+            "(RFC822)"
+        };
+        let raw = self
+            .session
+            .run(move |s| {
+                let fetches = s.uid_fetch(uid.to_string(), query)?;
+                Ok(fetches.iter().map(FetchedMessage::from).next())
+            })
+            .await?
+            .and_then(|fetched| fetched.body().map(<[u8]>::to_vec))
+            .ok_or_else(|| {
+                DEmailError::NotFound(format!("Message {} not found on the server", message.id))
+            })?;
+
+        if is_auto_submitted(&raw) {
+            info!("Not forwarding automatically sent message {}", message.id);
+            return Ok(());
+        }
+        forward(self.env, message, to, raw).await
+    }
+
+    async fn store(
+        &mut self,
+        message: &StoredMessage,
+        flags: &'static str,
+    ) -> Result<(), DEmailError> {
+        self.select(&message.folder_path).await?;
+        let uid = message.imap_uid.to_string();
+        self.session
+            .run(move |s| s.uid_store(&uid, flags).map(|_| ()))
+            .await
+    }
+
+    /// Expunges the message. Without UIDPLUS a plain EXPUNGE would also remove messages
+    /// other clients marked \Deleted, so the message is only left flagged then.
+    async fn expunge(&mut self, uid: u32) -> Result<(), DEmailError> {
+        if !self.session.has_capability("UIDPLUS") {
+            return Ok(());
+        }
+        self.session
+            .run(move |s| s.uid_expunge(uid.to_string()).map(|_| ()))
+            .await
+    }
+
+    async fn select(&mut self, folder: &str) -> Result<(), DEmailError> {
+        if self.selected.as_deref() == Some(folder) {
+            return Ok(());
+        }
+        let path = folder.to_string();
+        self.session
+            .run(move |s| s.select(&path).map(|_| ()))
+            .await?;
+        self.selected = Some(folder.to_string());
+        Ok(())
+    }
+
+    fn conn(&self) -> Result<r2d2::PooledConnection<ConnectionManager>, DEmailError> {
+        self.env
+            .pool
+            .get()
+            .map_err(|_| DEmailError::Database(rusqlite::Error::InvalidQuery))
+    }
+}
+
+/// Runs the account's enabled rules, or the given rule, over the messages stored in a
+/// folder. Returns how many messages matched.
+pub async fn run_on_folder(
+    app_state: &AppState,
+    folder_id: i64,
+    rule_id: Option<i64>,
+) -> Result<usize, DEmailError> {
+    let pool = &app_state.db_pool;
+    let (account_id, email_address, provider_type) = {
+        let conn = pool
+            .get()
+            .map_err(|_| DEmailError::Database(rusqlite::Error::InvalidQuery))?;
+        conn.query_row(
+            "SELECT a.id, a.email_address, a.provider_type
+             FROM folders f JOIN accounts a ON a.id = f.account_id
+             WHERE f.id = ?1",
+            [folder_id],
+            |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                ))
+            },
+        )
+        .optional()?
+        .ok_or_else(|| DEmailError::NotFound(format!("Folder {} not found", folder_id)))?
+    };
+
+    let mut rules = rules::list_rules(pool, Some(account_id))?;
+    match rule_id {
+        // A rule picked explicitly runs even while it is disabled
+        Some(rule_id) => {
+            rules.retain(|rule| rule.id == rule_id);
+            let rule = rules
+                .first_mut()
+                .ok_or_else(|| DEmailError::NotFound(format!("Rule {} not found", rule_id)))?;
+            rule.enabled = true;
+        }
+        None => rules.retain(|rule| rule.enabled),
+    }
+    let messages = rules::folder_messages(pool, folder_id)?;
+    if rules.is_empty() || messages.is_empty() {
+        return Ok(0);
+    }
+
+    let (env, mut session) = connect(app_state, account_id, email_address, &provider_type).await?;
+    let mut runner = RuleRunner::new(&env, &mut session);
+    let mut matched = 0;
+    for message in messages {
+        if runner.apply(&rules, &message).await? {
+            matched += 1;
+        }
+    }
+    Ok(matched)
+}
+
+/// Runs one action on stored messages of an account, outside any rule, as for the
+/// actions offered with a notification
+pub async fn run_on_messages(
+    app_state: &AppState,
+    account_id: i64,
+    message_ids: &[i64],
+    action: &RuleAction,
+) -> Result<(), DEmailError> {
+    let pool = &app_state.db_pool;
+    let (email_address, provider_type, messages) = {
+        let conn = pool
+            .get()
+            .map_err(|_| DEmailError::Database(rusqlite::Error::InvalidQuery))?;
+        let (email_address, provider_type) = conn
+            .query_row(
+                "SELECT email_address, provider_type FROM accounts WHERE id = ?1",
+                [account_id],
+                |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)),
+            )
+            .optional()?
+            .ok_or_else(|| DEmailError::NotFound(format!("Account {} not found", account_id)))?;
+        let mut messages = Vec::new();
+        for &message_id in message_ids {
+            match rules::get_stored_message(&conn, message_id)? {
+                Some(message) if message.account_id == account_id => messages.push(message),
+                _ => {
+                    return Err(DEmailError::NotFound(format!(
+                        "Message {} not found",
+                        message_id
+                    )))
+                }
+            }
+        }
+        (email_address, provider_type, messages)
+    };
+    if messages.is_empty() {
+        return Ok(());
+    }
+
+    let (env, mut session) = connect(app_state, account_id, email_address, &provider_type).await?;
+    let mut runner = RuleRunner::new(&env, &mut session);
+    for message in messages {
+        runner.run(&message, action).await?;
+    }
+    Ok(())
+}
+
+/// Checks out an IMAP session for an account, with what rule actions need besides
+async fn connect(
+    app_state: &AppState,
+    account_id: i64,
+    email_address: String,
+    provider_type: &str,
+) -> Result<(RuleEnv, PooledSession), DEmailError> {
+    let pool = &app_state.db_pool;
+    let config = app_state.app_config.lock().unwrap().clone();
+    let (provider, client) = client_for_provider(&config, provider_type)?;
+    let access_token = app_state
+        .token_manager
+        .access_token(pool, account_id, &client)
+        .await?;
+    let connector = OAuth2Connector::new(&provider.imap, &email_address, access_token.secret());
+    let session = app_state
+        .imap_pool
+        .checkout(account_id, Arc::new(connector))
+        .await?;
+    let env = RuleEnv {
+        pool: pool.clone(),
+        smtp: provider.smtp,
+        user_email: email_address,
+        access_token,
+    };
+    Ok((env, session))
+}
+
+/// Whether `from` is the account's address or one of its identities
+fn is_own_address(
+    conn: &Connection,
+    account_id: i64,
+    account_email: &str,
+    from: &str,
+) -> Result<bool, DEmailError> {
+    let Some((address, _)) = parse_email_address(from) else {
+        return Ok(false);
+    };
+    if address.eq_ignore_ascii_case(account_email) {
+        return Ok(true);
+    }
+    Ok(conn
+        .query_row(
+            "SELECT 1 FROM identities WHERE account_id = ?1 AND email_address = ?2 COLLATE NOCASE",
+            rusqlite::params![account_id, address],
+            |_| Ok(()),
+        )
+        .optional()?
+        .is_some())
+}
+
+/// Whether the message says it was sent automatically (RFC 3834), e.g. an auto-reply or
+/// a message another rule already forwarded
+fn is_auto_submitted(raw: &[u8]) -> bool {
+    rules::header_values(&rules::header_block(raw), "Auto-Submitted")
+        .iter()
+        .any(|value| {
+            let keyword = value.split(';').next().unwrap_or_default().trim();
+            !keyword.is_empty() && !keyword.eq_ignore_ascii_case("no")
+        })
+}
+
+/// Forwards the message from the account's address with the original attached as
+/// message/rfc822, marked `Auto-Submitted: auto-forwarded`
+async fn forward(
+    env: &RuleEnv,
+    message: &StoredMessage,
+    to: &str,
+    raw: Vec<u8>,
+) -> Result<(), DEmailError> {
+    let email = forward_message(env, message, to, raw)?;
+    smtp_send::send_email(email, &env.smtp, &env.user_email, &env.access_token).await
+}
+
+fn forward_message(
+    env: &RuleEnv,
+    message: &StoredMessage,
+    to: &str,
+    raw: Vec<u8>,
+) -> Result<lettre::Message, DEmailError> {
+    let facts = &message.facts;
+    let subject = if facts.subject.starts_with("Fwd: ") {
+        facts.subject.clone()
+    } else {
+        format!("Fwd: {}", facts.subject)
+    };
+    let date = chrono::DateTime::from_timestamp(message.date, 0)
+        .map(|dt| dt.format("%Y-%m-%d %H:%M:%S").to_string())
+        .unwrap_or_else(|| "Unknown date".to_string());
+    let note = format!(
+        "---------- Forwarded message ----------\nFrom: {}\nDate: {}\nSubject: {}\nTo: {}\n\nThe original message is attached.\n",
+        facts.from, date, facts.subject, facts.to
+    );
+
+    // message/rfc822 parts may only use 7bit, 8bit or binary (RFC 2046 5.2.1); base64
+    // is the fallback for originals that do not fit 8bit
+    let original =
+        Body::new_with_encoding(raw, ContentTransferEncoding::EightBit).unwrap_or_else(Body::new);
+    let attachment = SinglePart::builder()
+        .header(ContentType::parse("message/rfc822").unwrap())
+        .header(ContentDisposition::attachment("forwarded-message.eml"))
+        .body(original);
+
+    lettre::Message::builder()
+        .from(env.user_email.parse().map_err(|_| {
+            DEmailError::Validation(format!("Invalid from address: {}", env.user_email))
+        })?)
+        .to(to
+            .parse()
+            .map_err(|_| DEmailError::Validation(format!("Invalid forward address: {}", to)))?)
+        .subject(subject)
+        .raw_header(HeaderValue::new(
+            HeaderName::new_from_ascii_str("Auto-Submitted"),
+            "auto-forwarded".to_string(),
+        ))
+        .multipart(
+            MultiPart::mixed()
+                .singlepart(SinglePart::plain(note))
+                .singlepart(attachment),
+        )
+        .map_err(|e| DEmailError::Api(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::accounts::create_account;
+    use crate::core::cache::db::test_pool;
+    use crate::core::providers::Security;
+    use crate::core::rules::MessageFacts;
+
+    fn stored_message(from: &str) -> StoredMessage {
+        StoredMessage {
+            id: 1,
+            account_id: 1,
+            imap_uid: 7,
+            folder_id: 1,
+            folder_path: "INBOX".to_string(),
+            message_id_header: "<original@example.com>".to_string(),
+            date: 0,
+            facts: MessageFacts {
+                from: from.to_string(),
+                to: "me@example.org".to_string(),
+                subject: "Invoice".to_string(),
+                ..Default::default()
+            },
+        }
+    }
+
+    #[test]
+    fn test_auto_submitted_messages_are_not_forwarded() {
+        let raw = |header: &str| format!("{}From: a@example.com\r\n\r\nbody\r\n", header);
+        assert!(is_auto_submitted(
+            raw("Auto-Submitted: auto-replied\r\n").as_bytes()
+        ));
+        assert!(is_auto_submitted(
+            raw("auto-submitted: auto-forwarded; owner=x\r\n").as_bytes()
+        ));
+        assert!(!is_auto_submitted(raw("Auto-Submitted: no\r\n").as_bytes()));
+        assert!(!is_auto_submitted(raw("").as_bytes()));
+        // Only the header block counts
+        assert!(!is_auto_submitted(
+            b"From: a@example.com\r\n\r\nAuto-Submitted: auto-replied\r\n"
+        ));
+    }
+
+    #[test]
+    fn test_own_addresses_are_not_forwarded() {
+        let pool = test_pool();
+        let account = create_account(&pool, "me@example.org", "Me", "google").unwrap();
+        let conn = pool.get().unwrap();
+        conn.execute(
+            "INSERT INTO identities (account_id, email_address) VALUES (?1, 'alias@example.org')",
+            [account.id],
+        )
+        .unwrap();
+
+        let own = |from: &str| is_own_address(&conn, account.id, "me@example.org", from).unwrap();
+        assert!(own("Me <ME@example.org>"));
+        assert!(own("alias@Example.org"));
+        assert!(!own("Someone <someone@example.com>"));
+    }
+
+    #[test]
+    fn test_forward_attaches_the_original() {
+        let pool = test_pool();
+        let env = RuleEnv {
+            pool: Arc::new((*pool).clone()),
+            smtp: ServerEndpoint {
+                host: "smtp.example.org".to_string(),
+                port: 587,
+                security: Security::StartTls,
+            },
+            user_email: "me@example.org".to_string(),
+            access_token: AccessToken::new("token".to_string()),
+        };
+        let raw = b"From: Shop <billing@shop.example>\r\nSubject: Invoice\r\n\r\nPlease pay.\r\n";
+        let email = forward_message(
+            &env,
+            &stored_message("billing@shop.example"),
+            "archive@example.net",
+            raw.to_vec(),
+        )
+        .unwrap();
+        let formatted = String::from_utf8(email.formatted()).unwrap();
+
+        assert!(formatted.contains("Auto-Submitted: auto-forwarded\r\n"));
+        assert!(formatted.contains("Subject: Fwd: Invoice\r\n"));
+        assert!(formatted.contains("Content-Type: message/rfc822\r\n"));
+        assert!(formatted.contains("Content-Transfer-Encoding: 8bit\r\n"));
+        assert!(formatted.contains("From: Shop <billing@shop.example>\r\nSubject: Invoice"));
+    }
+}
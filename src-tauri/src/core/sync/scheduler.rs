@@ -0,0 +1,489 @@
+// Sync scheduler - Decides when each account syncs: its own interval from the settings
+// table, exponential backoff while it keeps failing, and nothing at all while offline.
+// "Sync now" requests wake the scheduler instead of racing it.
+
+use crate::core::accounts::get_accounts;
+use crate::core::cache::db::{delete_setting, get_setting, save_setting, Pool};
+use crate::core::providers::ProviderRegistry;
+use crate::core::sync::background_sync::sync_account;
+use crate::core::sync::status::get_sync_status;
+use crate::error::DEmailError;
+use crate::models::{Account, AccountSyncStatus};
+use crate::AppState;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tauri::{AppHandle, Manager};
+use tokio::net::TcpStream;
+use tokio::sync::{oneshot, Notify, Semaphore};
+use tokio::task::{Id, JoinError, JoinSet};
+use tracing::{info, warn};
+
+/// Settings key of the default interval; `<key>.<account id>` overrides it per account
+pub const SETTING_INTERVAL_MINUTES: &str = "sync_interval_minutes";
+pub const DEFAULT_INTERVAL_MINUTES: u64 = 5;
+/// Emitted with `true` or `false` when connectivity changes
+pub const NETWORK_STATUS_EVENT: &str = "network-status";
+
+/// Accounts synced at the same time
+const MAX_CONCURRENT_SYNCS: usize = 3;
+/// Upper bound for the retry delay of a failing account, unless its interval is longer
+const MAX_BACKOFF: Duration = Duration::from_secs(3600);
+/// Share of the retry delay added at random so failing accounts do not retry in lockstep
+const JITTER: f64 = 0.2;
+/// How often connectivity is probed while offline
+const OFFLINE_PROBE_INTERVAL: Duration = Duration::from_secs(15);
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Longest sleep, so new accounts and changed settings are picked up
+const MAX_IDLE_WAIT: Duration = Duration::from_secs(60);
+
+type SyncResult = Result<usize, String>;
+
+/// Account syncs in flight. Kept for the scheduler's lifetime, so a slow account never
+/// holds up the next pass for the others.
+struct RunningSyncs {
+    tasks: JoinSet<()>,
+    accounts: HashMap<Id, i64>,
+    permits: Arc<Semaphore>,
+}
+
+impl RunningSyncs {
+    fn new() -> Self {
+        Self {
+            tasks: JoinSet::new(),
+            accounts: HashMap::new(),
+            permits: Arc::new(Semaphore::new(MAX_CONCURRENT_SYNCS)),
+        }
+    }
+
+    fn contains(&self, account_id: i64) -> bool {
+        self.accounts.values().any(|running| *running == account_id)
+    }
+
+    /// Syncs the account once a permit is free and answers the waiting callers. A
+    /// failing account is recorded in its sync status and does not stop the others.
+    fn spawn(
+        &mut self,
+        app_handle: &AppHandle,
+        account: Account,
+        waiters: Vec<oneshot::Sender<SyncResult>>,
+    ) {
+        let account_id = account.id;
+        let app_handle = app_handle.clone();
+        let permits = self.permits.clone();
+        let task = self.tasks.spawn(async move {
+            let _permit = permits.acquire_owned().await;
+            // Failures are already recorded and reported by sync_account
+            let result = sync_account(&app_handle, &account)
+                .await
+                .map_err(|e| e.to_string());
+            for waiter in waiters {
+                let _ = waiter.send(result.clone());
+            }
+        });
+        self.accounts.insert(task.id(), account_id);
+    }
+
+    /// Forgets a finished sync so the account can be planned again
+    fn finished(&mut self, result: Result<(Id, ()), JoinError>) {
+        let id = match result {
+            Ok((id, ())) => id,
+            Err(e) => {
+                log::error!("Account sync task panicked: {}", e);
+                e.id()
+            }
+        };
+        self.accounts.remove(&id);
+    }
+}
+
+/// When an account syncs next
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AccountSchedule {
+    pub account_id: i64,
+    /// Per-account override; `None` uses the default interval
+    pub interval_minutes: Option<u64>,
+    /// Unix timestamp; `None` when the account only syncs on request
+    pub next_sync_at: Option<i64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SyncSchedule {
+    /// 0 syncs only on request
+    pub default_interval_minutes: u64,
+    pub online: bool,
+    pub accounts: Vec<AccountSchedule>,
+}
+
+/// Runs the background sync loop, shared through `AppState`
+pub struct SyncScheduler {
+    wake: Notify,
+    /// Accounts asked to sync now, with the callers waiting for the outcome
+    requests: Mutex<HashMap<i64, Vec<oneshot::Sender<SyncResult>>>>,
+    online: AtomicBool,
+}
+
+impl Default for SyncScheduler {
+    fn default() -> Self {
+        Self {
+            wake: Notify::new(),
+            requests: Mutex::new(HashMap::new()),
+            online: AtomicBool::new(true),
+        }
+    }
+}
+
+impl SyncScheduler {
+    /// Syncs the account as soon as the scheduler is free and returns the number of
+    /// messages fetched. A sync already running for the account is not duplicated; the
+    /// request is served by the next pass.
+    pub async fn sync_now(&self, account_id: i64) -> Result<usize, DEmailError> {
+        let (sender, receiver) = oneshot::channel();
+        self.requests
+            .lock()
+            .unwrap()
+            .entry(account_id)
+            .or_default()
+            .push(sender);
+        self.wake.notify_one();
+
+        receiver
+            .await
+            .map_err(|_| DEmailError::Unknown("Sync scheduler stopped".to_string()))?
+            .map_err(DEmailError::Api)
+    }
+
+    /// Wakes the scheduler so changed intervals take effect immediately
+    pub fn reschedule(&self) {
+        self.wake.notify_one();
+    }
+
+    pub fn is_online(&self) -> bool {
+        self.online.load(Ordering::SeqCst)
+    }
+
+    pub async fn run(&self, app_handle: AppHandle) {
+        let mut running = RunningSyncs::new();
+        loop {
+            let pool = app_handle.state::<AppState>().db_pool.clone();
+            let accounts = match get_accounts(&pool) {
+                Ok(accounts) => accounts,
+                Err(e) => {
+                    // The database is unreadable until the app is unlocked
+                    warn!("Sync scheduler could not read accounts: {}", e);
+                    self.fail_requests(&e.to_string());
+                    self.wait(&mut running, MAX_IDLE_WAIT).await;
+                    continue;
+                }
+            };
+            let (due, next_at) = self.plan(&pool, &accounts, &running);
+
+            // Connectivity is only probed when there is something to sync, or to notice
+            // that it came back
+            if !due.is_empty() || !self.is_online() {
+                let online = probe_connectivity(&app_handle, &accounts).await;
+                if online != self.online.swap(online, Ordering::SeqCst) {
+                    info!(
+                        "Network {}",
+                        if online { "available" } else { "unavailable" }
+                    );
+                    let _ = app_handle.emit_all(NETWORK_STATUS_EVENT, online);
+                }
+                if !online {
+                    self.fail_requests("No network connection");
+                    self.wait(&mut running, OFFLINE_PROBE_INTERVAL).await;
+                    continue;
+                }
+                if due.is_empty() {
+                    // Back online: anything that fell due meanwhile runs right away
+                    continue;
+                }
+            }
+
+            for account in due {
+                // Requests made from here on are served by the next pass
+                let waiters = self
+                    .requests
+                    .lock()
+                    .unwrap()
+                    .remove(&account.id)
+                    .unwrap_or_default();
+                running.spawn(&app_handle, account, waiters);
+            }
+
+            let now = chrono::Utc::now().timestamp();
+            let wait = next_at
+                .map(|at| Duration::from_secs((at - now).max(1) as u64))
+                .unwrap_or(MAX_IDLE_WAIT);
+            self.wait(&mut running, wait.min(MAX_IDLE_WAIT)).await;
+        }
+    }
+
+    /// Accounts to sync now and the time the next one falls due. Accounts with a sync
+    /// still running are left out; their requests wait for the sync to finish.
+    fn plan(
+        &self,
+        pool: &Pool,
+        accounts: &[Account],
+        running: &RunningSyncs,
+    ) -> (Vec<Account>, Option<i64>) {
+        let statuses = status_by_account(pool);
+        let requested: HashSet<i64> = {
+            let mut requests = self.requests.lock().unwrap();
+            // Requests for accounts deleted in the meantime are answered right away
+            requests.retain(|account_id, waiters| {
+                let exists = accounts.iter().any(|account| account.id == *account_id);
+                if !exists {
+                    for waiter in waiters.drain(..) {
+                        let _ = waiter.send(Err(format!("Account {} not found", account_id)));
+                    }
+                }
+                exists
+            });
+            requests.keys().copied().collect()
+        };
+        let now = chrono::Utc::now().timestamp();
+
+        let mut due = Vec::new();
+        let mut next_at: Option<i64> = None;
+        for account in accounts {
+            if running.contains(account.id) {
+                continue;
+            }
+            if requested.contains(&account.id) {
+                due.push(account.clone());
+                continue;
+            }
+            let interval = account_interval(pool, account.id);
+            match next_sync_at(statuses.get(&account.id), interval) {
+                Some(at) if at <= now => due.push(account.clone()),
+                Some(at) => next_at = Some(next_at.map_or(at, |next| next.min(at))),
+                None => {}
+            }
+        }
+        (due, next_at)
+    }
+
+    fn fail_requests(&self, error: &str) {
+        let requests = std::mem::take(&mut *self.requests.lock().unwrap());
+        for waiter in requests.into_values().flatten() {
+            let _ = waiter.send(Err(error.to_string()));
+        }
+    }
+
+    /// Sleeps until the timeout, the next request or the end of a running sync,
+    /// whichever comes first
+    async fn wait(&self, running: &mut RunningSyncs, timeout: Duration) {
+        tokio::select! {
+            _ = tokio::time::sleep(timeout) => {}
+            _ = self.wake.notified() => {}
+            Some(result) = running.tasks.join_next_with_id() => running.finished(result),
+        }
+    }
+}
+
+/// The schedule shown in the sync settings
+pub fn get_schedule(scheduler: &SyncScheduler, pool: &Pool) -> Result<SyncSchedule, DEmailError> {
+    let statuses = status_by_account(pool);
+    let accounts = get_accounts(pool)?
+        .into_iter()
+        .map(|account| AccountSchedule {
+            account_id: account.id,
+            interval_minutes: get_setting(pool, &account_setting(account.id))
+                .ok()
+                .flatten()
+                .and_then(|value| value.parse().ok()),
+            next_sync_at: next_sync_at(
+                statuses.get(&account.id),
+                account_interval(pool, account.id),
+            ),
+        })
+        .collect();
+
+    Ok(SyncSchedule {
+        default_interval_minutes: get_setting(pool, SETTING_INTERVAL_MINUTES)?
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(DEFAULT_INTERVAL_MINUTES),
+        online: scheduler.is_online(),
+        accounts,
+    })
+}
+
+/// Sets the default interval, or one account's when `account_id` is given. `None`
+/// removes an account's override; 0 minutes syncs only on request.
+pub fn set_interval(
+    pool: &Pool,
+    account_id: Option<i64>,
+    minutes: Option<u64>,
+) -> Result<(), DEmailError> {
+    let key = match account_id {
+        Some(account_id) => account_setting(account_id),
+        None => SETTING_INTERVAL_MINUTES.to_string(),
+    };
+    match minutes {
+        Some(minutes) => save_setting(pool, &key, &minutes.to_string()),
+        None => delete_setting(pool, &key),
+    }
+}
+
+fn account_setting(account_id: i64) -> String {
+    format!("{}.{}", SETTING_INTERVAL_MINUTES, account_id)
+}
+
+/// Interval of an account; `None` when it only syncs on request
+fn account_interval(pool: &Pool, account_id: i64) -> Option<Duration> {
+    let minutes = [
+        account_setting(account_id),
+        SETTING_INTERVAL_MINUTES.to_string(),
+    ]
+    .iter()
+    .find_map(|key| {
+        get_setting(pool, key)
+            .ok()
+            .flatten()
+            .and_then(|value| value.trim().parse::<u64>().ok())
+    })
+    .unwrap_or(DEFAULT_INTERVAL_MINUTES);
+    (minutes > 0).then(|| Duration::from_secs(minutes * 60))
+}
+
+fn status_by_account(pool: &Pool) -> HashMap<i64, AccountSyncStatus> {
+    match get_sync_status(pool) {
+        Ok(statuses) => statuses
+            .into_iter()
+            .map(|status| (status.account_id, status))
+            .collect(),
+        Err(e) => {
+            warn!("Could not read sync status: {}", e);
+            HashMap::new()
+        }
+    }
+}
+
+/// Unix time of the next automatic sync. An account that never synced is due at once;
+/// a failing one waits its interval doubled for every further consecutive failure.
+fn next_sync_at(status: Option<&AccountSyncStatus>, interval: Option<Duration>) -> Option<i64> {
+    let interval = interval?;
+    let Some(status) = status else {
+        return Some(0);
+    };
+    if status.consecutive_failures > 0 {
+        let failed_at = status.last_error_at.or(status.last_attempt_at).unwrap_or(0);
+        let delay = backoff(interval, status.consecutive_failures as u32);
+        // Seeded by the failure so the planned retry stays put between scheduler passes
+        let seed = (status.account_id as u64) ^ (failed_at as u64).rotate_left(32);
+        let jitter = StdRng::seed_from_u64(seed).gen_range(0.0..JITTER);
+        return Some(failed_at + delay.mul_f64(1.0 + jitter).as_secs() as i64);
+    }
+    match status.last_attempt_at {
+        Some(attempted_at) => Some(attempted_at + interval.as_secs() as i64),
+        None => Some(0),
+    }
+}
+
+/// Retry delay after the given number of consecutive failures
+fn backoff(interval: Duration, failures: u32) -> Duration {
+    let cap = interval.max(MAX_BACKOFF);
+    let exponent = failures.saturating_sub(1).min(16);
+    interval.saturating_mul(1 << exponent).min(cap)
+}
+
+/// Online when any account's IMAP server accepts a connection. Without accounts
+/// there is nothing to reach, which counts as online.
+async fn probe_connectivity(app_handle: &AppHandle, accounts: &[Account]) -> bool {
+    let config = app_handle
+        .state::<AppState>()
+        .app_config
+        .lock()
+        .unwrap()
+        .clone();
+    let registry = match ProviderRegistry::load(&config) {
+        Ok(registry) => registry,
+        Err(_) => ProviderRegistry::new(Vec::new()),
+    };
+    let mut endpoints: Vec<(String, u16)> = accounts
+        .iter()
+        .filter_map(|account| registry.get(&account.provider_type).ok())
+        .map(|provider| (provider.imap.host.clone(), provider.imap.port))
+        .collect();
+    endpoints.sort();
+    endpoints.dedup();
+    if endpoints.is_empty() {
+        return true;
+    }
+
+    for endpoint in endpoints {
+        if let Ok(Ok(_)) = tokio::time::timeout(PROBE_TIMEOUT, TcpStream::connect(endpoint)).await {
+            return true;
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status(
+        last_attempt_at: Option<i64>,
+        last_error_at: Option<i64>,
+        consecutive_failures: i64,
+    ) -> AccountSyncStatus {
+        AccountSyncStatus {
+            account_id: 7,
+            email_address: "user@example.org".to_string(),
+            state: "idle".to_string(),
+            last_attempt_at,
+            last_success_at: None,
+            last_error: None,
+            last_error_at,
+            messages_fetched: 0,
+            consecutive_failures,
+        }
+    }
+
+    #[test]
+    fn test_backoff_doubles_up_to_cap() {
+        let five_minutes = Duration::from_secs(300);
+        assert_eq!(backoff(five_minutes, 1), five_minutes);
+        assert_eq!(backoff(five_minutes, 2), Duration::from_secs(600));
+        assert_eq!(backoff(five_minutes, 3), Duration::from_secs(1200));
+        assert_eq!(backoff(five_minutes, 10), MAX_BACKOFF);
+        assert_eq!(backoff(five_minutes, u32::MAX), MAX_BACKOFF);
+
+        // An interval longer than the cap is never shortened
+        let day = Duration::from_secs(86_400);
+        assert_eq!(backoff(day, 4), day);
+    }
+
+    #[test]
+    fn test_next_sync_at() {
+        let interval = Some(Duration::from_secs(300));
+
+        // Never synced, or only on request
+        assert_eq!(next_sync_at(None, interval), Some(0));
+        assert_eq!(
+            next_sync_at(Some(&status(None, None, 0)), interval),
+            Some(0)
+        );
+        assert_eq!(next_sync_at(Some(&status(Some(1000), None, 0)), None), None);
+
+        assert_eq!(
+            next_sync_at(Some(&status(Some(1000), None, 0)), interval),
+            Some(1300)
+        );
+
+        // Failing accounts back off from the failure time, with bounded jitter
+        let failing = status(Some(1000), Some(1010), 3);
+        let at = next_sync_at(Some(&failing), interval).unwrap();
+        assert!((1010 + 1200..=1010 + 1440).contains(&at), "{}", at);
+        assert_eq!(next_sync_at(Some(&failing), interval), Some(at));
+
+        let longer = status(Some(1000), Some(1010), 4);
+        assert!(next_sync_at(Some(&longer), interval).unwrap() > at);
+    }
+}
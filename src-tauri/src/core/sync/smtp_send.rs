@@ -1,7 +1,9 @@
 use crate::core::accounts::get_refresh_token;
+use crate::core::providers::{Security, ServerEndpoint};
 use crate::error::DEmailError;
 use lettre::{
-    transport::smtp::authentication::Mechanism, AsyncSmtpTransport, AsyncTransport, Message,
+    transport::smtp::authentication::{Credentials, Mechanism},
+    AsyncSmtpTransport, AsyncTransport, Message,
 };
 use oauth2::{
     basic::BasicClient, reqwest::async_http_client, AccessToken, RefreshToken, TokenResponse,
@@ -9,27 +11,53 @@ use oauth2::{
 
 pub async fn send_email(
     message: Message,
-    provider: &str,
+    server: &ServerEndpoint,
     user_email: &str,
     access_token: &AccessToken,
 ) -> Result<(), DEmailError> {
-    let smtp_server = match provider {
-        "google" => "smtp.gmail.com",
-        "microsoft" => "smtp.office365.com",
-        _ => {
-            return Err(DEmailError::Smtp(lettre::transport::smtp::Error::from(
-                "Unsupported provider".to_string(),
-            )))
+    send_with(
+        message,
+        server,
+        Credentials::new(user_email.to_string(), access_token.secret().to_string()),
+        vec![Mechanism::XOAuth2],
+    )
+    .await
+}
+
+/// Sends through a server that signs in with a password rather than the provider's OAuth
+/// token, such as an identity's own SMTP server
+pub async fn send_email_with_password(
+    message: Message,
+    server: &ServerEndpoint,
+    username: &str,
+    password: &str,
+) -> Result<(), DEmailError> {
+    send_with(
+        message,
+        server,
+        Credentials::new(username.to_string(), password.to_string()),
+        vec![Mechanism::Plain, Mechanism::Login],
+    )
+    .await
+}
+
+async fn send_with(
+    message: Message,
+    server: &ServerEndpoint,
+    credentials: Credentials,
+    mechanisms: Vec<Mechanism>,
+) -> Result<(), DEmailError> {
+    let builder = match server.security {
+        Security::Tls => AsyncSmtpTransport::<tokio::io::DuplexStream>::relay(&server.host),
+        Security::StartTls => {
+            AsyncSmtpTransport::<tokio::io::DuplexStream>::starttls_relay(&server.host)
         }
     };
-
-    let mailer = AsyncSmtpTransport::<tokio::io::DuplexStream>::starttls_relay(smtp_server)
+    let mailer = builder
         .map_err(|e| DEmailError::Smtp(e))?
-        .credentials(lettre::transport::smtp::authentication::Credentials::new(
-            user_email.to_string(),
-            access_token.secret().to_string(),
-        ))
-        .authentication(vec![Mechanism::XOAuth2])
+        .port(server.port)
+        .credentials(credentials)
+        .authentication(mechanisms)
         .build();
 
     mailer
@@ -0,0 +1,184 @@
+// Sync status - Per-account sync state persisted in SQLite, so one failing account
+// is visible in the UI without stopping the others
+
+use crate::core::cache::db::Pool;
+use crate::error::DEmailError;
+use crate::models::AccountSyncStatus;
+use rusqlite::{params, Connection};
+
+/// Emitted with an `AccountSyncStatus` whenever an account starts or finishes syncing
+pub const SYNC_STATUS_EVENT: &str = "sync-status";
+/// Emitted with a `SyncProgress` as each folder of an account is synced
+pub const SYNC_PROGRESS_EVENT: &str = "sync-progress";
+
+pub const STATE_IDLE: &str = "idle";
+pub const STATE_SYNCING: &str = "syncing";
+pub const STATE_ERROR: &str = "error";
+
+/// Marks the start of a sync pass
+pub fn mark_started(pool: &Pool, account_id: i64) -> Result<AccountSyncStatus, DEmailError> {
+    let conn = pool
+        .get()
+        .map_err(|_| DEmailError::Database(rusqlite::Error::InvalidQuery))?;
+    conn.execute(
+        "INSERT INTO sync_status (account_id, state, last_attempt_at)
+         VALUES (?1, ?2, ?3)
+         ON CONFLICT(account_id) DO UPDATE SET state = ?2, last_attempt_at = ?3",
+        params![account_id, STATE_SYNCING, chrono::Utc::now().timestamp()],
+    )?;
+    get_account_status(&conn, account_id)
+}
+
+/// Records a completed pass and the number of messages it fetched
+pub fn record_success(
+    pool: &Pool,
+    account_id: i64,
+    messages_fetched: usize,
+) -> Result<AccountSyncStatus, DEmailError> {
+    let conn = pool
+        .get()
+        .map_err(|_| DEmailError::Database(rusqlite::Error::InvalidQuery))?;
+    conn.execute(
+        "INSERT INTO sync_status (account_id, state, last_success_at, messages_fetched)
+         VALUES (?1, ?2, ?3, ?4)
+         ON CONFLICT(account_id) DO UPDATE SET
+            state = ?2, last_success_at = ?3, messages_fetched = ?4,
+            last_error = NULL, consecutive_failures = 0",
+        params![
+            account_id,
+            STATE_IDLE,
+            chrono::Utc::now().timestamp(),
+            messages_fetched as i64
+        ],
+    )?;
+    get_account_status(&conn, account_id)
+}
+
+/// Records a failed pass; the last successful sync is kept
+pub fn record_failure(
+    pool: &Pool,
+    account_id: i64,
+    error: &str,
+) -> Result<AccountSyncStatus, DEmailError> {
+    let conn = pool
+        .get()
+        .map_err(|_| DEmailError::Database(rusqlite::Error::InvalidQuery))?;
+    conn.execute(
+        "INSERT INTO sync_status (account_id, state, last_error, last_error_at, consecutive_failures)
+         VALUES (?1, ?2, ?3, ?4, 1)
+         ON CONFLICT(account_id) DO UPDATE SET
+            state = ?2, last_error = ?3, last_error_at = ?4,
+            consecutive_failures = consecutive_failures + 1",
+        params![
+            account_id,
+            STATE_ERROR,
+            error,
+            chrono::Utc::now().timestamp()
+        ],
+    )?;
+    get_account_status(&conn, account_id)
+}
+
+/// Passes interrupted by a restart are no longer running
+pub fn reset_interrupted(pool: &Pool) -> Result<(), DEmailError> {
+    let conn = pool
+        .get()
+        .map_err(|_| DEmailError::Database(rusqlite::Error::InvalidQuery))?;
+    conn.execute(
+        "UPDATE sync_status SET state = ?1 WHERE state = ?2",
+        params![STATE_IDLE, STATE_SYNCING],
+    )?;
+    Ok(())
+}
+
+/// Status of every account, including accounts that have never been synced
+pub fn get_sync_status(pool: &Pool) -> Result<Vec<AccountSyncStatus>, DEmailError> {
+    let conn = pool
+        .get()
+        .map_err(|_| DEmailError::Database(rusqlite::Error::InvalidQuery))?;
+    let mut stmt = conn.prepare(&format!("{} ORDER BY a.id", STATUS_QUERY))?;
+    let statuses = stmt
+        .query_map([], row_to_status)?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(statuses)
+}
+
+fn get_account_status(
+    conn: &Connection,
+    account_id: i64,
+) -> Result<AccountSyncStatus, DEmailError> {
+    Ok(conn.query_row(
+        &format!("{} WHERE a.id = ?1", STATUS_QUERY),
+        [account_id],
+        row_to_status,
+    )?)
+}
+
+const STATUS_QUERY: &str = "SELECT a.id, a.email_address, COALESCE(s.state, 'idle'),
+        s.last_attempt_at, s.last_success_at, s.last_error, s.last_error_at,
+        COALESCE(s.messages_fetched, 0), COALESCE(s.consecutive_failures, 0)
+    FROM accounts a LEFT JOIN sync_status s ON s.account_id = a.id";
+
+fn row_to_status(row: &rusqlite::Row) -> rusqlite::Result<AccountSyncStatus> {
+    Ok(AccountSyncStatus {
+        account_id: row.get(0)?,
+        email_address: row.get(1)?,
+        state: row.get(2)?,
+        last_attempt_at: row.get(3)?,
+        last_success_at: row.get(4)?,
+        last_error: row.get(5)?,
+        last_error_at: row.get(6)?,
+        messages_fetched: row.get(7)?,
+        consecutive_failures: row.get(8)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::accounts::create_account;
+    use crate::core::cache::db::test_pool;
+
+    #[test]
+    fn test_sync_status_lifecycle() {
+        let pool = test_pool();
+        let first = create_account(&pool, "one@example.org", "One", "google").unwrap();
+        let second = create_account(&pool, "two@example.org", "Two", "microsoft").unwrap();
+
+        // Accounts that never synced are reported as idle
+        let statuses = get_sync_status(&pool).unwrap();
+        assert_eq!(statuses.len(), 2);
+        assert!(statuses
+            .iter()
+            .all(|s| s.state == STATE_IDLE && s.last_success_at.is_none()));
+
+        assert_eq!(mark_started(&pool, first.id).unwrap().state, STATE_SYNCING);
+        let success = record_success(&pool, first.id, 12).unwrap();
+        assert_eq!(success.state, STATE_IDLE);
+        assert_eq!(success.messages_fetched, 12);
+        assert!(success.last_success_at.is_some());
+
+        // A failing account does not affect the other one
+        mark_started(&pool, second.id).unwrap();
+        record_failure(&pool, second.id, "connection refused").unwrap();
+        let failure = record_failure(&pool, second.id, "connection refused").unwrap();
+        assert_eq!(failure.state, STATE_ERROR);
+        assert_eq!(failure.consecutive_failures, 2);
+        assert_eq!(failure.last_error.as_deref(), Some("connection refused"));
+
+        // Failures keep the last success; a success clears the error
+        mark_started(&pool, first.id).unwrap();
+        let failed = record_failure(&pool, first.id, "timeout").unwrap();
+        assert_eq!(failed.last_success_at, success.last_success_at);
+        let recovered = record_success(&pool, first.id, 0).unwrap();
+        assert!(recovered.last_error.is_none());
+        assert_eq!(recovered.consecutive_failures, 0);
+
+        mark_started(&pool, second.id).unwrap();
+        reset_interrupted(&pool).unwrap();
+        assert!(get_sync_status(&pool)
+            .unwrap()
+            .iter()
+            .all(|s| s.state != STATE_SYNCING));
+    }
+}
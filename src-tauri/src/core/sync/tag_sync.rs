@@ -0,0 +1,392 @@
+// Tag sync - Keeps tags in step with the server. IMAP keywords are read with FETCH FLAGS
+// and written with STORE. Gmail labels are read with one FETCH of X-GM-LABELS, sent as a
+// raw command and parsed here because imap-proto cannot parse that attribute, and
+// written by copying into, or expunging from, the label's folder. X-GM-MSGID is read the
+// same way.
+
+use crate::core::accounts::get_accounts;
+use crate::core::providers::client_for_provider;
+use crate::core::sync::imap_pool::{ImapSession, OAuth2Connector, PooledSession};
+use crate::core::tags::{self, RemoteMessage};
+use crate::error::DEmailError;
+use crate::AppState;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Whether the server is Gmail, whose tags are labels
+pub fn is_gmail(session: &ImapSession) -> bool {
+    session.has_capability("X-GM-EXT-1")
+}
+
+/// The tags of every message in the selected folder, by UID. `gmail_labels` holds the
+/// account's label folders on Gmail and is `None` elsewhere.
+pub async fn remote_tags(
+    session: &mut ImapSession,
+    gmail_labels: Option<&[String]>,
+) -> Result<HashMap<u32, Vec<String>>, DEmailError> {
+    match gmail_labels {
+        Some(labels) => {
+            let response = session
+                .run(|s| s.run_command_and_read_response("UID FETCH 1:* (X-GM-LABELS)"))
+                .await?;
+            Ok(parse_gmail_labels(&response, labels))
+        }
+        None => {
+            session
+                .run(|s| {
+                    let fetches = s.uid_fetch("1:*", "(UID FLAGS)")?;
+                    Ok(fetches
+                        .iter()
+                        .filter_map(|fetch| {
+                            let keywords = fetch
+                                .flags()
+                                .iter()
+                                .map(|flag| flag.to_string())
+                                .filter(|flag| tags::is_tag(flag))
+                                .collect();
+                            fetch.uid.map(|uid| (uid, keywords))
+                        })
+                        .collect())
+                })
+                .await
+        }
+    }
+}
+
+/// A value in an untagged FETCH response
+#[derive(Debug, PartialEq)]
+enum FetchValue {
+    Atom(String),
+    List(Vec<FetchValue>),
+}
+
+/// Reads the labels per UID from the raw response to `UID FETCH ... (X-GM-LABELS)`,
+/// keeping only the account's own labels (`labels`), not system ones like `\Inbox`
+fn parse_gmail_labels(response: &[u8], labels: &[String]) -> HashMap<u32, Vec<String>> {
+    fetch_items_by_uid(response)
+        .into_iter()
+        .map(|(uid, items)| {
+            let message_labels = match fetch_item(&items, "X-GM-LABELS") {
+                Some(FetchValue::List(values)) => values
+                    .iter()
+                    .filter_map(|value| match value {
+                        FetchValue::Atom(label) if labels.contains(label) => Some(label.clone()),
+                        _ => None,
+                    })
+                    .collect(),
+                _ => Vec::new(),
+            };
+            (uid, message_labels)
+        })
+        .collect()
+}
+
+/// Gmail's X-GM-MSGID of each message in `uid_set` in the selected folder, by UID. The
+/// id is the same in every label folder that holds the message.
+pub async fn gmail_message_ids(
+    session: &mut ImapSession,
+    uid_set: &str,
+) -> Result<HashMap<u32, String>, DEmailError> {
+    let command = format!("UID FETCH {} (X-GM-MSGID)", uid_set);
+    let response = session
+        .run(move |s| s.run_command_and_read_response(&command))
+        .await?;
+    Ok(parse_gmail_message_ids(&response))
+}
+
+fn parse_gmail_message_ids(response: &[u8]) -> HashMap<u32, String> {
+    fetch_items_by_uid(response)
+        .into_iter()
+        .filter_map(|(uid, items)| match fetch_item(&items, "X-GM-MSGID") {
+            Some(FetchValue::Atom(id)) => Some((uid, id.clone())),
+            _ => None,
+        })
+        .collect()
+}
+
+/// The attribute lists of the untagged FETCH responses in a raw response, by UID.
+/// Responses without a UID are skipped.
+fn fetch_items_by_uid(response: &[u8]) -> Vec<(u32, Vec<FetchValue>)> {
+    let mut fetches = Vec::new();
+    let mut pos = 0;
+    while pos < response.len() {
+        if response[pos..].starts_with(b"* ") {
+            pos += 2;
+            let _sequence = parse_fetch_value(response, &mut pos);
+            let is_fetch = matches!(
+                parse_fetch_value(response, &mut pos),
+                Some(FetchValue::Atom(name)) if name.eq_ignore_ascii_case("FETCH")
+            );
+            if let (true, Some(FetchValue::List(items))) =
+                (is_fetch, parse_fetch_value(response, &mut pos))
+            {
+                let uid = match fetch_item(&items, "UID") {
+                    Some(FetchValue::Atom(value)) => value.parse::<u32>().ok(),
+                    _ => None,
+                };
+                if let Some(uid) = uid {
+                    fetches.push((uid, items));
+                }
+            }
+        }
+        // On to the next line
+        pos = response[pos.min(response.len())..]
+            .iter()
+            .position(|&b| b == b'\n')
+            .map_or(response.len(), |offset| pos + offset + 1);
+    }
+    fetches
+}
+
+/// The value of the named attribute in a FETCH attribute list
+fn fetch_item<'a>(items: &'a [FetchValue], name: &str) -> Option<&'a FetchValue> {
+    items.chunks(2).find_map(|pair| match pair {
+        [FetchValue::Atom(item), value] if item.eq_ignore_ascii_case(name) => Some(value),
+        _ => None,
+    })
+}
+
+/// Parses one atom, quoted string, literal or parenthesized list at `pos`
+fn parse_fetch_value(data: &[u8], pos: &mut usize) -> Option<FetchValue> {
+    while data.get(*pos) == Some(&b' ') {
+        *pos += 1;
+    }
+    match *data.get(*pos)? {
+        b'(' => {
+            *pos += 1;
+            let mut values = Vec::new();
+            loop {
+                while data.get(*pos) == Some(&b' ') {
+                    *pos += 1;
+                }
+                if data.get(*pos)? == &b')' {
+                    *pos += 1;
+                    return Some(FetchValue::List(values));
+                }
+                values.push(parse_fetch_value(data, pos)?);
+            }
+        }
+        b'"' => {
+            *pos += 1;
+            let mut value = Vec::new();
+            loop {
+                match *data.get(*pos)? {
+                    b'\\' => {
+                        value.push(*data.get(*pos + 1)?);
+                        *pos += 2;
+                    }
+                    b'"' => {
+                        *pos += 1;
+                        return Some(FetchValue::Atom(String::from_utf8_lossy(&value).into()));
+                    }
+                    byte => {
+                        value.push(byte);
+                        *pos += 1;
+                    }
+                }
+            }
+        }
+        b'{' => {
+            let end = *pos + data[*pos..].iter().position(|&b| b == b'}')?;
+            let len: usize = std::str::from_utf8(&data[*pos + 1..end])
+                .ok()?
+                .parse()
+                .ok()?;
+            let start = end + 3;
+            let value = data.get(start..start.checked_add(len)?)?;
+            *pos = start + len;
+            Some(FetchValue::Atom(String::from_utf8_lossy(value).into()))
+        }
+        _ => {
+            let start = *pos;
+            while let Some(&byte) = data.get(*pos) {
+                if matches!(byte, b' ' | b'(' | b')' | b'\r' | b'\n') {
+                    break;
+                }
+                *pos += 1;
+            }
+            (*pos > start)
+                .then(|| FetchValue::Atom(String::from_utf8_lossy(&data[start..*pos]).into()))
+        }
+    }
+}
+
+/// Adds or removes a tag on the server. The message's folder must be selected, and
+/// stays selected afterwards.
+pub async fn push_tag(
+    session: &mut ImapSession,
+    message: &RemoteMessage,
+    tag: &str,
+    add: bool,
+) -> Result<(), DEmailError> {
+    let uid = message.imap_uid.to_string();
+    let tag = tag.to_string();
+    if !is_gmail(session) {
+        let query = format!("{}FLAGS ({})", if add { "+" } else { "-" }, tag);
+        return session
+            .run(move |s| s.uid_store(&uid, &query).map(|_| ()))
+            .await;
+    }
+
+    if add {
+        // Copying a message into a label's folder labels it
+        return session
+            .run(move |s| {
+                // LIST and COPY take the mailbox name as given
+                if s.list(Some(""), Some(&quote(&tag)))?.is_empty() {
+                    s.create(&tag)?;
+                }
+                s.uid_copy(&uid, quote(&tag))
+            })
+            .await;
+    }
+
+    // Expunging the message from the label's folder removes the label
+    if message.message_id_header.is_empty() {
+        return Err(DEmailError::Validation(
+            "The message has no Message-ID to find it under the label".to_string(),
+        ));
+    }
+    let search = format!("HEADER Message-ID {}", quote(&message.message_id_header));
+    let folder = message.folder_path.clone();
+    let uidplus = session.has_capability("UIDPLUS");
+    session
+        .run(move |s| {
+            s.select(&tag)?;
+            let uids = s.uid_search(&search)?;
+            if !uids.is_empty() {
+                let uid_set = uids
+                    .iter()
+                    .map(|uid| uid.to_string())
+                    .collect::<Vec<_>>()
+                    .join(",");
+                s.uid_store(&uid_set, "+FLAGS (\\Deleted)")?;
+                if uidplus {
+                    s.uid_expunge(&uid_set)?;
+                } else {
+                    s.expunge()?;
+                }
+            }
+            s.select(&folder).map(|_| ())
+        })
+        .await
+}
+
+/// Adds or removes a tag on a stored message, on the server first. Returns the
+/// message's tags afterwards.
+pub async fn set_message_tag(
+    app_state: &AppState,
+    message_id: i64,
+    tag: &str,
+    add: bool,
+) -> Result<Vec<String>, DEmailError> {
+    let pool = &app_state.db_pool;
+    let message = {
+        let conn = pool
+            .get()
+            .map_err(|_| DEmailError::Database(rusqlite::Error::InvalidQuery))?;
+        tags::remote_message(&conn, message_id)?
+    }
+    .ok_or_else(|| DEmailError::NotFound(format!("Message {} not found", message_id)))?;
+    let mut session = account_session(app_state, message.account_id).await?;
+
+    let gmail = is_gmail(&session);
+    let tag = if add {
+        tags::validate_tag(tag, gmail)?
+    } else {
+        tag.to_string()
+    };
+    let folder = message.folder_path.clone();
+    session.run(move |s| s.select(&folder).map(|_| ())).await?;
+    push_tag(&mut session, &message, &tag, add).await?;
+
+    let conn = pool
+        .get()
+        .map_err(|_| DEmailError::Database(rusqlite::Error::InvalidQuery))?;
+    // A Gmail label belongs to the message, so every stored copy carries it
+    let copies = if gmail && !message.message_id_header.is_empty() {
+        tags::copies_of(&conn, message.account_id, &message.message_id_header)?
+    } else {
+        vec![message_id]
+    };
+    for copy in copies {
+        if add {
+            tags::add_tag(&conn, copy, &tag)?;
+        } else {
+            tags::remove_tag(&conn, copy, &tag)?;
+        }
+    }
+    tags::message_tags(&conn, message_id)
+}
+
+/// Checks out a pooled IMAP session for an account
+pub async fn account_session(
+    app_state: &AppState,
+    account_id: i64,
+) -> Result<PooledSession, DEmailError> {
+    let pool = &app_state.db_pool;
+    let account = get_accounts(pool)?
+        .into_iter()
+        .find(|account| account.id == account_id)
+        .ok_or_else(|| DEmailError::NotFound(format!("Account {} not found", account_id)))?;
+
+    let config = app_state.app_config.lock().unwrap().clone();
+    let (provider, client) = client_for_provider(&config, &account.provider_type)?;
+    let access_token = app_state
+        .token_manager
+        .access_token(pool, account.id, &client)
+        .await?;
+    let connector = OAuth2Connector::new(
+        &provider.imap,
+        &account.email_address,
+        access_token.secret(),
+    );
+    app_state
+        .imap_pool
+        .checkout(account.id, Arc::new(connector))
+        .await
+}
+
+/// Quotes a search argument or mailbox name as an IMAP quoted string
+pub fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_gmail_labels() {
+        let response = b"* 1 FETCH (X-GM-LABELS (\\Inbox Receipts \"Team \\\"A\\\"\") UID 101)\r\n\
+* 2 FETCH (UID 102 X-GM-LABELS ())\r\n\
+* 3 FETCH (UID 103 X-GM-LABELS ({4}\r\nTrip Unknown))\r\n\
+* 4 EXISTS\r\n\
+* 5 FETCH (X-GM-LABELS (Receipts))\r\n";
+        let labels = vec![
+            "Receipts".to_string(),
+            "Team \"A\"".to_string(),
+            "Trip".to_string(),
+        ];
+        let tags = parse_gmail_labels(response, &labels);
+
+        assert_eq!(tags[&101], vec!["Receipts", "Team \"A\""]);
+        assert!(tags[&102].is_empty());
+        assert_eq!(tags[&103], vec!["Trip"]);
+        // No UID, nothing to attach the labels to
+        assert_eq!(tags.len(), 3);
+    }
+
+    #[test]
+    fn test_parse_gmail_message_ids() {
+        let response = b"* 1 FETCH (X-GM-MSGID 1278455344230334865 UID 101)\r\n\
+* 2 FETCH (UID 102 X-GM-MSGID 1278455344230334866)\r\n\
+* 3 FETCH (X-GM-MSGID 1278455344230334867)\r\n\
+A4 OK Success\r\n";
+        let ids = parse_gmail_message_ids(response);
+
+        assert_eq!(ids[&101], "1278455344230334865");
+        assert_eq!(ids[&102], "1278455344230334866");
+        assert_eq!(ids.len(), 2);
+    }
+}
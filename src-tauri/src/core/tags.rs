@@ -0,0 +1,357 @@
+// Tags module - Tags on messages, stored in `message_flags`. On most servers a tag is an
+// IMAP keyword such as `$Label1` or `work`; on Gmail it is a label, and label folders
+// are not synced as folders so a labelled message is stored once. Keeping tags in step
+// with the server lives in `sync::tag_sync`.
+
+use crate::core::cache::db::Pool;
+use crate::error::DEmailError;
+use crate::models::MessageHeader;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Keywords servers and clients use for their own bookkeeping rather than as tags
+const SYSTEM_KEYWORDS: &[&str] = &[
+    "$forwarded",
+    "$mdnsent",
+    "$junk",
+    "$notjunk",
+    "junk",
+    "nonjunk",
+    "$phishing",
+    "$submitpending",
+    "$submitted",
+    "$snoozed",
+];
+
+/// Names other clients show for the keywords they use as their default tags
+const KEYWORD_NAMES: &[(&str, &str)] = &[
+    ("$label1", "Important"),
+    ("$label2", "Work"),
+    ("$label3", "Personal"),
+    ("$label4", "To Do"),
+    ("$label5", "Later"),
+];
+
+const MAX_TAG_LENGTH: usize = 100;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TagCount {
+    pub tag: String,
+    /// What to show for the tag, such as "Work" for `$Label2`
+    pub name: String,
+    pub count: i64,
+    pub unread: i64,
+}
+
+/// Where a stored message lives on the server
+#[derive(Debug, Clone)]
+pub struct RemoteMessage {
+    pub account_id: i64,
+    pub folder_path: String,
+    pub imap_uid: u32,
+    pub message_id_header: String,
+}
+
+/// Whether an IMAP flag or Gmail label is a tag: system flags (`\Seen`, Gmail's
+/// `\Important`) and bookkeeping keywords are not
+pub fn is_tag(flag: &str) -> bool {
+    !flag.is_empty()
+        && !flag.starts_with('\\')
+        && !SYSTEM_KEYWORDS.contains(&flag.to_ascii_lowercase().as_str())
+}
+
+pub fn display_name(tag: &str) -> String {
+    let lower = tag.to_ascii_lowercase();
+    KEYWORD_NAMES
+        .iter()
+        .find(|(keyword, _)| *keyword == lower)
+        .map(|(_, name)| name.to_string())
+        .unwrap_or_else(|| tag.to_string())
+}
+
+/// Whether a Gmail folder is a label rather than the inbox or a system folder
+pub fn is_gmail_label_folder(path: &str) -> bool {
+    !path.eq_ignore_ascii_case("INBOX")
+        && !path.starts_with("[Gmail]")
+        && !path.starts_with("[Google Mail]")
+}
+
+/// Checks a tag the user entered and returns it trimmed. IMAP keywords are atoms, so
+/// outside Gmail they cannot contain spaces or IMAP specials.
+pub fn validate_tag(tag: &str, gmail: bool) -> Result<String, DEmailError> {
+    let tag = tag.trim();
+    if tag.is_empty() {
+        return Err(DEmailError::Validation("Tag cannot be empty".to_string()));
+    }
+    if tag.chars().count() > MAX_TAG_LENGTH {
+        return Err(DEmailError::Validation(format!(
+            "Tags can be at most {} characters",
+            MAX_TAG_LENGTH
+        )));
+    }
+    if tag.chars().any(char::is_control) {
+        return Err(DEmailError::Validation(
+            "Tags cannot contain control characters".to_string(),
+        ));
+    }
+    if !is_tag(tag) || (gmail && !is_gmail_label_folder(tag)) {
+        return Err(DEmailError::Validation(format!(
+            "\"{}\" is reserved by the server",
+            tag
+        )));
+    }
+    if !gmail
+        && tag
+            .chars()
+            .any(|c| !c.is_ascii() || c == ' ' || "(){%*\"\\]".contains(c))
+    {
+        return Err(DEmailError::Validation(
+            "Tags on this server can only use ASCII letters, digits and punctuation other than ( ) { % * \" \\ ]"
+                .to_string(),
+        ));
+    }
+    Ok(tag.to_string())
+}
+
+pub fn message_tags(conn: &Connection, message_id: i64) -> Result<Vec<String>, DEmailError> {
+    let mut stmt =
+        conn.prepare("SELECT flag FROM message_flags WHERE message_id = ?1 ORDER BY flag")?;
+    let tags = stmt
+        .query_map([message_id], |row| row.get(0))?
+        .collect::<Result<Vec<String>, _>>()?;
+    Ok(tags)
+}
+
+pub fn add_tag(conn: &Connection, message_id: i64, tag: &str) -> Result<(), DEmailError> {
+    conn.execute(
+        "INSERT OR IGNORE INTO message_flags (message_id, flag) VALUES (?1, ?2)",
+        params![message_id, tag.trim()],
+    )?;
+    Ok(())
+}
+
+pub fn remove_tag(conn: &Connection, message_id: i64, tag: &str) -> Result<(), DEmailError> {
+    conn.execute(
+        "DELETE FROM message_flags WHERE message_id = ?1 AND flag = ?2",
+        params![message_id, tag],
+    )?;
+    Ok(())
+}
+
+/// Replaces a message's tags with the ones the server reported
+pub fn set_tags(conn: &Connection, message_id: i64, tags: &[String]) -> Result<(), DEmailError> {
+    let current = message_tags(conn, message_id)?;
+    for tag in current.iter().filter(|tag| !tags.contains(tag)) {
+        remove_tag(conn, message_id, tag)?;
+    }
+    for tag in tags.iter().filter(|tag| !current.contains(tag)) {
+        add_tag(conn, message_id, tag)?;
+    }
+    Ok(())
+}
+
+/// Brings the tags of a folder's stored messages in line with `remote`, the tags the
+/// server reported per UID
+pub fn apply_remote_tags(
+    conn: &Connection,
+    folder_id: i64,
+    remote: &HashMap<u32, Vec<String>>,
+) -> Result<(), DEmailError> {
+    let mut stmt = conn.prepare("SELECT id, imap_uid FROM messages WHERE folder_id = ?1")?;
+    let messages = stmt
+        .query_map([folder_id], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<Result<Vec<(i64, u32)>, _>>()?;
+    for (message_id, uid) in messages {
+        set_tags(
+            conn,
+            message_id,
+            remote.get(&uid).map_or(&[], Vec::as_slice),
+        )?;
+    }
+    Ok(())
+}
+
+/// The account's tags with how many messages carry each, sorted by name
+pub fn list_tags(pool: &Pool, account_id: i64) -> Result<Vec<TagCount>, DEmailError> {
+    let conn = pool
+        .get()
+        .map_err(|_| DEmailError::Database(rusqlite::Error::InvalidQuery))?;
+    let mut stmt = conn.prepare(
+        "SELECT f.flag, COUNT(*), COALESCE(SUM(m.is_read = 0), 0)
+         FROM message_flags f JOIN messages m ON m.id = f.message_id
+         WHERE m.account_id = ?1
+         GROUP BY f.flag",
+    )?;
+    let mut tags = stmt
+        .query_map([account_id], |row| {
+            let tag: String = row.get(0)?;
+            Ok(TagCount {
+                name: display_name(&tag),
+                tag,
+                count: row.get(1)?,
+                unread: row.get(2)?,
+            })
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    tags.sort_by_key(|tag| tag.name.to_lowercase());
+    Ok(tags)
+}
+
+pub fn messages_with_tag(
+    pool: &Pool,
+    account_id: i64,
+    tag: &str,
+) -> Result<Vec<MessageHeader>, DEmailError> {
+    let conn = pool
+        .get()
+        .map_err(|_| DEmailError::Database(rusqlite::Error::InvalidQuery))?;
+    let mut stmt = conn.prepare(
+        "SELECT m.id, m.subject, m.from_header, m.date, m.is_read, m.has_attachments, m.is_starred
+         FROM messages m JOIN message_flags f ON f.message_id = m.id
+         WHERE m.account_id = ?1 AND f.flag = ?2
+         ORDER BY m.date DESC",
+    )?;
+    let messages = stmt
+        .query_map(params![account_id, tag], |row| {
+            Ok(MessageHeader {
+                id: row.get(0)?,
+                subject: row.get::<_, Option<String>>(1)?.unwrap_or_default(),
+                from: row.get(2)?,
+                date: row.get(3)?,
+                is_read: row.get(4)?,
+                has_attachments: row.get(5)?,
+                is_starred: row.get(6)?,
+            })
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(messages)
+}
+
+pub fn remote_message(
+    conn: &Connection,
+    message_id: i64,
+) -> Result<Option<RemoteMessage>, DEmailError> {
+    let message = conn
+        .query_row(
+            "SELECT m.account_id, f.path, m.imap_uid, m.message_id_header
+             FROM messages m JOIN folders f ON f.id = m.folder_id
+             WHERE m.id = ?1",
+            [message_id],
+            |row| {
+                Ok(RemoteMessage {
+                    account_id: row.get(0)?,
+                    folder_path: row.get(1)?,
+                    imap_uid: row.get(2)?,
+                    message_id_header: row.get::<_, Option<String>>(3)?.unwrap_or_default(),
+                })
+            },
+        )
+        .optional()?;
+    Ok(message)
+}
+
+/// Stored copies of the same message in the account, which on Gmail share its labels
+pub fn copies_of(
+    conn: &Connection,
+    account_id: i64,
+    message_id_header: &str,
+) -> Result<Vec<i64>, DEmailError> {
+    let mut stmt =
+        conn.prepare("SELECT id FROM messages WHERE account_id = ?1 AND message_id_header = ?2")?;
+    let ids = stmt
+        .query_map(params![account_id, message_id_header], |row| row.get(0))?
+        .collect::<Result<Vec<i64>, _>>()?;
+    Ok(ids)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::accounts::create_account;
+    use crate::core::cache::db::{save_message, test_folder, test_message, test_pool};
+    use crate::models::Message;
+
+    fn store_message(pool: &Pool, account_id: i64, folder_id: i64, uid: u32) -> i64 {
+        save_message(
+            pool,
+            &Message {
+                date: uid as i64,
+                is_read: uid > 1,
+                ..test_message(account_id, folder_id, uid)
+            },
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_tag_rules() {
+        assert!(is_tag("$Label1"));
+        assert!(is_tag("work"));
+        assert!(!is_tag("\\Seen"));
+        assert!(!is_tag("$Forwarded"));
+        assert!(!is_tag("NonJunk"));
+        assert_eq!(display_name("$label2"), "Work");
+        assert_eq!(display_name("receipts"), "receipts");
+
+        assert_eq!(validate_tag(" work ", false).unwrap(), "work");
+        assert!(validate_tag("to do", false).is_err());
+        assert!(validate_tag("\\Flagged", false).is_err());
+        assert_eq!(validate_tag("to do", true).unwrap(), "to do");
+        assert!(validate_tag("[Gmail]/Spam", true).is_err());
+        assert!(validate_tag("inbox", true).is_err());
+
+        assert!(is_gmail_label_folder("Receipts"));
+        assert!(!is_gmail_label_folder("INBOX"));
+        assert!(!is_gmail_label_folder("[Gmail]/All Mail"));
+    }
+
+    #[test]
+    fn test_remote_tags_and_counts() {
+        let pool = test_pool();
+        let account_id = create_account(&pool, "me@example.com", "Me", "google")
+            .unwrap()
+            .id;
+        let folder = test_folder(&pool, account_id, "INBOX");
+        let first = store_message(&pool, account_id, folder.id, 1);
+        let second = store_message(&pool, account_id, folder.id, 2);
+        let conn = pool.get().unwrap();
+
+        add_tag(&conn, first, "stale").unwrap();
+        let remote = HashMap::from([
+            (1, vec!["work".to_string(), "$Label1".to_string()]),
+            (2, vec!["work".to_string()]),
+        ]);
+        apply_remote_tags(&conn, folder.id, &remote).unwrap();
+        assert_eq!(message_tags(&conn, first).unwrap(), vec!["$Label1", "work"]);
+
+        let tags = list_tags(&pool, account_id).unwrap();
+        assert_eq!(
+            tags,
+            vec![
+                TagCount {
+                    tag: "$Label1".to_string(),
+                    name: "Important".to_string(),
+                    count: 1,
+                    unread: 1,
+                },
+                TagCount {
+                    tag: "work".to_string(),
+                    name: "work".to_string(),
+                    count: 2,
+                    unread: 1,
+                },
+            ]
+        );
+        let tagged = messages_with_tag(&pool, account_id, "work").unwrap();
+        assert_eq!(
+            tagged.iter().map(|m| m.id).collect::<Vec<_>>(),
+            vec![second, first]
+        );
+
+        remove_tag(&conn, second, "work").unwrap();
+        assert!(message_tags(&conn, second).unwrap().is_empty());
+        let remote = remote_message(&conn, first).unwrap().unwrap();
+        assert_eq!((remote.folder_path.as_str(), remote.imap_uid), ("INBOX", 1));
+    }
+}
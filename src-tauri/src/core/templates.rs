@@ -0,0 +1,581 @@
+// Templates module - Canned responses kept apart from drafts: a subject, body and files
+// with `{{placeholders}}` that are filled from the message being answered or typed in
+// when a draft is made from the template. Templates move between installations as
+// JSON files.
+
+use crate::core::attachments::read_attachment_file;
+use crate::core::cache::db::{write_draft, Pool};
+use crate::core::contacts::parse_email_address;
+use crate::core::reply_forward::{prepare_compose, ComposeMode};
+use crate::core::signatures::fill_placeholders;
+use crate::error::DEmailError;
+use crate::models::{Draft, Message};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Version written to exported template files
+const EXPORT_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TemplateAttachment {
+    pub id: i64,
+    pub template_id: i64,
+    pub filename: String,
+    pub mime_type: String,
+    pub size_bytes: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MessageTemplate {
+    pub id: i64,
+    /// Offered for every account when unset
+    pub account_id: Option<i64>,
+    pub name: String,
+    pub subject: String,
+    pub body: String,
+    /// Read-only here; files are added with `add_template_attachment`
+    #[serde(default)]
+    pub attachments: Vec<TemplateAttachment>,
+    #[serde(default)]
+    pub updated_at: i64,
+}
+
+/// A placeholder of a template and, when known, the value it will be filled with
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Placeholder {
+    pub name: String,
+    pub value: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct TemplateFile {
+    version: u32,
+    templates: Vec<ExportedTemplate>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ExportedTemplate {
+    name: String,
+    subject: String,
+    body: String,
+    #[serde(default)]
+    attachments: Vec<ExportedAttachment>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ExportedAttachment {
+    filename: String,
+    mime_type: String,
+    /// Base64
+    data: String,
+}
+
+fn template_attachments(
+    conn: &Connection,
+    template_id: i64,
+) -> Result<Vec<TemplateAttachment>, DEmailError> {
+    let mut stmt = conn.prepare(
+        "SELECT id, template_id, filename, mime_type, LENGTH(data)
+         FROM template_attachments WHERE template_id = ?1 ORDER BY id",
+    )?;
+    let attachments = stmt
+        .query_map([template_id], |row| {
+            Ok(TemplateAttachment {
+                id: row.get(0)?,
+                template_id: row.get(1)?,
+                filename: row.get(2)?,
+                mime_type: row.get(3)?,
+                size_bytes: row.get(4)?,
+            })
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(attachments)
+}
+
+fn row_to_template(row: &rusqlite::Row) -> rusqlite::Result<MessageTemplate> {
+    Ok(MessageTemplate {
+        id: row.get(0)?,
+        account_id: row.get(1)?,
+        name: row.get(2)?,
+        subject: row.get(3)?,
+        body: row.get(4)?,
+        attachments: Vec::new(),
+        updated_at: row.get(5)?,
+    })
+}
+
+/// Templates for an account, including those shared by all accounts; every template
+/// when `account_id` is `None`
+pub fn list_templates(
+    conn: &Connection,
+    account_id: Option<i64>,
+) -> Result<Vec<MessageTemplate>, DEmailError> {
+    let mut stmt = conn.prepare(
+        "SELECT id, account_id, name, subject, body, updated_at FROM templates
+         WHERE ?1 IS NULL OR account_id IS NULL OR account_id = ?1
+         ORDER BY name COLLATE NOCASE",
+    )?;
+    let mut templates = stmt
+        .query_map([account_id], row_to_template)?
+        .collect::<Result<Vec<_>, _>>()?;
+    for template in &mut templates {
+        template.attachments = template_attachments(conn, template.id)?;
+    }
+    Ok(templates)
+}
+
+pub fn get_template(conn: &Connection, template_id: i64) -> Result<MessageTemplate, DEmailError> {
+    let mut template = conn
+        .query_row(
+            "SELECT id, account_id, name, subject, body, updated_at FROM templates WHERE id = ?1",
+            [template_id],
+            row_to_template,
+        )
+        .optional()?
+        .ok_or_else(|| DEmailError::NotFound(format!("Template {} not found", template_id)))?;
+    template.attachments = template_attachments(conn, template_id)?;
+    Ok(template)
+}
+
+/// Creates or updates a template's text; its attachments are left as they are
+pub fn save_template(conn: &Connection, template: &MessageTemplate) -> Result<i64, DEmailError> {
+    if template.name.trim().is_empty() {
+        return Err(DEmailError::Validation(
+            "A template needs a name".to_string(),
+        ));
+    }
+    let now = chrono::Utc::now().timestamp();
+
+    if template.id == 0 {
+        conn.execute(
+            "INSERT INTO templates (account_id, name, subject, body, created_at, updated_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?5)",
+            params![
+                template.account_id,
+                template.name.trim(),
+                template.subject,
+                template.body,
+                now
+            ],
+        )?;
+        Ok(conn.last_insert_rowid())
+    } else {
+        let updated = conn.execute(
+            "UPDATE templates SET account_id = ?1, name = ?2, subject = ?3, body = ?4,
+                 updated_at = ?5
+             WHERE id = ?6",
+            params![
+                template.account_id,
+                template.name.trim(),
+                template.subject,
+                template.body,
+                now,
+                template.id
+            ],
+        )?;
+        if updated == 0 {
+            return Err(DEmailError::NotFound(format!(
+                "Template {} not found",
+                template.id
+            )));
+        }
+        Ok(template.id)
+    }
+}
+
+pub fn delete_template(conn: &Connection, template_id: i64) -> Result<(), DEmailError> {
+    conn.execute(
+        "DELETE FROM template_attachments WHERE template_id = ?1",
+        [template_id],
+    )?;
+    conn.execute("DELETE FROM templates WHERE id = ?1", [template_id])?;
+    Ok(())
+}
+
+fn insert_attachment(
+    conn: &Connection,
+    template_id: i64,
+    filename: &str,
+    mime_type: &str,
+    data: &[u8],
+) -> Result<i64, DEmailError> {
+    conn.execute(
+        "INSERT INTO template_attachments (template_id, filename, mime_type, data)
+         VALUES (?1, ?2, ?3, ?4)",
+        params![template_id, filename, mime_type, data],
+    )?;
+    Ok(conn.last_insert_rowid())
+}
+
+/// Stores a copy of a file with the template
+pub fn add_template_attachment(
+    conn: &Connection,
+    template_id: i64,
+    file_path: &str,
+) -> Result<i64, DEmailError> {
+    get_template(conn, template_id)?;
+    let (filename, mime_type, data) = read_attachment_file(file_path)?;
+    insert_attachment(conn, template_id, &filename, &mime_type, &data)
+}
+
+pub fn remove_template_attachment(
+    conn: &Connection,
+    attachment_id: i64,
+) -> Result<(), DEmailError> {
+    conn.execute(
+        "DELETE FROM template_attachments WHERE id = ?1",
+        [attachment_id],
+    )?;
+    Ok(())
+}
+
+/// The placeholder names used in a template's subject and body, in order of appearance
+pub fn placeholder_names(template: &MessageTemplate) -> Vec<String> {
+    let mut names: Vec<String> = Vec::new();
+    for text in [&template.subject, &template.body] {
+        fill_placeholders(text, |name| {
+            if !names.iter().any(|known| known == name) {
+                names.push(name.to_string());
+            }
+            None
+        });
+    }
+    names
+}
+
+/// Values known from the message being replied to: the sender's name and address, as
+/// parsed from its From header, and the original subject
+pub fn reply_values(original: &Message) -> HashMap<String, String> {
+    let mut values = HashMap::new();
+    if let Some((email, name)) = parse_email_address(&original.from_header) {
+        if let Some(name) = name {
+            let mut parts = name.split_whitespace();
+            if let Some(first) = parts.next() {
+                values.insert("first_name".to_string(), first.to_string());
+            }
+            if let Some(last) = parts.last() {
+                values.insert("last_name".to_string(), last.to_string());
+            }
+            values.insert("name".to_string(), name);
+        }
+        values.insert("email".to_string(), email);
+    }
+    values.insert("subject".to_string(), original.subject.clone());
+    values
+}
+
+/// Lists a template's placeholders with the values the original message provides; those
+/// left without a value are for the user to fill in
+pub fn template_placeholders(
+    conn: &Connection,
+    template_id: i64,
+    original: Option<&Message>,
+) -> Result<Vec<Placeholder>, DEmailError> {
+    let template = get_template(conn, template_id)?;
+    let known = original.map(reply_values).unwrap_or_default();
+    Ok(placeholder_names(&template)
+        .into_iter()
+        .map(|name| Placeholder {
+            value: known.get(&name).cloned(),
+            name,
+        })
+        .collect())
+}
+
+/// Makes a draft from a template, as a reply to `original` when given. Placeholders take
+/// the user's `values` first, then what the original message provides; unknown ones are
+/// left as written. Replies keep the reply subject, quote and signature, with the
+/// template's body on top; attachments are copied to the draft.
+pub fn create_draft(
+    pool: &Pool,
+    account_id: i64,
+    template_id: i64,
+    original: Option<&Message>,
+    values: &HashMap<String, String>,
+) -> Result<Draft, DEmailError> {
+    let template = {
+        let conn = pool
+            .get()
+            .map_err(|_| DEmailError::Database(rusqlite::Error::InvalidQuery))?;
+        get_template(&conn, template_id)?
+    };
+    if template
+        .account_id
+        .is_some_and(|template_account| template_account != account_id)
+    {
+        return Err(DEmailError::Validation(format!(
+            "Template {} belongs to another account",
+            template_id
+        )));
+    }
+
+    let known = original.map(reply_values).unwrap_or_default();
+    let lookup = |name: &str| {
+        values
+            .get(name)
+            .filter(|value| !value.is_empty())
+            .or_else(|| known.get(name))
+            .cloned()
+    };
+    let mode = if original.is_some() {
+        ComposeMode::Reply
+    } else {
+        ComposeMode::New
+    };
+    let compose = prepare_compose(pool, account_id, mode, original, None)?;
+
+    let mut draft = Draft {
+        id: 0,
+        account_id,
+        to_addresses: compose.to,
+        cc_addresses: compose.cc,
+        bcc_addresses: None,
+        subject: match original {
+            Some(_) => compose.subject,
+            None => fill_placeholders(&template.subject, lookup),
+        },
+        body_plain: Some(format!(
+            "{}{}",
+            fill_placeholders(&template.body, lookup).trim_end(),
+            compose.body
+        )),
+        body_html: None,
+        created_at: 0,
+        updated_at: 0,
+        revision: 0,
+        conflict: false,
+    };
+    let mut conn = pool
+        .get()
+        .map_err(|_| DEmailError::Database(rusqlite::Error::InvalidQuery))?;
+    // The draft and its copied attachments are saved together or not at all
+    let tx = conn.transaction()?;
+    draft.id = write_draft(&tx, &draft)?;
+    tx.execute(
+        "INSERT INTO draft_attachments (draft_id, filename, mime_type, data)
+         SELECT ?1, filename, mime_type, data FROM template_attachments
+         WHERE template_id = ?2 ORDER BY id",
+        params![draft.id, template_id],
+    )?;
+    let (created_at, updated_at, revision) = tx.query_row(
+        "SELECT created_at, updated_at, revision FROM drafts WHERE id = ?1",
+        [draft.id],
+        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+    )?;
+    tx.commit()?;
+    draft.created_at = created_at;
+    draft.updated_at = updated_at;
+    draft.revision = revision;
+    Ok(draft)
+}
+
+/// Writes templates, with their attachments, to a JSON file. Returns how many were written.
+pub fn export_templates(
+    conn: &Connection,
+    account_id: Option<i64>,
+    path: &str,
+) -> Result<usize, DEmailError> {
+    let mut templates = Vec::new();
+    for template in list_templates(conn, account_id)? {
+        let mut stmt = conn.prepare(
+            "SELECT filename, mime_type, data FROM template_attachments
+             WHERE template_id = ?1 ORDER BY id",
+        )?;
+        let attachments = stmt
+            .query_map([template.id], |row| {
+                Ok(ExportedAttachment {
+                    filename: row.get(0)?,
+                    mime_type: row.get(1)?,
+                    data: openssl::base64::encode_block(&row.get::<_, Vec<u8>>(2)?),
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        templates.push(ExportedTemplate {
+            name: template.name,
+            subject: template.subject,
+            body: template.body,
+            attachments,
+        });
+    }
+
+    let count = templates.len();
+    let file = TemplateFile {
+        version: EXPORT_VERSION,
+        templates,
+    };
+    std::fs::write(path, serde_json::to_string_pretty(&file)?)?;
+    Ok(count)
+}
+
+/// Adds the templates of an exported file, for one account or all when `account_id` is
+/// `None`. Returns how many were imported.
+pub fn import_templates(
+    conn: &Connection,
+    account_id: Option<i64>,
+    path: &str,
+) -> Result<usize, DEmailError> {
+    let file: TemplateFile = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+    if file.version > EXPORT_VERSION {
+        return Err(DEmailError::Validation(format!(
+            "Template file version {} is newer than this version of DEmail supports",
+            file.version
+        )));
+    }
+
+    let tx = conn.unchecked_transaction()?;
+    let count = file.templates.len();
+    for exported in file.templates {
+        let template_id = save_template(
+            &tx,
+            &MessageTemplate {
+                id: 0,
+                account_id,
+                name: exported.name,
+                subject: exported.subject,
+                body: exported.body,
+                attachments: Vec::new(),
+                updated_at: 0,
+            },
+        )?;
+        for attachment in exported.attachments {
+            let data = openssl::base64::decode_block(&attachment.data).map_err(|e| {
+                DEmailError::Validation(format!(
+                    "Attachment {} is not valid base64: {}",
+                    attachment.filename, e
+                ))
+            })?;
+            insert_attachment(
+                &tx,
+                template_id,
+                &attachment.filename,
+                &attachment.mime_type,
+                &data,
+            )?;
+        }
+    }
+    tx.commit()?;
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::accounts::create_account;
+    use crate::core::cache::db::{save_message, test_folder, test_message, test_pool};
+    use crate::core::drafts::list_draft_attachments;
+
+    fn original(pool: &Pool, account_id: i64) -> Message {
+        let folder = test_folder(pool, account_id, "INBOX");
+        let mut message = Message {
+            message_id_header: "<q@example.com>".to_string(),
+            from_header: "\"Ada Lovelace\" <ada@example.net>".to_string(),
+            to_header: "support@example.com".to_string(),
+            subject: "Printer on fire".to_string(),
+            date: 1_700_000_000,
+            body_plain: Some("Help!".to_string()),
+            ..test_message(account_id, folder.id, 1)
+        };
+        message.id = save_message(pool, &message).unwrap();
+        message
+    }
+
+    #[test]
+    fn test_draft_from_template_fills_placeholders() {
+        let pool = test_pool();
+        let account = create_account(&pool, "support@example.com", "Support", "google").unwrap();
+        let original = original(&pool, account.id);
+        let conn = pool.get().unwrap();
+        let template_id = save_template(
+            &conn,
+            &MessageTemplate {
+                id: 0,
+                account_id: None,
+                name: "Fire".to_string(),
+                subject: "Ticket {{ticket}}".to_string(),
+                body: "Hi {{first_name}},\n\nTicket {{ticket}} is {{status}}.\n".to_string(),
+                attachments: Vec::new(),
+                updated_at: 0,
+            },
+        )
+        .unwrap();
+        insert_attachment(&conn, template_id, "steps.txt", "text/plain", b"unplug").unwrap();
+
+        let placeholders = template_placeholders(&conn, template_id, Some(&original)).unwrap();
+        assert_eq!(
+            placeholders,
+            vec![
+                Placeholder {
+                    name: "ticket".to_string(),
+                    value: None
+                },
+                Placeholder {
+                    name: "first_name".to_string(),
+                    value: Some("Ada".to_string())
+                },
+                Placeholder {
+                    name: "status".to_string(),
+                    value: None
+                },
+            ]
+        );
+        drop(conn);
+
+        let values = HashMap::from([("ticket".to_string(), "#42".to_string())]);
+        let draft = create_draft(&pool, account.id, template_id, Some(&original), &values).unwrap();
+        assert_eq!(draft.to_addresses, original.from_header);
+        assert_eq!(draft.subject, "Re: Printer on fire");
+        let body = draft.body_plain.unwrap();
+        assert!(body.starts_with("Hi Ada,\n\nTicket #42 is {{status}}.\n\n"));
+        assert!(body.contains("> Help!"));
+
+        let conn = pool.get().unwrap();
+        let attachments = list_draft_attachments(&conn, draft.id).unwrap();
+        assert_eq!(attachments.len(), 1);
+        assert_eq!(attachments[0].size_bytes, 6);
+
+        // A new message keeps the template's own subject
+        let draft = create_draft(&pool, account.id, template_id, None, &values).unwrap();
+        assert_eq!(draft.subject, "Ticket #42");
+    }
+
+    #[test]
+    fn test_export_import_roundtrip() {
+        let pool = test_pool();
+        let conn = pool.get().unwrap();
+        let template_id = save_template(
+            &conn,
+            &MessageTemplate {
+                id: 0,
+                account_id: None,
+                name: "Refund".to_string(),
+                subject: "Your refund".to_string(),
+                body: "Refunded.".to_string(),
+                attachments: Vec::new(),
+                updated_at: 0,
+            },
+        )
+        .unwrap();
+        insert_attachment(
+            &conn,
+            template_id,
+            "policy.pdf",
+            "application/pdf",
+            &[0, 1, 2],
+        )
+        .unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("templates.json");
+        let path = path.to_str().unwrap();
+        assert_eq!(export_templates(&conn, None, path).unwrap(), 1);
+        assert_eq!(import_templates(&conn, None, path).unwrap(), 1);
+
+        let templates = list_templates(&conn, None).unwrap();
+        assert_eq!(templates.len(), 2);
+        let imported = templates.iter().find(|t| t.id != template_id).unwrap();
+        assert_eq!(imported.body, "Refunded.");
+        assert_eq!(imported.attachments[0].filename, "policy.pdf");
+        assert_eq!(imported.attachments[0].size_bytes, 3);
+    }
+}
@@ -81,6 +81,9 @@ pub fn get_thread_messages(conn: &Connection, thread_id: i64) -> Result<Vec<Mess
             is_starred: row.get(14)?,
             thread_id: row.get(15)?,
             attachments: Vec::new(), // Loaded separately if needed
+            security: None,
+            authentication: None,
+            remote_content: None,
         })
     })?;
 
@@ -0,0 +1,206 @@
+// Token manager - Caches OAuth access tokens, persists rotated refresh tokens and
+// flags accounts whose refresh token was revoked
+
+use crate::core::accounts::{get_refresh_token, set_needs_reauth, store_refresh_token};
+use crate::core::cache::db::Pool;
+use crate::error::DEmailError;
+use oauth2::basic::{BasicClient, BasicErrorResponse, BasicErrorResponseType};
+use oauth2::reqwest::async_http_client;
+use oauth2::{AccessToken, RefreshToken, RequestTokenError, TokenResponse};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+
+/// Tokens are refreshed this long before they expire
+const EXPIRY_MARGIN: Duration = Duration::from_secs(120);
+/// Lifetime assumed when the provider does not send `expires_in`
+const DEFAULT_LIFETIME: Duration = Duration::from_secs(3600);
+
+struct CachedToken {
+    access_token: AccessToken,
+    expires_at: Instant,
+}
+
+impl CachedToken {
+    fn new(access_token: AccessToken, expires_in: Option<Duration>, now: Instant) -> Self {
+        Self {
+            access_token,
+            expires_at: now + expires_in.unwrap_or(DEFAULT_LIFETIME),
+        }
+    }
+
+    fn is_fresh(&self, now: Instant) -> bool {
+        now + EXPIRY_MARGIN < self.expires_at
+    }
+}
+
+/// An account's cached token. Its lock is held across the refresh so concurrent callers
+/// for the same account wait for one exchange instead of racing a rotating refresh token.
+type TokenSlot = Arc<Mutex<Option<CachedToken>>>;
+
+/// Hands out access tokens per account, shared through `AppState`
+#[derive(Default)]
+pub struct TokenManager {
+    accounts: std::sync::Mutex<HashMap<i64, TokenSlot>>,
+}
+
+impl TokenManager {
+    fn slot(&self, account_id: i64) -> TokenSlot {
+        self.accounts
+            .lock()
+            .unwrap()
+            .entry(account_id)
+            .or_default()
+            .clone()
+    }
+
+    /// Returns a cached access token, or exchanges the refresh token for a new one
+    pub async fn access_token(
+        &self,
+        pool: &Pool,
+        account_id: i64,
+        client: &BasicClient,
+    ) -> Result<AccessToken, DEmailError> {
+        let slot = self.slot(account_id);
+        let mut cache = slot.lock().await;
+        if let Some(cached) = cache.as_ref() {
+            if cached.is_fresh(Instant::now()) {
+                return Ok(cached.access_token.clone());
+            }
+        }
+
+        let refresh_token = get_refresh_token(account_id)?;
+        let token_result = match client
+            .exchange_refresh_token(&RefreshToken::new(refresh_token.clone()))
+            .request_async(async_http_client)
+            .await
+        {
+            Ok(token_result) => token_result,
+            Err(e) => {
+                *cache = None;
+                if is_invalid_grant(&e) {
+                    warn!("Refresh token for account {} was rejected", account_id);
+                    set_needs_reauth(pool, account_id, true)?;
+                    return Err(DEmailError::Auth(format!(
+                        "Account {} must be re-authenticated",
+                        account_id
+                    )));
+                }
+                return Err(DEmailError::OAuth(e.to_string()));
+            }
+        };
+
+        // Providers that rotate refresh tokens invalidate the old one
+        if let Some(rotated) = token_result.refresh_token() {
+            if rotated.secret() != &refresh_token {
+                store_refresh_token(account_id, rotated.secret())?;
+                info!("Stored rotated refresh token for account {}", account_id);
+            }
+        }
+        set_needs_reauth(pool, account_id, false)?;
+
+        let cached = CachedToken::new(
+            token_result.access_token().clone(),
+            token_result.expires_in(),
+            Instant::now(),
+        );
+        let access_token = cached.access_token.clone();
+        *cache = Some(cached);
+        Ok(access_token)
+    }
+
+    /// Drops the cached access token, e.g. after re-authentication or account removal.
+    /// A refresh still running for the account only fills the slot being dropped here.
+    pub fn invalidate(&self, account_id: i64) {
+        self.accounts.lock().unwrap().remove(&account_id);
+    }
+
+    /// Drops every cached access token, as when a restored backup replaced the accounts
+    pub fn clear(&self) {
+        self.accounts.lock().unwrap().clear();
+    }
+}
+
+/// Whether the token endpoint rejected the refresh token itself (revoked, expired or
+/// already rotated) rather than failing for a transient reason
+fn is_invalid_grant<RE: std::error::Error + 'static>(
+    error: &RequestTokenError<RE, BasicErrorResponse>,
+) -> bool {
+    matches!(
+        error,
+        RequestTokenError::ServerResponse(response)
+            if *response.error() == BasicErrorResponseType::InvalidGrant
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cached_token_expiry() {
+        let now = Instant::now();
+        let token = CachedToken::new(
+            AccessToken::new("token".to_string()),
+            Some(Duration::from_secs(3600)),
+            now,
+        );
+        assert!(token.is_fresh(now));
+        assert!(token.is_fresh(now + Duration::from_secs(3000)));
+        // Refreshed shortly before the provider's expiry, not after
+        assert!(!token.is_fresh(now + Duration::from_secs(3500)));
+
+        let short = CachedToken::new(
+            AccessToken::new("token".to_string()),
+            Some(Duration::from_secs(60)),
+            now,
+        );
+        assert!(!short.is_fresh(now));
+
+        let unknown = CachedToken::new(AccessToken::new("token".to_string()), None, now);
+        assert_eq!(unknown.expires_at, now + DEFAULT_LIFETIME);
+    }
+
+    #[tokio::test]
+    async fn test_accounts_lock_independently() {
+        let manager = TokenManager::default();
+        let first = manager.slot(1);
+        let _refreshing = first.lock().await;
+        // Another account's token is not held up by a refresh of the first one
+        assert!(manager.slot(2).try_lock().is_ok());
+        assert!(manager.slot(1).try_lock().is_err());
+
+        manager.invalidate(1);
+        assert!(manager.slot(1).try_lock().unwrap().is_none());
+        assert!(!Arc::ptr_eq(&first, &manager.slot(1)));
+
+        let second = manager.slot(2);
+        manager.clear();
+        assert!(!Arc::ptr_eq(&second, &manager.slot(2)));
+    }
+
+    #[test]
+    fn test_invalid_grant_detection() {
+        let invalid_grant: RequestTokenError<std::io::Error, BasicErrorResponse> =
+            RequestTokenError::ServerResponse(BasicErrorResponse::new(
+                BasicErrorResponseType::InvalidGrant,
+                Some("Token has been expired or revoked.".to_string()),
+                None,
+            ));
+        assert!(is_invalid_grant(&invalid_grant));
+
+        let invalid_client: RequestTokenError<std::io::Error, BasicErrorResponse> =
+            RequestTokenError::ServerResponse(BasicErrorResponse::new(
+                BasicErrorResponseType::InvalidClient,
+                None,
+                None,
+            ));
+        assert!(!is_invalid_grant(&invalid_client));
+
+        let transport: RequestTokenError<std::io::Error, BasicErrorResponse> =
+            RequestTokenError::Request(std::io::Error::other("connection reset"));
+        assert!(!is_invalid_grant(&transport));
+    }
+}
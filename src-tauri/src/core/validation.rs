@@ -6,6 +6,8 @@ const MAX_SUBJECT_LENGTH: usize = 998; // RFC 2822 limit
 const MAX_BODY_LENGTH: usize = 10_000_000; // 10MB limit for email body
 const MAX_TO_ADDRESSES_LENGTH: usize = 2000; // Multiple email addresses
 const MAX_FILENAME_LENGTH: usize = 255; // Most filesystems limit
+const MIN_PASSPHRASE_LENGTH: usize = 8;
+const MAX_PASSPHRASE_LENGTH: usize = 1024;
 
 /// Validates an email address using RFC 5322 standards
 pub fn validate_email(email: &str) -> Result<(), DEmailError> {
@@ -238,6 +240,27 @@ pub fn validate_search_query(query: &str) -> Result<(), DEmailError> {
     Ok(())
 }
 
+/// Validates a passphrase used to encrypt local data
+pub fn validate_passphrase(passphrase: &str) -> Result<(), DEmailError> {
+    let length = passphrase.chars().count();
+
+    if length < MIN_PASSPHRASE_LENGTH {
+        return Err(DEmailError::Validation(format!(
+            "Passphrase too short: {} characters (min {})",
+            length, MIN_PASSPHRASE_LENGTH
+        )));
+    }
+
+    if length > MAX_PASSPHRASE_LENGTH {
+        return Err(DEmailError::Validation(format!(
+            "Passphrase too long: {} characters (max {})",
+            length, MAX_PASSPHRASE_LENGTH
+        )));
+    }
+
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -315,4 +338,13 @@ mod tests {
         assert!(validate_search_query("DROP TABLE--").is_err());
         assert!(validate_search_query("/* comment */").is_err());
     }
+
+    #[test]
+    fn test_validate_passphrase() {
+        assert!(validate_passphrase("correct horse").is_ok());
+        assert!(validate_passphrase("12345678").is_ok());
+        assert!(validate_passphrase("short").is_err());
+        assert!(validate_passphrase("").is_err());
+        assert!(validate_passphrase(&"A".repeat(1025)).is_err());
+    }
 }
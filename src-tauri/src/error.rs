@@ -29,6 +29,14 @@ pub enum DEmailError {
     Auth(String),
     #[error("PDF generation error: {0}")]
     Pdf(String),
+    #[error("Backup error: {0}")]
+    Backup(String),
+    #[error("Crypto error: {0}")]
+    Crypto(String),
+    #[error("Database encryption error: {0}")]
+    Encryption(String),
+    #[error("Sieve error: {0}")]
+    Sieve(String),
     #[error("Validation error: {0}")]
     Validation(String),
     #[error("Unknown error: {0}")]
@@ -8,11 +8,20 @@ mod core;
 mod error;
 mod models;
 
+use crate::core::app_lock::{self, AppLock};
 use crate::core::auth::{AppState, OAuth2StateMap};
-use crate::core::cache::db;
+use crate::core::cache::{db, encryption};
+use crate::core::config;
+use crate::core::remote_content;
 use crate::core::sync::background_sync;
+use crate::core::sync::draft_sync::DraftSyncState;
+use crate::core::sync::reminders;
+use crate::core::sync::imap_pool::ImapPool;
+use crate::core::sync::scheduler::SyncScheduler;
+use crate::core::tokens::TokenManager;
 use std::collections::HashMap;
 use std::sync::Arc;
+use tauri::http::ResponseBuilder;
 use tauri::{Manager, RunEvent};
 
 fn main() {
@@ -30,23 +39,59 @@ fn main() {
             let handle = app.handle();
 
             let app_config = app.config();
+
+            // Client secrets saved in plaintext by earlier versions move to the keyring
+            if let Err(e) = config::migrate_plaintext_secrets(&app_config) {
+                tracing::warn!("Failed to migrate OAuth client secrets: {}", e);
+            }
+
             let db_pool =
                 db::initialize_pool(&app_config).expect("Database pool initialization failed");
 
+            // A passphrase-protected database is read once it is unlocked
+            let app_lock = AppLock::default();
+            if !encryption::is_locked() {
+                app_lock
+                    .load(&db_pool, true)
+                    .expect("App lock initialization failed");
+            }
+
             app.manage(AppState {
                 db_pool: Arc::new(db_pool),
                 app_config: Arc::new(std::sync::Mutex::new(app_config.clone())),
+                app_lock: Arc::new(app_lock),
+                token_manager: Arc::new(TokenManager::default()),
+                sync_scheduler: Arc::new(SyncScheduler::default()),
+                imap_pool: Arc::new(ImapPool::default()),
+                draft_sync: Arc::new(DraftSyncState::default()),
             });
 
-            app.manage(OAuth2StateMap(std::sync::Mutex::new(HashMap::new())));
+            app.manage::<OAuth2StateMap>(std::sync::Mutex::new(HashMap::new()));
 
             background_sync::start_background_sync(handle.clone());
+            reminders::start_reminders(handle.clone());
+            app_lock::start_idle_watcher(handle.clone());
 
             Ok(())
         })
+        // Allowed remote images are served from the local cache instead of being
+        // fetched by the webview
+        .register_uri_scheme_protocol(remote_content::PROXY_SCHEME, |app, request| {
+            let app_state = app.state::<AppState>();
+            match remote_content::serve_proxy_request(&app_state.db_pool, request.uri()) {
+                Ok((mime_type, data)) => ResponseBuilder::new()
+                    .status(200)
+                    .mimetype(&mime_type)
+                    .header("Cache-Control", "max-age=86400")
+                    .body(data),
+                Err(e) => {
+                    tracing::warn!("Remote content proxy: {}", e);
+                    ResponseBuilder::new().status(404).body(Vec::new())
+                }
+            }
+        })
         .invoke_handler(tauri::generate_handler![
             api::add_account,
-            api::handle_callback,
             api::get_accounts,
             api::delete_account,
             api::get_folders,
@@ -56,11 +101,63 @@ fn main() {
             api::start_export,
             api::save_oauth_provider_config,
             api::get_oauth_provider_configs,
+            api::list_oauth_providers,
+            api::save_custom_oauth_provider,
+            api::delete_custom_oauth_provider,
             api::mark_message_read,
             api::mark_message_unread,
             api::refresh_account,
+            api::get_sync_status,
+            api::get_sync_schedule,
+            api::set_sync_interval,
+            api::list_rules,
+            api::save_rule,
+            api::delete_rule,
+            api::dry_run_rule,
+            api::run_rules_on_folder,
+            api::list_sieve_scripts,
+            api::get_sieve_script,
+            api::put_sieve_script,
+            api::check_sieve_script,
+            api::activate_sieve_script,
+            api::delete_sieve_script,
+            api::generate_sieve_script,
+            api::list_tags,
+            api::get_message_tags,
+            api::get_messages_by_tag,
+            api::add_tag,
+            api::remove_tag,
+            api::snooze_message,
+            api::set_follow_up,
+            api::cancel_reminder,
+            api::list_reminders,
+            api::get_notification_settings,
+            api::save_notification_settings,
+            api::run_notification_action,
+            api::get_unread_count,
+            api::list_identities,
+            api::save_identity,
+            api::delete_identity,
+            api::get_reply_identity,
+            api::prepare_compose,
+            api::get_signature_settings,
+            api::save_signature_settings,
+            api::list_templates,
+            api::save_template,
+            api::delete_template,
+            api::add_template_attachment,
+            api::remove_template_attachment,
+            api::get_template_placeholders,
+            api::create_draft_from_template,
+            api::export_templates,
+            api::import_templates,
+            api::list_draft_attachments,
+            api::add_draft_attachment,
+            api::remove_draft_attachment,
+            api::sync_drafts,
             api::search_messages,
             api::save_draft,
+            api::close_draft,
             api::get_drafts,
             api::delete_draft,
             api::save_signature,
@@ -89,6 +186,35 @@ fn main() {
             api::get_frequent_contacts,
             api::get_thread_messages,
             api::get_thread,
+            api::create_backup,
+            api::restore_backup,
+            api::import_pgp_key,
+            api::list_pgp_keys,
+            api::export_pgp_key,
+            api::delete_pgp_key,
+            api::decrypt_message,
+            api::import_smime_identity,
+            api::import_smime_certificate,
+            api::list_smime_certificates,
+            api::delete_smime_certificate,
+            api::add_dkim_key,
+            api::list_dkim_keys,
+            api::delete_dkim_key,
+            api::load_remote_content,
+            api::add_remote_content_exception,
+            api::list_remote_content_exceptions,
+            api::delete_remote_content_exception,
+            api::get_database_encryption_status,
+            api::enable_database_encryption,
+            api::unlock_database,
+            api::change_database_passphrase,
+            api::get_app_lock_status,
+            api::set_app_lock_passphrase,
+            api::disable_app_lock,
+            api::set_app_lock_idle_timeout,
+            api::lock_app,
+            api::unlock_app,
+            api::record_user_activity,
         ])
         .build(context)
         .expect("error while building tauri application")
@@ -6,6 +6,9 @@ pub struct Account {
     pub email_address: String,
     pub display_name: String,
     pub provider_type: String,
+    /// Set when the provider rejected the refresh token; the account must be added again
+    #[serde(default)]
+    pub needs_reauth: bool,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -48,6 +51,91 @@ pub struct Message {
     pub is_starred: bool,
     pub thread_id: Option<i64>,
     pub attachments: Vec<Attachment>,
+    pub security: Option<MessageSecurity>,
+    pub authentication: Option<MessageAuthentication>,
+    pub remote_content: Option<RemoteContentReport>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct MessageSecurity {
+    pub message_id: i64,
+    pub protocol: String,
+    pub is_signed: bool,
+    pub signature_status: String,
+    pub signer: Option<String>,
+    pub signer_key_id: Option<String>,
+    pub is_encrypted: bool,
+    pub decryption_status: String,
+    pub details: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct MessageAuthentication {
+    pub message_id: i64,
+    pub authserv_id: Option<String>,
+    pub spf: Option<String>,
+    pub dkim: Option<String>,
+    pub dmarc: Option<String>,
+    pub arc: Option<String>,
+    pub dkim_local: Option<String>,
+    pub dkim_domain: Option<String>,
+    pub verdict: String,
+    pub warnings: Vec<SenderWarning>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct SenderWarning {
+    pub kind: String,
+    pub message: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DkimKey {
+    pub domain: String,
+    pub selector: String,
+    pub record: String,
+    pub created_at: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct RemoteContentReport {
+    pub remote_allowed: bool,
+    pub blocked_images: u32,
+    pub blocked_styles: u32,
+    pub tracking_pixels: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RemoteContentException {
+    pub id: i64,
+    pub kind: String,
+    pub value: String,
+    pub created_at: i64,
+}
+
+/// Last sync outcome of an account
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AccountSyncStatus {
+    pub account_id: i64,
+    pub email_address: String,
+    /// `idle`, `syncing` or `error`
+    pub state: String,
+    pub last_attempt_at: Option<i64>,
+    pub last_success_at: Option<i64>,
+    pub last_error: Option<String>,
+    pub last_error_at: Option<i64>,
+    /// Messages fetched by the last successful pass
+    pub messages_fetched: i64,
+    pub consecutive_failures: i64,
+}
+
+/// Progress of an account's sync pass, one event per folder
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SyncProgress {
+    pub account_id: i64,
+    pub folder: String,
+    pub folders_done: usize,
+    pub folders_total: usize,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -63,9 +151,18 @@ pub struct Attachment {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct OAuthProviderConfig {
     pub client_id: String,
+    /// Kept in the OS keyring; never written to `demail_config.json` or sent to the frontend
+    #[serde(default, skip_serializing)]
     pub client_secret: String,
 }
 
+/// Provider config as returned to the frontend
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OAuthProviderInfo {
+    pub client_id: String,
+    pub has_client_secret: bool,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Draft {
     pub id: i64,
@@ -78,6 +175,13 @@ pub struct Draft {
     pub body_html: Option<String>,
     pub created_at: i64,
     pub updated_at: i64,
+    /// Counts changes to the draft. An editor sends back the revision it loaded, so a
+    /// version imported from the server in the meantime is not overwritten.
+    #[serde(default)]
+    pub revision: i64,
+    /// The version from another device, kept next to a local edit made at the same time
+    #[serde(default)]
+    pub conflict: bool,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
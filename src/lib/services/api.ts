@@ -1,22 +1,50 @@
 import { invoke } from '@tauri-apps/api/tauri';
 import type {
   Account,
+  AccountSyncStatus,
   Folder,
   Message,
   MessageHeader,
   OAuthProviderConfig,
+  OAuthProviderInfo,
+  ProviderDefinition,
+  SignInPrompt,
+  SyncSchedule,
+  Rule,
+  RuleMatch,
+  SieveOverview,
+  VacationSettings,
+  TagCount,
+  Reminder,
+  NotificationSettings,
+  NotificationAction,
+  UnreadCount,
+  Identity,
+  SignatureSettings,
+  ComposeMode,
+  ComposeData,
+  MessageTemplate,
+  TemplatePlaceholder,
+  DraftAttachment,
   Draft,
+  DraftSyncReport,
   EmailSignature,
   Contact,
   Thread,
+  BackupManifest,
+  PgpKeyInfo,
+  SmimeCertificateInfo,
+  DkimKey,
+  RemoteContentException,
+  RemoteContentExceptionKind,
+  OutgoingSecurity,
+  DatabaseEncryptionMode,
+  DatabaseEncryptionStatus,
+  AppLockStatus,
 } from '../types';
 
-export const addAccount = (emailAddress: string): Promise<string> => {
-  return invoke('add_account', { emailAddress });
-};
-
-export const handleCallback = (code: string, state: string): Promise<Account> => {
-  return invoke('handle_callback', { code, state });
+export const addAccount = (emailAddress: string, deviceCode = false): Promise<SignInPrompt> => {
+  return invoke('add_account', { emailAddress, deviceCode });
 };
 
 export const getAccounts = (): Promise<Account[]> => {
@@ -34,10 +62,24 @@ export const saveOauthProviderConfig = (
   return invoke('save_oauth_provider_config', { provider, config });
 };
 
-export const getOauthProviderConfigs = (): Promise<Record<string, OAuthProviderConfig>> => {
+export const getOauthProviderConfigs = (): Promise<Record<string, OAuthProviderInfo>> => {
   return invoke('get_oauth_provider_configs');
 };
 
+export const listOauthProviders = (): Promise<ProviderDefinition[]> => {
+  return invoke('list_oauth_providers');
+};
+
+export const saveCustomOauthProvider = (
+  provider: ProviderDefinition
+): Promise<ProviderDefinition> => {
+  return invoke('save_custom_oauth_provider', { provider });
+};
+
+export const deleteCustomOauthProvider = (providerId: string): Promise<void> => {
+  return invoke('delete_custom_oauth_provider', { providerId });
+};
+
 export const getFolders = (accountId: number): Promise<Folder[]> => {
   return invoke('get_folders', { accountId });
 };
@@ -54,9 +96,12 @@ export const sendEmail = (
   accountId: number,
   to: string,
   subject: string,
-  body: string
+  body: string,
+  security: OutgoingSecurity | null = null,
+  identityId: number | null = null,
+  draftId: number | null = null
 ): Promise<void> => {
-  return invoke('send_email', { accountId, to, subject, body });
+  return invoke('send_email', { accountId, to, subject, body, security, identityId, draftId });
 };
 
 export const startExport = (accountId: number, destinationPath: string): Promise<void> => {
@@ -75,6 +120,261 @@ export const refreshAccount = (accountId: number): Promise<void> => {
   return invoke('refresh_account', { accountId });
 };
 
+export const getSyncStatus = (): Promise<AccountSyncStatus[]> => {
+  return invoke('get_sync_status');
+};
+
+export const getSyncSchedule = (): Promise<SyncSchedule> => {
+  return invoke('get_sync_schedule');
+};
+
+/** Omit accountId to set the default; minutes null makes an account use the default */
+export const setSyncInterval = (
+  accountId: number | null,
+  minutes: number | null
+): Promise<void> => {
+  return invoke('set_sync_interval', { accountId, minutes });
+};
+
+// ==================== Rules ====================
+
+/** Omit accountId to list the rules of every account */
+export const listRules = (accountId: number | null = null): Promise<Rule[]> => {
+  return invoke('list_rules', { accountId });
+};
+
+/** Creates the rule when its id is 0 */
+export const saveRule = (rule: Rule): Promise<Rule> => {
+  return invoke('save_rule', { rule });
+};
+
+export const deleteRule = (ruleId: number): Promise<void> => {
+  return invoke('delete_rule', { ruleId });
+};
+
+export const dryRunRule = (rule: Rule, folderId: number): Promise<RuleMatch[]> => {
+  return invoke('dry_run_rule', { rule, folderId });
+};
+
+/** Returns how many messages matched; omit ruleId to run every enabled rule */
+export const runRulesOnFolder = (folderId: number, ruleId: number | null = null): Promise<number> => {
+  return invoke('run_rules_on_folder', { folderId, ruleId });
+};
+
+// ==================== Sieve ====================
+
+export const listSieveScripts = (accountId: number): Promise<SieveOverview> => {
+  return invoke('list_sieve_scripts', { accountId });
+};
+
+export const getSieveScript = (accountId: number, name: string): Promise<string> => {
+  return invoke('get_sieve_script', { accountId, name });
+};
+
+export const putSieveScript = (
+  accountId: number,
+  name: string,
+  script: string,
+  activate: boolean
+): Promise<void> => {
+  return invoke('put_sieve_script', { accountId, name, script, activate });
+};
+
+/** Resolves with the server's warnings, if any; rejects with its error */
+export const checkSieveScript = (accountId: number, script: string): Promise<string | null> => {
+  return invoke('check_sieve_script', { accountId, script });
+};
+
+/** Pass null to deactivate all scripts */
+export const activateSieveScript = (accountId: number, name: string | null): Promise<void> => {
+  return invoke('activate_sieve_script', { accountId, name });
+};
+
+export const deleteSieveScript = (accountId: number, name: string): Promise<void> => {
+  return invoke('delete_sieve_script', { accountId, name });
+};
+
+export const generateSieveScript = (
+  accountId: number,
+  vacation: VacationSettings | null
+): Promise<string> => {
+  return invoke('generate_sieve_script', { accountId, vacation });
+};
+
+// ==================== Tags ====================
+
+export const listTags = (accountId: number): Promise<TagCount[]> => {
+  return invoke('list_tags', { accountId });
+};
+
+export const getMessageTags = (messageId: number): Promise<string[]> => {
+  return invoke('get_message_tags', { messageId });
+};
+
+export const getMessagesByTag = (accountId: number, tag: string): Promise<MessageHeader[]> => {
+  return invoke('get_messages_by_tag', { accountId, tag });
+};
+
+export const addTag = (messageId: number, tag: string): Promise<string[]> => {
+  return invoke('add_tag', { messageId, tag });
+};
+
+export const removeTag = (messageId: number, tag: string): Promise<string[]> => {
+  return invoke('remove_tag', { messageId, tag });
+};
+
+// ==================== Reminders ====================
+
+/** Hides a message until `until` (Unix timestamp), when it returns unread */
+export const snoozeMessage = (messageId: number, until: number): Promise<Reminder> => {
+  return invoke('snooze_message', { messageId, until });
+};
+
+/** Reminds about a sent message at `dueAt` unless a reply arrives first */
+export const setFollowUp = (messageId: number, dueAt: number): Promise<Reminder> => {
+  return invoke('set_follow_up', { messageId, dueAt });
+};
+
+export const cancelReminder = (reminderId: number): Promise<void> => {
+  return invoke('cancel_reminder', { reminderId });
+};
+
+/** Omit accountId to list the reminders of every account */
+export const listReminders = (accountId: number | null = null): Promise<Reminder[]> => {
+  return invoke('list_reminders', { accountId });
+};
+
+// ==================== Notifications ====================
+
+export const getNotificationSettings = (): Promise<NotificationSettings> => {
+  return invoke('get_notification_settings');
+};
+
+export const saveNotificationSettings = (settings: NotificationSettings): Promise<void> => {
+  return invoke('save_notification_settings', { settings });
+};
+
+/** Marks the messages of a new-mail notification read, or archives them, on the server */
+export const runNotificationAction = (
+  accountId: number,
+  messageIds: number[],
+  action: NotificationAction
+): Promise<void> => {
+  return invoke('run_notification_action', { accountId, messageIds, action });
+};
+
+export const getUnreadCount = (): Promise<UnreadCount> => {
+  return invoke('get_unread_count');
+};
+
+// ==================== Identities ====================
+
+export const listIdentities = (accountId: number): Promise<Identity[]> => {
+  return invoke('list_identities', { accountId });
+};
+
+/** A non-empty password is stored in the keyring for the identity's own SMTP server */
+export const saveIdentity = (
+  identity: Identity,
+  smtpPassword: string | null = null
+): Promise<number> => {
+  return invoke('save_identity', { identity, smtpPassword });
+};
+
+export const deleteIdentity = (identityId: number): Promise<void> => {
+  return invoke('delete_identity', { identityId });
+};
+
+export const getReplyIdentity = (messageId: number): Promise<Identity | null> => {
+  return invoke('get_reply_identity', { messageId });
+};
+
+// ==================== Signatures & Compose ====================
+
+/** Prepares a compose window with the signature inserted; `messageId` is the original */
+export const prepareCompose = (
+  accountId: number,
+  mode: ComposeMode,
+  messageId: number | null = null,
+  identityId: number | null = null
+): Promise<ComposeData> => {
+  return invoke('prepare_compose', { accountId, mode, messageId, identityId });
+};
+
+export const getSignatureSettings = (): Promise<SignatureSettings> => {
+  return invoke('get_signature_settings');
+};
+
+export const saveSignatureSettings = (settings: SignatureSettings): Promise<void> => {
+  return invoke('save_signature_settings', { settings });
+};
+
+// ==================== Templates ====================
+
+/** Templates for the account plus those shared by all accounts; all of them when null */
+export const listTemplates = (accountId: number | null = null): Promise<MessageTemplate[]> => {
+  return invoke('list_templates', { accountId });
+};
+
+export const saveTemplate = (template: MessageTemplate): Promise<number> => {
+  return invoke('save_template', { template });
+};
+
+export const deleteTemplate = (templateId: number): Promise<void> => {
+  return invoke('delete_template', { templateId });
+};
+
+export const addTemplateAttachment = (templateId: number, filePath: string): Promise<number> => {
+  return invoke('add_template_attachment', { templateId, filePath });
+};
+
+export const removeTemplateAttachment = (attachmentId: number): Promise<void> => {
+  return invoke('remove_template_attachment', { attachmentId });
+};
+
+export const getTemplatePlaceholders = (
+  templateId: number,
+  messageId: number | null = null
+): Promise<TemplatePlaceholder[]> => {
+  return invoke('get_template_placeholders', { templateId, messageId });
+};
+
+/** Creates a draft from a template, as a reply to `messageId` when given */
+export const createDraftFromTemplate = (
+  accountId: number,
+  templateId: number,
+  messageId: number | null,
+  values: Record<string, string>
+): Promise<Draft> => {
+  return invoke('create_draft_from_template', { accountId, templateId, messageId, values });
+};
+
+export const exportTemplates = (
+  destinationPath: string,
+  accountId: number | null = null
+): Promise<number> => {
+  return invoke('export_templates', { accountId, destinationPath });
+};
+
+export const importTemplates = (
+  sourcePath: string,
+  accountId: number | null = null
+): Promise<number> => {
+  return invoke('import_templates', { sourcePath, accountId });
+};
+
+export const listDraftAttachments = (draftId: number): Promise<DraftAttachment[]> => {
+  return invoke('list_draft_attachments', { draftId });
+};
+
+export const addDraftAttachment = (draftId: number, filePath: string): Promise<number> => {
+  return invoke('add_draft_attachment', { draftId, filePath });
+};
+
+export const removeDraftAttachment = (attachmentId: number): Promise<void> => {
+  return invoke('remove_draft_attachment', { attachmentId });
+};
+
 export const searchMessages = (accountId: number, query: string): Promise<MessageHeader[]> => {
   return invoke('search_messages', { accountId, query });
 };
@@ -83,6 +383,11 @@ export const saveDraft = (draft: Draft): Promise<number> => {
   return invoke('save_draft', { draft });
 };
 
+/** Uploads the draft's pending edits to the server when its editor closes */
+export const closeDraft = (draftId: number): Promise<void> => {
+  return invoke('close_draft', { draftId });
+};
+
 export const getDrafts = (accountId: number): Promise<Draft[]> => {
   return invoke('get_drafts', { accountId });
 };
@@ -91,6 +396,10 @@ export const deleteDraft = (draftId: number): Promise<void> => {
   return invoke('delete_draft', { draftId });
 };
 
+export const syncDrafts = (accountId: number): Promise<DraftSyncReport> => {
+  return invoke('sync_drafts', { accountId });
+};
+
 export const saveSignature = (signature: EmailSignature): Promise<number> => {
   return invoke('save_signature', { signature });
 };
@@ -222,3 +531,158 @@ export const getThreadMessages = (threadId: number): Promise<Message[]> => {
 export const getThread = (threadId: number): Promise<Thread> => {
   return invoke('get_thread', { threadId });
 };
+
+// ==================== Backup & Restore ====================
+
+export const createBackup = (
+  destinationPath: string,
+  passphrase: string,
+  includeCredentials: boolean
+): Promise<BackupManifest> => {
+  return invoke('create_backup', { destinationPath, passphrase, includeCredentials });
+};
+
+export const restoreBackup = (sourcePath: string, passphrase: string): Promise<BackupManifest> => {
+  return invoke('restore_backup', { sourcePath, passphrase });
+};
+
+// ==================== OpenPGP ====================
+
+export const importPgpKey = (
+  armoredKey: string,
+  passphrase: string | null = null
+): Promise<PgpKeyInfo> => {
+  return invoke('import_pgp_key', { armoredKey, passphrase });
+};
+
+export const listPgpKeys = (): Promise<PgpKeyInfo[]> => {
+  return invoke('list_pgp_keys');
+};
+
+export const exportPgpKey = (fingerprint: string, includeSecret: boolean): Promise<string> => {
+  return invoke('export_pgp_key', { fingerprint, includeSecret });
+};
+
+export const deletePgpKey = (fingerprint: string): Promise<void> => {
+  return invoke('delete_pgp_key', { fingerprint });
+};
+
+export const decryptMessage = (messageId: number, passphrase: string): Promise<Message> => {
+  return invoke('decrypt_message', { messageId, passphrase });
+};
+
+// ==================== S/MIME ====================
+
+export const importSmimeIdentity = (
+  pkcs12Path: string,
+  password: string
+): Promise<SmimeCertificateInfo> => {
+  return invoke('import_smime_identity', { pkcs12Path, password });
+};
+
+export const importSmimeCertificate = (
+  certificatePath: string,
+  trustAnchor: boolean
+): Promise<SmimeCertificateInfo> => {
+  return invoke('import_smime_certificate', { certificatePath, trustAnchor });
+};
+
+export const listSmimeCertificates = (): Promise<SmimeCertificateInfo[]> => {
+  return invoke('list_smime_certificates');
+};
+
+export const deleteSmimeCertificate = (fingerprint: string): Promise<void> => {
+  return invoke('delete_smime_certificate', { fingerprint });
+};
+
+// ==================== Sender Authentication ====================
+
+export const addDkimKey = (domain: string, selector: string, record: string): Promise<DkimKey> => {
+  return invoke('add_dkim_key', { domain, selector, record });
+};
+
+export const listDkimKeys = (): Promise<DkimKey[]> => {
+  return invoke('list_dkim_keys');
+};
+
+export const deleteDkimKey = (domain: string, selector: string): Promise<void> => {
+  return invoke('delete_dkim_key', { domain, selector });
+};
+
+// ==================== Remote Content ====================
+
+export const loadRemoteContent = (messageId: number): Promise<Message> => {
+  return invoke('load_remote_content', { messageId });
+};
+
+export const addRemoteContentException = (
+  kind: RemoteContentExceptionKind,
+  value: string
+): Promise<RemoteContentException> => {
+  return invoke('add_remote_content_exception', { kind, value });
+};
+
+export const listRemoteContentExceptions = (): Promise<RemoteContentException[]> => {
+  return invoke('list_remote_content_exceptions');
+};
+
+export const deleteRemoteContentException = (exceptionId: number): Promise<void> => {
+  return invoke('delete_remote_content_exception', { exceptionId });
+};
+
+// ==================== Database Encryption ====================
+
+export const getDatabaseEncryptionStatus = (): Promise<DatabaseEncryptionStatus> => {
+  return invoke('get_database_encryption_status');
+};
+
+export const enableDatabaseEncryption = (
+  mode: Exclude<DatabaseEncryptionMode, 'none'>,
+  passphrase: string | null = null
+): Promise<DatabaseEncryptionStatus> => {
+  return invoke('enable_database_encryption', { mode, passphrase });
+};
+
+export const unlockDatabase = (passphrase: string): Promise<void> => {
+  return invoke('unlock_database', { passphrase });
+};
+
+export const changeDatabasePassphrase = (
+  currentPassphrase: string | null,
+  newPassphrase: string
+): Promise<void> => {
+  return invoke('change_database_passphrase', { currentPassphrase, newPassphrase });
+};
+
+// ==================== App Lock ====================
+
+export const getAppLockStatus = (): Promise<AppLockStatus> => {
+  return invoke('get_app_lock_status');
+};
+
+export const setAppLockPassphrase = (
+  currentPassphrase: string | null,
+  newPassphrase: string
+): Promise<void> => {
+  return invoke('set_app_lock_passphrase', { currentPassphrase, newPassphrase });
+};
+
+export const disableAppLock = (passphrase: string): Promise<void> => {
+  return invoke('disable_app_lock', { passphrase });
+};
+
+export const setAppLockIdleTimeout = (minutes: number): Promise<void> => {
+  return invoke('set_app_lock_idle_timeout', { minutes });
+};
+
+export const lockApp = (): Promise<void> => {
+  return invoke('lock_app');
+};
+
+export const unlockApp = (passphrase: string): Promise<void> => {
+  return invoke('unlock_app', { passphrase });
+};
+
+export const recordUserActivity = (): Promise<void> => {
+  return invoke('record_user_activity');
+};
@@ -10,12 +10,14 @@ import {
   starMessage as apiStarMessage,
   unstarMessage as apiUnstarMessage,
   getStarredMessages,
+  getMessagesByTag,
   bulkMarkRead as apiBulkMarkRead,
   bulkMarkUnread as apiBulkMarkUnread,
   bulkDeleteMessages as apiBulkDeleteMessages,
   bulkStarMessages as apiBulkStarMessages,
   bulkUnstarMessages as apiBulkUnstarMessages,
   getThreadMessages,
+  snoozeMessage as apiSnoozeMessage,
 } from '../services/api';
 
 interface MailboxStore {
@@ -250,6 +252,20 @@ const createMailboxStore = () => {
     }
   };
 
+  const snoozeMessage = async (messageId: number, until: number) => {
+    await apiSnoozeMessage(messageId, until);
+    update((state) => {
+      const updatedMessages = state.messages.filter((msg) => msg.id !== messageId);
+      const updatedSelectedMessage =
+        state.selectedMessage?.id === messageId ? null : state.selectedMessage;
+      return {
+        ...state,
+        messages: updatedMessages,
+        selectedMessage: updatedSelectedMessage,
+      };
+    });
+  };
+
   const moveMessage = async (messageId: number, targetFolderId: number) => {
     try {
       await import('../services/api').then((api) => api.moveMessage(messageId, targetFolderId));
@@ -314,6 +330,28 @@ const createMailboxStore = () => {
     }
   };
 
+  const loadTaggedMessages = async (tag: string) => {
+    update((state) => ({ ...state, selectedFolder: null, loading: true, error: null }));
+    try {
+      const { selectedAccount } = await new Promise<MailboxStore>((resolve) => {
+        const unsub = subscribe((state) => {
+          resolve(state);
+          unsub();
+        });
+      });
+
+      if (!selectedAccount) {
+        update((state) => ({ ...state, loading: false }));
+        return;
+      }
+
+      const messages = await getMessagesByTag(selectedAccount.id, tag);
+      update((state) => ({ ...state, messages, hasMore: false, loading: false }));
+    } catch (error) {
+      update((state) => ({ ...state, error: String(error), loading: false }));
+    }
+  };
+
   const loadStarredMessages = async () => {
     update((state) => ({ ...state, loading: true, error: null }));
     try {
@@ -545,6 +583,31 @@ const createMailboxStore = () => {
     }
   };
 
+  // Reloads the first page of the selected folder, keeping the open message
+  const refreshFolder = async () => {
+    const { selectedFolder, pageSize } = await new Promise<MailboxStore>((resolve) => {
+      const unsub = subscribe((state) => {
+        resolve(state);
+        unsub();
+      });
+    });
+    if (!selectedFolder) return;
+
+    try {
+      const total = await countMessagesInFolder(selectedFolder.id);
+      const messages = await getMessagesPaginated(selectedFolder.id, pageSize, 0);
+      update((state) => ({
+        ...state,
+        messages,
+        totalMessages: total,
+        currentPage: 1,
+        hasMore: messages.length === pageSize && total > pageSize,
+      }));
+    } catch (error) {
+      update((state) => ({ ...state, error: String(error) }));
+    }
+  };
+
   return {
     subscribe,
     fetchAccounts,
@@ -557,9 +620,11 @@ const createMailboxStore = () => {
     searchInMessages,
     deleteMessage,
     moveMessage,
+    snoozeMessage,
     starMessage,
     unstarMessage,
     loadStarredMessages,
+    loadTaggedMessages,
     bulkMarkRead,
     bulkMarkUnread,
     bulkDelete,
@@ -568,6 +633,7 @@ const createMailboxStore = () => {
     loadThreadMessages,
     loadMoreMessages,
     selectFolderWithPagination,
+    refreshFolder,
   };
 };
 
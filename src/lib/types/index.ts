@@ -3,6 +3,7 @@ export interface Account {
   email_address: string;
   display_name: string;
   provider_type: string;
+  needs_reauth: boolean;
 }
 
 export interface Folder {
@@ -41,6 +42,72 @@ export interface Message {
   is_starred: boolean;
   thread_id: number | null;
   attachments: Attachment[];
+  security: MessageSecurity | null;
+  authentication: MessageAuthentication | null;
+  remote_content: RemoteContentReport | null;
+}
+
+export type SignatureStatus = 'none' | 'valid' | 'invalid' | 'unknown_key' | 'untrusted' | 'signer_mismatch';
+export type DecryptionStatus = 'none' | 'decrypted' | 'needs_passphrase' | 'no_key' | 'failed';
+
+export interface MessageSecurity {
+  message_id: number;
+  protocol: string;
+  is_signed: boolean;
+  signature_status: SignatureStatus;
+  signer: string | null;
+  signer_key_id: string | null;
+  is_encrypted: boolean;
+  decryption_status: DecryptionStatus;
+  details: string | null;
+}
+
+export type TrustVerdict = 'trusted' | 'unknown' | 'suspicious' | 'dangerous';
+export type SenderWarningKind =
+  | 'authentication_failed'
+  | 'display_name_spoof'
+  | 'lookalike_domain'
+  | 'reply_to_mismatch';
+
+export interface SenderWarning {
+  kind: SenderWarningKind;
+  message: string;
+}
+
+export interface MessageAuthentication {
+  message_id: number;
+  authserv_id: string | null;
+  spf: string | null;
+  dkim: string | null;
+  dmarc: string | null;
+  arc: string | null;
+  dkim_local: string | null;
+  dkim_domain: string | null;
+  verdict: TrustVerdict;
+  warnings: SenderWarning[];
+}
+
+export interface DkimKey {
+  domain: string;
+  selector: string;
+  record: string;
+  created_at: number;
+}
+
+export interface RemoteContentReport {
+  remote_allowed: boolean;
+  blocked_images: number;
+  blocked_styles: number;
+  tracking_pixels: string[];
+}
+
+export type RemoteContentExceptionKind = 'sender' | 'domain';
+
+export interface RemoteContentException {
+  id: number;
+  kind: RemoteContentExceptionKind;
+  value: string;
+  created_at: number;
 }
 
 export interface Attachment {
@@ -52,11 +119,287 @@ export interface Attachment {
   local_path: string | null;
 }
 
+export type SyncState = 'idle' | 'syncing' | 'error';
+
+export interface AccountSyncStatus {
+  account_id: number;
+  email_address: string;
+  state: SyncState;
+  last_attempt_at: number | null;
+  last_success_at: number | null;
+  last_error: string | null;
+  last_error_at: number | null;
+  messages_fetched: number;
+  consecutive_failures: number;
+}
+
+export interface SyncProgress {
+  account_id: number;
+  folder: string;
+  folders_done: number;
+  folders_total: number;
+}
+
+export interface AccountSchedule {
+  account_id: number;
+  /** Per-account override; null uses the default interval */
+  interval_minutes: number | null;
+  /** Unix timestamp; null when the account only syncs on request */
+  next_sync_at: number | null;
+}
+
+export interface SyncSchedule {
+  /** 0 syncs only on request */
+  default_interval_minutes: number;
+  online: boolean;
+  accounts: AccountSchedule[];
+}
+
+export type TextOperator = 'contains' | 'not_contains' | 'is' | 'starts_with' | 'ends_with';
+
+export type RuleCondition =
+  | { field: 'from' | 'to' | 'cc' | 'subject' | 'body' | 'list_id'; operator: TextOperator; value: string }
+  | { field: 'header'; name: string; operator: TextOperator; value: string }
+  | { field: 'has_attachment'; value: boolean }
+  | { field: 'size'; operator: 'larger_than' | 'smaller_than'; bytes: number };
+
+export type RuleAction =
+  | { type: 'move'; folder: string }
+  | { type: 'star' }
+  | { type: 'mark_read' }
+  | { type: 'delete' }
+  | { type: 'label'; label: string }
+  | { type: 'forward'; to: string }
+  | { type: 'run_later'; delay_minutes: number; action: RuleAction };
+
+export interface Rule {
+  id: number;
+  /** null applies the rule to every account */
+  account_id: number | null;
+  name: string;
+  enabled: boolean;
+  position: number;
+  /** All conditions must match, otherwise any one of them */
+  match_all: boolean;
+  stop_processing: boolean;
+  conditions: RuleCondition[];
+  actions: RuleAction[];
+}
+
+export interface RuleMatch {
+  message_id: number;
+  from_header: string;
+  subject: string;
+  date: number;
+}
+
+export interface SieveCapabilities {
+  implementation: string | null;
+  /** Sieve extensions such as fileinto or vacation */
+  extensions: string[];
+  sasl: string[];
+  starttls: boolean;
+  version: string | null;
+}
+
+export interface SieveScript {
+  name: string;
+  active: boolean;
+}
+
+export interface SieveOverview {
+  capabilities: SieveCapabilities;
+  scripts: SieveScript[];
+}
+
+export interface VacationSettings {
+  subject: string;
+  body: string;
+  /** Each sender gets the reply at most once in this many days */
+  days: number;
+  /** Other addresses of the account whose mail is answered too */
+  addresses: string[];
+  /** YYYY-MM-DD, inclusive */
+  start: string | null;
+  end: string | null;
+}
+
+export interface TagCount {
+  /** IMAP keyword or Gmail label */
+  tag: string;
+  /** What to show, such as "Work" for $Label2 */
+  name: string;
+  count: number;
+  unread: number;
+}
+
+export type ReminderKind = 'snooze' | 'follow_up';
+
+export interface Reminder {
+  id: number;
+  message_id: number;
+  account_id: number;
+  kind: ReminderKind;
+  /** Unix timestamp */
+  due_at: number;
+  created_at: number;
+  subject: string;
+  from_header: string;
+  to_header: string;
+}
+
+export type NotifyMode = 'all' | 'vip_only' | 'off';
+
+export interface QuietHours {
+  /** Local time in minutes after midnight; wraps past midnight when later than end */
+  start: number;
+  end: number;
+}
+
+export interface NotificationSettings {
+  enabled: boolean;
+  /** Accounts not listed notify for all mail */
+  accounts: Record<number, NotifyMode>;
+  /** Folders not listed notify only when they are an inbox */
+  folders: Record<number, boolean>;
+  vip_senders: string[];
+  quiet_hours: QuietHours | null;
+}
+
+export interface MailNotification {
+  account_id: number;
+  title: string;
+  body: string;
+  message_ids: number[];
+}
+
+export type NotificationAction = 'mark_read' | 'archive';
+
+export interface UnreadCount {
+  total: number;
+  accounts: { account_id: number; unread: number }[];
+}
+
+export interface IdentitySmtp {
+  host: string;
+  port: number;
+  security: ServerSecurity;
+  /** The identity's address is used when empty */
+  username: string;
+}
+
+export interface Identity {
+  id: number;
+  account_id: number;
+  email_address: string;
+  display_name: string;
+  reply_to: string | null;
+  signature_id: number | null;
+  /** Sends through the account's provider when null */
+  smtp: IdentitySmtp | null;
+  is_default: boolean;
+}
+
+export type SignaturePlacement = 'above_quote' | 'below_quote';
+
+export interface SignatureSettings {
+  placement: SignaturePlacement;
+}
+
+export type ComposeMode = 'new' | 'reply' | 'reply_all' | 'forward';
+
+export interface ComposeData {
+  to: string;
+  cc: string | null;
+  subject: string;
+  /** Includes the signature and any quote */
+  body: string;
+  identity_id: number | null;
+}
+
+export interface TemplateAttachment {
+  id: number;
+  template_id: number;
+  filename: string;
+  mime_type: string;
+  size_bytes: number;
+}
+
+export interface MessageTemplate {
+  id: number;
+  /** Offered for every account when null */
+  account_id: number | null;
+  name: string;
+  subject: string;
+  body: string;
+  attachments: TemplateAttachment[];
+  updated_at: number;
+}
+
+export interface TemplatePlaceholder {
+  name: string;
+  /** Known from the message being replied to */
+  value: string | null;
+}
+
+export interface DraftAttachment {
+  id: number;
+  draft_id: number;
+  filename: string;
+  mime_type: string;
+  size_bytes: number;
+}
+
 export interface OAuthProviderConfig {
   client_id: string;
   client_secret: string;
 }
 
+export interface OAuthProviderInfo {
+  client_id: string;
+  has_client_secret: boolean;
+}
+
+export type ServerSecurity = 'tls' | 'starttls';
+
+export interface ServerEndpoint {
+  host: string;
+  port: number;
+  security: ServerSecurity;
+}
+
+export interface DeviceAuthorization {
+  user_code: string;
+  verification_uri: string;
+  verification_uri_complete: string | null;
+  expires_in: number;
+}
+
+export type SignInPrompt =
+  | { kind: 'browser'; auth_url: string }
+  | ({ kind: 'device_code' } & DeviceAuthorization);
+
+export interface ProviderDefinition {
+  id: string;
+  name: string;
+  issuer: string | null;
+  auth_url: string;
+  token_url: string;
+  userinfo_url: string | null;
+  device_auth_url: string | null;
+  email_claims: string[];
+  name_claims: string[];
+  scopes: string[];
+  auth_params: Record<string, string>;
+  imap: ServerEndpoint;
+  smtp: ServerEndpoint;
+  /** ManageSieve server for server-side filters, if the provider has one */
+  sieve?: ServerEndpoint | null;
+  domains: string[];
+  mx_patterns: string[];
+  builtin: boolean;
+}
+
 export interface Draft {
   id: number;
   account_id: number;
@@ -68,6 +411,18 @@ export interface Draft {
   body_html: string | null;
   created_at: number;
   updated_at: number;
+  /** The revision the editor loaded; a newer version from the server is not overwritten */
+  revision: number;
+  /** The version from another device, kept next to a local edit made at the same time */
+  conflict: boolean;
+}
+
+export interface DraftSyncReport {
+  account_id: number;
+  uploaded: number;
+  imported: number;
+  removed: number;
+  conflicts: number;
 }
 
 export interface EmailSignature {
@@ -102,3 +457,57 @@ export interface Thread {
   created_at: number;
   updated_at: number;
 }
+
+export interface BackupManifest {
+  app_version: string;
+  schema_version: number;
+  created_at: number;
+  account_count: number;
+  includes_config: boolean;
+  includes_credentials: boolean;
+}
+
+export interface PgpKeyInfo {
+  id: number;
+  fingerprint: string;
+  key_id: string;
+  user_ids: string[];
+  primary_email: string | null;
+  has_secret: boolean;
+  created_at: number;
+}
+
+export interface OutgoingSecurity {
+  protocol: string;
+  sign: boolean;
+  encrypt: boolean;
+  passphrase: string | null;
+}
+
+export interface SmimeCertificateInfo {
+  id: number;
+  fingerprint: string;
+  email: string | null;
+  subject: string;
+  issuer: string;
+  not_after: number;
+  has_private_key: boolean;
+  is_trust_anchor: boolean;
+  created_at: number;
+}
+
+export type DatabaseEncryptionMode = 'none' | 'keyring' | 'passphrase';
+
+export interface DatabaseEncryptionStatus {
+  supported: boolean;
+  mode: DatabaseEncryptionMode;
+  encrypted: boolean;
+  locked: boolean;
+  restart_required: boolean;
+}
+
+export interface AppLockStatus {
+  enabled: boolean;
+  locked: boolean;
+  idle_timeout_minutes: number;
+}